@@ -0,0 +1,15 @@
+struct VsOut {
+    float4 position: SV_Position;
+    [[vk::location(0)]] float2 uv: TEXCOORD0;
+};
+
+// A single triangle covering the whole viewport; no vertex buffers needed.
+VsOut main(uint vid: SV_VertexID) {
+    const float2 pos = float2(vid == 1 ? 3.0 : -1.0, vid == 2 ? 3.0 : -1.0);
+
+    VsOut vsout;
+    vsout.position = float4(pos, 0.0, 1.0);
+    // The graph's viewports are flipped, so +y in clip space is the top of the image
+    vsout.uv = float2(pos.x + 1.0, 1.0 - pos.y) * 0.5;
+    return vsout;
+}
@@ -0,0 +1,51 @@
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] RWTexture2D<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    uint2 input_extent;
+    uint2 output_extent;
+    uint filter_mode;
+};
+
+#define FILTER_AVERAGE 0
+#define FILTER_MIN 1
+#define FILTER_MAX 2
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    if (any(px >= output_extent)) {
+        return;
+    }
+
+    // Output extents are floored, so for odd inputs the last output texel
+    // also covers the trailing input row/column.
+    const uint2 src_min = px * input_extent / output_extent;
+    const uint2 src_max = ((px + 1) * input_extent + output_extent - 1) / output_extent;
+
+    float4 res = input_tex[src_min];
+    uint count = 1;
+
+    for (uint y = src_min.y; y < src_max.y; ++y) {
+        for (uint x = src_min.x; x < src_max.x; ++x) {
+            if (x == src_min.x && y == src_min.y) {
+                continue;
+            }
+
+            const float4 val = input_tex[uint2(x, y)];
+
+            if (FILTER_MIN == filter_mode) {
+                res = min(res, val);
+            } else if (FILTER_MAX == filter_mode) {
+                res = max(res, val);
+            } else {
+                res += val;
+            }
+            ++count;
+        }
+    }
+
+    if (FILTER_AVERAGE == filter_mode) {
+        res /= count;
+    }
+
+    output_tex[px] = res;
+}
@@ -0,0 +1,11 @@
+[[vk::binding(0)]] RWTexture2D<float4> output_tex;
+[[vk::binding(1)]] cbuffer _ {
+    float4 output_tex_size;
+};
+
+// Fills secondary windows, e.g. the debug view of `kajiya-simple`.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float2 uv = (px + 0.5) * output_tex_size.zw;
+    output_tex[px] = float4(uv, 1.0 - uv.x, 1);
+}
@@ -3,7 +3,7 @@ use kajiya::RenderOverrideFlags;
 use kajiya_simple::*;
 
 use crate::{
-    runtime::{RuntimeState, MAX_FPS_LIMIT},
+    runtime::{RuntimeState, MAX_FPS_LIMIT, MB},
     PersistedState,
 };
 
@@ -14,9 +14,23 @@ impl RuntimeState {
         }
 
         ctx.world_renderer.rg_debug_hook = self.locked_rg_debug_hook.clone();
+        self.gpu_pass_timings
+            .push(kajiya::rg::RenderGraphStatistics::latest());
 
         if self.show_gui {
+            let shader_compile_errors = &ctx.shader_compile_errors;
+            let memory_heaps = ctx.world_renderer.device().physical_device().memory_heaps();
+
             ctx.imgui.take().unwrap().frame(|ui| {
+                if !shader_compile_errors.is_empty() {
+                    let style = ui.push_style_color(imgui::StyleColor::Text, [1.0, 0.2, 0.2, 1.0]);
+                    for err in shader_compile_errors {
+                        ui.text(err.to_string());
+                    }
+                    style.pop(ui);
+                    ui.separator();
+                }
+
                 if imgui::CollapsingHeader::new(im_str!("Tweaks"))
                     .default_open(true)
                     .build(ui)
@@ -414,15 +428,54 @@ impl RuntimeState {
                     .default_open(true)
                     .build(ui)
                 {
-                    let gpu_stats = gpu_profiler::get_stats();
+                    let mut gpu_stats = self.gpu_pass_timings.average();
                     ui.text(format!("CPU frame time: {:.3}ms", ctx.dt_filtered * 1000.0));
+                    ui.text(format!("GPU frame time: {:.3}ms", gpu_stats.total_ms()));
+                    ui.text(format!("GPU barriers: {:.3}ms", gpu_stats.barriers_ms));
+
+                    let rg_cpu_stats = &ctx.render_graph_cpu_stats;
+                    if rg_cpu_stats.retained {
+                        ui.text(format!(
+                            "Render graph: retained, {:.3}ms saved",
+                            rg_cpu_stats.saved_ms()
+                        ));
+                    } else {
+                        ui.text(format!(
+                            "Render graph build: {:.3}ms",
+                            rg_cpu_stats.build_ms
+                        ));
+                    }
 
-                    let ordered_scopes = gpu_stats.get_ordered();
-                    let gpu_time_ms: f64 = ordered_scopes.iter().map(|(_, ms)| ms).sum();
-
-                    ui.text(format!("GPU frame time: {:.3}ms", gpu_time_ms));
+                    let descriptor_stats = ctx.world_renderer.device().descriptor_allocator_stats();
+                    ui.text(format!(
+                        "Descriptor sets: {} ({} pools)",
+                        descriptor_stats.sets_allocated, descriptor_stats.pools_created
+                    ));
+
+                    let layout_stats = ctx.world_renderer.device().layout_cache_stats();
+                    ui.text(format!(
+                        "Pipeline layouts: {} ({} set layouts)",
+                        layout_stats.pipeline_layouts, layout_stats.descriptor_set_layouts
+                    ));
+
+                    let transient_stats = &ctx.transient_resource_stats;
+                    ui.text(format!(
+                        "Transient images: {} created, {} pooled",
+                        transient_stats.images_created, transient_stats.pooled_images
+                    ));
+                    ui.text(format!(
+                        "Transient buffers: {} created, {} pooled",
+                        transient_stats.buffers_created, transient_stats.pooled_buffers
+                    ));
+
+                    ui.checkbox(im_str!("Sort by cost"), &mut self.sort_gpu_passes_by_cost);
+                    if self.sort_gpu_passes_by_cost {
+                        gpu_stats.passes.sort_by(|(_, a), (_, b)| {
+                            b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal)
+                        });
+                    }
 
-                    for (scope, ms) in ordered_scopes {
+                    for (scope, ms) in gpu_stats.passes {
                         if scope.name == "debug" || scope.name.starts_with('_') {
                             continue;
                         }
@@ -438,7 +491,11 @@ impl RuntimeState {
                             }
                         });
 
-                        ui.text(format!("{}: {:.3}ms", scope.name, ms));
+                        if gpu_stats.skipped_passes.contains(&scope) {
+                            ui.text_disabled(format!("{}: skipped", scope.name));
+                        } else {
+                            ui.text(format!("{}: {:.3}ms", scope.name, ms));
+                        }
 
                         if let Some(style) = style {
                             style.pop(ui);
@@ -460,6 +517,46 @@ impl RuntimeState {
                         }
                     }
                 }
+
+                if imgui::CollapsingHeader::new(im_str!("Transient memory")).build(ui) {
+                    let memory = &self.gpu_pass_timings.latest().transient_memory;
+                    ui.text(format!("Peak: {:.1} MB", memory.peak_bytes as f64 / MB));
+                    ui.text(format!(
+                        "Without aliasing: {:.1} MB",
+                        memory.total_bytes as f64 / MB
+                    ));
+                    ui.separator();
+
+                    let mut resources: Vec<_> = memory.resources.iter().collect();
+                    resources.sort_by_key(|res| std::cmp::Reverse(res.size_bytes));
+
+                    for res in resources {
+                        let passes = res.passes.map_or("unused".to_owned(), |(first, last)| {
+                            format!("passes {}..={}", first, last)
+                        });
+                        ui.text(format!(
+                            "{}: {:.2} MB, {}",
+                            res.name,
+                            res.size_bytes as f64 / MB,
+                            passes
+                        ));
+                    }
+                }
+
+                if imgui::CollapsingHeader::new(im_str!("GPU memory heaps")).build(ui) {
+                    for heap in &memory_heaps.heaps {
+                        ui.text(format!(
+                            "Heap {}: {:.1} MB, {:?}",
+                            heap.index,
+                            heap.size as f64 / MB,
+                            heap.flags
+                        ));
+
+                        for (type_idx, property_flags) in &heap.memory_types {
+                            ui.text(format!("    Type {}: {:?}", type_idx, property_flags));
+                        }
+                    }
+                }
             });
         }
     }
@@ -11,6 +11,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use anyhow::Context as _;
 use kajiya_simple::*;
 use opt::*;
 use persisted::*;
@@ -34,6 +35,12 @@ impl AppState {
             .temporal_upsampling(opt.temporal_upsampling)
             .default_log_level(log::LevelFilter::Info)
             .fullscreen(opt.fullscreen.then(|| FullscreenMode::Exclusive))
+            .secondary_window(opt.debug_window.then(|| {
+                WindowBuilder::new()
+                    .with_title("kajiya debug view")
+                    .with_inner_size(winit::dpi::LogicalSize::new(640.0, 360.0))
+                    .with_resizable(false)
+            }))
             .build(
                 WindowBuilder::new()
                     .with_title("kajiya")
@@ -41,6 +48,8 @@ impl AppState {
                     .with_decorations(!opt.no_window_decorations),
             )?;
 
+        kajiya.world_renderer.rg_graphviz_dump_path = opt.dump_rg.clone();
+
         let runtime = RuntimeState::new(&mut persisted, &mut kajiya.world_renderer, opt);
 
         Ok(Self {
@@ -84,6 +93,49 @@ impl AppState {
     }
 }
 
+/// Renders the persisted view of the scene offscreen, and writes it to `opt.output`.
+fn render_headless(mut persisted: PersistedState, opt: &Opt) -> anyhow::Result<()> {
+    let mut kajiya = SimpleMainLoop::builder()
+        .resolution([opt.width, opt.height])
+        .graphics_debugging(opt.graphics_debugging)
+        .physical_device_index(opt.physical_device_index)
+        .temporal_upsampling(opt.temporal_upsampling)
+        .default_log_level(log::LevelFilter::Info)
+        .build_headless()?;
+
+    kajiya.world_renderer.rg_graphviz_dump_path = opt.dump_rg.clone();
+
+    let mut runtime = RuntimeState::new(&mut persisted, &mut kajiya.world_renderer, opt);
+
+    if let Some(scene) = opt.scene.as_ref() {
+        runtime.load_scene(&mut persisted, &mut kajiya.world_renderer, scene)?;
+    } else if let Some(mesh) = opt.mesh.as_ref() {
+        runtime.add_mesh_instance(
+            &mut persisted,
+            &mut kajiya.world_renderer,
+            MeshSource::File(mesh.clone()),
+            SceneElementTransform {
+                position: Vec3::ZERO,
+                rotation_euler_degrees: Vec3::ZERO,
+                scale: Vec3::splat(opt.mesh_scale),
+            },
+        )?;
+    }
+
+    let frame_desc = RuntimeState::headless_frame(
+        &persisted,
+        &mut kajiya.world_renderer,
+        kajiya.render_extent(),
+    );
+    let data = kajiya.render_to_image(&frame_desc, opt.headless_frames)?;
+
+    kajiya::frame_capture::write_image(&opt.output, &kajiya.output_desc(), &data)
+        .with_context(|| format!("Writing {:?}", opt.output))?;
+    println!("Wrote {:?}", opt.output);
+
+    Ok(())
+}
+
 const APP_STATE_CONFIG_FILE_PATH: &str = "view_state.ron";
 
 fn main() -> anyhow::Result<()> {
@@ -101,6 +153,10 @@ fn main() -> anyhow::Result<()> {
         persisted.scene = SceneState::default();
     }
 
+    if opt.headless {
+        return render_headless(persisted, &opt);
+    }
+
     let mut state = AppState::new(persisted, &opt)?;
 
     if let Some(scene) = opt.scene.as_ref() {
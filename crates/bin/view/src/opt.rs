@@ -35,6 +35,30 @@ pub struct Opt {
     #[structopt(long)]
     pub graphics_debugging: bool,
 
+    /// Open a second window next to the main view, rendered on the same device
+    #[structopt(long)]
+    pub debug_window: bool,
+
     #[structopt(long)]
     pub physical_device_index: Option<usize>,
+
+    /// Write the first frame's render graph to this file, in the Graphviz DOT format
+    #[structopt(long)]
+    pub dump_rg: Option<PathBuf>,
+
+    /// Render offscreen without a window, write the result to `--output`, and exit
+    #[structopt(long)]
+    pub headless: bool,
+
+    /// The image written in `--headless` mode
+    #[structopt(long, default_value = "frame.png")]
+    pub output: PathBuf,
+
+    /// Frames rendered in `--headless` mode before writing the output, letting temporal effects converge
+    #[structopt(long, default_value = "16")]
+    pub headless_frames: usize,
+
+    /// Log the transient memory of the render graph whenever it changes
+    #[structopt(long)]
+    pub print_rg_memory: bool,
 }
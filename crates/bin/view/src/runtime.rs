@@ -4,7 +4,7 @@ use anyhow::Context;
 
 use dolly::prelude::*;
 use kajiya::{
-    rg::GraphDebugHook,
+    rg::{GraphDebugHook, RenderGraphStatisticsHistory},
     world_renderer::{AddMeshOptions, MeshHandle, WorldRenderer},
 };
 use kajiya_simple::*;
@@ -26,6 +26,11 @@ use std::{
 
 pub const MAX_FPS_LIMIT: u32 = 256;
 
+pub const MB: f64 = (1024 * 1024) as f64;
+
+/// Number of frames GPU pass timings are averaged over in the GUI
+const GPU_PASS_TIMING_FRAMES: usize = 30;
+
 pub struct RuntimeState {
     pub camera: CameraRig,
     pub mouse: MouseState,
@@ -38,6 +43,11 @@ pub struct RuntimeState {
 
     pub max_fps: u32,
     pub locked_rg_debug_hook: Option<GraphDebugHook>,
+    pub gpu_pass_timings: RenderGraphStatisticsHistory,
+    pub sort_gpu_passes_by_cost: bool,
+    print_rg_memory: bool,
+    /// Peak and total bytes last logged by `print_rg_memory`
+    printed_rg_memory: Option<(u64, u64)>,
     pub grab_cursor_pos: winit::dpi::PhysicalPosition<f64>,
 
     pub reset_path_tracer: bool,
@@ -61,7 +71,7 @@ impl RuntimeState {
     pub fn new(
         persisted: &mut PersistedState,
         world_renderer: &mut WorldRenderer,
-        _opt: &Opt,
+        opt: &Opt,
     ) -> Self {
         let camera: CameraRig = CameraRig::builder()
             .with(Position::new(persisted.camera.position))
@@ -107,6 +117,10 @@ impl RuntimeState {
 
             max_fps: MAX_FPS_LIMIT,
             locked_rg_debug_hook: None,
+            gpu_pass_timings: RenderGraphStatisticsHistory::new(GPU_PASS_TIMING_FRAMES),
+            sort_gpu_passes_by_cost: false,
+            print_rg_memory: opt.print_rg_memory,
+            printed_rg_memory: None,
             grab_cursor_pos: Default::default(),
 
             reset_path_tracer: false,
@@ -411,6 +425,34 @@ impl RuntimeState {
         }
     }
 
+    fn log_rg_memory(&mut self) {
+        let memory = &self.gpu_pass_timings.latest().transient_memory;
+        let summary = Some((memory.peak_bytes, memory.total_bytes));
+        if memory.resources.is_empty() || self.printed_rg_memory == summary {
+            return;
+        }
+        self.printed_rg_memory = summary;
+
+        log::info!(
+            "Render graph transient memory: {:.1} MB peak, {:.1} MB without aliasing",
+            memory.peak_bytes as f64 / MB,
+            memory.total_bytes as f64 / MB
+        );
+        for res in &memory.resources {
+            log::info!(
+                "  {}: {:.2} MB, passes {}, {:?}",
+                res.name,
+                res.size_bytes as f64 / MB,
+                res.passes
+                    .map_or("none".to_owned(), |(first, last)| format!(
+                        "{}..={}",
+                        first, last
+                    )),
+                res.desc
+            );
+        }
+    }
+
     pub fn frame(
         &mut self,
         mut ctx: FrameContext,
@@ -431,6 +473,9 @@ impl RuntimeState {
         let orig_render_overrides = ctx.world_renderer.render_overrides;
 
         self.do_gui(persisted, &mut ctx);
+        if self.print_rg_memory {
+            self.log_rg_memory();
+        }
         self.update_lights(persisted, &mut ctx);
         self.update_objects(persisted, &mut ctx);
         self.update_sun(persisted, &mut ctx);
@@ -443,6 +488,12 @@ impl RuntimeState {
             self.add_sequence_keyframe(persisted);
         }
 
+        if self.keyboard.was_just_pressed(VirtualKeyCode::F12) {
+            let dir = kajiya::frame_capture::timestamped_capture_dir();
+            log::info!("Capturing the frame to {:?}", dir);
+            ctx.world_renderer.rg_frame_capture_dir = Some(dir);
+        }
+
         if self.keyboard.was_just_pressed(VirtualKeyCode::P) {
             match self.sequence_playback_state {
                 SequencePlaybackState::NotPlaying => {
@@ -454,15 +505,7 @@ impl RuntimeState {
             };
         }
 
-        ctx.world_renderer.ev_shift = persisted.exposure.ev_shift;
-        ctx.world_renderer.contrast = persisted.exposure.contrast;
-        ctx.world_renderer.dynamic_exposure.enabled = persisted.exposure.use_dynamic_adaptation;
-        ctx.world_renderer.dynamic_exposure.speed_log2 =
-            persisted.exposure.dynamic_adaptation_speed;
-        ctx.world_renderer.dynamic_exposure.histogram_clipping.low =
-            persisted.exposure.dynamic_adaptation_low_clip;
-        ctx.world_renderer.dynamic_exposure.histogram_clipping.high =
-            persisted.exposure.dynamic_adaptation_high_clip;
+        Self::apply_exposure(persisted, ctx.world_renderer);
 
         if persisted.should_reset_path_tracer(&orig_persisted_state)
             || ctx.world_renderer.render_overrides != orig_render_overrides
@@ -495,6 +538,39 @@ impl RuntimeState {
         }
     }
 
+    fn apply_exposure(persisted: &PersistedState, world_renderer: &mut WorldRenderer) {
+        world_renderer.ev_shift = persisted.exposure.ev_shift;
+        world_renderer.contrast = persisted.exposure.contrast;
+        world_renderer.dynamic_exposure.enabled = persisted.exposure.use_dynamic_adaptation;
+        world_renderer.dynamic_exposure.speed_log2 = persisted.exposure.dynamic_adaptation_speed;
+        world_renderer.dynamic_exposure.histogram_clipping.low =
+            persisted.exposure.dynamic_adaptation_low_clip;
+        world_renderer.dynamic_exposure.histogram_clipping.high =
+            persisted.exposure.dynamic_adaptation_high_clip;
+    }
+
+    /// A frame from the persisted camera and sun, without any input or smoothing.
+    pub fn headless_frame(
+        persisted: &PersistedState,
+        world_renderer: &mut WorldRenderer,
+        render_extent: [u32; 2],
+    ) -> WorldFrameDesc {
+        Self::apply_exposure(persisted, world_renderer);
+        world_renderer.sun_size_multiplier = persisted.light.sun.size_multiplier;
+
+        let lens = CameraLens {
+            aspect_ratio: render_extent[0] as f32 / render_extent[1] as f32,
+            vertical_fov: persisted.camera.vertical_fov,
+            ..Default::default()
+        };
+
+        WorldFrameDesc {
+            camera_matrices: (persisted.camera.position, persisted.camera.rotation).through(&lens),
+            render_extent,
+            sun_direction: persisted.light.sun.controller.towards_sun(),
+        }
+    }
+
     pub fn is_sequence_playing(&self) -> bool {
         matches!(
             &self.sequence_playback_state,
@@ -65,6 +65,24 @@ impl DynamicConstants {
         buffer_offset as _
     }
 
+    /// Copies `data` into the current frame's region, e.g. as the source of a buffer upload.
+    /// Returns `None` if it doesn't fit.
+    pub fn try_push_bytes(&mut self, data: &[u8]) -> Option<u32> {
+        if self.frame_offset_bytes + data.len() > DYNAMIC_CONSTANTS_SIZE_BYTES {
+            return None;
+        }
+
+        let buffer_offset = self.current_offset() as usize;
+        self.buffer.allocation.mapped_slice_mut().unwrap()
+            [buffer_offset..buffer_offset + data.len()]
+            .copy_from_slice(data);
+
+        self.frame_offset_bytes +=
+            (data.len() + DYNAMIC_CONSTANTS_ALIGNMENT - 1) & !(DYNAMIC_CONSTANTS_ALIGNMENT - 1);
+
+        Some(buffer_offset as _)
+    }
+
     pub fn push_from_iter<T: Copy, Iter: Iterator<Item = T>>(&mut self, iter: Iter) -> u32 {
         let t_size = size_of::<T>();
         let t_align = align_of::<T>();
@@ -1,11 +1,15 @@
+use crate::vulkan::physical_device::MemoryHeapsInfo;
 use backtrace::Backtrace as Bt;
 
 #[derive(Debug, thiserror::Error)]
 pub enum BackendError {
-    #[error("Allocation failed for {name:?}: {inner:?}")]
+    #[error("Allocation failed for {name:?}: {inner:?}; requested {size} bytes with memory type bits {memory_type_bits:#b}. Memory heaps:\n{memory_heaps}")]
     Allocation {
         inner: gpu_allocator::AllocationError,
         name: String,
+        size: u64,
+        memory_type_bits: u32,
+        memory_heaps: MemoryHeapsInfo,
     },
 
     #[error("Vulkan error: {err:?}; {trace:?}")]
@@ -13,6 +17,16 @@ pub enum BackendError {
 
     #[error("Invalid resource access: {info:?}")]
     ResourceAccess { info: String },
+
+    #[error("Invalid image {field}: {info}")]
+    InvalidImageDesc { field: &'static str, info: String },
+
+    #[error("Descriptor set {set} binding {binding} doesn't match the shader: {info}")]
+    DescriptorBinding {
+        set: u32,
+        binding: u32,
+        info: String,
+    },
 }
 
 impl From<ash::vk::Result> for BackendError {
@@ -4,7 +4,11 @@ use hotwatch::Hotwatch;
 use lazy_static::lazy_static;
 use normpath::PathExt;
 use parking_lot::Mutex;
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
 use turbosloth::*;
 
 lazy_static! {
@@ -12,6 +16,23 @@ lazy_static! {
         Mutex::new(Hotwatch::new_with_custom_delay(std::time::Duration::from_millis(100)).unwrap());
 }
 
+lazy_static! {
+    static ref FILE_CHANGE_LISTENERS: Mutex<Vec<Box<dyn Fn(&Path) -> bool + Send>>> =
+        Default::default();
+}
+
+/// Calls `listener` with the canonical path of every loaded file which gets modified or removed.
+/// The listener is dropped once it returns `false`.
+pub fn add_file_change_listener(listener: impl Fn(&Path) -> bool + Send + 'static) {
+    FILE_CHANGE_LISTENERS.lock().push(Box::new(listener));
+}
+
+fn notify_file_change(path: &Path) {
+    FILE_CHANGE_LISTENERS
+        .lock()
+        .retain(|listener| listener(path));
+}
+
 lazy_static! {
     static ref VFS_MOUNT_POINTS: Mutex<HashMap<String, PathBuf>> = Mutex::new(
         vec![
@@ -123,12 +144,19 @@ impl LazyWorker for LoadFile {
 
     async fn run(self, ctx: RunContext) -> Self::Output {
         let invalidation_trigger = ctx.get_invalidation_trigger();
+        let path = self.path.clone();
 
         FILE_WATCHER
             .lock()
             .watch(self.path.clone(), move |event| {
-                if matches!(event, hotwatch::Event::Write(_)) {
+                if matches!(
+                    event,
+                    hotwatch::Event::Write(_)
+                        | hotwatch::Event::Remove(_)
+                        | hotwatch::Event::Rename(_, _)
+                ) {
                     invalidation_trigger();
+                    notify_file_change(&path);
                 }
             })
             .with_context(|| format!("LoadFile: trying to watch {:?}", self.path))?;
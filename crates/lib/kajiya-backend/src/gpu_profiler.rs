@@ -18,7 +18,7 @@ impl Default for GpuProfilerQueryId {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct RenderScopeDesc {
     pub name: String,
     pub id: u64,
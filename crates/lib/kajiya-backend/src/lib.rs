@@ -7,6 +7,7 @@ pub mod gpu_profiler;
 pub mod pipeline_cache;
 pub mod rust_shader_compiler;
 pub mod shader_compiler;
+pub mod shader_dependencies;
 pub mod transient_resource_cache;
 pub mod vulkan;
 
@@ -1,14 +1,17 @@
 use crate::{
     rust_shader_compiler::CompileRustShader,
-    shader_compiler::{CompileShader, CompiledShader},
+    shader_compiler::{CompileShader, CompiledShader, ShaderCompileError},
+    shader_dependencies::ShaderDependencyGraph,
     vulkan::{
         ray_tracing::{create_ray_tracing_pipeline, RayTracingPipeline, RayTracingPipelineDesc},
         shader::*,
     },
 };
+use anyhow::Context as _;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{collections::HashMap, sync::Arc};
+use parking_lot::Mutex;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use turbosloth::*;
 
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
@@ -26,10 +29,25 @@ pub struct RasterPipelineHandle(usize);
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
 pub struct RtPipelineHandle(usize);
 
+#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+enum AnyPipelineHandle {
+    Compute(ComputePipelineHandle),
+    Raster(RasterPipelineHandle),
+    Rt(RtPipelineHandle),
+}
+
 pub struct CompiledPipelineShaders {
     shaders: Vec<PipelineShader<Arc<CompiledShader>>>,
 }
 
+impl CompiledPipelineShaders {
+    fn dependencies(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.shaders
+            .iter()
+            .flat_map(|shader| shader.code.dependencies.iter().cloned())
+    }
+}
+
 #[derive(Clone, Hash)]
 pub struct CompilePipelineShaders {
     shader_descs: Vec<PipelineShaderDesc>,
@@ -51,6 +69,9 @@ impl LazyWorker for CompilePipelineShaders {
                     path: path.clone(),
                     profile: match desc.stage {
                         ShaderPipelineStage::Vertex => "vs".to_owned(),
+                        ShaderPipelineStage::TessControl => "hs".to_owned(),
+                        ShaderPipelineStage::TessEval => "ds".to_owned(),
+                        ShaderPipelineStage::Geometry => "gs".to_owned(),
                         ShaderPipelineStage::Pixel => "ps".to_owned(),
                         ShaderPipelineStage::RayGen
                         | ShaderPipelineStage::RayMiss
@@ -95,13 +116,37 @@ pub struct PipelineCache {
     raster_entries: HashMap<RasterPipelineHandle, RasterPipelineCacheEntry>,
     rt_entries: HashMap<RtPipelineHandle, RtPipelineCacheEntry>,
 
-    compute_shader_to_handle: HashMap<ShaderSource, ComputePipelineHandle>,
-    raster_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RasterPipelineHandle>,
+    compute_shader_to_handle:
+        HashMap<(ShaderSource, String, Option<SpecializationData>), ComputePipelineHandle>,
+    raster_shaders_to_handle:
+        HashMap<(Vec<PipelineShaderDesc>, RasterPipelineStateKey), RasterPipelineHandle>,
     rt_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RtPipelineHandle>,
+
+    dependencies: ShaderDependencyGraph<AnyPipelineHandle>,
+    // Filled in by the file watcher
+    changed_files: Arc<Mutex<Vec<PathBuf>>>,
+    // Pipelines being recompiled in the background after a source change
+    reloads: HashMap<AnyPipelineHandle, smol::Task<anyhow::Result<CompileTaskOutput>>>,
+    // Failed reloads, whose pipelines keep using the last version which built
+    compile_errors: HashMap<AnyPipelineHandle, ShaderCompileError>,
 }
 
 impl PipelineCache {
     pub fn new(lazy_cache: &Arc<LazyCache>) -> Self {
+        let changed_files: Arc<Mutex<Vec<PathBuf>>> = Default::default();
+
+        {
+            let changed_files = Arc::downgrade(&changed_files);
+            crate::file::add_file_change_listener(move |path| {
+                if let Some(changed_files) = changed_files.upgrade() {
+                    changed_files.lock().push(path.to_owned());
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
         Self {
             lazy_cache: lazy_cache.clone(),
 
@@ -113,12 +158,22 @@ impl PipelineCache {
 
             raster_shaders_to_handle: Default::default(),
             rt_shaders_to_handle: Default::default(),
+
+            dependencies: Default::default(),
+            changed_files,
+            reloads: Default::default(),
+            compile_errors: Default::default(),
         }
     }
 
-    // TODO: should probably use the `desc` as key as well
+    // TODO: `descriptor_set_opts`, `push_constants_bytes` and `allow_unknown_samplers` aren't
+    // part of the key, so descs differing only in those get the first one's pipeline
     pub fn register_compute(&mut self, desc: &ComputePipelineDesc) -> ComputePipelineHandle {
-        match self.compute_shader_to_handle.entry(desc.source.clone()) {
+        match self.compute_shader_to_handle.entry((
+            desc.source.clone(),
+            desc.entry().to_owned(),
+            desc.specialization.clone(),
+        )) {
             std::collections::hash_map::Entry::Occupied(occupied) => *occupied.get(),
             std::collections::hash_map::Entry::Vacant(vacant) => {
                 let handle = ComputePipelineHandle(self.compute_entries.len());
@@ -162,13 +217,13 @@ impl PipelineCache {
         shaders: &[PipelineShaderDesc],
         desc: &RasterPipelineDesc,
     ) -> RasterPipelineHandle {
-        if let Some(handle) = self.raster_shaders_to_handle.get(shaders) {
+        let key = (shaders.to_owned(), desc.state_key());
+        if let Some(handle) = self.raster_shaders_to_handle.get(&key) {
             return *handle;
         }
 
         let handle = RasterPipelineHandle(self.raster_entries.len());
-        self.raster_shaders_to_handle
-            .insert(shaders.to_owned(), handle);
+        self.raster_shaders_to_handle.insert(key, handle);
         self.raster_entries.insert(
             handle,
             RasterPipelineCacheEntry {
@@ -226,25 +281,116 @@ impl PipelineCache {
             .unwrap()
     }
 
-    fn invalidate_stale_pipelines(&mut self) {
-        for entry in self.compute_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
-            }
+    /// Starts recompiling the pipelines whose sources have changed. Until that finishes,
+    /// the previous pipelines remain in use.
+    fn start_reloads(&mut self, device: &Arc<crate::vulkan::device::Device>) {
+        let changed_files = std::mem::take(&mut *self.changed_files.lock());
+        let changed = self
+            .dependencies
+            .invalidate(changed_files.iter().map(PathBuf::as_path));
+
+        let compute = self.compute_entries.iter().map(|(&handle, entry)| {
+            (
+                AnyPipelineHandle::Compute(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+        let raster = self.raster_entries.iter().map(|(&handle, entry)| {
+            (
+                AnyPipelineHandle::Raster(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+        let rt = self.rt_entries.iter().map(|(&handle, entry)| {
+            (
+                AnyPipelineHandle::Rt(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+
+        // A stale lazy handle which is already being re-evaluated doesn't need another reload,
+        // but a file change reported while compiling restarts it.
+        let to_reload: Vec<AnyPipelineHandle> = compute
+            .chain(raster)
+            .chain(rt)
+            .filter(|&(handle, has_pipeline, is_stale)| {
+                has_pipeline
+                    && (changed.contains(&handle)
+                        || (is_stale && !self.reloads.contains_key(&handle)))
+            })
+            .map(|(handle, ..)| handle)
+            .collect();
+
+        for handle in to_reload {
+            let task = self.spawn_build(device, handle);
+            self.reloads.insert(handle, task);
         }
+    }
 
-        for entry in self.raster_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+    /// Errors from reloading pipelines after their sources changed. Cleared once
+    /// the pipeline builds again.
+    pub fn compile_errors(&self) -> impl Iterator<Item = &ShaderCompileError> {
+        self.compile_errors.values()
+    }
+
+    fn pipeline_name(&self, handle: AnyPipelineHandle) -> String {
+        fn source_name(source: &ShaderSource) -> String {
+            match source {
+                ShaderSource::Rust { entry } => entry.clone(),
+                ShaderSource::Hlsl { path } => path.display().to_string(),
             }
         }
 
-        for entry in self.rt_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+        let shaders = match handle {
+            AnyPipelineHandle::Compute(handle) => {
+                return source_name(&self.compute_entries[&handle].desc.source);
+            }
+            AnyPipelineHandle::Raster(handle) => self
+                .raster_shaders_to_handle
+                .iter()
+                .find_map(|((shaders, _), &h)| (h == handle).then(|| shaders)),
+            AnyPipelineHandle::Rt(handle) => self
+                .rt_shaders_to_handle
+                .iter()
+                .find_map(|(shaders, &h)| (h == handle).then(|| shaders)),
+        };
+
+        shaders
+            .into_iter()
+            .flatten()
+            .map(|desc| source_name(&desc.source))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Swaps in the pipelines whose background recompilation has finished. If that fails,
+    /// the previous pipelines are kept, and the error is reported via `compile_errors`.
+    fn finish_reloads(&mut self, device: &Arc<crate::vulkan::device::Device>) {
+        let mut finished = Vec::new();
+        self.reloads.retain(
+            |&handle, task| match futures::FutureExt::now_or_never(task) {
+                Some(compiled) => {
+                    finished.push((handle, compiled));
+                    false
+                }
+                None => true,
+            },
+        );
+
+        for (handle, compiled) in finished {
+            match compiled {
+                Ok(built) => {
+                    self.install_pipeline(device, built);
+                    self.compile_errors.remove(&handle);
+                }
+                Err(err) => {
+                    log::error!("Failed to reload a pipeline: {:?}", err);
+                    let err = ShaderCompileError::from_error(self.pipeline_name(handle), &err);
+                    self.compile_errors.insert(handle, err);
+                }
             }
         }
     }
@@ -253,136 +399,261 @@ impl PipelineCache {
         &mut self,
         device: &Arc<crate::vulkan::device::Device>,
     ) -> anyhow::Result<()> {
-        // Prepare build tasks for compute
-        let compute = self.compute_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+        let compute = self
+            .compute_entries
+            .iter()
+            .filter(|(_, entry)| entry.pipeline.is_none())
+            .map(|(&handle, _)| AnyPipelineHandle::Compute(handle));
+        let raster = self
+            .raster_entries
+            .iter()
+            .filter(|(_, entry)| entry.pipeline.is_none())
+            .map(|(&handle, _)| AnyPipelineHandle::Raster(handle));
+        let rt = self
+            .rt_entries
+            .iter()
+            .filter(|(_, entry)| entry.pipeline.is_none())
+            .map(|(&handle, _)| AnyPipelineHandle::Rt(handle));
+
+        let missing: Vec<AnyPipelineHandle> = compute.chain(raster).chain(rt).collect();
+
+        if !missing.is_empty() {
+            let t0 = std::time::Instant::now();
+
+            // Compile and create all the things
+            let build_tasks: Vec<_> = missing
+                .iter()
+                .map(|&handle| self.spawn_build(device, handle))
+                .collect();
+            let built: Vec<CompileTaskOutput> =
+                smol::block_on(futures::future::try_join_all(build_tasks))?;
+
+            for built in built {
+                self.install_pipeline(device, built);
+            }
+
+            log::info!("Built {} pipelines in {:?}", missing.len(), t0.elapsed());
+        }
+
+        Ok(())
+    }
+
+    /// Compiles the shaders of a pipeline and creates it, both off the calling thread.
+    fn spawn_build(
+        &self,
+        device: &Arc<crate::vulkan::device::Device>,
+        handle: AnyPipelineHandle,
+    ) -> smol::Task<anyhow::Result<CompileTaskOutput>> {
+        let device = device.clone();
+
+        match handle {
+            AnyPipelineHandle::Compute(handle) => {
+                let entry = &self.compute_entries[&handle];
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
+                let desc = entry.desc.clone();
+
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Compute { handle, compiled })
+                    let t0 = std::time::Instant::now();
+                    let compiled = task.await?;
+
+                    let pipeline = smol::unblock({
+                        let compiled = compiled.clone();
+                        move || {
+                            log::trace!(
+                                "Creating compute pipeline {:?}:{:?}",
+                                compiled.name,
+                                desc.entry(),
+                            );
+                            create_compute_pipeline(&*device, &compiled.spirv, &desc)
+                                .with_context(|| format!("Shader {:?}", compiled.name))
+                        }
+                    })
+                    .await?;
+
+                    log::debug!(
+                        "Built compute pipeline {:?} in {:?}",
+                        compiled.name,
+                        t0.elapsed()
+                    );
+
+                    Ok(CompileTaskOutput::Compute {
+                        handle,
+                        pipeline,
+                        dependencies: compiled.dependencies.clone(),
+                    })
                 })
-            })
-        });
-
-        // Prepare build tasks for raster
-        let raster = self.raster_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+            }
+            AnyPipelineHandle::Raster(handle) => {
+                let entry = &self.raster_entries[&handle];
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
+                let desc = entry.desc.clone();
+
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Raster { handle, compiled })
+                    let t0 = std::time::Instant::now();
+                    let compiled = task.await?;
+
+                    let name = compiled
+                        .shaders
+                        .iter()
+                        .map(|shader| format!("{:?}:{:?}", shader.desc.stage, shader.desc.entry))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    let compiled_shaders = compiled
+                        .shaders
+                        .iter()
+                        .map(|shader| PipelineShader {
+                            code: shader.code.spirv.clone(),
+                            desc: shader.desc.clone(),
+                        })
+                        .collect::<Vec<_>>();
+
+                    let pipeline = smol::unblock({
+                        let name = name.clone();
+                        move || {
+                            log::trace!("Creating raster pipeline {}", name);
+                            create_raster_pipeline(&*device, &compiled_shaders, &desc)
+                        }
+                    })
+                    .await?;
+
+                    log::debug!("Built raster pipeline {} in {:?}", name, t0.elapsed());
+
+                    Ok(CompileTaskOutput::Raster {
+                        handle,
+                        pipeline,
+                        dependencies: compiled.dependencies().collect(),
+                    })
                 })
-            })
-        });
-
-        // Prepare build tasks for rt
-        let rt = self.rt_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+            }
+            AnyPipelineHandle::Rt(handle) => {
+                let entry = &self.rt_entries[&handle];
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
+                let desc = entry.desc.clone();
+
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Rt { handle, compiled })
+                    let t0 = std::time::Instant::now();
+                    let compiled = task.await?;
+
+                    let name = compiled
+                        .shaders
+                        .iter()
+                        .map(|shader| {
+                            format!(
+                                "{} {:?}:{:?}",
+                                shader.code.name, shader.desc.stage, shader.desc.entry
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    let compiled_shaders = compiled
+                        .shaders
+                        .iter()
+                        .map(|shader| PipelineShader {
+                            code: shader.code.spirv.clone(),
+                            desc: shader.desc.clone(),
+                        })
+                        .collect::<Vec<_>>();
+
+                    let pipeline = smol::unblock({
+                        let name = name.clone();
+                        move || {
+                            log::trace!("Creating rt pipeline {}", name);
+                            create_ray_tracing_pipeline(&*device, &compiled_shaders, &desc)
+                                .context("create_ray_tracing_pipeline")
+                        }
+                    })
+                    .await?;
+
+                    log::debug!("Built rt pipeline {} in {:?}", name, t0.elapsed());
+
+                    Ok(CompileTaskOutput::Rt {
+                        handle,
+                        pipeline,
+                        dependencies: compiled.dependencies().collect(),
+                    })
                 })
-            })
-        });
+            }
+        }
+    }
 
-        // Gather all the build tasks together
-        let shader_tasks: Vec<_> = compute.chain(raster).chain(rt).collect();
-
-        if !shader_tasks.is_empty() {
-            // Compile all the things
-            let compiled: Vec<CompileTaskOutput> =
-                smol::block_on(futures::future::try_join_all(shader_tasks))?;
-
-            // Build pipelines from all compiled shaders
-            for compiled in compiled {
-                match compiled {
-                    CompileTaskOutput::Compute { handle, compiled } => {
-                        let entry = self.compute_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating compute pipeline {:?}:{:?}",
-                            compiled.name,
-                            entry.desc.source.entry(),
-                        );
-                        entry.pipeline = Some(Arc::new(create_compute_pipeline(
-                            &*device,
-                            &compiled.spirv,
-                            &entry.desc,
-                        )));
-                    }
-                    CompileTaskOutput::Raster { handle, compiled } => {
-                        let entry = self.raster_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating raster pipeline {}",
-                            compiled
-                                .shaders
-                                .iter()
-                                .map(|shader| format!(
-                                    "{:?}:{:?}",
-                                    shader.desc.stage, shader.desc.entry
-                                ))
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
-
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_raster_pipeline(&*device, &compiled_shaders, &entry.desc)
-                                .expect("create_raster_pipeline"),
-                        ));
-                    }
-                    CompileTaskOutput::Rt { handle, compiled } => {
-                        let entry = self.rt_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating rt pipeline {}",
-                            compiled
-                                .shaders
-                                .iter()
-                                .map(|shader| format!(
-                                    "{} {:?}:{:?}",
-                                    shader.code.name, shader.desc.stage, shader.desc.entry
-                                ))
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
-
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_ray_tracing_pipeline(&*device, &compiled_shaders, &entry.desc)
-                                .expect("create_ray_tracing_pipeline"),
-                        ));
+    /// Makes a newly built pipeline current. If it replaces a previous one, that pipeline
+    /// is destroyed once the frames which could still be using it have retired.
+    fn install_pipeline(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+        built: CompileTaskOutput,
+    ) {
+        match built {
+            CompileTaskOutput::Compute {
+                handle,
+                pipeline,
+                dependencies,
+            } => {
+                let entry = self.compute_entries.get_mut(&handle).unwrap();
+                if let Some(previous) = entry.pipeline.replace(Arc::new(pipeline)) {
+                    device.defer_release(previous.pipeline);
+                    device
+                        .shader_module_cache
+                        .release(&device.raw, &previous.shader_modules);
+                }
+                self.dependencies
+                    .set_dependencies(AnyPipelineHandle::Compute(handle), dependencies);
+            }
+            CompileTaskOutput::Raster {
+                handle,
+                pipeline,
+                dependencies,
+            } => {
+                let entry = self.raster_entries.get_mut(&handle).unwrap();
+                if let Some(previous) = entry.pipeline.replace(Arc::new(pipeline)) {
+                    device.defer_release(previous.pipeline);
+                    device
+                        .shader_module_cache
+                        .release(&device.raw, &previous.shader_modules);
+                }
+                self.dependencies
+                    .set_dependencies(AnyPipelineHandle::Raster(handle), dependencies);
+            }
+            CompileTaskOutput::Rt {
+                handle,
+                pipeline,
+                dependencies,
+            } => {
+                let entry = self.rt_entries.get_mut(&handle).unwrap();
+                if let Some(previous) = entry.pipeline.replace(Arc::new(pipeline)) {
+                    device.defer_release(previous.pipeline);
+                    device
+                        .shader_module_cache
+                        .release(&device.raw, &previous.shader_modules);
+
+                    // The shader binding table contains group handles of the old pipeline,
+                    // so it's retired together with it.
+                    match Arc::try_unwrap(previous) {
+                        Ok(previous) => {
+                            for buffer in previous.sbt.into_buffers() {
+                                device.defer_release_buffer(buffer);
+                            }
+                        }
+                        Err(_) => log::warn!(
+                            "Leaking the shader binding table of a replaced ray tracing pipeline which is still in use"
+                        ),
                     }
                 }
+                self.dependencies
+                    .set_dependencies(AnyPipelineHandle::Rt(handle), dependencies);
             }
         }
-
-        Ok(())
     }
 
     pub fn prepare_frame(
         &mut self,
         device: &Arc<crate::vulkan::device::Device>,
     ) -> anyhow::Result<()> {
-        self.invalidate_stale_pipelines();
+        self.start_reloads(device);
         self.parallel_compile_shaders(device)?;
+        self.finish_reloads(device);
 
         Ok(())
     }
@@ -391,14 +662,92 @@ impl PipelineCache {
 enum CompileTaskOutput {
     Compute {
         handle: ComputePipelineHandle,
-        compiled: Arc<CompiledShader>,
+        pipeline: ComputePipeline,
+        dependencies: Vec<PathBuf>,
     },
     Raster {
         handle: RasterPipelineHandle,
-        compiled: Arc<CompiledPipelineShaders>,
+        pipeline: RasterPipeline,
+        dependencies: Vec<PathBuf>,
     },
     Rt {
         handle: RtPipelineHandle,
-        compiled: Arc<CompiledPipelineShaders>,
+        pipeline: RayTracingPipeline,
+        dependencies: Vec<PathBuf>,
     },
 }
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn test_raster_key_includes_render_pass() {
+    use crate::vulkan::{
+        device::Device, instance::Instance, physical_device::enumerate_physical_devices,
+    };
+    use ash::vk;
+
+    let instance = Instance::builder().build().unwrap();
+    let pdevice = Arc::new(
+        enumerate_physical_devices(&instance)
+            .unwrap()
+            .into_iter()
+            .next()
+            .expect("no Vulkan device"),
+    );
+    let device = Device::builder(&pdevice).build().unwrap();
+
+    let render_pass = |attachment: RenderPassAttachmentDesc| {
+        create_render_pass(
+            &device,
+            RenderPassDesc {
+                color_attachments: &[attachment],
+                ..Default::default()
+            },
+        )
+    };
+
+    let rgba8 = RenderPassAttachmentDesc::new(vk::Format::R8G8B8A8_UNORM);
+    let passes = [
+        render_pass(rgba8),
+        render_pass(RenderPassAttachmentDesc::new(
+            vk::Format::R16G16B16A16_SFLOAT,
+        )),
+        render_pass(rgba8.samples(vk::SampleCountFlags::TYPE_4)),
+        // Compatible with the first one
+        render_pass(rgba8.clear_input()),
+    ];
+
+    let shaders = [
+        PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+            .hlsl_source("/shaders/test_vs.hlsl")
+            .build()
+            .unwrap(),
+        PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+            .hlsl_source("/shaders/test_ps.hlsl")
+            .build()
+            .unwrap(),
+    ];
+
+    let mut cache = PipelineCache::new(&LazyCache::create());
+    let handles: Vec<RasterPipelineHandle> = passes
+        .iter()
+        .map(|render_pass| {
+            let desc = RasterPipelineDesc::builder()
+                .render_pass(render_pass.clone())
+                .build()
+                .unwrap();
+            cache.register_raster(&shaders, &desc)
+        })
+        .collect();
+
+    assert!(handles[0] != handles[1]);
+    assert!(handles[0] != handles[2]);
+    assert!(handles[1] != handles[2]);
+    assert!(handles[0] == handles[3]);
+
+    let with_push_constants = RasterPipelineDesc::builder()
+        .render_pass(passes[0].clone())
+        .push_constants_bytes(8)
+        .build()
+        .unwrap();
+    assert!(cache.register_raster(&shaders, &with_push_constants) != handles[0]);
+}
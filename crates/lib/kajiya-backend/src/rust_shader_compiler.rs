@@ -39,14 +39,13 @@ impl LazyWorker for CompileRustShader {
                 anyhow::anyhow!("No Rust-GPU module found for entry point {}", self.entry)
             })?;
 
-        let spirv_blob = LoadFile::new(format!("/rust-shaders-compiled/{}", shader_file))?
-            .into_lazy()
-            .eval(&ctx)
-            .await?;
+        let spirv_path = format!("/rust-shaders-compiled/{}", shader_file);
+        let spirv_blob = LoadFile::new(&spirv_path)?.into_lazy().eval(&ctx).await?;
 
         Ok(CompiledShader {
             name: "rust-gpu".to_owned(),
             spirv: (*spirv_blob).clone(),
+            dependencies: vec![crate::file::canonical_path_from_vfs(spirv_path)?],
         })
     }
 }
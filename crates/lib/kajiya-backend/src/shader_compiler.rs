@@ -1,13 +1,18 @@
-use crate::file::LoadFile;
+use crate::{file::LoadFile, shader_dependencies::IncludeTracker};
 use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
 use relative_path::RelativePathBuf;
 use std::{path::PathBuf, sync::Arc};
 use turbosloth::*;
 
+/// Shader model used for all HLSL profiles, e.g. `cs_6_5` or `lib_6_5`
+const HLSL_SHADER_MODEL: &str = "6_5";
+
 pub struct CompiledShader {
     pub name: String,
     pub spirv: Bytes,
+    /// Canonical paths of every file used to compile the shader, including headers
+    pub dependencies: Vec<PathBuf>,
 }
 
 #[derive(Clone, Hash)]
@@ -34,25 +39,56 @@ impl LazyWorker for CompileShader {
             .unwrap_or_else(|| "unknown".to_string());
 
         match ext.as_str() {
-            "glsl" => unimplemented!(),
+            "glsl" | "comp" | "vert" | "frag" => {
+                let kind = glsl_shader_kind(&ext, &self.profile)?;
+                let file_path = self.path.to_str().unwrap().to_owned();
+                let source = LoadFile::new(self.path.clone())?
+                    .into_lazy()
+                    .eval(&ctx)
+                    .await?;
+                let source = std::str::from_utf8(&source)
+                    .with_context(|| format!("shader path: {:?}", self.path))?;
+                let (spirv, dependencies) =
+                    compile_generic_shader_glsl_impl(&name, &file_path, source, kind, &ctx)?;
+
+                Ok(CompiledShader {
+                    name,
+                    spirv,
+                    dependencies,
+                })
+            }
             "spv" => {
+                let dependencies = vec![crate::file::canonical_path_from_vfs(&self.path)?];
                 let spirv = LoadFile::new(self.path.clone())?.run(ctx).await?;
-                Ok(CompiledShader { name, spirv })
+
+                Ok(CompiledShader {
+                    name,
+                    spirv,
+                    dependencies,
+                })
             }
             "hlsl" => {
                 let file_path = self.path.to_str().unwrap().to_owned();
-                let source = shader_prepper::process_file(
-                    &file_path,
-                    &mut ShaderIncludeProvider { ctx },
-                    String::new(),
-                );
+                let mut include_provider = ShaderIncludeProvider::new(ctx);
+                let source =
+                    shader_prepper::process_file(&file_path, &mut include_provider, Vec::new());
                 let source = source
                     .map_err(|err| anyhow!("{}", err))
                     .with_context(|| format!("shader path: {:?}", self.path))?;
-                let target_profile = format!("{}_6_4", self.profile);
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, &target_profile)?;
+                let target_profile = format!("{}_{}", self.profile, HLSL_SHADER_MODEL);
+
+                // DXC is CPU-bound; run it on the blocking pool so shaders compile in parallel
+                let spirv = smol::unblock({
+                    let name = name.clone();
+                    move || compile_generic_shader_hlsl_impl(&name, &source, &target_profile)
+                })
+                .await?;
 
-                Ok(CompiledShader { name, spirv })
+                Ok(CompiledShader {
+                    name,
+                    spirv,
+                    dependencies: include_provider.tracker.dependencies,
+                })
             }
             _ => anyhow::bail!("Unrecognized shader file extension: {}", ext),
         }
@@ -77,8 +113,8 @@ impl LazyWorker for CompileRayTracingShader {
         let file_path = self.path.to_str().unwrap().to_owned();
         let source = shader_prepper::process_file(
             &file_path,
-            &mut ShaderIncludeProvider { ctx },
-            String::new(),
+            &mut ShaderIncludeProvider::new(ctx),
+            Vec::new(),
         );
         let source = source.map_err(|err| anyhow!("{}", err))?;
 
@@ -95,10 +131,10 @@ impl LazyWorker for CompileRayTracingShader {
             .unwrap_or_else(|| "unknown".to_string());
 
         match ext.as_str() {
-            "glsl" => unimplemented!(),
+            "glsl" => anyhow::bail!("GLSL ray tracing shaders are not supported"),
             "hlsl" => {
-                let target_profile = "lib_6_4";
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, target_profile)?;
+                let target_profile = format!("lib_{}", HLSL_SHADER_MODEL);
+                let spirv = compile_generic_shader_hlsl_impl(&name, &source, &target_profile)?;
 
                 Ok(RayTracingShader { name, spirv })
             }
@@ -109,26 +145,39 @@ impl LazyWorker for CompileRayTracingShader {
 
 struct ShaderIncludeProvider {
     ctx: RunContext,
+    tracker: IncludeTracker,
+}
+
+impl ShaderIncludeProvider {
+    fn new(ctx: RunContext) -> Self {
+        Self {
+            ctx,
+            tracker: Default::default(),
+        }
+    }
 }
 
 impl<'a> shader_prepper::IncludeProvider for ShaderIncludeProvider {
-    type IncludeContext = String;
+    /// The chain of files leading to the include, ending with the including file
+    type IncludeContext = Vec<String>;
 
     fn get_include(
         &mut self,
         path: &str,
-        parent_file: &Self::IncludeContext,
+        include_stack: &Self::IncludeContext,
     ) -> std::result::Result<
         (String, Self::IncludeContext),
         shader_prepper::BoxedIncludeProviderError,
     > {
-        let resolved_path = if let Some('/') = path.chars().next() {
-            path.to_owned()
-        } else {
-            let mut folder: RelativePathBuf = parent_file.into();
-            folder.pop();
-            folder.join(path).as_str().to_string()
-        };
+        let parent_file = include_stack.last().map_or("", String::as_str);
+        let resolved_path = resolve_include_path(path, parent_file);
+
+        let mut child_stack = include_stack.clone();
+        child_stack.push(resolved_path.clone());
+
+        if !self.tracker.include(include_stack, &resolved_path) {
+            return Ok((String::new(), child_stack));
+        }
 
         let blob: Arc<Bytes> = smol::block_on(
             crate::file::LoadFile::new(&resolved_path)
@@ -137,25 +186,236 @@ impl<'a> shader_prepper::IncludeProvider for ShaderIncludeProvider {
                 .eval(&self.ctx),
         )?;
 
-        Ok((String::from_utf8(blob.to_vec())?, resolved_path))
+        Ok((String::from_utf8(blob.to_vec())?, child_stack))
     }
 }
 
-pub fn get_cs_local_size_from_spirv(spirv: &[u32]) -> Result<[u32; 3]> {
+/// Paths starting with `/` are relative to the asset root; others to the including file.
+fn resolve_include_path(path: &str, parent_file: &str) -> String {
+    if let Some('/') = path.chars().next() {
+        path.to_owned()
+    } else {
+        let mut folder: RelativePathBuf = parent_file.into();
+        folder.pop();
+        folder.join(path).as_str().to_string()
+    }
+}
+
+/// GLSL stages are picked by the file extension, or by the pipeline stage for `.glsl` files.
+fn glsl_shader_kind(ext: &str, profile: &str) -> Result<shaderc::ShaderKind> {
+    Ok(match (ext, profile) {
+        ("comp", _) | ("glsl", "cs") => shaderc::ShaderKind::Compute,
+        ("vert", _) | ("glsl", "vs") => shaderc::ShaderKind::Vertex,
+        ("frag", _) | ("glsl", "ps") => shaderc::ShaderKind::Fragment,
+        ("glsl", "hs") => shaderc::ShaderKind::TessControl,
+        ("glsl", "ds") => shaderc::ShaderKind::TessEvaluation,
+        ("glsl", "gs") => shaderc::ShaderKind::Geometry,
+        _ => bail!(
+            "Unsupported GLSL shader: .{} file for profile {}",
+            ext,
+            profile
+        ),
+    })
+}
+
+/// Numeric values of the SPIR-V execution models used by pipeline stages
+pub mod execution_model {
+    pub const VERTEX: u32 = 0;
+    pub const TESSELLATION_CONTROL: u32 = 1;
+    pub const TESSELLATION_EVALUATION: u32 = 2;
+    pub const GEOMETRY: u32 = 3;
+    pub const FRAGMENT: u32 = 4;
+    pub const GL_COMPUTE: u32 = 5;
+    pub const RAY_GENERATION: u32 = 5313;
+    pub const CLOSEST_HIT: u32 = 5316;
+    pub const MISS: u32 = 5317;
+}
+
+fn execution_model_name(execution_model: u32) -> &'static str {
+    match execution_model {
+        0 => "Vertex",
+        1 => "TessellationControl",
+        2 => "TessellationEvaluation",
+        3 => "Geometry",
+        4 => "Fragment",
+        5 => "GLCompute",
+        5313 => "RayGeneration",
+        5314 => "Intersection",
+        5315 => "AnyHit",
+        5316 => "ClosestHit",
+        5317 => "Miss",
+        5318 => "Callable",
+        _ => "Unknown",
+    }
+}
+
+/// A `(name, execution model, function ID)` for every entry point in the module.
+fn spirv_entry_points(module: &rspirv::dr::Module) -> Vec<(String, u32, u32)> {
+    use rspirv::dr::Operand::{ExecutionModel, IdRef, LiteralString};
+
+    module
+        .entry_points
+        .iter()
+        .filter_map(|inst| match inst.operands.as_slice() {
+            [ExecutionModel(model), IdRef(function), LiteralString(name), ..] => {
+                Some((name.clone(), *model as u32, *function))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks that `entry` exists in the module, and is of one of the `expected` execution models.
+/// Modules can contain several entry points, e.g. when produced from a shader library.
+pub fn validate_entry_point(spirv: &[u32], entry: &str, expected: &[u32]) -> Result<()> {
+    let mut loader = rspirv::dr::Loader::new();
+    rspirv::binary::parse_words(spirv, &mut loader)
+        .map_err(|err| anyhow!("Could not parse SPIR-V: {:?}", err))?;
+    let entry_points = spirv_entry_points(&loader.module());
+
+    let expected_names = || {
+        expected
+            .iter()
+            .map(|&model| execution_model_name(model))
+            .collect::<Vec<_>>()
+            .join(" or ")
+    };
+
+    match entry_points.iter().find(|(name, ..)| name == entry) {
+        Some((_, model, _)) if expected.contains(model) => Ok(()),
+        Some((_, model, _)) => bail!(
+            "Entry point {:?} is a {} shader, but a {} one is needed",
+            entry,
+            execution_model_name(*model),
+            expected_names()
+        ),
+        None => bail!(
+            "Entry point {:?} not found. Available entry points: {}",
+            entry,
+            entry_points
+                .iter()
+                .map(|(name, model, _)| format!("{} ({})", name, execution_model_name(*model)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Returns the workgroup size of the `entry` compute shader. `spec_constant` provides specialized
+/// values by constant ID, which override the defaults of spec constants making up `WorkgroupSize`.
+pub fn get_cs_local_size_from_spirv(
+    spirv: &[u32],
+    entry: &str,
+    spec_constant: impl Fn(u32) -> Option<u32>,
+) -> Result<[u32; 3]> {
+    use rspirv::dr::Operand::{BuiltIn, Decoration, ExecutionMode, IdRef, LiteralInt32};
+
     let mut loader = rspirv::dr::Loader::new();
-    rspirv::binary::parse_words(spirv, &mut loader).unwrap();
+    rspirv::binary::parse_words(spirv, &mut loader)
+        .map_err(|err| anyhow!("Could not parse SPIR-V: {:?}", err))?;
     let module = loader.module();
 
+    let entry_function = spirv_entry_points(&module)
+        .into_iter()
+        .find_map(|(name, _, function)| (name == entry).then(|| function))
+        .ok_or_else(|| anyhow!("Could not find entry point {:?}", entry))?;
+
+    // A constant decorated with the `WorkgroupSize` built-in takes precedence over `LocalSize`.
+    // With specialization constants (e.g. `local_size_x_id` in GLSL), that's the only place
+    // the specialized size can be found.
+    let workgroup_size_id = module.annotations.iter().find_map(|inst| {
+        // OpDecorate
+        if inst.class.opcode as u32 != 71 {
+            return None;
+        }
+
+        match inst.operands.as_slice() {
+            // Decoration::BuiltIn, BuiltIn::WorkgroupSize
+            [IdRef(target), Decoration(decoration), BuiltIn(built_in)]
+                if *decoration as u32 == 11 && *built_in as u32 == 25 =>
+            {
+                Some(*target)
+            }
+            _ => None,
+        }
+    });
+
+    // Value of a constant making up the workgroup size, taking specialization into account
+    let constant_value = |id: u32| -> Result<u32> {
+        let spec_id = module.annotations.iter().find_map(|inst| {
+            match (inst.class.opcode as u32, inst.operands.as_slice()) {
+                // OpDecorate, Decoration::SpecId
+                (71, [IdRef(target), Decoration(decoration), LiteralInt32(spec_id)])
+                    if *target == id && *decoration as u32 == 1 =>
+                {
+                    Some(*spec_id)
+                }
+                _ => None,
+            }
+        });
+
+        if let Some(value) = spec_id.and_then(&spec_constant) {
+            return Ok(value);
+        }
+
+        let inst = module
+            .types_global_values
+            .iter()
+            .find(|inst| inst.result_id == Some(id))
+            .ok_or_else(|| anyhow!("Could not find workgroup size constant {}", id))?;
+
+        match inst.operands.as_slice() {
+            [LiteralInt32(value)] => Ok(*value),
+            _ => bail!("Could not parse workgroup size constant {}", id),
+        }
+    };
+
+    if let Some(workgroup_size_id) = workgroup_size_id {
+        let composite = module
+            .types_global_values
+            .iter()
+            .find(|inst| inst.result_id == Some(workgroup_size_id))
+            .ok_or_else(|| anyhow!("Could not find the WorkgroupSize constant"))?;
+
+        if let [IdRef(x), IdRef(y), IdRef(z)] = *composite.operands.as_slice() {
+            return Ok([constant_value(x)?, constant_value(y)?, constant_value(z)?]);
+        } else {
+            bail!("Could not parse the WorkgroupSize constant");
+        }
+    }
+
     for inst in module.global_inst_iter() {
         //if spirv_headers::Op::ExecutionMode == inst.class.opcode {
         if inst.class.opcode as u32 == 16 {
-            let local_size = &inst.operands[2..5];
-            use rspirv::dr::Operand::LiteralInt32;
+            match *inst.operands.as_slice() {
+                // ExecutionMode::LocalSize
+                [IdRef(function), ExecutionMode(mode), ref local_size @ ..]
+                    if function == entry_function && mode as u32 == 17 =>
+                {
+                    if let [LiteralInt32(x), LiteralInt32(y), LiteralInt32(z)] = *local_size {
+                        return Ok([x, y, z]);
+                    } else {
+                        bail!("Could not parse the ExecutionMode SPIR-V op");
+                    }
+                }
+                _ => {}
+            }
+        }
 
-            if let [LiteralInt32(x), LiteralInt32(y), LiteralInt32(z)] = *local_size {
-                return Ok([x, y, z]);
-            } else {
-                bail!("Could not parse the ExecutionMode SPIR-V op");
+        // OpExecutionModeId
+        if inst.class.opcode as u32 == 331 {
+            match *inst.operands.as_slice() {
+                // ExecutionMode::LocalSizeId
+                [IdRef(function), ExecutionMode(mode), ref local_size @ ..]
+                    if function == entry_function && mode as u32 == 38 =>
+                {
+                    if let [IdRef(x), IdRef(y), IdRef(z)] = *local_size {
+                        return Ok([constant_value(x)?, constant_value(y)?, constant_value(z)?]);
+                    } else {
+                        bail!("Could not parse the ExecutionModeId SPIR-V op");
+                    }
+                }
+                _ => {}
             }
         }
     }
@@ -163,6 +423,283 @@ pub fn get_cs_local_size_from_spirv(spirv: &[u32]) -> Result<[u32; 3]> {
     Err(anyhow!("Could not find a ExecutionMode SPIR-V op"))
 }
 
+/// Returns the size of the push constant block declared by a shader, or zero if there is none.
+pub fn get_push_constants_size_from_spirv(spirv: &[u32]) -> Result<u32> {
+    use rspirv::dr::Operand::{IdRef, StorageClass};
+
+    let mut loader = rspirv::dr::Loader::new();
+    rspirv::binary::parse_words(spirv, &mut loader)
+        .map_err(|err| anyhow!("Could not parse SPIR-V: {:?}", err))?;
+    let module = loader.module();
+
+    let pointer_type = module.types_global_values.iter().find_map(|inst| {
+        // OpVariable
+        if inst.class.opcode as u32 != 59 {
+            return None;
+        }
+
+        match inst.operands.as_slice() {
+            // StorageClass::PushConstant
+            [StorageClass(storage_class), ..] if *storage_class as u32 == 9 => inst.result_type,
+            _ => None,
+        }
+    });
+
+    let pointer_type = match pointer_type {
+        Some(pointer_type) => pointer_type,
+        None => return Ok(0),
+    };
+
+    match spirv_type(&module, pointer_type)?.operands.as_slice() {
+        [StorageClass(_), IdRef(block_type)] => spirv_type_size(&module, *block_type),
+        _ => bail!("Could not parse the push constant pointer type"),
+    }
+}
+
+fn spirv_type(module: &rspirv::dr::Module, id: u32) -> Result<&rspirv::dr::Instruction> {
+    module
+        .types_global_values
+        .iter()
+        .find(|inst| inst.result_id == Some(id))
+        .ok_or_else(|| anyhow!("Could not find SPIR-V type {}", id))
+}
+
+/// Size of a type laid out with explicit offsets and strides, as push constant blocks are.
+fn spirv_type_size(module: &rspirv::dr::Module, type_id: u32) -> Result<u32> {
+    use rspirv::dr::Operand::{Decoration, IdRef, LiteralInt32};
+
+    let inst = spirv_type(module, type_id)?;
+
+    // Values of `OpMemberDecorate %type_id <member>` with the given decoration
+    let member_decoration = |member_idx: usize, decoration_id: u32| {
+        module.annotations.iter().find_map(|inst| {
+            match (inst.class.opcode as u32, inst.operands.as_slice()) {
+                (72, [IdRef(target), LiteralInt32(member), Decoration(decoration), rest @ ..])
+                    if *target == type_id
+                        && *member as usize == member_idx
+                        && *decoration as u32 == decoration_id =>
+                {
+                    Some(match rest {
+                        [LiteralInt32(value)] => Some(*value),
+                        _ => None,
+                    })
+                }
+                _ => None,
+            }
+        })
+    };
+
+    match (inst.class.opcode as u32, inst.operands.as_slice()) {
+        // OpTypeInt, OpTypeFloat
+        (21, [LiteralInt32(width), ..]) | (22, [LiteralInt32(width), ..]) => Ok(width / 8),
+        // OpTypeVector, OpTypeMatrix without an explicit stride
+        (23, [IdRef(component), LiteralInt32(count)])
+        | (24, [IdRef(component), LiteralInt32(count)]) => {
+            Ok(spirv_type_size(module, *component)? * count)
+        }
+        // OpTypeArray
+        (28, [IdRef(element), IdRef(length)]) => {
+            let length = match spirv_type(module, *length)?.operands.as_slice() {
+                [LiteralInt32(length)] => *length,
+                _ => bail!("Could not parse the length of SPIR-V array {}", type_id),
+            };
+
+            // OpDecorate %type_id ArrayStride
+            let stride = module.annotations.iter().find_map(|inst| {
+                match (inst.class.opcode as u32, inst.operands.as_slice()) {
+                    (71, [IdRef(target), Decoration(decoration), LiteralInt32(stride)])
+                        if *target == type_id && *decoration as u32 == 6 =>
+                    {
+                        Some(*stride)
+                    }
+                    _ => None,
+                }
+            });
+
+            Ok(match stride {
+                Some(stride) => stride * length,
+                None => spirv_type_size(module, *element)? * length,
+            })
+        }
+        // OpTypeStruct
+        (30, members) => {
+            let mut size = 0;
+
+            for (member_idx, member) in members.iter().enumerate() {
+                let member_type = match member {
+                    IdRef(member_type) => *member_type,
+                    _ => bail!(
+                        "Could not parse member {} of struct {}",
+                        member_idx,
+                        type_id
+                    ),
+                };
+
+                // Offset
+                let offset = member_decoration(member_idx, 35).flatten().unwrap_or(size);
+
+                let member_size = match (
+                    spirv_type(module, member_type)?.operands.as_slice(),
+                    member_decoration(member_idx, 7).flatten(),
+                ) {
+                    // A matrix with a MatrixStride; the stride is between rows if it's RowMajor
+                    ([IdRef(column_type), LiteralInt32(columns)], Some(stride)) => {
+                        let rows = match spirv_type(module, *column_type)?.operands.as_slice() {
+                            [IdRef(_), LiteralInt32(rows)] => *rows,
+                            _ => bail!("Could not parse the column type of a matrix"),
+                        };
+
+                        if member_decoration(member_idx, 4).is_some() {
+                            stride * rows
+                        } else {
+                            stride * columns
+                        }
+                    }
+                    _ => spirv_type_size(module, member_type)?,
+                };
+
+                size = size.max(offset + member_size);
+            }
+
+            Ok(size)
+        }
+        _ => bail!(
+            "Unsupported type in a push constant block: {:?}",
+            inst.class.opname
+        ),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderDiagnosticSeverity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// A message from the shader compiler, located in the original (pre-include) source file.
+#[derive(Clone, Debug)]
+pub struct ShaderDiagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub severity: ShaderDiagnosticSeverity,
+    pub message: String,
+}
+
+/// Returned (wrapped in `anyhow::Error`) when DXC rejects a shader.
+#[derive(Clone, Debug)]
+pub struct ShaderCompileError {
+    pub name: String,
+    pub diagnostics: Vec<ShaderDiagnostic>,
+    /// The unprocessed compiler output
+    pub output: String,
+}
+
+impl ShaderCompileError {
+    /// Wraps any failure to build a pipeline, keeping DXC diagnostics when there are some.
+    pub fn from_error(name: impl Into<String>, err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<ShaderCompileError>() {
+            return err.clone();
+        }
+
+        Self {
+            name: name.into(),
+            diagnostics: Vec::new(),
+            output: format!("{:#}", err),
+        }
+    }
+}
+
+impl std::fmt::Display for ShaderCompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Failed to compile shader {:?}", self.name)?;
+
+        if self.diagnostics.is_empty() {
+            return write!(f, "{}", self.output);
+        }
+
+        for diag in &self.diagnostics {
+            writeln!(
+                f,
+                "{}:{}:{}: {:?}: {}",
+                diag.file, diag.line, diag.column, diag.severity, diag.message
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ShaderCompileError {}
+
+/// Parses a `<name>:<line>:<column>: <severity>: <message>` line of DXC output.
+fn parse_dxc_diagnostic(line: &str) -> Option<(usize, usize, ShaderDiagnosticSeverity, &str)> {
+    line.match_indices(':').find_map(|(idx, _)| {
+        let mut parts = line[idx + 1..].splitn(4, ':');
+        let line = parts.next()?.parse().ok()?;
+        let column = parts.next()?.parse().ok()?;
+        let severity = match parts.next()?.trim() {
+            "error" | "fatal error" => ShaderDiagnosticSeverity::Error,
+            "warning" => ShaderDiagnosticSeverity::Warning,
+            "note" => ShaderDiagnosticSeverity::Note,
+            _ => return None,
+        };
+
+        Some((line, column, severity, parts.next()?.trim()))
+    })
+}
+
+/// Maps a line in the concatenated source back to the file and line it came from.
+fn source_location(
+    name: &str,
+    source: &[shader_prepper::SourceChunk],
+    line: usize,
+) -> (String, usize) {
+    let mut first_line = 1;
+
+    for chunk in source {
+        let line_count = chunk.source.matches('\n').count();
+        if line < first_line + line_count {
+            return (
+                chunk.file.clone(),
+                chunk.line_offset + line - first_line + 1,
+            );
+        }
+
+        first_line += line_count;
+    }
+
+    (name.to_owned(), line)
+}
+
+fn shader_compile_error(
+    name: &str,
+    source: &[shader_prepper::SourceChunk],
+    output: String,
+) -> ShaderCompileError {
+    let diagnostics = output
+        .lines()
+        .filter_map(parse_dxc_diagnostic)
+        .map(|(line, column, severity, message)| {
+            let (file, line) = source_location(name, source, line);
+            ShaderDiagnostic {
+                file,
+                line,
+                column,
+                severity,
+                message: message.to_owned(),
+            }
+        })
+        .collect();
+
+    ShaderCompileError {
+        name: name.to_owned(),
+        diagnostics,
+        output,
+    }
+}
+
 fn compile_generic_shader_hlsl_impl(
     name: &str,
     source: &[shader_prepper::SourceChunk],
@@ -173,25 +710,270 @@ fn compile_generic_shader_hlsl_impl(
         source_text += &s.source;
     }
 
+    let mut args = vec![
+        "-spirv",
+        "-HV",
+        "2021",
+        "-enable-templates",
+        //"-enable-16bit-types",
+        "-fspv-target-env=vulkan1.2",
+        "-WX",  // warnings as errors
+        "-Ges", // strict mode
+    ];
+
+    if cfg!(debug_assertions) {
+        args.push("-Zi");
+    }
+
     let t0 = std::time::Instant::now();
-    let spirv = hassle_rs::compile_hlsl(
-        name,
-        &source_text,
-        "main",
-        target_profile,
-        &[
-            "-spirv",
-            "-enable-templates",
-            //"-enable-16bit-types",
-            "-fspv-target-env=vulkan1.2",
-            "-WX",  // warnings as errors
-            "-Ges", // strict mode
-        ],
-        &[],
-    )
-    .map_err(|err| anyhow!("{}", err))?;
+    let spirv = hassle_rs::compile_hlsl(name, &source_text, "main", target_profile, &args, &[])
+        .map_err(|err| match err {
+            hassle_rs::HassleError::CompileError(output) => {
+                anyhow::Error::new(shader_compile_error(name, source, output))
+            }
+            err => anyhow!("{}", err),
+        })?;
 
     log::trace!("dxc took {:?} for {}", t0.elapsed(), name,);
 
     Ok(spirv.into())
 }
+
+fn compile_generic_shader_glsl_impl(
+    name: &str,
+    path: &str,
+    source: &str,
+    kind: shaderc::ShaderKind,
+    ctx: &RunContext,
+) -> Result<(Bytes, Vec<PathBuf>)> {
+    // Every resolved include along with its depth, so that failures can show how
+    // the compiler got to the offending file.
+    let include_chain = std::cell::RefCell::new(Vec::<(usize, String)>::new());
+    let tracker = std::cell::RefCell::new(IncludeTracker::default());
+    tracker.borrow_mut().include(&[] as &[&str], path);
+
+    let compiler =
+        shaderc::Compiler::new().ok_or_else(|| anyhow!("Failed to create the GLSL compiler"))?;
+    let mut options = shaderc::CompileOptions::new()
+        .ok_or_else(|| anyhow!("Failed to create GLSL compile options"))?;
+
+    options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_2 as u32,
+    );
+    options.set_warnings_as_errors();
+
+    if cfg!(debug_assertions) {
+        options.set_generate_debug_info();
+    }
+
+    options.set_include_callback(|requested, include_type, requesting, depth| {
+        let resolved_path = match include_type {
+            shaderc::IncludeType::Relative => resolve_include_path(requested, requesting),
+            // `#include <...>` is rooted at the directory of the shader being compiled
+            shaderc::IncludeType::Standard => resolve_include_path(requested, path),
+        };
+
+        // Walk back up the chain to find the files which led to this include
+        let mut include_stack = include_chain
+            .borrow()
+            .iter()
+            .rev()
+            .scan(depth, |next_depth, (depth, file)| {
+                Some((*depth < *next_depth).then(|| {
+                    *next_depth = *depth;
+                    file.clone()
+                }))
+            })
+            .flatten()
+            .chain(std::iter::once(path.to_owned()))
+            .collect::<Vec<_>>();
+        include_stack.reverse();
+
+        include_chain
+            .borrow_mut()
+            .push((depth, resolved_path.clone()));
+
+        if !tracker.borrow_mut().include(&include_stack, &resolved_path) {
+            return Ok(shaderc::ResolvedInclude {
+                resolved_name: resolved_path,
+                content: String::new(),
+            });
+        }
+
+        let blob: Arc<Bytes> = smol::block_on(
+            crate::file::LoadFile::new(&resolved_path)
+                .map_err(|err| format!("{:?}", err))?
+                .into_lazy()
+                .eval(ctx),
+        )
+        .map_err(|err| format!("Failed loading shader include {}: {:?}", requested, err))?;
+
+        Ok(shaderc::ResolvedInclude {
+            resolved_name: resolved_path,
+            content: String::from_utf8(blob.to_vec()).map_err(|err| err.to_string())?,
+        })
+    });
+
+    let t0 = std::time::Instant::now();
+    let spirv = compiler
+        .compile_into_spirv(source, kind, path, "main", Some(&options))
+        .map_err(|err| {
+            let mut message = format!("Failed to compile shader {:?}: {}", name, err);
+
+            let include_chain = include_chain.borrow();
+            if !include_chain.is_empty() {
+                message += "\nInclude chain:\n";
+                message += path;
+                for (depth, file) in include_chain.iter() {
+                    message += &format!("\n{}{}", "  ".repeat(*depth), file);
+                }
+            }
+
+            anyhow!(message)
+        })?;
+
+    log::trace!("shaderc took {:?} for {}", t0.elapsed(), name);
+
+    let dependencies = std::mem::take(&mut tracker.borrow_mut().dependencies);
+    Ok((Bytes::copy_from_slice(spirv.as_binary_u8()), dependencies))
+}
+
+#[test]
+fn test_parse_dxc_diagnostic() {
+    assert_eq!(
+        parse_dxc_diagnostic("blur:12:5: error: use of undeclared identifier 'foo'"),
+        Some((
+            12,
+            5,
+            ShaderDiagnosticSeverity::Error,
+            "use of undeclared identifier 'foo'"
+        ))
+    );
+    assert_eq!(
+        parse_dxc_diagnostic("blur:3:1: warning: implicit truncation: float4 to float3"),
+        Some((
+            3,
+            1,
+            ShaderDiagnosticSeverity::Warning,
+            "implicit truncation: float4 to float3"
+        ))
+    );
+    assert_eq!(parse_dxc_diagnostic("    float4 x = foo;"), None);
+    assert_eq!(parse_dxc_diagnostic("        ^"), None);
+}
+
+#[test]
+fn test_push_constants_size_from_spirv() {
+    // Hand-assembled module with `struct { float4 a; float2 b; }` in a push constant block
+    #[rustfmt::skip]
+    let spirv: &[u32] = &[
+        0x0723_0203, 0x0001_0000, 0, 7, 0,
+        // OpCapability Shader
+        (2 << 16) | 17, 1,
+        // OpMemoryModel Logical GLSL450
+        (3 << 16) | 14, 0, 1,
+        // OpMemberDecorate %4 0 Offset 0
+        (5 << 16) | 72, 4, 0, 35, 0,
+        // OpMemberDecorate %4 1 Offset 16
+        (5 << 16) | 72, 4, 1, 35, 16,
+        // OpDecorate %4 Block
+        (3 << 16) | 71, 4, 2,
+        // %1 = OpTypeFloat 32
+        (3 << 16) | 22, 1, 32,
+        // %2 = OpTypeVector %1 4
+        (4 << 16) | 23, 2, 1, 4,
+        // %3 = OpTypeVector %1 2
+        (4 << 16) | 23, 3, 1, 2,
+        // %4 = OpTypeStruct %2 %3
+        (4 << 16) | 30, 4, 2, 3,
+        // %5 = OpTypePointer PushConstant %4
+        (4 << 16) | 32, 5, 9, 4,
+        // %6 = OpVariable %5 PushConstant
+        (4 << 16) | 59, 5, 6, 9,
+    ];
+
+    assert_eq!(get_push_constants_size_from_spirv(spirv).unwrap(), 24);
+
+    // The same module without the variable has no push constants
+    assert_eq!(
+        get_push_constants_size_from_spirv(&spirv[..spirv.len() - 4]).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_multiple_entry_points() {
+    // Hand-assembled module with two compute entry points of different group sizes
+    #[rustfmt::skip]
+    let spirv: &[u32] = &[
+        0x0723_0203, 0x0001_0000, 0, 3, 0,
+        // OpCapability Shader
+        (2 << 16) | 17, 1,
+        // OpMemoryModel Logical GLSL450
+        (3 << 16) | 14, 0, 1,
+        // OpEntryPoint GLCompute %1 "main"
+        (5 << 16) | 15, 5, 1, u32::from_le_bytes(*b"main"), 0,
+        // OpEntryPoint GLCompute %2 "blur"
+        (5 << 16) | 15, 5, 2, u32::from_le_bytes(*b"blur"), 0,
+        // OpExecutionMode %1 LocalSize 8 8 1
+        (6 << 16) | 16, 1, 17, 8, 8, 1,
+        // OpExecutionMode %2 LocalSize 64 1 1
+        (6 << 16) | 16, 2, 17, 64, 1, 1,
+    ];
+
+    assert_eq!(
+        get_cs_local_size_from_spirv(spirv, "main", |_| None).unwrap(),
+        [8, 8, 1]
+    );
+    assert_eq!(
+        get_cs_local_size_from_spirv(spirv, "blur", |_| None).unwrap(),
+        [64, 1, 1]
+    );
+
+    assert!(validate_entry_point(spirv, "blur", &[execution_model::GL_COMPUTE]).is_ok());
+
+    let err = validate_entry_point(spirv, "bulr", &[execution_model::GL_COMPUTE]).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("main (GLCompute), blur (GLCompute)"));
+
+    let err = validate_entry_point(spirv, "main", &[execution_model::VERTEX]).unwrap_err();
+    assert!(err.to_string().contains("is a GLCompute shader"));
+}
+
+#[test]
+fn test_local_size_id() {
+    // Hand-assembled module whose group size comes from `LocalSizeId`, with Y specializable
+    #[rustfmt::skip]
+    let spirv: &[u32] = &[
+        0x0723_0203, 0x0001_0200, 0, 6, 0,
+        // OpCapability Shader
+        (2 << 16) | 17, 1,
+        // OpMemoryModel Logical GLSL450
+        (3 << 16) | 14, 0, 1,
+        // OpEntryPoint GLCompute %1 "main"
+        (5 << 16) | 15, 5, 1, u32::from_le_bytes(*b"main"), 0,
+        // OpExecutionModeId %1 LocalSizeId %3 %4 %5
+        (6 << 16) | 331, 1, 38, 3, 4, 5,
+        // OpDecorate %4 SpecId 7
+        (4 << 16) | 71, 4, 1, 7,
+        // %2 = OpTypeInt 32 0
+        (4 << 16) | 21, 2, 32, 0,
+        // %3 = OpConstant %2 16
+        (4 << 16) | 43, 2, 3, 16,
+        // %4 = OpSpecConstant %2 4
+        (4 << 16) | 50, 2, 4, 4,
+        // %5 = OpConstant %2 1
+        (4 << 16) | 43, 2, 5, 1,
+    ];
+
+    assert_eq!(
+        get_cs_local_size_from_spirv(spirv, "main", |_| None).unwrap(),
+        [16, 4, 1]
+    );
+    assert_eq!(
+        get_cs_local_size_from_spirv(spirv, "main", |id| (id == 7).then(|| 8)).unwrap(),
+        [16, 8, 1]
+    );
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    path::{Path, PathBuf},
+};
+
+/// Records the files touched while compiling a shader, i.e. the shader itself and everything
+/// it includes, directly or not.
+#[derive(Default)]
+pub struct IncludeTracker {
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl IncludeTracker {
+    /// Called for every include resolved by the compiler. `include_stack` is the chain of files
+    /// which led to this include, starting with the top-level shader.
+    ///
+    /// Returns `false` for circular includes, which should then resolve to an empty file.
+    pub fn include(&mut self, include_stack: &[impl AsRef<str>], resolved_path: &str) -> bool {
+        if let Ok(path) = crate::file::canonical_path_from_vfs(resolved_path) {
+            if !self.dependencies.contains(&path) {
+                self.dependencies.push(path);
+            }
+        }
+
+        if include_stack
+            .iter()
+            .any(|parent| parent.as_ref() == resolved_path)
+        {
+            log::warn!(
+                "Circular shader include: {} -> {}",
+                include_stack
+                    .iter()
+                    .map(AsRef::as_ref)
+                    .collect::<Vec<_>>()
+                    .join(" -> "),
+                resolved_path
+            );
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Maps source files to the pipelines compiled from them, so that a change to a shared
+/// header rebuilds every pipeline including it.
+pub struct ShaderDependencyGraph<K> {
+    dependencies: HashMap<K, HashSet<PathBuf>>,
+    dependents: HashMap<PathBuf, HashSet<K>>,
+}
+
+impl<K> Default for ShaderDependencyGraph<K> {
+    fn default() -> Self {
+        Self {
+            dependencies: Default::default(),
+            dependents: Default::default(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> ShaderDependencyGraph<K> {
+    /// Replaces the dependencies of `key` with the files used by its latest compilation.
+    pub fn set_dependencies(&mut self, key: K, files: impl IntoIterator<Item = PathBuf>) {
+        let files: HashSet<PathBuf> = files.into_iter().collect();
+
+        if let Some(previous) = self.dependencies.remove(&key) {
+            for file in previous.difference(&files) {
+                if let Some(dependents) = self.dependents.get_mut(file) {
+                    dependents.remove(&key);
+                    if dependents.is_empty() {
+                        self.dependents.remove(file);
+                    }
+                }
+            }
+        }
+
+        for file in &files {
+            self.dependents.entry(file.clone()).or_default().insert(key);
+        }
+
+        self.dependencies.insert(key, files);
+    }
+
+    pub fn dependencies(&self, key: K) -> impl Iterator<Item = &Path> {
+        self.dependencies
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns every pipeline which used any of the `changed` files in its latest compilation.
+    ///
+    /// Files which no longer exist can't be re-read to find out what still includes them,
+    /// so all their previous dependents are rebuilt.
+    pub fn invalidate<'a>(&self, changed: impl IntoIterator<Item = &'a Path>) -> HashSet<K> {
+        let mut invalidated = HashSet::new();
+
+        for file in changed {
+            let dependents = match self.dependents.get(file) {
+                Some(dependents) => dependents,
+                None => continue,
+            };
+
+            if !file.exists() {
+                log::warn!(
+                    "Shader source {:?} was removed; rebuilding {} pipeline(s) which referenced it",
+                    file,
+                    dependents.len()
+                );
+            }
+
+            invalidated.extend(dependents.iter().copied());
+        }
+
+        invalidated
+    }
+}
+
+#[test]
+fn test_shader_dependency_graph() {
+    // Fake include resolver: file -> files it includes. `cycle_a` and `cycle_b` include each other.
+    let includes: HashMap<&str, &[&str]> = vec![
+        ("blur.hlsl", &["common.hlsl", "math.hlsl"][..]),
+        ("tonemap.hlsl", &["common.hlsl"][..]),
+        ("common.hlsl", &["math.hlsl"][..]),
+        ("math.hlsl", &[][..]),
+        ("cycle_a.hlsl", &["cycle_b.hlsl"][..]),
+        ("cycle_b.hlsl", &["cycle_a.hlsl"][..]),
+    ]
+    .into_iter()
+    .collect();
+
+    fn compile(
+        includes: &HashMap<&str, &[&str]>,
+        tracker: &mut IncludeTracker,
+        stack: &mut Vec<String>,
+        file: &str,
+    ) {
+        if !tracker.include(stack, file) {
+            return;
+        }
+
+        stack.push(file.to_owned());
+        for include in includes[file] {
+            compile(includes, tracker, stack, include);
+        }
+        stack.pop();
+    }
+
+    let mut graph = ShaderDependencyGraph::default();
+    for (key, shader) in [(0, "blur.hlsl"), (1, "tonemap.hlsl"), (2, "cycle_a.hlsl")] {
+        let mut tracker = IncludeTracker::default();
+        compile(&includes, &mut tracker, &mut Vec::new(), shader);
+        graph.set_dependencies(key, tracker.dependencies);
+    }
+
+    let invalidate = |graph: &ShaderDependencyGraph<i32>, file: &str| {
+        let mut keys: Vec<i32> = graph
+            .invalidate(std::iter::once(Path::new(file)))
+            .into_iter()
+            .collect();
+        keys.sort_unstable();
+        keys
+    };
+
+    assert_eq!(invalidate(&graph, "math.hlsl"), vec![0, 1]);
+    assert_eq!(invalidate(&graph, "common.hlsl"), vec![0, 1]);
+    assert_eq!(invalidate(&graph, "blur.hlsl"), vec![0]);
+    assert_eq!(invalidate(&graph, "cycle_b.hlsl"), vec![2]);
+    assert_eq!(invalidate(&graph, "unrelated.hlsl"), Vec::<i32>::new());
+
+    // Recompiling `tonemap` without the common header drops it from that header's dependents
+    graph.set_dependencies(1, vec![PathBuf::from("tonemap.hlsl")]);
+    assert_eq!(invalidate(&graph, "common.hlsl"), vec![0]);
+    assert_eq!(graph.dependencies(1).count(), 1);
+}
@@ -1,45 +1,145 @@
-use crate::vulkan::{
-    buffer::{Buffer, BufferDesc},
-    image::{Image, ImageDesc},
+use crate::{
+    vulkan::{
+        buffer::{Buffer, BufferDesc},
+        device::Device,
+        image::{Image, ImageDesc},
+    },
+    BackendError,
 };
 use std::collections::HashMap;
 
+/// Pooled resources which go unused for this many frames are released.
+const MAX_UNUSED_FRAMES: u64 = 8;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TransientResourceCacheStats {
+    /// Images created in the last frame because the cache had none with a matching desc
+    pub images_created: u32,
+    /// Buffers created in the last frame because the cache had none with a matching desc
+    pub buffers_created: u32,
+    /// Images released in the last frame after going unused
+    pub images_released: u32,
+    /// Buffers released in the last frame after going unused
+    pub buffers_released: u32,
+    /// Images currently waiting in the cache
+    pub pooled_images: u32,
+    /// Buffers currently waiting in the cache
+    pub pooled_buffers: u32,
+}
+
+/// Render graph transients, keyed by desc. Resources are returned to the cache
+/// once the frame using them retires, and tagged with the frame they were last used in.
 #[derive(Default)]
 pub struct TransientResourceCache {
-    images: HashMap<ImageDesc, Vec<Image>>,
-    buffers: HashMap<BufferDesc, Vec<Buffer>>,
+    images: HashMap<ImageDesc, Vec<(Image, u64)>>,
+    buffers: HashMap<BufferDesc, Vec<(Buffer, u64)>>,
+    frame_index: u64,
+    frame_stats: TransientResourceCacheStats,
+    last_frame_stats: TransientResourceCacheStats,
 }
 
 impl TransientResourceCache {
     pub fn get_image(&mut self, desc: &ImageDesc) -> Option<Image> {
         if let Some(entry) = self.images.get_mut(desc) {
-            entry.pop()
+            entry.pop().map(|(image, _)| image)
         } else {
             None
         }
     }
 
-    pub fn insert_image(&mut self, image: Image) {
-        if let Some(entry) = self.images.get_mut(&image.desc) {
-            entry.push(image)
+    pub fn get_or_create_image(
+        &mut self,
+        device: &Device,
+        desc: ImageDesc,
+    ) -> Result<Image, BackendError> {
+        if let Some(image) = self.get_image(&desc) {
+            Ok(image)
         } else {
-            self.images.insert(image.desc, vec![image]);
+            self.frame_stats.images_created += 1;
+            device.create_image(desc, vec![])
         }
     }
 
+    pub fn insert_image(&mut self, image: Image) {
+        let frame_index = self.frame_index;
+        self.images
+            .entry(image.desc)
+            .or_default()
+            .push((image, frame_index));
+    }
+
     pub fn get_buffer(&mut self, desc: &BufferDesc) -> Option<Buffer> {
         if let Some(entry) = self.buffers.get_mut(desc) {
-            entry.pop()
+            entry.pop().map(|(buffer, _)| buffer)
         } else {
             None
         }
     }
 
-    pub fn insert_buffer(&mut self, buffer: Buffer) {
-        if let Some(entry) = self.buffers.get_mut(&buffer.desc) {
-            entry.push(buffer)
+    pub fn get_or_create_buffer(
+        &mut self,
+        device: &Device,
+        desc: BufferDesc,
+        name: &str,
+    ) -> Result<Buffer, BackendError> {
+        if let Some(buffer) = self.get_buffer(&desc) {
+            Ok(buffer)
         } else {
-            self.buffers.insert(buffer.desc, vec![buffer]);
+            self.frame_stats.buffers_created += 1;
+            device.create_buffer(desc, name, None)
+        }
+    }
+
+    pub fn insert_buffer(&mut self, buffer: Buffer) {
+        let frame_index = self.frame_index;
+        self.buffers
+            .entry(buffer.desc)
+            .or_default()
+            .push((buffer, frame_index));
+    }
+
+    /// Call once per frame, after returning the retired frame's resources.
+    /// Releases resources unused for `MAX_UNUSED_FRAMES` through the device's deferred release queue.
+    pub fn maintain(&mut self, device: &Device) {
+        let frame_index = self.frame_index;
+        let is_stale = |last_used: u64| frame_index - last_used > MAX_UNUSED_FRAMES;
+
+        for entry in self.images.values_mut() {
+            // Entries are pushed in frame order, and popped from the back
+            while entry
+                .first()
+                .map_or(false, |(_, last_used)| is_stale(*last_used))
+            {
+                let (image, _) = entry.remove(0);
+                device.defer_release_image(image);
+                self.frame_stats.images_released += 1;
+            }
+        }
+
+        for entry in self.buffers.values_mut() {
+            while entry
+                .first()
+                .map_or(false, |(_, last_used)| is_stale(*last_used))
+            {
+                let (buffer, _) = entry.remove(0);
+                device.defer_release_buffer(buffer);
+                self.frame_stats.buffers_released += 1;
+            }
         }
+
+        self.images.retain(|_, entry| !entry.is_empty());
+        self.buffers.retain(|_, entry| !entry.is_empty());
+
+        self.frame_stats.pooled_images = self.images.values().map(|entry| entry.len() as u32).sum();
+        self.frame_stats.pooled_buffers =
+            self.buffers.values().map(|entry| entry.len() as u32).sum();
+
+        self.last_frame_stats = std::mem::take(&mut self.frame_stats);
+        self.frame_index += 1;
+    }
+
+    /// Counters for the most recent frame passed to `maintain`.
+    pub fn stats(&self) -> TransientResourceCacheStats {
+        self.last_frame_stats
     }
 }
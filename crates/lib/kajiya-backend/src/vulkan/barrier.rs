@@ -332,6 +332,71 @@ pub fn get_access_info(access_type: AccessType) -> AccessInfo {
     }
 }
 
+/// Access info for a resource used with several access types at once, e.g. a depth buffer
+/// sampled while also bound as a read-only attachment. Stage and access masks are merged,
+/// and the image layout is one which all of the access types can use.
+///
+/// Fails if the access types need conflicting layouts, e.g. a color attachment write
+/// together with a sampled read.
+pub fn get_combined_access_info(access_types: &[AccessType]) -> anyhow::Result<AccessInfo> {
+    let mut stage_mask = vk::PipelineStageFlags::empty();
+    let mut access_mask = vk::AccessFlags::empty();
+    let mut image_layout = vk::ImageLayout::UNDEFINED;
+    let mut layout_source = AccessType::Nothing;
+
+    for access_type in access_types {
+        let info = get_access_info(*access_type);
+        stage_mask |= info.stage_mask;
+        access_mask |= info.access_mask;
+
+        if info.image_layout == vk::ImageLayout::UNDEFINED || info.image_layout == image_layout {
+            continue;
+        }
+
+        if image_layout == vk::ImageLayout::UNDEFINED {
+            image_layout = info.image_layout;
+            layout_source = *access_type;
+            continue;
+        }
+
+        image_layout = combine_image_layouts(image_layout, info.image_layout).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{:?} and {:?} need conflicting image layouts ({:?} and {:?})",
+                layout_source,
+                access_type,
+                get_access_info(layout_source).image_layout,
+                info.image_layout
+            )
+        })?;
+    }
+
+    Ok(AccessInfo {
+        stage_mask,
+        access_mask,
+        image_layout,
+    })
+}
+
+fn combine_image_layouts(a: vk::ImageLayout, b: vk::ImageLayout) -> Option<vk::ImageLayout> {
+    let is_any_of = |layouts: &[vk::ImageLayout]| layouts.contains(&a) && layouts.contains(&b);
+
+    if is_any_of(&[
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+    ]) {
+        // Read-only depth can be sampled while bound as an attachment
+        Some(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+    } else if is_any_of(&[
+        vk::ImageLayout::GENERAL,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+    ]) {
+        Some(vk::ImageLayout::GENERAL)
+    } else {
+        None
+    }
+}
+
 pub fn image_aspect_mask_from_format(format: vk::Format) -> vk::ImageAspectFlags {
     match format {
         vk::Format::D16_UNORM => vk::ImageAspectFlags::DEPTH,
@@ -392,3 +457,78 @@ pub fn image_aspect_mask_from_access_type_and_format(
         _ => None,
     }*/
 }
+
+#[test]
+fn test_combined_access_info() {
+    let layout = |access_types: &[AccessType]| {
+        get_combined_access_info(access_types)
+            .ok()
+            .map(|info| info.image_layout)
+    };
+
+    // Legal combinations
+    assert_eq!(
+        layout(&[AccessType::ComputeShaderWrite]),
+        Some(vk::ImageLayout::GENERAL)
+    );
+    assert_eq!(
+        layout(&[
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+            AccessType::ComputeShaderReadOther,
+        ]),
+        Some(vk::ImageLayout::GENERAL)
+    );
+    assert_eq!(
+        layout(&[
+            AccessType::DepthStencilAttachmentRead,
+            AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+        ]),
+        Some(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+    );
+    assert_eq!(
+        layout(&[
+            AccessType::TransferRead,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        ]),
+        Some(vk::ImageLayout::GENERAL)
+    );
+    assert_eq!(
+        layout(&[AccessType::Nothing, AccessType::TransferRead]),
+        Some(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+    );
+
+    // Conflicting layouts
+    assert_eq!(
+        layout(&[
+            AccessType::ColorAttachmentWrite,
+            AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+        ]),
+        None
+    );
+    assert_eq!(
+        layout(&[
+            AccessType::DepthStencilAttachmentWrite,
+            AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+        ]),
+        None
+    );
+    assert_eq!(
+        layout(&[AccessType::TransferWrite, AccessType::TransferRead]),
+        None
+    );
+    assert_eq!(
+        layout(&[AccessType::Present, AccessType::ComputeShaderReadOther]),
+        None
+    );
+
+    let info = get_combined_access_info(&[
+        AccessType::VertexShaderReadSampledImageOrUniformTexelBuffer,
+        AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+    ])
+    .unwrap();
+    assert_eq!(
+        info.stage_mask,
+        vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER
+    );
+    assert_eq!(info.access_mask, vk::AccessFlags::SHADER_READ);
+}
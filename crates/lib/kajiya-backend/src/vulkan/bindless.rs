@@ -0,0 +1,369 @@
+use super::{
+    buffer::Buffer,
+    device::{Device, RESERVED_DESCRIPTOR_COUNT},
+    image::{Image, ImageViewDesc},
+    physical_device::PhysicalDevice,
+    shader::default_descriptor_set_stage_flags,
+};
+use crate::BackendError;
+use ash::vk;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+/// Bindings below this index hold single storage buffers with renderer-wide data,
+/// e.g. mesh and vertex buffers. They're written directly into `BindlessDescriptorSet::raw`.
+pub const BINDLESS_STORAGE_BUFFER_SLOT_COUNT: u32 = 3;
+
+/// Binding index of the `SAMPLED_IMAGE` runtime array in the bindless set.
+pub const BINDLESS_IMAGES_BINDING_INDEX: u32 = 3;
+
+/// Binding index of the `STORAGE_BUFFER` runtime array in the bindless set.
+pub const BINDLESS_BUFFERS_BINDING_INDEX: u32 = 4;
+
+lazy_static::lazy_static! {
+    /// The layout of the device-wide bindless set, in the same form as reflected shader layouts.
+    /// Can be passed to `DescriptorSetLayoutOpts::replace` to make a pipeline use the bindless set.
+    pub static ref BINDLESS_DESCRIPTOR_SET_LAYOUT: HashMap<u32, rspirv_reflect::DescriptorInfo> = [
+        // `meshes`
+        (0, rspirv_reflect::DescriptorInfo {
+            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
+            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
+            name: Default::default(),
+        }),
+        // `vertices`
+        (1, rspirv_reflect::DescriptorInfo {
+            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
+            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
+            name: Default::default(),
+        }),
+        // `bindless_texture_sizes`
+        (2, rspirv_reflect::DescriptorInfo {
+            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
+            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
+            name: Default::default(),
+        }),
+        // `bindless_textures`
+        (BINDLESS_IMAGES_BINDING_INDEX, rspirv_reflect::DescriptorInfo {
+            ty: rspirv_reflect::DescriptorType::SAMPLED_IMAGE,
+            dimensionality: rspirv_reflect::DescriptorDimensionality::RuntimeArray,
+            name: Default::default(),
+        }),
+        // `bindless_buffers`
+        (BINDLESS_BUFFERS_BINDING_INDEX, rspirv_reflect::DescriptorInfo {
+            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
+            dimensionality: rspirv_reflect::DescriptorDimensionality::RuntimeArray,
+            name: Default::default(),
+        }),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+}
+
+/// Returns true if a reflected descriptor set declares at least one of the bindless arrays,
+/// and all of its bindings line up with the bindless set layout.
+pub fn is_bindless_descriptor_set_layout(
+    set: &HashMap<u32, rspirv_reflect::DescriptorInfo>,
+) -> bool {
+    let is_runtime_array = |binding: &rspirv_reflect::DescriptorInfo| {
+        matches!(
+            binding.dimensionality,
+            rspirv_reflect::DescriptorDimensionality::RuntimeArray
+        )
+    };
+
+    set.values().any(is_runtime_array)
+        && set.iter().all(|(binding_index, binding)| {
+            BINDLESS_DESCRIPTOR_SET_LAYOUT
+                .get(binding_index)
+                .map_or(false, |bindless| {
+                    bindless.ty == binding.ty
+                        && is_runtime_array(bindless) == is_runtime_array(binding)
+                })
+        })
+}
+
+/// How many descriptors of a bindless array fit within an update-after-bind limit,
+/// leaving room for the regular bindings of the pipeline.
+fn bindless_descriptor_count(update_after_bind_limit: u32) -> u32 {
+    (512 * 1024).min(update_after_bind_limit.saturating_sub(RESERVED_DESCRIPTOR_COUNT))
+}
+
+pub(crate) fn max_bindless_image_count(pdevice: &PhysicalDevice) -> u32 {
+    let props = &pdevice.descriptor_indexing_properties;
+    bindless_descriptor_count(
+        props
+            .max_per_stage_descriptor_update_after_bind_sampled_images
+            .min(props.max_descriptor_set_update_after_bind_sampled_images),
+    )
+}
+
+pub(crate) fn max_bindless_buffer_count(pdevice: &PhysicalDevice) -> u32 {
+    let props = &pdevice.descriptor_indexing_properties;
+    bindless_descriptor_count(
+        props
+            .max_per_stage_descriptor_update_after_bind_storage_buffers
+            .min(props.max_descriptor_set_update_after_bind_storage_buffers)
+            .saturating_sub(BINDLESS_STORAGE_BUFFER_SLOT_COUNT),
+    )
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct BindlessHandle(pub u32);
+
+pub struct BindlessDescriptorSet {
+    pub raw: vk::DescriptorSet,
+    pub layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    max_image_count: u32,
+    max_buffer_count: u32,
+    next_image: Mutex<u32>,
+    next_buffer: Mutex<u32>,
+}
+
+impl BindlessDescriptorSet {
+    pub(crate) fn new(
+        device: &ash::Device,
+        pdevice: &PhysicalDevice,
+        ray_tracing_enabled: bool,
+    ) -> Self {
+        let max_image_count = max_bindless_image_count(pdevice);
+        let max_buffer_count = max_bindless_buffer_count(pdevice);
+        let stage_flags = default_descriptor_set_stage_flags(ray_tracing_enabled);
+
+        // Must match what `create_descriptor_set_layouts` produces for `BINDLESS_DESCRIPTOR_SET_LAYOUT`,
+        // so that pipeline layouts using it are compatible with this set.
+        let set_binding_flags = [
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND,
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND,
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND,
+            vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
+                | vk::DescriptorBindingFlags::PARTIALLY_BOUND,
+            vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
+                | vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT,
+        ];
+
+        let mut binding_flags_create_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+                .binding_flags(&set_binding_flags)
+                .build();
+
+        let layout = unsafe {
+            device
+                .create_descriptor_set_layout(
+                    &vk::DescriptorSetLayoutCreateInfo::builder()
+                        .bindings(&[
+                            // `meshes`
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(0)
+                                .descriptor_count(1)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .stage_flags(stage_flags)
+                                .build(),
+                            // `vertices`
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(1)
+                                .descriptor_count(1)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .stage_flags(stage_flags)
+                                .build(),
+                            // `bindless_texture_sizes`
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(2)
+                                .descriptor_count(1)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .stage_flags(stage_flags)
+                                .build(),
+                            // `bindless_textures`
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(BINDLESS_IMAGES_BINDING_INDEX)
+                                .descriptor_count(max_image_count)
+                                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                                .stage_flags(stage_flags)
+                                .build(),
+                            // `bindless_buffers`
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(BINDLESS_BUFFERS_BINDING_INDEX)
+                                .descriptor_count(max_buffer_count)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .stage_flags(stage_flags)
+                                .build(),
+                        ])
+                        .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+                        .push_next(&mut binding_flags_create_info)
+                        .build(),
+                    None,
+                )
+                .unwrap()
+        };
+
+        let descriptor_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: max_image_count,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: BINDLESS_STORAGE_BUFFER_SLOT_COUNT + max_buffer_count,
+            },
+        ];
+
+        let pool = unsafe {
+            device
+                .create_descriptor_pool(
+                    &vk::DescriptorPoolCreateInfo::builder()
+                        .pool_sizes(&descriptor_sizes)
+                        .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+                        .max_sets(1),
+                    None,
+                )
+                .unwrap()
+        };
+
+        let mut variable_descriptor_count_allocate_info =
+            vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+                .descriptor_counts(std::slice::from_ref(&max_buffer_count))
+                .build();
+
+        let raw = unsafe {
+            device
+                .allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::builder()
+                        .descriptor_pool(pool)
+                        .set_layouts(std::slice::from_ref(&layout))
+                        .push_next(&mut variable_descriptor_count_allocate_info)
+                        .build(),
+                )
+                .unwrap()[0]
+        };
+
+        Self {
+            raw,
+            layout,
+            pool,
+            max_image_count,
+            max_buffer_count,
+            next_image: Mutex::new(0),
+            next_buffer: Mutex::new(0),
+        }
+    }
+
+    /// Destroys the set along with its pool, and the layout.
+    /// Must only be called once no pipelines or command buffers using them remain.
+    pub(crate) unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_descriptor_pool(self.pool, None);
+        device.destroy_descriptor_set_layout(self.layout, None);
+    }
+}
+
+impl Device {
+    pub fn bindless_descriptor_set(&self) -> &BindlessDescriptorSet {
+        &self.bindless
+    }
+
+    /// Writes the image's default view into the next free slot of the bindless texture array,
+    /// and returns the index at which shaders can find it.
+    pub fn register_bindless_image(&self, image: &Image) -> Result<BindlessHandle, BackendError> {
+        let view = image.view(self, &ImageViewDesc::default())?;
+        self.register_bindless_image_view(view)
+    }
+
+    /// Like `register_bindless_image`, but with an explicit view, which must be
+    /// in `SHADER_READ_ONLY_OPTIMAL` layout whenever shaders access it.
+    pub fn register_bindless_image_view(
+        &self,
+        view: vk::ImageView,
+    ) -> Result<BindlessHandle, BackendError> {
+        let index = {
+            let mut next_image = self.bindless.next_image.lock();
+            if *next_image >= self.bindless.max_image_count {
+                return Err(BackendError::ResourceAccess {
+                    info: format!(
+                        "Out of bindless image slots ({} max)",
+                        self.bindless.max_image_count
+                    ),
+                });
+            }
+
+            let index = *next_image;
+            *next_image += 1;
+            index
+        };
+
+        let image_info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(view)
+            .build();
+
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.bindless.raw)
+            .dst_binding(BINDLESS_IMAGES_BINDING_INDEX)
+            .dst_array_element(index)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(std::slice::from_ref(&image_info))
+            .build();
+
+        unsafe {
+            self.raw
+                .update_descriptor_sets(std::slice::from_ref(&write), &[]);
+        }
+
+        Ok(BindlessHandle(index))
+    }
+
+    /// Writes the buffer into the next free slot of the bindless storage buffer array.
+    pub fn register_bindless_buffer(
+        &self,
+        buffer: &Buffer,
+    ) -> Result<BindlessHandle, BackendError> {
+        let index = {
+            let mut next_buffer = self.bindless.next_buffer.lock();
+            if *next_buffer >= self.bindless.max_buffer_count {
+                return Err(BackendError::ResourceAccess {
+                    info: format!(
+                        "Out of bindless buffer slots ({} max)",
+                        self.bindless.max_buffer_count
+                    ),
+                });
+            }
+
+            let index = *next_buffer;
+            *next_buffer += 1;
+            index
+        };
+
+        let buffer_info = vk::DescriptorBufferInfo::builder()
+            .buffer(buffer.raw)
+            .range(vk::WHOLE_SIZE)
+            .build();
+
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.bindless.raw)
+            .dst_binding(BINDLESS_BUFFERS_BINDING_INDEX)
+            .dst_array_element(index)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(std::slice::from_ref(&buffer_info))
+            .build();
+
+        unsafe {
+            self.raw
+                .update_descriptor_sets(std::slice::from_ref(&write), &[]);
+        }
+
+        Ok(BindlessHandle(index))
+    }
+}
+
+#[test]
+fn test_bindless_descriptor_count() {
+    assert_eq!(bindless_descriptor_count(0), 0);
+    assert_eq!(bindless_descriptor_count(RESERVED_DESCRIPTOR_COUNT), 0);
+    assert_eq!(
+        bindless_descriptor_count(RESERVED_DESCRIPTOR_COUNT + 100),
+        100
+    );
+    assert_eq!(bindless_descriptor_count(u32::MAX), 512 * 1024);
+}
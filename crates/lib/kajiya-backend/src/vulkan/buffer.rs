@@ -1,16 +1,83 @@
 use crate::BackendError;
 
-use super::device::Device;
+use super::{device::Device, physical_device::PhysicalDevice};
 use ash::vk;
 use gpu_allocator::{AllocationCreateDesc, MemoryLocation};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const NO_GPU_WRITE: u64 = u64::MAX;
 
 pub struct Buffer {
     pub raw: vk::Buffer,
     pub desc: BufferDesc,
     pub allocation: gpu_allocator::SubAllocation,
+    memory_type_bits: u32,
+    gpu_write_frame: AtomicU64,
 }
 
 impl Buffer {
+    /// Records that the GPU writes to this buffer in the frame currently being recorded.
+    /// `mapped_slice` will refuse to read it until that frame has retired.
+    pub fn mark_gpu_write(&self, device: &Device) {
+        self.gpu_write_frame
+            .store(device.frame_index(), Ordering::Release);
+    }
+
+    /// Persistently mapped contents of a host-visible buffer, typically one created with
+    /// `BufferDesc::new_gpu_to_cpu`. Non-coherent memory is invalidated before returning.
+    pub fn mapped_slice(&self, device: &Device) -> &[u8] {
+        let gpu_write_frame = self.gpu_write_frame.load(Ordering::Acquire);
+        assert!(
+            gpu_write_frame == NO_GPU_WRITE || gpu_write_frame < device.retired_frame_count(),
+            "Buffer was written by frame {}, which the GPU has not finished yet",
+            gpu_write_frame
+        );
+
+        let mapped_slice = self
+            .allocation
+            .mapped_slice()
+            .expect("mapped_slice: buffer memory is not host-visible");
+
+        if !self.is_host_coherent(device) {
+            // Offset and size need to be multiples of the atom size
+            let atom_size = device
+                .physical_device()
+                .properties
+                .limits
+                .non_coherent_atom_size;
+            let offset = self.allocation.offset() / atom_size * atom_size;
+            let end = self.allocation.offset() + self.allocation.size();
+            let size = (end - offset + atom_size - 1) / atom_size * atom_size;
+
+            unsafe {
+                device
+                    .raw
+                    .invalidate_mapped_memory_ranges(&[vk::MappedMemoryRange::builder()
+                        .memory(self.allocation.memory())
+                        .offset(offset)
+                        .size(size)
+                        .build()])
+                    .expect("invalidate_mapped_memory_ranges");
+            }
+        }
+
+        &mapped_slice[..self.desc.size]
+    }
+
+    /// Conservatively checks whether all host-visible memory types the buffer could
+    /// have been allocated from are coherent.
+    fn is_host_coherent(&self, device: &Device) -> bool {
+        let memory_properties = &device.physical_device().memory_properties;
+
+        memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| self.memory_type_bits & (1 << idx) != 0)
+            .map(|(_, ty)| ty.property_flags)
+            .filter(|flags| flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE))
+            .all(|flags| flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT))
+    }
+
     pub fn device_address(&self, device: &Device) -> u64 {
         unsafe {
             device.raw.get_buffer_device_address(
@@ -65,14 +132,18 @@ impl BufferDesc {
 impl Device {
     pub(crate) fn create_buffer_impl(
         raw: &ash::Device,
+        pdevice: &PhysicalDevice,
         allocator: &mut gpu_allocator::VulkanAllocator,
         desc: BufferDesc,
         name: &str,
+        concurrent_queue_families: &[u32],
     ) -> Result<Buffer, BackendError> {
         let buffer_info = vk::BufferCreateInfo {
             size: desc.size as u64,
             usage: desc.usage,
-            sharing_mode: vk::SharingMode::EXCLUSIVE,
+            sharing_mode: super::device::sharing_mode(concurrent_queue_families),
+            queue_family_index_count: concurrent_queue_families.len() as u32,
+            p_queue_family_indices: concurrent_queue_families.as_ptr(),
             ..Default::default()
         };
 
@@ -105,6 +176,9 @@ impl Device {
             .map_err(move |err| BackendError::Allocation {
                 inner: err,
                 name: name.to_owned(),
+                size: requirements.size,
+                memory_type_bits: requirements.memory_type_bits,
+                memory_heaps: pdevice.memory_heaps(),
             })?;
 
         // Bind memory to the buffer
@@ -117,6 +191,8 @@ impl Device {
             raw: buffer,
             desc,
             allocation,
+            memory_type_bits: requirements.memory_type_bits,
+            gpu_write_frame: AtomicU64::new(NO_GPU_WRITE),
         })
     }
 
@@ -131,8 +207,14 @@ impl Device {
         if initial_data.is_some() {
             desc.usage |= vk::BufferUsageFlags::TRANSFER_DST;
         }
-        let buffer =
-            Self::create_buffer_impl(&self.raw, &mut self.global_allocator.lock(), desc, &name)?;
+        let buffer = Self::create_buffer_impl(
+            &self.raw,
+            &self.pdevice,
+            &mut self.global_allocator.lock(),
+            desc,
+            &name,
+            &self.concurrent_queue_families,
+        )?;
 
         if let Some(initial_data) = initial_data {
             let scratch_desc =
@@ -140,9 +222,11 @@ impl Device {
 
             let mut scratch_buffer = Self::create_buffer_impl(
                 &self.raw,
+                &self.pdevice,
                 &mut self.global_allocator.lock(),
                 scratch_desc,
                 &format!("Initial data for {:?}", name),
+                &self.concurrent_queue_families,
             )?;
 
             scratch_buffer.allocation.mapped_slice_mut().unwrap()[0..initial_data.len()]
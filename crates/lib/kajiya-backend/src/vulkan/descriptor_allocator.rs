@@ -0,0 +1,275 @@
+use ash::vk;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// Max sets in the first pool allocated for a frame. Each subsequent pool doubles this,
+/// up to `MAX_SETS_PER_POOL_LIMIT`.
+const INITIAL_MAX_SETS_PER_POOL: u32 = 256;
+
+/// Frames which need more sets get more pools of this size, rather than ever larger ones.
+const MAX_SETS_PER_POOL_LIMIT: u32 = 4096;
+
+/// Per-set descriptor counts used to size pools. Multiplied by the pool's `max_sets`.
+const DESCRIPTOR_COUNTS_PER_SET: &[(vk::DescriptorType, u32)] = &[
+    (vk::DescriptorType::SAMPLER, 4),
+    (vk::DescriptorType::SAMPLED_IMAGE, 8),
+    (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2),
+    (vk::DescriptorType::STORAGE_IMAGE, 4),
+    (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1),
+    (vk::DescriptorType::UNIFORM_TEXEL_BUFFER, 1),
+    (vk::DescriptorType::STORAGE_BUFFER, 4),
+    (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 1),
+];
+
+const ACCELERATION_STRUCTURE_COUNT_PER_SET: u32 = 1;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DescriptorAllocatorStats {
+    /// Descriptor sets allocated since the last reset
+    pub sets_allocated: u32,
+    /// Pools currently owned by the allocator
+    pub pools_created: u32,
+}
+
+/// Describes a descriptor set to be allocated by `FrameDescriptorAllocator`.
+pub struct FrameDescriptorSetDesc<'a> {
+    pub layout: vk::DescriptorSetLayout,
+    /// Total descriptors of each type in the set, accounting for `variable_descriptor_count`.
+    /// Used to create a dedicated pool when the set doesn't fit the regular ones.
+    pub descriptor_counts: &'a [vk::DescriptorPoolSize],
+    /// The layout was created with `UPDATE_AFTER_BIND_POOL`
+    pub update_after_bind: bool,
+    /// Array length of the binding with `VARIABLE_DESCRIPTOR_COUNT`, if the layout has one
+    pub variable_descriptor_count: Option<u32>,
+}
+
+#[derive(Default)]
+struct PoolChain {
+    pools: Vec<vk::DescriptorPool>,
+    current_pool: usize,
+}
+
+impl PoolChain {
+    fn reset(&mut self, device: &ash::Device) {
+        for pool in &self.pools {
+            unsafe {
+                device
+                    .reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())
+                    .expect("reset_descriptor_pool");
+            }
+        }
+
+        self.current_pool = 0;
+    }
+}
+
+/// Allocates transient descriptor sets for one frame in flight.
+///
+/// Sets are carved out of a list of pools; when the current pool runs out,
+/// a new one twice the size of the previous is created, up to a limit. Everything is reset
+/// at once when the frame is recycled, keeping the pools around for reuse.
+///
+/// Sets too large for the regular pools (e.g. big variable-count arrays) get
+/// dedicated pools, which are destroyed when the frame is recycled.
+pub struct FrameDescriptorAllocator {
+    regular: PoolChain,
+    update_after_bind: PoolChain,
+    dedicated_pools: Vec<vk::DescriptorPool>,
+    sets_allocated: u32,
+    ray_tracing_enabled: bool,
+}
+
+impl FrameDescriptorAllocator {
+    pub(crate) fn new(ray_tracing_enabled: bool) -> Self {
+        Self {
+            regular: Default::default(),
+            update_after_bind: Default::default(),
+            dedicated_pools: Vec::new(),
+            sets_allocated: 0,
+            ray_tracing_enabled,
+        }
+    }
+
+    fn create_pool(
+        device: &ash::Device,
+        max_sets: u32,
+        pool_sizes: &[vk::DescriptorPoolSize],
+        update_after_bind: bool,
+    ) -> vk::DescriptorPool {
+        let flags = if update_after_bind {
+            vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND
+        } else {
+            vk::DescriptorPoolCreateFlags::empty()
+        };
+
+        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(max_sets)
+            .pool_sizes(pool_sizes)
+            .flags(flags);
+
+        unsafe { device.create_descriptor_pool(&descriptor_pool_create_info, None) }
+            .expect("create_descriptor_pool")
+    }
+
+    fn pool_max_sets(pool_index: usize) -> u32 {
+        let doublings = (MAX_SETS_PER_POOL_LIMIT / INITIAL_MAX_SETS_PER_POOL).trailing_zeros();
+        INITIAL_MAX_SETS_PER_POOL << (pool_index as u32).min(doublings)
+    }
+
+    fn pool_sizes(ray_tracing_enabled: bool, max_sets: u32) -> Vec<vk::DescriptorPoolSize> {
+        let mut pool_sizes: Vec<vk::DescriptorPoolSize> = DESCRIPTOR_COUNTS_PER_SET
+            .iter()
+            .map(|&(ty, count)| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: count * max_sets,
+            })
+            .collect();
+
+        // Only valid when the extension is enabled
+        if ray_tracing_enabled {
+            pool_sizes.push(vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                descriptor_count: ACCELERATION_STRUCTURE_COUNT_PER_SET * max_sets,
+            });
+        }
+
+        pool_sizes
+    }
+
+    fn try_allocate(
+        device: &ash::Device,
+        pool: vk::DescriptorPool,
+        desc: &FrameDescriptorSetDesc,
+    ) -> Result<vk::DescriptorSet, vk::Result> {
+        let variable_descriptor_counts: Vec<u32> =
+            desc.variable_descriptor_count.into_iter().collect();
+        let mut variable_descriptor_count_allocate_info =
+            vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+                .descriptor_counts(&variable_descriptor_counts);
+
+        let mut descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(std::slice::from_ref(&desc.layout));
+
+        if desc.variable_descriptor_count.is_some() {
+            descriptor_set_allocate_info = descriptor_set_allocate_info
+                .push_next(&mut variable_descriptor_count_allocate_info);
+        }
+
+        unsafe { device.allocate_descriptor_sets(&descriptor_set_allocate_info) }
+            .map(|sets| sets[0])
+    }
+
+    /// Allocates a descriptor set, valid until the frame is retired.
+    pub fn allocate(
+        &mut self,
+        device: &ash::Device,
+        desc: &FrameDescriptorSetDesc,
+    ) -> vk::DescriptorSet {
+        let ray_tracing_enabled = self.ray_tracing_enabled;
+        let chain = if desc.update_after_bind {
+            &mut self.update_after_bind
+        } else {
+            &mut self.regular
+        };
+
+        loop {
+            let fresh_pool = chain.current_pool == chain.pools.len();
+            if fresh_pool {
+                let max_sets = Self::pool_max_sets(chain.pools.len());
+                chain.pools.push(Self::create_pool(
+                    device,
+                    max_sets,
+                    &Self::pool_sizes(ray_tracing_enabled, max_sets),
+                    desc.update_after_bind,
+                ));
+
+                trace!(
+                    "Created descriptor pool #{} with {} max sets",
+                    chain.pools.len(),
+                    max_sets
+                );
+            }
+
+            match Self::try_allocate(device, chain.pools[chain.current_pool], desc) {
+                Ok(set) => {
+                    self.sets_allocated += 1;
+                    return set;
+                }
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY)
+                | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {
+                    if fresh_pool {
+                        // Even an empty pool can't fit it; the set needs its own.
+                        // The fresh pool stays current for subsequent allocations.
+                        return self.allocate_dedicated(device, desc);
+                    }
+
+                    // Move on to the next pool, creating it if needed
+                    chain.current_pool += 1;
+                }
+                Err(err) => panic!("allocate_descriptor_sets: {:?}", err),
+            }
+        }
+    }
+
+    fn allocate_dedicated(
+        &mut self,
+        device: &ash::Device,
+        desc: &FrameDescriptorSetDesc,
+    ) -> vk::DescriptorSet {
+        let pool = Self::create_pool(device, 1, desc.descriptor_counts, desc.update_after_bind);
+        self.dedicated_pools.push(pool);
+
+        let set = Self::try_allocate(device, pool, desc).expect("allocate_descriptor_sets");
+        self.sets_allocated += 1;
+        set
+    }
+
+    /// Returns all sets to their pools. Must only be called once the GPU is done with the frame.
+    pub(crate) fn reset(&mut self, device: &ash::Device) {
+        self.regular.reset(device);
+        self.update_after_bind.reset(device);
+
+        for pool in self.dedicated_pools.drain(..) {
+            unsafe {
+                device.destroy_descriptor_pool(pool, None);
+            }
+        }
+
+        self.sets_allocated = 0;
+    }
+
+    /// Destroys all the pools. Must only be called once the GPU is done with all the sets.
+    pub(crate) unsafe fn destroy(&mut self, device: &ash::Device) {
+        self.reset(device);
+
+        for pool in self
+            .regular
+            .pools
+            .drain(..)
+            .chain(self.update_after_bind.pools.drain(..))
+        {
+            device.destroy_descriptor_pool(pool, None);
+        }
+
+        self.regular.current_pool = 0;
+        self.update_after_bind.current_pool = 0;
+    }
+
+    pub fn stats(&self) -> DescriptorAllocatorStats {
+        DescriptorAllocatorStats {
+            sets_allocated: self.sets_allocated,
+            pools_created: (self.regular.pools.len()
+                + self.update_after_bind.pools.len()
+                + self.dedicated_pools.len()) as u32,
+        }
+    }
+}
+
+#[test]
+fn test_pool_max_sets() {
+    let max_sets: Vec<u32> = (0..8)
+        .map(FrameDescriptorAllocator::pool_max_sets)
+        .collect();
+    assert_eq!(max_sets, [256, 512, 1024, 2048, 4096, 4096, 4096, 4096]);
+}
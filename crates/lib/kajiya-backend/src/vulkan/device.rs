@@ -1,10 +1,22 @@
 use crate::{vulkan::buffer::BufferDesc, BackendError};
 
 use super::{
+    barrier::image_aspect_mask_from_format,
+    bindless::{self, BindlessDescriptorSet},
     buffer::Buffer,
+    descriptor_allocator::{
+        DescriptorAllocatorStats, FrameDescriptorAllocator, FrameDescriptorSetDesc,
+    },
+    device_features::{DeviceFeature, DeviceFeatureSet, FeatureChain},
     error::CrashMarkerNames,
-    physical_device::{PhysicalDevice, QueueFamily},
+    image::{self, try_format_texel_size_bytes, Image, ImageDesc},
+    instance::ApiVersion,
+    layout_cache::{LayoutCache, LayoutCacheStats},
+    physical_device::{PhysicalDevice, PhysicalDeviceFeatures, QueueFamily},
     profiler::VkProfilerData,
+    shader::{self, RenderPass},
+    shader_module_cache::ShaderModuleCache,
+    surface::Surface,
 };
 use anyhow::Result;
 use ash::{
@@ -17,8 +29,11 @@ use log::{debug, error, info, trace, warn};
 use parking_lot::Mutex;
 use std::{
     collections::{HashMap, HashSet},
-    os::raw::c_char,
-    sync::Arc,
+    ffi::{CStr, CString},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
 };
 
 /// Descriptor count to subtract from the max bindless descriptor count,
@@ -29,6 +44,50 @@ pub const RESERVED_DESCRIPTOR_COUNT: u32 = 32;
 pub struct Queue {
     pub raw: vk::Queue,
     pub family: QueueFamily,
+    /// Within `family`
+    pub index: u32,
+    /// Held while submitting or presenting, which need external synchronization
+    submit_lock: Mutex<()>,
+}
+
+impl Queue {
+    /// `vkQueueSubmit`, serialized with other submissions to this queue.
+    ///
+    /// # Safety
+    ///
+    /// As with `ash::Device::queue_submit`
+    pub unsafe fn submit(
+        &self,
+        device: &ash::Device,
+        submits: &[vk::SubmitInfo],
+        fence: vk::Fence,
+    ) -> ash::prelude::VkResult<()> {
+        let _lock = self.submit_lock.lock();
+        device.queue_submit(self.raw, submits, fence)
+    }
+
+    /// `vkQueuePresentKHR`, serialized with submissions to this queue.
+    /// Returns whether the swapchain is suboptimal.
+    ///
+    /// # Safety
+    ///
+    /// As with `ash::extensions::khr::Swapchain::queue_present`
+    pub unsafe fn present(
+        &self,
+        swapchain_fns: &khr::Swapchain,
+        present_info: &vk::PresentInfoKHR,
+    ) -> ash::prelude::VkResult<bool> {
+        let _lock = self.submit_lock.lock();
+        swapchain_fns.queue_present(self.raw, present_info)
+    }
+}
+
+/// What a queue is used for. Several kinds can share a queue; see `Device::queue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueueKind {
+    Universal,
+    AsyncCompute,
+    Present,
 }
 
 pub trait DeferredRelease: Copy {
@@ -41,31 +100,145 @@ impl DeferredRelease for vk::DescriptorPool {
     }
 }
 
+impl DeferredRelease for vk::Framebuffer {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.framebuffers.push(self);
+    }
+}
+
+impl DeferredRelease for vk::Pipeline {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.pipelines.push(self);
+    }
+}
+
+/// A swapchain dropped while the GPU may still be using its images.
+/// Keeps the surface alive, as it must outlive the swapchain.
+pub struct RetiredSwapchain {
+    pub fns: khr::Swapchain,
+    pub raw: vk::SwapchainKHR,
+    pub surface: Arc<Surface>,
+}
+
 #[derive(Default)]
 pub struct PendingResourceReleases {
     pub descriptor_pools: Vec<vk::DescriptorPool>,
+    pub framebuffers: Vec<vk::Framebuffer>,
+    pub pipelines: Vec<vk::Pipeline>,
+    pub semaphores: Vec<vk::Semaphore>,
+    pub image_views: Vec<vk::ImageView>,
+    pub buffers: Vec<Buffer>,
+    pub images: Vec<Image>,
+    pub swapchains: Vec<RetiredSwapchain>,
 }
 
 impl PendingResourceReleases {
-    fn release_all(&mut self, device: &ash::Device) {
+    fn release_all(&mut self, device: &ash::Device, allocator: &Mutex<VulkanAllocator>) {
         unsafe {
             for res in self.descriptor_pools.drain(..) {
                 device.destroy_descriptor_pool(res, None);
             }
+
+            for res in self.framebuffers.drain(..) {
+                device.destroy_framebuffer(res, None);
+            }
+
+            for res in self.pipelines.drain(..) {
+                device.destroy_pipeline(res, None);
+            }
+
+            for res in self.semaphores.drain(..) {
+                device.destroy_semaphore(res, None);
+            }
+
+            for res in self.image_views.drain(..) {
+                device.destroy_image_view(res, None);
+            }
+
+            for res in self.buffers.drain(..) {
+                device.destroy_buffer(res.raw, None);
+                allocator
+                    .lock()
+                    .free(res.allocation)
+                    .expect("buffer memory deallocated");
+            }
+
+            for res in self.images.drain(..) {
+                for view in res.views.into_inner().values() {
+                    device.destroy_image_view(*view, None);
+                }
+
+                device.destroy_image(res.raw, None);
+                if let Some(allocation) = res.allocation {
+                    allocator
+                        .lock()
+                        .free(allocation)
+                        .expect("image memory deallocated");
+                }
+            }
+
+            // After the image views, which may belong to swapchain images.
+            // Dropping the surface afterwards destroys it, unless it's still in use.
+            for res in self.swapchains.drain(..) {
+                res.fns.destroy_swapchain(res.raw, None);
+            }
         }
     }
 }
 
 pub struct DeviceFrame {
     //pub(crate) linear_allocator_pool: vk_mem::AllocatorPool,
-    pub swapchain_acquired_semaphore: Option<vk::Semaphore>,
-    pub rendering_complete_semaphore: Option<vk::Semaphore>,
     pub main_command_buffer: CommandBuffer,
     pub presentation_command_buffer: CommandBuffer,
+    /// Present if the device has an async compute queue
+    pub async_compute: Option<AsyncComputeFrame>,
     pub pending_resource_releases: Mutex<PendingResourceReleases>,
+    pub descriptor_allocator: Mutex<FrameDescriptorAllocator>,
     pub profiler_data: VkProfilerData,
 }
 
+/// Command buffers and semaphores used when render graph passes are split between
+/// the universal queue and `Device::async_compute_queue`. The frame is submitted as:
+///
+/// 1. `DeviceFrame::main_command_buffer`, signaling `fork_semaphore`
+/// 2. `command_buffer` on the async compute queue, waiting on `fork_semaphore`, signaling `join_semaphore`
+/// 3. `overlap_command_buffer`, running alongside async compute
+/// 4. `join_command_buffer`, waiting on `join_semaphore`
+pub struct AsyncComputeFrame {
+    pub command_buffer: CommandBuffer,
+    pub overlap_command_buffer: CommandBuffer,
+    pub join_command_buffer: CommandBuffer,
+    pub fork_semaphore: vk::Semaphore,
+    pub join_semaphore: vk::Semaphore,
+}
+
+impl AsyncComputeFrame {
+    fn new(
+        device: &ash::Device,
+        universal_queue_family: &QueueFamily,
+        async_compute_queue_family: &QueueFamily,
+    ) -> Result<Self> {
+        let create_semaphore =
+            || unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) };
+
+        Ok(Self {
+            command_buffer: CommandBuffer::new(device, async_compute_queue_family)?,
+            overlap_command_buffer: CommandBuffer::new(device, universal_queue_family)?,
+            join_command_buffer: CommandBuffer::new(device, universal_queue_family)?,
+            fork_semaphore: create_semaphore()?,
+            join_semaphore: create_semaphore()?,
+        })
+    }
+
+    pub fn command_buffers(&self) -> [&CommandBuffer; 3] {
+        [
+            &self.command_buffer,
+            &self.overlap_command_buffer,
+            &self.join_command_buffer,
+        ]
+    }
+}
+
 pub struct CommandBuffer {
     pub raw: vk::CommandBuffer,
     pub submit_done_fence: vk::Fence,
@@ -108,11 +281,160 @@ impl CommandBuffer {
     }
 }
 
+/// Layout of image data copied into a buffer by `CommandBuffer::copy_image_to_buffer`.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageReadbackLayout {
+    /// Texels per row in the buffer, including padding
+    pub row_length: u32,
+    /// Bytes between the starts of consecutive rows
+    pub row_pitch_bytes: usize,
+    pub extent: [u32; 3],
+    pub size_bytes: usize,
+    pub texel_size_bytes: usize,
+}
+
+impl ImageReadbackLayout {
+    /// Row pitch alignment friendly to all vendors' copy engines
+    const ROW_PITCH_ALIGNMENT: usize = 256;
+
+    /// Fails for formats without a known texel size, e.g. block-compressed ones.
+    pub fn new(desc: &ImageDesc, mip_level: u32) -> Result<Self, BackendError> {
+        let texel_size = try_format_texel_size_bytes(desc.format).ok_or_else(|| {
+            BackendError::InvalidImageDesc {
+                field: "format",
+                info: format!("{:?} can't be copied to a buffer", desc.format),
+            }
+        })? as usize;
+        let extent = [
+            (desc.extent[0] >> mip_level).max(1),
+            (desc.extent[1] >> mip_level).max(1),
+            (desc.extent[2] >> mip_level).max(1),
+        ];
+
+        let aligned_row_bytes = (extent[0] as usize * texel_size + Self::ROW_PITCH_ALIGNMENT - 1)
+            / Self::ROW_PITCH_ALIGNMENT
+            * Self::ROW_PITCH_ALIGNMENT;
+
+        // `bufferRowLength` is in texels, so the pitch must also be a multiple of the texel size
+        let row_length = ((aligned_row_bytes + texel_size - 1) / texel_size) as u32;
+        let row_pitch_bytes = row_length as usize * texel_size;
+
+        Ok(Self {
+            row_length,
+            row_pitch_bytes,
+            extent,
+            size_bytes: row_pitch_bytes * extent[1] as usize * extent[2] as usize,
+            texel_size_bytes: texel_size,
+        })
+    }
+}
+
+impl CommandBuffer {
+    /// Copies one mip of `image` into `buffer` using the layout from `ImageReadbackLayout`,
+    /// and marks the buffer as written this frame.
+    ///
+    /// The image must be in `TRANSFER_SRC_OPTIMAL`, and `buffer` needs `TRANSFER_DST` usage.
+    pub fn copy_image_to_buffer(
+        &self,
+        device: &Device,
+        image: &Image,
+        buffer: &Buffer,
+        mip_level: u32,
+    ) -> Result<ImageReadbackLayout, BackendError> {
+        let layout = ImageReadbackLayout::new(&image.desc, mip_level)?;
+        assert!(
+            buffer.desc.size >= layout.size_bytes,
+            "copy_image_to_buffer: buffer too small ({} < {})",
+            buffer.desc.size,
+            layout.size_bytes
+        );
+
+        let region = vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(layout.row_length)
+            .buffer_image_height(layout.extent[1])
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(image_aspect_mask_from_format(image.desc.format))
+                    .mip_level(mip_level)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_extent(vk::Extent3D {
+                width: layout.extent[0],
+                height: layout.extent[1],
+                depth: layout.extent[2],
+            })
+            .build();
+
+        unsafe {
+            device.raw.cmd_copy_image_to_buffer(
+                self.raw,
+                image.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer.raw,
+                std::slice::from_ref(&region),
+            );
+        }
+
+        buffer.mark_gpu_write(device);
+
+        Ok(layout)
+    }
+
+    /// Binds `(buffer, offset)` pairs to consecutive vertex input bindings, starting at `first_binding`.
+    pub fn bind_vertex_buffers(
+        &self,
+        device: &Device,
+        first_binding: u32,
+        buffers: &[(&Buffer, u64)],
+    ) {
+        let raw_buffers: Vec<vk::Buffer> = buffers.iter().map(|(buffer, _)| buffer.raw).collect();
+        let offsets: Vec<vk::DeviceSize> = buffers.iter().map(|(_, offset)| *offset).collect();
+
+        unsafe {
+            device
+                .raw
+                .cmd_bind_vertex_buffers(self.raw, first_binding, &raw_buffers, &offsets);
+        }
+    }
+
+    pub fn bind_index_buffer(
+        &self,
+        device: &Device,
+        buffer: &Buffer,
+        offset: u64,
+        index_type: vk::IndexType,
+    ) {
+        unsafe {
+            device
+                .raw
+                .cmd_bind_index_buffer(self.raw, buffer.raw, offset, index_type);
+        }
+    }
+
+    /// Sets the stencil reference of pipelines created with `StencilStateDesc::dynamic_reference`.
+    pub fn set_stencil_reference(
+        &self,
+        device: &Device,
+        face_mask: vk::StencilFaceFlags,
+        reference: u32,
+    ) {
+        unsafe {
+            device
+                .raw
+                .cmd_set_stencil_reference(self.raw, face_mask, reference);
+        }
+    }
+}
+
 impl DeviceFrame {
     pub fn new(
         device: &ash::Device,
         global_allocator: &mut VulkanAllocator,
         queue_family: &QueueFamily,
+        async_compute_queue_family: Option<&QueueFamily>,
+        ray_tracing_enabled: bool,
     ) -> Self {
         Self {
             /*linear_allocator_pool: global_allocator
@@ -122,24 +444,183 @@ impl DeviceFrame {
                 info
             })
             .expect("linear allocator"),*/
-            swapchain_acquired_semaphore: None,
-            rendering_complete_semaphore: None,
             main_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
             presentation_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
+            async_compute: async_compute_queue_family.map(|async_compute_queue_family| {
+                AsyncComputeFrame::new(device, queue_family, async_compute_queue_family).unwrap()
+            }),
             pending_resource_releases: Default::default(),
+            descriptor_allocator: Mutex::new(FrameDescriptorAllocator::new(ray_tracing_enabled)),
             profiler_data: VkProfilerData::new(device, global_allocator),
         }
     }
 }
 
+pub(crate) fn sharing_mode(concurrent_queue_families: &[u32]) -> vk::SharingMode {
+    if concurrent_queue_families.is_empty() {
+        vk::SharingMode::EXCLUSIVE
+    } else {
+        vk::SharingMode::CONCURRENT
+    }
+}
+
+/// Optional features, resolved when the device is created.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceCapabilities {
+    /// `VK_KHR_acceleration_structure` and `VK_KHR_ray_tracing_pipeline` are enabled
+    pub ray_tracing: bool,
+    /// `VK_KHR_ray_query` is enabled. Requires `ray_tracing`.
+    pub ray_query: bool,
+    /// `fillModeNonSolid` is enabled, allowing `LINE` and `POINT` polygon modes
+    pub fill_mode_non_solid: bool,
+    /// `wideLines` is enabled, allowing line widths other than 1.0
+    pub wide_lines: bool,
+    /// `geometryShader` is enabled
+    pub geometry_shader: bool,
+    /// `tessellationShader` is enabled
+    pub tessellation_shader: bool,
+    /// `VK_EXT_conditional_rendering` is enabled
+    pub conditional_rendering: bool,
+    /// Required features, and the requested ones which are supported
+    pub features: DeviceFeatureSet,
+}
+
+/// Picks the features a `Device` is created with. See `Device::builder`.
+pub struct DeviceBuilder {
+    pdevice: Arc<PhysicalDevice>,
+    ray_tracing: bool,
+    /// Indexed by `QueueKind`
+    queue_priorities: [f32; 3],
+    required_features: DeviceFeatureSet,
+    requested_features: DeviceFeatureSet,
+    extra_extensions: Vec<&'static CStr>,
+    extra_feature_structs: Vec<Box<dyn ExtraFeatureStruct>>,
+}
+
+/// Lets `DeviceBuilder` keep structs of any type for the `VkPhysicalDeviceFeatures2` chain.
+trait ExtraFeatureStruct {
+    fn push_next<'a>(
+        &'a mut self,
+        features2: vk::PhysicalDeviceFeatures2Builder<'a>,
+    ) -> vk::PhysicalDeviceFeatures2Builder<'a>;
+}
+
+impl<T: vk::ExtendsPhysicalDeviceFeatures2> ExtraFeatureStruct for T {
+    fn push_next<'a>(
+        &'a mut self,
+        features2: vk::PhysicalDeviceFeatures2Builder<'a>,
+    ) -> vk::PhysicalDeviceFeatures2Builder<'a> {
+        // Chain only this struct. All of them start like `VkBaseOutStructure`.
+        unsafe {
+            (*(self as *mut T as *mut vk::BaseOutStructure)).p_next = std::ptr::null_mut();
+        }
+
+        features2.push_next(self)
+    }
+}
+
+impl DeviceBuilder {
+    fn new(pdevice: &Arc<PhysicalDevice>) -> Self {
+        Self {
+            pdevice: pdevice.clone(),
+            ray_tracing: true,
+            queue_priorities: [1.0; 3],
+            // What the renderer can't do without
+            required_features: [
+                DeviceFeature::DescriptorIndexing,
+                DeviceFeature::BufferDeviceAddress,
+                DeviceFeature::ImagelessFramebuffer,
+                DeviceFeature::ScalarBlockLayout,
+                DeviceFeature::ShaderInt8,
+            ]
+            .into_iter()
+            .collect(),
+            requested_features: [
+                DeviceFeature::TimelineSemaphore,
+                DeviceFeature::SeparateDepthStencilLayouts,
+                DeviceFeature::VulkanMemoryModel,
+            ]
+            .into_iter()
+            .collect(),
+            extra_extensions: Vec::new(),
+            extra_feature_structs: Vec::new(),
+        }
+    }
+
+    /// Ray tracing is enabled if the device supports it, including `VulkanMemoryModel`,
+    /// unless disabled here. See `DeviceCapabilities::ray_tracing`.
+    pub fn enable_ray_tracing(mut self, enable: bool) -> Self {
+        self.ray_tracing = enable;
+        self
+    }
+
+    /// In `0.0..=1.0`, defaulting to 1.0. Drivers may give more time to
+    /// higher priority queues of the same family. Ignored for queues shared with another kind.
+    pub fn queue_priority(mut self, kind: QueueKind, priority: f32) -> Self {
+        self.queue_priorities[kind as usize] = priority.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Creating the device fails if the feature is unsupported.
+    pub fn require_feature(mut self, feature: DeviceFeature) -> Self {
+        self.required_features.insert(feature);
+        self
+    }
+
+    /// Enabled if supported; check `DeviceCapabilities::features`.
+    pub fn request_feature(mut self, feature: DeviceFeature) -> Self {
+        self.requested_features.insert(feature);
+        self
+    }
+
+    /// Extensions which kajiya doesn't use itself, e.g. for experiments.
+    /// Creating the device fails if any are unsupported. See `Device::is_extension_enabled`.
+    pub fn extra_extensions(mut self, extensions: &[&'static CStr]) -> Self {
+        self.extra_extensions.extend_from_slice(extensions);
+        self
+    }
+
+    /// Chains `features` into device creation, e.g. to enable features of `extra_extensions`.
+    /// Its `p_next` is ignored. Must not be a struct kajiya chains itself,
+    /// such as `PhysicalDeviceVulkan12Features`; use `require_feature` for those instead.
+    pub fn push_feature_struct(
+        mut self,
+        features: impl vk::ExtendsPhysicalDeviceFeatures2 + 'static,
+    ) -> Self {
+        self.extra_feature_structs.push(Box::new(features));
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<Device>> {
+        Device::create(self)
+    }
+}
+
 pub struct Device {
     pub raw: ash::Device,
     pub(crate) pdevice: Arc<PhysicalDevice>,
     pub(crate) instance: Arc<super::instance::Instance>,
-    pub universal_queue: Queue,
+    pub universal_queue: Arc<Queue>,
+    /// A queue which render graph passes can run on concurrently with the universal one.
+    /// Either from a compute-only family, or a second queue of the universal family.
+    pub async_compute_queue: Option<Arc<Queue>>,
+    /// Set if the universal queue can't present. See `Device::present_queue`.
+    pub present_queue: Option<Arc<Queue>>,
+    /// Resources are shared between these queue families with `CONCURRENT` sharing,
+    /// so that they don't need queue family ownership transfers.
+    /// Empty if there's only the universal queue.
+    pub(crate) concurrent_queue_families: Vec<u32>,
     pub(crate) global_allocator: Arc<Mutex<VulkanAllocator>>,
-    pub(crate) immutable_samplers: HashMap<SamplerDesc, vk::Sampler>,
+    pub(crate) immutable_samplers: Mutex<HashMap<SamplerDesc, vk::Sampler>>,
     pub(crate) setup_cb: Mutex<CommandBuffer>,
+    pub(crate) layout_cache: LayoutCache,
+    /// Queried on first use by `format_properties`
+    format_properties: Mutex<HashMap<vk::Format, vk::FormatProperties>>,
+    pub(crate) shader_module_cache: ShaderModuleCache,
+    /// Internally synchronized, so pipelines can be created from any thread
+    pub(crate) pipeline_cache: vk::PipelineCache,
+    /// Every render pass created via `create_render_pass`, so their framebuffers can be invalidated
+    pub(crate) render_passes: Mutex<Vec<Weak<RenderPass>>>,
 
     pub(crate) crash_tracking_buffer: Buffer,
     pub(crate) crash_marker_names: Mutex<CrashMarkerNames>,
@@ -148,10 +629,19 @@ pub struct Device {
     pub ray_tracing_pipeline_ext: khr::RayTracingPipeline,
     // pub ray_query_ext: khr::RayQuery,
     pub ray_tracing_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
+    conditional_rendering_ext: Option<vk::ExtConditionalRenderingFn>,
 
     frames: [Mutex<Arc<DeviceFrame>>; 2],
+    frame_index: AtomicU64,
+    retired_frame_count: AtomicU64,
+    /// Released once the frame they were queued in has retired; see `defer_release_swapchain`
+    frame_releases: Mutex<Vec<(u64, PendingResourceReleases)>>,
 
-    ray_tracing_enabled: bool,
+    capabilities: DeviceCapabilities,
+    /// Including those promoted to the core API version in use
+    enabled_extensions: HashSet<CString>,
+
+    pub(crate) bindless: BindlessDescriptorSet,
 }
 
 // Allowing `Send` on `frames` is technically unsound. There are some checks
@@ -162,139 +652,383 @@ unsafe impl Send for Device {}
 
 unsafe impl Sync for Device {}
 
+/// The core Vulkan version which a device extension used by kajiya was promoted into.
+fn promoted_to_core_in(extension: &CStr) -> Option<ApiVersion> {
+    let promoted_to_1_1 = [
+        vk::KhrMaintenance1Fn::name(),
+        vk::KhrMaintenance2Fn::name(),
+        vk::KhrMaintenance3Fn::name(),
+        vk::KhrGetMemoryRequirements2Fn::name(),
+        vk::KhrDescriptorUpdateTemplateFn::name(),
+        vk::KhrShaderDrawParametersFn::name(),
+    ];
+    let promoted_to_1_2 = [
+        vk::ExtDescriptorIndexingFn::name(),
+        vk::ExtScalarBlockLayoutFn::name(),
+        vk::KhrImagelessFramebufferFn::name(),
+        vk::KhrImageFormatListFn::name(),
+        vk::KhrShaderFloat16Int8Fn::name(),
+        vk::KhrVulkanMemoryModelFn::name(),
+        vk::KhrBufferDeviceAddressFn::name(),
+        vk::KhrTimelineSemaphoreFn::name(),
+        vk::KhrSeparateDepthStencilLayoutsFn::name(),
+    ];
+
+    if promoted_to_1_1.contains(&extension) {
+        Some(ApiVersion::V1_1)
+    } else if promoted_to_1_2.contains(&extension) {
+        Some(ApiVersion::V1_2)
+    } else {
+        None
+    }
+}
+
+/// Extensions the device can't be created without. The swapchain one is only
+/// needed with `presentation`. Those of `DeviceFeature`s are enabled along with them.
+fn required_device_extensions(presentation: bool) -> Vec<&'static CStr> {
+    let mut extensions = vec![
+        vk::KhrMaintenance1Fn::name(),
+        vk::KhrMaintenance2Fn::name(),
+        vk::KhrMaintenance3Fn::name(),
+        vk::KhrGetMemoryRequirements2Fn::name(),
+        vk::KhrImageFormatListFn::name(),
+        vk::KhrDescriptorUpdateTemplateFn::name(),
+        // DLSS
+        #[cfg(feature = "dlss")]
+        CStr::from_bytes_with_nul(b"VK_NVX_binary_import\0").unwrap(),
+        #[cfg(feature = "dlss")]
+        CStr::from_bytes_with_nul(b"VK_KHR_push_descriptor\0").unwrap(),
+        #[cfg(feature = "dlss")]
+        vk::NvxImageViewHandleFn::name(),
+    ];
+
+    if presentation {
+        extensions.push(khr::Swapchain::name());
+    }
+
+    extensions
+}
+
+/// Those of `extensions` which are neither in `supported`, nor part of the core API.
+fn missing_device_extensions(
+    extensions: &[&'static CStr],
+    supported: &HashSet<String>,
+    is_core: &dyn Fn(&CStr) -> bool,
+) -> Vec<&'static CStr> {
+    extensions
+        .iter()
+        .copied()
+        .filter(|&ext| !is_core(ext) && !supported.contains(ext.to_string_lossy().as_ref()))
+        .collect()
+}
+
+/// The `(family index, queue index)` of each of `families`. Each family hands out its queues
+/// in order, and once they run out, the rest share its queue 0.
+fn assign_queue_indices(families: &[QueueFamily]) -> Vec<(u32, u32)> {
+    let mut next_index: HashMap<u32, u32> = HashMap::new();
+
+    families
+        .iter()
+        .map(|family| {
+            let next = next_index.entry(family.index).or_insert(0);
+            let queue_index = if *next < family.properties.queue_count {
+                *next += 1;
+                *next - 1
+            } else {
+                0
+            };
+
+            (family.index, queue_index)
+        })
+        .collect()
+}
+
+/// The universal queue family, and a separate one for presentation if no graphics family can present.
+/// Present support is only known for devices filtered with `with_presentation_support`;
+/// without it, the first graphics family is used for everything.
+fn select_queue_families(
+    queue_families: &[QueueFamily],
+) -> Option<(QueueFamily, Option<QueueFamily>)> {
+    let graphics = || {
+        queue_families
+            .iter()
+            .filter(|qf| qf.properties.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+    };
+
+    if let Some(universal) = graphics().find(|qf| qf.present_support) {
+        return Some((*universal, None));
+    }
+
+    let universal = *graphics().next()?;
+    let present = queue_families.iter().find(|qf| qf.present_support).copied();
+
+    Some((universal, present))
+}
+
 impl Device {
-    pub fn create(pdevice: &Arc<PhysicalDevice>) -> Result<Arc<Self>> {
-        let supported_extensions: HashSet<String> = unsafe {
-            let extension_properties = pdevice
-                .instance
-                .raw
-                .enumerate_device_extension_properties(pdevice.raw)?;
-            debug!("Extension properties:\n{:#?}", &extension_properties);
+    /// Requires the features kajiya can't do without, requests the optional ones,
+    /// and enables ray tracing if supported.
+    pub fn builder(pdevice: &Arc<PhysicalDevice>) -> DeviceBuilder {
+        DeviceBuilder::new(pdevice)
+    }
 
-            extension_properties
-                .iter()
-                .map(|ext| {
-                    std::ffi::CStr::from_ptr(ext.extension_name.as_ptr() as *const c_char)
-                        .to_string_lossy()
-                        .as_ref()
-                        .to_owned()
-                })
-                .collect()
+    fn create(builder: DeviceBuilder) -> Result<Arc<Self>> {
+        let DeviceBuilder {
+            pdevice,
+            ray_tracing,
+            queue_priorities,
+            required_features,
+            requested_features,
+            extra_extensions,
+            mut extra_feature_structs,
+        } = builder;
+        let pdevice = &pdevice;
+
+        let supported_extensions = &pdevice.extensions;
+        debug!("Supported extensions:\n{:#?}", supported_extensions);
+
+        // Extensions promoted to this version are part of the core, and don't need enabling
+        let api_version = pdevice.instance.api_version().min(pdevice.api_version());
+        let is_core = |ext: &CStr| promoted_to_core_in(ext).map_or(false, |v| v <= api_version);
+
+        let presentation = pdevice.presentation_requested
+            && pdevice.instance.is_extension_enabled(khr::Surface::name());
+        let mut required_extensions = required_device_extensions(presentation);
+        required_extensions.extend(extra_extensions);
+
+        let missing_extensions =
+            missing_device_extensions(&required_extensions, supported_extensions, &is_core);
+        if !missing_extensions.is_empty() {
+            anyhow::bail!(
+                "{} (driver version {:#x}) is missing required Vulkan device extensions: {}",
+                pdevice.name(),
+                pdevice.properties.driver_version,
+                missing_extensions
+                    .iter()
+                    .map(|ext| ext.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let mut device_extension_names = required_extensions;
+
+        let feature_supported = |feature: DeviceFeature| {
+            let ext = feature.extension();
+            (is_core(ext) || pdevice.supports_extension_cstr(ext))
+                && feature.is_supported_by(&pdevice.features)
         };
 
-        let mut device_extension_names = vec![
-            vk::ExtDescriptorIndexingFn::name().as_ptr(),
-            vk::ExtScalarBlockLayoutFn::name().as_ptr(),
-            vk::KhrMaintenance1Fn::name().as_ptr(),
-            vk::KhrMaintenance2Fn::name().as_ptr(),
-            vk::KhrMaintenance3Fn::name().as_ptr(),
-            vk::KhrGetMemoryRequirements2Fn::name().as_ptr(),
-            vk::ExtDescriptorIndexingFn::name().as_ptr(),
-            vk::KhrImagelessFramebufferFn::name().as_ptr(),
-            vk::KhrImageFormatListFn::name().as_ptr(),
-            vk::KhrDescriptorUpdateTemplateFn::name().as_ptr(),
-            // Rust-GPU
-            vk::KhrShaderFloat16Int8Fn::name().as_ptr(),
-            // DLSS
-            #[cfg(feature = "dlss")]
-            {
-                b"VK_NVX_binary_import\0".as_ptr() as *const i8
-            },
-            #[cfg(feature = "dlss")]
-            {
-                b"VK_KHR_push_descriptor\0".as_ptr() as *const i8
-            },
-            #[cfg(feature = "dlss")]
-            vk::NvxImageViewHandleFn::name().as_ptr(),
-        ];
+        let missing_features: Vec<&str> = required_features
+            .iter()
+            .filter(|&feature| !feature_supported(feature))
+            .map(DeviceFeature::name)
+            .collect();
+        if !missing_features.is_empty() {
+            anyhow::bail!(
+                "{} (driver version {:#x}) doesn't support required Vulkan features: {}",
+                pdevice.name(),
+                pdevice.properties.driver_version,
+                missing_features.join(", ")
+            );
+        }
 
-        let ray_tracing_extensions = [
-            vk::KhrVulkanMemoryModelFn::name().as_ptr(), // used in ray tracing shaders
-            vk::KhrPipelineLibraryFn::name().as_ptr(),   // rt dep
-            vk::KhrDeferredHostOperationsFn::name().as_ptr(), // rt dep
-            vk::KhrBufferDeviceAddressFn::name().as_ptr(), // rt dep
-            vk::KhrAccelerationStructureFn::name().as_ptr(),
-            vk::KhrRayTracingPipelineFn::name().as_ptr(),
-        ];
+        let mut enabled_features = required_features;
+        for feature in requested_features.iter() {
+            if enabled_features.contains(feature) {
+                continue;
+            }
 
-        let ray_tracing_enabled = unsafe {
-            ray_tracing_extensions.iter().all(|ext| {
-                let ext = std::ffi::CStr::from_ptr(*ext).to_string_lossy();
+            if feature_supported(feature) {
+                enabled_features.insert(feature);
+            } else {
+                info!("Optional Vulkan feature not supported: {}", feature.name());
+            }
+        }
 
-                let supported = supported_extensions.contains(ext.as_ref());
+        device_extension_names.extend(enabled_features.iter().map(DeviceFeature::extension));
 
-                if !supported {
-                    log::info!("Ray tracing extension not supported: {}", ext);
+        let ray_tracing_enabled = ray_tracing && {
+            let mut missing = pdevice.missing_ray_tracing_support();
+            for feature in [
+                DeviceFeature::BufferDeviceAddress,
+                DeviceFeature::VulkanMemoryModel,
+            ] {
+                if !enabled_features.contains(feature) && !missing.contains(&feature.name()) {
+                    missing.push(feature.name());
                 }
+            }
 
-                supported
-            })
+            if !missing.is_empty() {
+                info!("Ray tracing not supported; missing {}", missing.join(", "));
+            }
+
+            missing.is_empty()
         };
 
         if ray_tracing_enabled {
-            log::info!("All ray tracing extensions are supported");
+            info!("Ray tracing is supported");
+            device_extension_names.extend([
+                vk::KhrPipelineLibraryFn::name(),
+                vk::KhrDeferredHostOperationsFn::name(),
+                vk::KhrAccelerationStructureFn::name(),
+                vk::KhrRayTracingPipelineFn::name(),
+            ]);
+        } else if !ray_tracing {
+            info!("Ray tracing is disabled");
+        }
 
-            device_extension_names.extend(ray_tracing_extensions.iter());
+        let ray_query_enabled = ray_tracing_enabled && pdevice.ray_query_supported();
+
+        if ray_query_enabled {
+            device_extension_names.push(vk::KhrRayQueryFn::name());
+        } else if ray_tracing_enabled {
+            info!(
+                "Optional device extension not supported: {}",
+                vk::KhrRayQueryFn::name().to_string_lossy()
+            );
         }
 
-        if pdevice.presentation_requested {
-            device_extension_names.push(khr::Swapchain::name().as_ptr());
+        let conditional_rendering_supported =
+            pdevice.supports_extension_cstr(vk::ExtConditionalRenderingFn::name());
+
+        if conditional_rendering_supported {
+            device_extension_names.push(vk::ExtConditionalRenderingFn::name());
+        } else {
+            info!(
+                "Optional device extension not supported: {}",
+                vk::ExtConditionalRenderingFn::name().to_string_lossy()
+            );
         }
 
-        unsafe {
-            for &ext in &device_extension_names {
-                let ext = std::ffi::CStr::from_ptr(ext).to_string_lossy();
-                if !supported_extensions.contains(ext.as_ref()) {
-                    panic!("Device extension not supported: {}", ext);
-                }
-            }
+        // Must be enabled whenever the device advertises it
+        if pdevice.is_portability_subset() {
+            info!("Using a Vulkan portability subset implementation");
+            device_extension_names.push(vk::KhrPortabilitySubsetFn::name());
         }
 
-        let priorities = [1.0];
+        let enabled_extensions: HashSet<CString> = device_extension_names
+            .iter()
+            .map(|&ext| ext.to_owned())
+            .collect();
+
+        device_extension_names.retain(|&ext| !is_core(ext));
+        // Extra extensions may repeat ones enabled anyway
+        device_extension_names.sort_unstable();
+        device_extension_names.dedup();
+        info!("Using Vulkan {}", api_version);
+
+        let device_extension_names: Vec<*const i8> = device_extension_names
+            .iter()
+            .map(|ext| ext.as_ptr())
+            .collect();
+
+        let (universal_family, present_family) =
+            if let Some(families) = select_queue_families(&pdevice.queue_families) {
+                families
+            } else {
+                anyhow::bail!("No suitable render queue found");
+            };
+
+        if let Some(present_family) = present_family {
+            info!(
+                "Queue family {} can't present; using queue family {} for presentation",
+                universal_family.index, present_family.index
+            );
+        }
 
-        let universal_queue = pdevice
+        // Dedicated compute families are the ones which can best overlap with graphics work.
+        // Otherwise a second queue of the universal family still lets some work overlap.
+        // Passes are timed with queries, so timestamps must be supported too.
+        let async_compute_family = pdevice
             .queue_families
             .iter()
-            .filter(|qf| qf.properties.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+            .find(|qf| {
+                qf.properties.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                    && !qf.properties.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                    && qf.properties.timestamp_valid_bits > 0
+            })
             .copied()
-            .next();
-
-        let universal_queue = if let Some(universal_queue) = universal_queue {
-            universal_queue
+            .or_else(|| (universal_family.properties.queue_count > 1).then(|| universal_family));
+
+        let queue_kinds: Vec<(QueueKind, QueueFamily)> =
+            std::iter::once((QueueKind::Universal, universal_family))
+                .chain(async_compute_family.map(|family| (QueueKind::AsyncCompute, family)))
+                .chain(present_family.map(|family| (QueueKind::Present, family)))
+                .collect();
+        let queue_indices = assign_queue_indices(
+            &queue_kinds
+                .iter()
+                .map(|(_, family)| *family)
+                .collect::<Vec<_>>(),
+        );
+
+        if let Some(async_compute_family) = async_compute_family {
+            info!(
+                "Using queue {} of family {} for async compute",
+                queue_indices[1].1, async_compute_family.index
+            );
         } else {
-            anyhow::bail!("No suitable render queue found");
-        };
+            info!("No async compute queue found; all passes will run on the universal queue");
+        }
+
+        // One priority per created queue of each family, from the first kind using it
+        let mut family_priorities: Vec<(u32, Vec<f32>)> = Vec::new();
+        for (&(kind, _), &(family_index, queue_index)) in queue_kinds.iter().zip(&queue_indices) {
+            let pos = match family_priorities
+                .iter()
+                .position(|(idx, _)| *idx == family_index)
+            {
+                Some(pos) => pos,
+                None => {
+                    family_priorities.push((family_index, Vec::new()));
+                    family_priorities.len() - 1
+                }
+            };
 
-        let universal_queue_info = [vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(universal_queue.index)
-            .queue_priorities(&priorities)
-            .build()];
+            let priorities = &mut family_priorities[pos].1;
+            if queue_index as usize == priorities.len() {
+                priorities.push(queue_priorities[kind as usize]);
+            }
+        }
 
-        let mut scalar_block = vk::PhysicalDeviceScalarBlockLayoutFeaturesEXT::default();
-        let mut descriptor_indexing = vk::PhysicalDeviceDescriptorIndexingFeaturesEXT::default();
-        let mut imageless_framebuffer =
-            vk::PhysicalDeviceImagelessFramebufferFeaturesKHR::default();
-        let mut shader_float16_int8 = vk::PhysicalDeviceShaderFloat16Int8Features::default();
-        let mut vulkan_memory_model = vk::PhysicalDeviceVulkanMemoryModelFeaturesKHR::default();
-        let mut get_buffer_device_address_features =
-            ash::vk::PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let queue_infos: Vec<vk::DeviceQueueCreateInfo> = family_priorities
+            .iter()
+            .map(|(queue_family_index, priorities)| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(*queue_family_index)
+                    .queue_priorities(priorities)
+                    .build()
+            })
+            .collect();
 
-        let mut acceleration_structure_features =
-            ash::vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        // Only swapchain images are accessed by the present queue, and they're shared separately.
+        // Queues of the same family don't need sharing.
+        let concurrent_queue_families: Vec<u32> = match async_compute_family {
+            Some(family) if family.index != universal_family.index => {
+                vec![universal_family.index, family.index]
+            }
+            _ => Vec::new(),
+        };
 
-        let mut ray_tracing_pipeline_features =
-            ash::vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+        // Everything supported by the enabled features and extensions gets enabled
+        let PhysicalDeviceFeatures {
+            core: core_features,
+            acceleration_structure: mut acceleration_structure_features,
+            ray_tracing_pipeline: mut ray_tracing_pipeline_features,
+            ray_query: mut ray_query_features,
+            conditional_rendering: mut conditional_rendering_features,
+            portability_subset: mut portability_subset_features,
+            ..
+        } = pdevice.features;
 
         unsafe {
             let instance = &pdevice.instance.raw;
 
-            let mut features2 = vk::PhysicalDeviceFeatures2::builder()
-                .push_next(&mut scalar_block)
-                .push_next(&mut descriptor_indexing)
-                .push_next(&mut imageless_framebuffer)
-                .push_next(&mut shader_float16_int8)
-                .push_next(&mut vulkan_memory_model)
-                .push_next(&mut get_buffer_device_address_features);
+            let mut feature_chain =
+                FeatureChain::new(&pdevice.features, enabled_features, api_version);
+            let mut features2 = feature_chain
+                .push_next(vk::PhysicalDeviceFeatures2::builder().features(core_features));
 
             if ray_tracing_enabled {
                 features2 = features2
@@ -302,59 +1036,28 @@ impl Device {
                     .push_next(&mut ray_tracing_pipeline_features);
             }
 
-            let mut features2 = features2.build();
-
-            instance
-                .fp_v1_1()
-                .get_physical_device_features2(pdevice.raw, &mut features2);
-
-            debug!("{:#?}", &scalar_block);
-            debug!("{:#?}", &descriptor_indexing);
-            debug!("{:#?}", &imageless_framebuffer);
-            debug!("{:#?}", &shader_float16_int8);
-            debug!("{:#?}", &vulkan_memory_model);
-            debug!("{:#?}", &get_buffer_device_address_features);
-
-            // The suggested `#[rustfmt::skip]` is not stable
-            #[allow(clippy::deprecated_cfg_attr)]
-            #[cfg_attr(rustfmt, rustfmt_skip)]
-            {
-                assert!(scalar_block.scalar_block_layout != 0);
-
-                assert!(descriptor_indexing.shader_uniform_texel_buffer_array_dynamic_indexing != 0);
-                assert!(descriptor_indexing.shader_storage_texel_buffer_array_dynamic_indexing != 0);
-                assert!(descriptor_indexing.shader_sampled_image_array_non_uniform_indexing != 0);
-                assert!(descriptor_indexing.shader_storage_image_array_non_uniform_indexing != 0);
-                assert!(descriptor_indexing.shader_uniform_texel_buffer_array_non_uniform_indexing != 0);
-                assert!(descriptor_indexing.shader_storage_texel_buffer_array_non_uniform_indexing != 0);
-                assert!(descriptor_indexing.descriptor_binding_sampled_image_update_after_bind != 0);
-                assert!(descriptor_indexing.descriptor_binding_update_unused_while_pending != 0);
-                assert!(descriptor_indexing.descriptor_binding_partially_bound != 0);
-                assert!(descriptor_indexing.descriptor_binding_variable_descriptor_count != 0);
-                assert!(descriptor_indexing.runtime_descriptor_array != 0);
-
-                assert!(imageless_framebuffer.imageless_framebuffer != 0);
+            if ray_query_enabled {
+                features2 = features2.push_next(&mut ray_query_features);
+            }
 
-                assert!(shader_float16_int8.shader_int8 != 0);
+            if conditional_rendering_supported {
+                features2 = features2.push_next(&mut conditional_rendering_features);
+            }
 
-                if ray_tracing_enabled {
-                    assert!(descriptor_indexing.shader_uniform_buffer_array_non_uniform_indexing != 0);
-                    assert!(descriptor_indexing.shader_storage_buffer_array_non_uniform_indexing != 0);
+            if let Some(portability_subset_features) = &mut portability_subset_features {
+                features2 = features2.push_next(portability_subset_features);
+            }
 
-                    assert!(vulkan_memory_model.vulkan_memory_model != 0);
+            for features in &mut extra_feature_structs {
+                features2 = features.push_next(features2);
+            }
 
-                    assert!(acceleration_structure_features.acceleration_structure != 0);
-                    assert!(acceleration_structure_features.descriptor_binding_acceleration_structure_update_after_bind != 0);
+            let mut features2 = features2.build();
 
-                    assert!(ray_tracing_pipeline_features.ray_tracing_pipeline != 0);
-                    assert!(ray_tracing_pipeline_features.ray_tracing_pipeline_trace_rays_indirect != 0);
-
-                    assert!(get_buffer_device_address_features.buffer_device_address != 0);
-                }
-            }
+            debug!("Enabled features: {:?}", enabled_features);
 
             let device_create_info = vk::DeviceCreateInfo::builder()
-                .queue_create_infos(&universal_queue_info)
+                .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names)
                 .push_next(&mut features2)
                 .build();
@@ -375,16 +1078,54 @@ impl Device {
                     log_allocations: true,
                     ..Default::default()
                 },
-                buffer_device_address: true,
+                buffer_device_address: enabled_features
+                    .contains(DeviceFeature::BufferDeviceAddress),
             });
 
-            let universal_queue = Queue {
-                raw: device.get_device_queue(universal_queue.index, 0),
-                family: universal_queue,
-            };
+            // Kinds assigned the same queue share the `Queue`, and with it the submission lock
+            let mut queues: Vec<Arc<Queue>> = Vec::new();
+            let mut queue_by_kind: HashMap<QueueKind, Arc<Queue>> = HashMap::new();
+            for (&(kind, family), &(family_index, queue_index)) in
+                queue_kinds.iter().zip(&queue_indices)
+            {
+                let queue = match queues
+                    .iter()
+                    .find(|q| q.family.index == family_index && q.index == queue_index)
+                {
+                    Some(queue) => queue.clone(),
+                    None => {
+                        let queue = Arc::new(Queue {
+                            raw: device.get_device_queue(family_index, queue_index),
+                            family,
+                            index: queue_index,
+                            submit_lock: Mutex::new(()),
+                        });
+                        queues.push(queue.clone());
+                        queue
+                    }
+                };
+
+                queue_by_kind.insert(kind, queue);
+            }
+
+            let universal_queue = queue_by_kind.remove(&QueueKind::Universal).unwrap();
+            let async_compute_queue = queue_by_kind.remove(&QueueKind::AsyncCompute);
+            let present_queue = queue_by_kind.remove(&QueueKind::Present);
 
-            let frame0 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
-            let frame1 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
+            let frame0 = DeviceFrame::new(
+                &device,
+                &mut global_allocator,
+                &universal_queue.family,
+                async_compute_queue.as_ref().map(|queue| &queue.family),
+                ray_tracing_enabled,
+            );
+            let frame1 = DeviceFrame::new(
+                &device,
+                &mut global_allocator,
+                &universal_queue.family,
+                async_compute_queue.as_ref().map(|queue| &queue.family),
+                ray_tracing_enabled,
+            );
             //let frame2 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
 
             let immutable_samplers = Self::create_samplers(&device);
@@ -396,35 +1137,80 @@ impl Device {
                 khr::RayTracingPipeline::new(&pdevice.instance.raw, &device);
             //let ray_query_ext = khr::RayQuery::new(&pdevice.instance.raw, &device);
             let ray_tracing_pipeline_properties =
-                khr::RayTracingPipeline::get_properties(&pdevice.instance.raw, pdevice.raw);
+                pdevice.ray_tracing_pipeline_properties.unwrap_or_default();
+
+            // All supported core features are enabled, so this is just a matter of support
+            let capabilities = DeviceCapabilities {
+                ray_tracing: ray_tracing_enabled,
+                ray_query: ray_query_enabled,
+                fill_mode_non_solid: features2.features.fill_mode_non_solid != 0,
+                wide_lines: features2.features.wide_lines != 0,
+                geometry_shader: features2.features.geometry_shader != 0,
+                tessellation_shader: features2.features.tessellation_shader != 0,
+                conditional_rendering: conditional_rendering_supported
+                    && conditional_rendering_features.conditional_rendering != 0,
+                features: enabled_features,
+            };
+            info!("{:?}", capabilities);
+
+            let conditional_rendering_ext = if capabilities.conditional_rendering {
+                Some(vk::ExtConditionalRenderingFn::load(|name| {
+                    std::mem::transmute(
+                        instance.get_device_proc_addr(device.handle(), name.as_ptr()),
+                    )
+                }))
+            } else {
+                None
+            };
 
             let crash_tracking_buffer = Self::create_buffer_impl(
                 &device,
+                pdevice,
                 &mut global_allocator,
                 BufferDesc::new_gpu_to_cpu(4, vk::BufferUsageFlags::TRANSFER_DST),
                 "crash tracking buffer",
+                &concurrent_queue_families,
             )?;
 
+            let bindless = BindlessDescriptorSet::new(&device, pdevice, ray_tracing_enabled);
+
+            let pipeline_cache =
+                device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)?;
+
             Ok(Arc::new(Device {
                 pdevice: pdevice.clone(),
                 instance: pdevice.instance.clone(),
                 raw: device,
                 universal_queue,
+                async_compute_queue,
+                present_queue,
+                concurrent_queue_families,
                 global_allocator: Arc::new(Mutex::new(global_allocator)),
-                immutable_samplers,
+                immutable_samplers: Mutex::new(immutable_samplers),
                 setup_cb: Mutex::new(setup_cb),
+                layout_cache: Default::default(),
+                format_properties: Default::default(),
+                shader_module_cache: Default::default(),
+                pipeline_cache,
+                render_passes: Default::default(),
                 crash_tracking_buffer,
                 crash_marker_names: Default::default(),
                 acceleration_structure_ext,
                 ray_tracing_pipeline_ext,
                 // ray_query_ext,
                 ray_tracing_pipeline_properties,
+                conditional_rendering_ext,
                 frames: [
                     Mutex::new(Arc::new(frame0)),
                     Mutex::new(Arc::new(frame1)),
                     //Mutex::new(Arc::new(frame2)),
                 ],
-                ray_tracing_enabled,
+                frame_index: AtomicU64::new(0),
+                retired_frame_count: AtomicU64::new(0),
+                frame_releases: Default::default(),
+                capabilities,
+                enabled_extensions,
+                bindless,
             }))
         }
     }
@@ -445,32 +1231,8 @@ impl Device {
         for &texel_filter in &texel_filters {
             for &mipmap_mode in &mipmap_modes {
                 for &address_modes in &address_modes {
-                    let anisotropy_enable = texel_filter == vk::Filter::LINEAR;
-
-                    result.insert(
-                        SamplerDesc {
-                            texel_filter,
-                            mipmap_mode,
-                            address_modes,
-                        },
-                        unsafe {
-                            device.create_sampler(
-                                &vk::SamplerCreateInfo::builder()
-                                    .mag_filter(texel_filter)
-                                    .min_filter(texel_filter)
-                                    .mipmap_mode(mipmap_mode)
-                                    .address_mode_u(address_modes)
-                                    .address_mode_v(address_modes)
-                                    .address_mode_w(address_modes)
-                                    .max_lod(vk::LOD_CLAMP_NONE)
-                                    .max_anisotropy(16.0)
-                                    .anisotropy_enable(anisotropy_enable)
-                                    .build(),
-                                None,
-                            )
-                        }
-                        .expect("create_sampler"),
-                    );
+                    let desc = SamplerDesc::new(texel_filter, mipmap_mode, address_modes);
+                    result.insert(desc, Self::create_sampler(device, desc));
                 }
             }
         }
@@ -478,11 +1240,33 @@ impl Device {
         result
     }
 
+    fn create_sampler(device: &ash::Device, desc: SamplerDesc) -> vk::Sampler {
+        let mut create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(desc.texel_filter)
+            .min_filter(desc.texel_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_modes)
+            .address_mode_v(desc.address_modes)
+            .address_mode_w(desc.address_modes)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .max_anisotropy(desc.max_anisotropy.max(1) as f32)
+            .anisotropy_enable(desc.max_anisotropy > 0)
+            .border_color(desc.border_color);
+
+        if let Some(compare_op) = desc.compare_op {
+            create_info = create_info.compare_enable(true).compare_op(compare_op);
+        }
+
+        unsafe { device.create_sampler(&create_info, None) }.expect("create_sampler")
+    }
+
+    /// Returns a sampler matching `desc`, creating it on first use.
     pub fn get_sampler(&self, desc: SamplerDesc) -> vk::Sampler {
         *self
             .immutable_samplers
-            .get(&desc)
-            .unwrap_or_else(|| panic!("Sampler not found: {:?}", desc))
+            .lock()
+            .entry(desc)
+            .or_insert_with(|| Self::create_sampler(&self.raw, desc))
     }
 
     pub fn begin_frame(&self) -> Arc<DeviceFrame> {
@@ -502,14 +1286,24 @@ impl Device {
             unsafe {
                 puffin::profile_scope!("wait submit done");
 
+                let mut submit_done_fences = vec![
+                    frame0.main_command_buffer.submit_done_fence,
+                    frame0.presentation_command_buffer.submit_done_fence,
+                ];
+                if let Some(async_compute) = &frame0.async_compute {
+                    submit_done_fences.extend(
+                        async_compute
+                            .command_buffers()
+                            .iter()
+                            .map(|cb| cb.submit_done_fence),
+                    );
+                }
+
                 self.raw
                     .wait_for_fences(
-                        // Note: need to wait for both command buffers so that the GPU won't
+                        // Note: need to wait for all command buffers so that the GPU won't
                         // be accessing frame[0] any more after this.
-                        &[
-                            frame0.main_command_buffer.submit_done_fence,
-                            frame0.presentation_command_buffer.submit_done_fence,
-                        ],
+                        &submit_done_fences,
                         true,
                         std::u64::MAX,
                     )
@@ -539,16 +1333,85 @@ impl Device {
             frame0
                 .pending_resource_releases
                 .get_mut()
-                .release_all(&self.raw);
+                .release_all(&self.raw, &self.global_allocator);
+
+            frame0.descriptor_allocator.get_mut().reset(&self.raw);
+
+            // `frame0` was last submitted two frames ago; all frames up to that one are now done.
+            let retired_frame_count = self.frame_index.load(Ordering::Acquire).saturating_sub(1);
+            self.retired_frame_count
+                .store(retired_frame_count, Ordering::Release);
+
+            self.frame_releases
+                .lock()
+                .retain_mut(|(frame_index, releases)| {
+                    let retired = *frame_index < retired_frame_count;
+                    if retired {
+                        releases.release_all(&self.raw, &self.global_allocator);
+                    }
+                    !retired
+                });
         }
 
         frame0.clone()
     }
 
+    /// Allocates a descriptor set which stays valid until the GPU is done with the current frame.
+    pub fn allocate_frame_descriptor_set(
+        &self,
+        desc: &FrameDescriptorSetDesc,
+    ) -> vk::DescriptorSet {
+        self.frames[0]
+            .lock()
+            .descriptor_allocator
+            .lock()
+            .allocate(&self.raw, desc)
+    }
+
+    /// Number of unique descriptor set and pipeline layouts shared by pipelines.
+    pub fn layout_cache_stats(&self) -> LayoutCacheStats {
+        self.layout_cache.stats()
+    }
+
+    /// Descriptor allocation counters for the frame currently being recorded.
+    pub fn descriptor_allocator_stats(&self) -> DescriptorAllocatorStats {
+        self.frames[0].lock().descriptor_allocator.lock().stats()
+    }
+
     pub fn defer_release(&self, resource: impl DeferredRelease) {
         resource.enqueue_release(&mut self.frames[0].lock().pending_resource_releases.lock());
     }
 
+    /// Like `defer_release`, but for buffers, which also own their memory.
+    pub fn defer_release_buffer(&self, buffer: Buffer) {
+        self.frames[0]
+            .lock()
+            .pending_resource_releases
+            .lock()
+            .buffers
+            .push(buffer);
+    }
+
+    /// Releases the resources of a swapchain once the GPU is done with the frame being recorded,
+    /// or the next one if called between frames. Either way, that covers all submitted work,
+    /// unlike `defer_release`, which is only safe while recording a frame.
+    /// Only that swapchain is affected; others on the same device keep presenting.
+    pub(crate) fn defer_release_swapchain(&self, releases: PendingResourceReleases) {
+        self.frame_releases
+            .lock()
+            .push((self.frame_index(), releases));
+    }
+
+    /// Like `defer_release`, but for images, which also own their views and memory.
+    pub fn defer_release_image(&self, image: Image) {
+        self.frames[0]
+            .lock()
+            .pending_resource_releases
+            .lock()
+            .images
+            .push(image);
+    }
+
     pub fn with_setup_cb(
         &self,
         callback: impl FnOnce(vk::CommandBuffer),
@@ -573,12 +1436,8 @@ impl Device {
             let submit_info =
                 vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&cb.raw));
 
-            self.raw
-                .queue_submit(
-                    self.universal_queue.raw,
-                    &[submit_info.build()],
-                    vk::Fence::null(),
-                )
+            self.universal_queue
+                .submit(&self.raw, &[submit_info.build()], vk::Fence::null())
                 .expect("queue submit failed.");
 
             log::trace!("device_wait_idle");
@@ -605,6 +1464,18 @@ impl Device {
             std::mem::swap(frame0, frame1);
             //std::mem::swap(frame1, frame2);
         }
+
+        self.frame_index.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Index of the frame currently being recorded.
+    pub fn frame_index(&self) -> u64 {
+        self.frame_index.load(Ordering::Acquire)
+    }
+
+    /// Number of frames the GPU has finished executing, as of the last `begin_frame`.
+    pub fn retired_frame_count(&self) -> u64 {
+        self.retired_frame_count.load(Ordering::Acquire)
     }
 
     pub fn physical_device(&self) -> &PhysicalDevice {
@@ -615,18 +1486,171 @@ impl Device {
         self.instance.debug_utils.as_ref()
     }
 
+    /// Labels a Vulkan object for debuggers and validation messages. No-op without debug utils.
+    pub fn set_debug_name<T: vk::Handle>(&self, object: T, name: &str) {
+        let debug_utils = match self.debug_utils() {
+            Some(debug_utils) => debug_utils,
+            None => return,
+        };
+
+        let name = match std::ffi::CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+
+        unsafe {
+            let _ = debug_utils.debug_utils_set_object_name(
+                self.raw.handle(),
+                &vk::DebugUtilsObjectNameInfoEXT::builder()
+                    .object_type(T::TYPE)
+                    .object_handle(object.as_raw())
+                    .object_name(&name),
+            );
+        }
+    }
+
+    /// The queue to present swapchain images on.
+    pub fn present_queue(&self) -> &Queue {
+        self.queue(QueueKind::Present)
+    }
+
+    /// Kinds without a queue of their own share the universal queue.
+    pub fn queue(&self, kind: QueueKind) -> &Queue {
+        let queue = match kind {
+            QueueKind::Universal => None,
+            QueueKind::AsyncCompute => self.async_compute_queue.as_ref(),
+            QueueKind::Present => self.present_queue.as_ref(),
+        };
+
+        queue.unwrap_or(&self.universal_queue)
+    }
+
     pub fn max_bindless_descriptor_count(&self) -> u32 {
-        (512 * 1024).min(
-            self.pdevice
-                .properties
-                .limits
-                .max_per_stage_descriptor_sampled_images
-                - RESERVED_DESCRIPTOR_COUNT,
-        )
+        bindless::max_bindless_image_count(&self.pdevice)
+    }
+
+    pub fn max_bindless_buffer_count(&self) -> u32 {
+        bindless::max_bindless_buffer_count(&self.pdevice)
+    }
+
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    /// Whether the extension was enabled, e.g. via `DeviceBuilder::extra_extensions`.
+    /// Extensions promoted to the core API version in use count as enabled.
+    pub fn is_extension_enabled(&self, name: &CStr) -> bool {
+        self.enabled_extensions.contains(name)
+    }
+
+    /// Features of a Vulkan portability implementation such as MoltenVK, all of which are enabled;
+    /// `None` on fully conformant devices. Check e.g. `image_view_format_swizzle` before relying on it.
+    pub fn portability_subset_features(
+        &self,
+    ) -> Option<&vk::PhysicalDevicePortabilitySubsetFeaturesKHR> {
+        self.pdevice.features.portability_subset.as_ref()
     }
 
     pub fn ray_tracing_enabled(&self) -> bool {
-        self.ray_tracing_enabled
+        self.capabilities.ray_tracing
+    }
+
+    /// Until `cmd_end_conditional_rendering`, draws, dispatches and clears recorded into `cb`
+    /// are discarded if the 32-bit value at the start of `buffer` is zero.
+    ///
+    /// Returns `false` without recording anything if conditional rendering isn't supported.
+    pub fn cmd_begin_conditional_rendering(
+        &self,
+        cb: vk::CommandBuffer,
+        buffer: vk::Buffer,
+    ) -> bool {
+        if let Some(ext) = &self.conditional_rendering_ext {
+            unsafe {
+                ext.cmd_begin_conditional_rendering_ext(
+                    cb,
+                    &vk::ConditionalRenderingBeginInfoEXT::builder()
+                        .buffer(buffer)
+                        .offset(0)
+                        .build(),
+                );
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn cmd_end_conditional_rendering(&self, cb: vk::CommandBuffer) {
+        let ext = self
+            .conditional_rendering_ext
+            .as_ref()
+            .expect("conditional rendering not supported");
+        unsafe {
+            ext.cmd_end_conditional_rendering_ext(cb);
+        }
+    }
+
+    /// A combined depth-stencil format usable as a depth attachment on this device.
+    /// Prefers `D32_SFLOAT_S8_UINT`, falling back to `D24_UNORM_S8_UINT`; Vulkan guarantees one of them.
+    pub fn depth_stencil_format(&self) -> vk::Format {
+        [
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D24_UNORM_S8_UINT,
+        ]
+        .iter()
+        .copied()
+        .find(|&format| {
+            self.format_features(format)
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        })
+        .expect("No supported depth-stencil format")
+    }
+
+    /// Features supported by `format` with optimal tiling.
+    pub fn format_features(&self, format: vk::Format) -> vk::FormatFeatureFlags {
+        self.format_properties(format).optimal_tiling_features
+    }
+
+    pub fn format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        *self
+            .format_properties
+            .lock()
+            .entry(format)
+            .or_insert_with(|| unsafe {
+                self.instance
+                    .raw
+                    .get_physical_device_format_properties(self.pdevice.raw, format)
+            })
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.pdevice.properties.limits
+    }
+
+    /// Checks `desc` against the device limits and the features of its format,
+    /// as done by `create_image`. See `image::validate_image_desc`.
+    pub fn validate_image_desc(&self, desc: &ImageDesc) -> Result<(), BackendError> {
+        image::validate_image_desc(desc, self.limits(), &self.format_properties(desc.format))
+    }
+
+    /// Releases the cached framebuffers of all live render passes. Must be called when
+    /// the swapchain is recreated, as the framebuffers are sized after the old one.
+    pub fn clear_framebuffer_caches(&self) {
+        self.render_passes.lock().retain(|render_pass| {
+            if let Some(render_pass) = render_pass.upgrade() {
+                render_pass.framebuffer_cache.clear(self);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Stage flags which pipelines use for descriptor sets other than set 0,
+    /// unless overridden via `DescriptorSetLayoutOpts::stage_flags`.
+    /// Externally created layouts bound to those sets need to match.
+    pub fn default_descriptor_set_stage_flags(&self) -> vk::ShaderStageFlags {
+        shader::default_descriptor_set_stage_flags(self.ray_tracing_enabled())
     }
 }
 
@@ -635,6 +1659,27 @@ impl Drop for Device {
         unsafe {
             log::trace!("device_wait_idle");
             let _ = self.raw.device_wait_idle();
+
+            // Resources released in the last two frames, such as the swapchains of closed windows
+            for frame in &self.frames {
+                frame
+                    .lock()
+                    .pending_resource_releases
+                    .lock()
+                    .release_all(&self.raw, &self.global_allocator);
+            }
+            for (_, mut releases) in self.frame_releases.get_mut().drain(..) {
+                releases.release_all(&self.raw, &self.global_allocator);
+            }
+
+            for frame in &self.frames {
+                frame.lock().descriptor_allocator.lock().destroy(&self.raw);
+            }
+
+            self.bindless.destroy(&self.raw);
+            self.layout_cache.destroy(&self.raw);
+            self.shader_module_cache.destroy(&self.raw);
+            self.raw.destroy_pipeline_cache(self.pipeline_cache, None);
         }
     }
 }
@@ -667,4 +1712,196 @@ pub struct SamplerDesc {
     pub texel_filter: vk::Filter,
     pub mipmap_mode: vk::SamplerMipmapMode,
     pub address_modes: vk::SamplerAddressMode,
+    /// Zero disables anisotropic filtering
+    pub max_anisotropy: u8,
+    /// Makes this a comparison (shadow) sampler
+    pub compare_op: Option<vk::CompareOp>,
+    pub border_color: vk::BorderColor,
+}
+
+impl SamplerDesc {
+    /// Linear filtering gets 16x anisotropy; no comparison, transparent black border.
+    pub fn new(
+        texel_filter: vk::Filter,
+        mipmap_mode: vk::SamplerMipmapMode,
+        address_modes: vk::SamplerAddressMode,
+    ) -> Self {
+        Self {
+            texel_filter,
+            mipmap_mode,
+            address_modes,
+            max_anisotropy: if texel_filter == vk::Filter::LINEAR {
+                16
+            } else {
+                0
+            },
+            compare_op: None,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+        }
+    }
+}
+
+#[test]
+fn test_select_queue_families() {
+    let family = |index: u32, queue_flags: vk::QueueFlags, present_support: bool| QueueFamily {
+        index,
+        properties: vk::QueueFamilyProperties {
+            queue_flags,
+            queue_count: 1,
+            ..Default::default()
+        },
+        present_support,
+    };
+    let indices = |families: Option<(QueueFamily, Option<QueueFamily>)>| {
+        families.map(|(universal, present)| (universal.index, present.map(|qf| qf.index)))
+    };
+
+    let graphics = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE;
+    let compute = vk::QueueFlags::COMPUTE;
+    let transfer = vk::QueueFlags::TRANSFER;
+
+    // The first graphics family which can present
+    assert_eq!(
+        indices(select_queue_families(&[
+            family(0, graphics, false),
+            family(1, compute, true),
+            family(2, graphics, true),
+        ])),
+        Some((2, None))
+    );
+
+    // No graphics family can present, so presentation gets its own queue
+    assert_eq!(
+        indices(select_queue_families(&[
+            family(0, transfer, false),
+            family(1, graphics, false),
+            family(2, compute, true),
+        ])),
+        Some((1, Some(2)))
+    );
+
+    // Present support unknown
+    assert_eq!(
+        indices(select_queue_families(&[
+            family(0, compute, false),
+            family(1, graphics, false),
+        ])),
+        Some((1, None))
+    );
+
+    assert_eq!(
+        indices(select_queue_families(&[family(0, compute, true)])),
+        None
+    );
+}
+
+#[test]
+fn test_assign_queue_indices() {
+    let family = |index: u32, queue_count: u32| QueueFamily {
+        index,
+        properties: vk::QueueFamilyProperties {
+            queue_flags: vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+            queue_count,
+            ..Default::default()
+        },
+        present_support: true,
+    };
+
+    // Universal and async compute from the same family
+    assert_eq!(
+        assign_queue_indices(&[family(0, 16), family(0, 16)]),
+        [(0, 0), (0, 1)]
+    );
+
+    // Only one queue, so it's shared
+    assert_eq!(
+        assign_queue_indices(&[family(0, 1), family(0, 1)]),
+        [(0, 0), (0, 0)]
+    );
+
+    assert_eq!(
+        assign_queue_indices(&[family(0, 2), family(1, 1), family(1, 1)]),
+        [(0, 0), (1, 0), (1, 0)]
+    );
+}
+
+#[test]
+fn test_missing_device_extensions() {
+    let not_core = |_: &CStr| false;
+    let core_1_2 = |ext: &CStr| promoted_to_core_in(ext).map_or(false, |v| v <= ApiVersion::V1_2);
+    let swapchain = khr::Swapchain::name();
+    let imageless = vk::KhrImagelessFramebufferFn::name();
+    let ray_tracing = vk::KhrRayTracingPipelineFn::name();
+
+    let cases: [(&[&CStr], &[&CStr], &dyn Fn(&CStr) -> bool, &[&CStr]); 5] = [
+        // (required, supported, is_core, missing)
+        (
+            &[swapchain, imageless],
+            &[swapchain, imageless],
+            &not_core,
+            &[],
+        ),
+        (
+            &[swapchain, imageless],
+            &[swapchain],
+            &not_core,
+            &[imageless],
+        ),
+        (&[swapchain, imageless], &[swapchain], &core_1_2, &[]),
+        (
+            &[swapchain, imageless, ray_tracing],
+            &[],
+            &core_1_2,
+            &[swapchain, ray_tracing],
+        ),
+        (&[], &[ray_tracing], &not_core, &[]),
+    ];
+
+    for (required, supported, is_core, missing) in cases {
+        let supported: HashSet<String> = supported
+            .iter()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .collect();
+
+        assert_eq!(
+            missing_device_extensions(required, &supported, is_core),
+            missing
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn test_extra_extensions() {
+    use super::{instance::Instance, physical_device::enumerate_physical_devices};
+
+    let instance = Instance::builder().build().unwrap();
+    let pdevice = Arc::new(
+        enumerate_physical_devices(&instance)
+            .unwrap()
+            .into_iter()
+            .next()
+            .expect("no Vulkan device"),
+    );
+
+    let unknown = CStr::from_bytes_with_nul(b"VK_KAJIYA_unknown_extension\0").unwrap();
+    let err = Device::builder(&pdevice)
+        .extra_extensions(&[unknown])
+        .build()
+        .err()
+        .expect("unsupported extensions can't be enabled");
+    assert!(err.to_string().contains("VK_KAJIYA_unknown_extension"));
+
+    // Only adds queries, so it's harmless to enable
+    let memory_budget = vk::ExtMemoryBudgetFn::name();
+    if !pdevice.supports_extension_cstr(memory_budget) {
+        return;
+    }
+
+    let device = Device::builder(&pdevice)
+        .extra_extensions(&[memory_budget])
+        .build()
+        .unwrap();
+    assert!(device.is_extension_enabled(memory_budget));
+    assert!(!device.is_extension_enabled(unknown));
 }
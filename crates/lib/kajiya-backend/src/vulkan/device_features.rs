@@ -0,0 +1,387 @@
+//! Vulkan 1.1 and 1.2 features which a `DeviceBuilder` can require or request.
+//!
+//! On Vulkan 1.2 devices they're enabled through `VkPhysicalDeviceVulkan11Features`
+//! and `VkPhysicalDeviceVulkan12Features`. Older devices get the structs
+//! of the extensions which the features were promoted from instead.
+
+use super::{instance::ApiVersion, physical_device::PhysicalDeviceFeatures};
+use ash::vk;
+use std::ffi::CStr;
+
+/// Features which kajiya can enable; see `DeviceBuilder::require_feature`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceFeature {
+    /// The non-uniform indexing, update-after-bind and partially bound descriptors used by bindless
+    DescriptorIndexing,
+    BufferDeviceAddress,
+    TimelineSemaphore,
+    /// Used by `FramebufferCache`
+    ImagelessFramebuffer,
+    SeparateDepthStencilLayouts,
+    ScalarBlockLayout,
+    /// Along with `shaderFloat16` if supported. Used by Rust-GPU shaders.
+    ShaderInt8,
+    /// Used in ray tracing shaders
+    VulkanMemoryModel,
+    ShaderDrawParameters,
+}
+
+impl DeviceFeature {
+    pub const ALL: [Self; 9] = [
+        Self::DescriptorIndexing,
+        Self::BufferDeviceAddress,
+        Self::TimelineSemaphore,
+        Self::ImagelessFramebuffer,
+        Self::SeparateDepthStencilLayouts,
+        Self::ScalarBlockLayout,
+        Self::ShaderInt8,
+        Self::VulkanMemoryModel,
+        Self::ShaderDrawParameters,
+    ];
+
+    /// As in the Vulkan spec, e.g. `descriptorIndexing`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DescriptorIndexing => "descriptorIndexing",
+            Self::BufferDeviceAddress => "bufferDeviceAddress",
+            Self::TimelineSemaphore => "timelineSemaphore",
+            Self::ImagelessFramebuffer => "imagelessFramebuffer",
+            Self::SeparateDepthStencilLayouts => "separateDepthStencilLayouts",
+            Self::ScalarBlockLayout => "scalarBlockLayout",
+            Self::ShaderInt8 => "shaderInt8",
+            Self::VulkanMemoryModel => "vulkanMemoryModel",
+            Self::ShaderDrawParameters => "shaderDrawParameters",
+        }
+    }
+
+    /// The extension which provides the feature before it was promoted to core.
+    pub fn extension(self) -> &'static CStr {
+        match self {
+            Self::DescriptorIndexing => vk::ExtDescriptorIndexingFn::name(),
+            Self::BufferDeviceAddress => vk::KhrBufferDeviceAddressFn::name(),
+            Self::TimelineSemaphore => vk::KhrTimelineSemaphoreFn::name(),
+            Self::ImagelessFramebuffer => vk::KhrImagelessFramebufferFn::name(),
+            Self::SeparateDepthStencilLayouts => vk::KhrSeparateDepthStencilLayoutsFn::name(),
+            Self::ScalarBlockLayout => vk::ExtScalarBlockLayoutFn::name(),
+            Self::ShaderInt8 => vk::KhrShaderFloat16Int8Fn::name(),
+            Self::VulkanMemoryModel => vk::KhrVulkanMemoryModelFn::name(),
+            Self::ShaderDrawParameters => vk::KhrShaderDrawParametersFn::name(),
+        }
+    }
+
+    /// Whether the device reports the feature. Doesn't check for the extension.
+    pub(crate) fn is_supported_by(self, features: &PhysicalDeviceFeatures) -> bool {
+        match self {
+            Self::DescriptorIndexing => {
+                let f = &features.descriptor_indexing;
+                [
+                    f.shader_uniform_texel_buffer_array_dynamic_indexing,
+                    f.shader_storage_texel_buffer_array_dynamic_indexing,
+                    f.shader_sampled_image_array_non_uniform_indexing,
+                    f.shader_storage_image_array_non_uniform_indexing,
+                    f.shader_uniform_texel_buffer_array_non_uniform_indexing,
+                    f.shader_storage_texel_buffer_array_non_uniform_indexing,
+                    f.descriptor_binding_sampled_image_update_after_bind,
+                    f.descriptor_binding_storage_buffer_update_after_bind,
+                    f.descriptor_binding_update_unused_while_pending,
+                    f.descriptor_binding_partially_bound,
+                    f.descriptor_binding_variable_descriptor_count,
+                    f.runtime_descriptor_array,
+                ]
+                .iter()
+                .all(|&supported| supported != 0)
+            }
+            Self::BufferDeviceAddress => features.buffer_device_address.buffer_device_address != 0,
+            Self::TimelineSemaphore => features.timeline_semaphore.timeline_semaphore != 0,
+            Self::ImagelessFramebuffer => features.imageless_framebuffer.imageless_framebuffer != 0,
+            Self::SeparateDepthStencilLayouts => {
+                features
+                    .separate_depth_stencil_layouts
+                    .separate_depth_stencil_layouts
+                    != 0
+            }
+            Self::ScalarBlockLayout => features.scalar_block_layout.scalar_block_layout != 0,
+            Self::ShaderInt8 => features.shader_float16_int8.shader_int8 != 0,
+            Self::VulkanMemoryModel => features.vulkan_memory_model.vulkan_memory_model != 0,
+            Self::ShaderDrawParameters => {
+                features.shader_draw_parameters.shader_draw_parameters != 0
+            }
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Prints as the names of its features.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceFeatureSet(u32);
+
+impl DeviceFeatureSet {
+    pub fn contains(self, feature: DeviceFeature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    pub fn insert(&mut self, feature: DeviceFeature) {
+        self.0 |= feature.bit();
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = DeviceFeature> {
+        DeviceFeature::ALL
+            .into_iter()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+impl FromIterator<DeviceFeature> for DeviceFeatureSet {
+    fn from_iter<I: IntoIterator<Item = DeviceFeature>>(iter: I) -> Self {
+        let mut set = Self::default();
+        for feature in iter {
+            set.insert(feature);
+        }
+        set
+    }
+}
+
+impl std::fmt::Debug for DeviceFeatureSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.iter().map(DeviceFeature::name))
+            .finish()
+    }
+}
+
+/// The feature structs chained into `VkDeviceCreateInfo`. Enabled features
+/// get everything the device supports in their structs; the rest stay zeroed.
+pub(crate) struct FeatureChain {
+    api_version: ApiVersion,
+    enabled: DeviceFeatureSet,
+    vulkan11: vk::PhysicalDeviceVulkan11Features,
+    vulkan12: vk::PhysicalDeviceVulkan12Features,
+    descriptor_indexing: vk::PhysicalDeviceDescriptorIndexingFeatures,
+    buffer_device_address: vk::PhysicalDeviceBufferDeviceAddressFeatures,
+    timeline_semaphore: vk::PhysicalDeviceTimelineSemaphoreFeatures,
+    imageless_framebuffer: vk::PhysicalDeviceImagelessFramebufferFeatures,
+    separate_depth_stencil_layouts: vk::PhysicalDeviceSeparateDepthStencilLayoutsFeatures,
+    scalar_block_layout: vk::PhysicalDeviceScalarBlockLayoutFeatures,
+    shader_float16_int8: vk::PhysicalDeviceShaderFloat16Int8Features,
+    vulkan_memory_model: vk::PhysicalDeviceVulkanMemoryModelFeatures,
+    shader_draw_parameters: vk::PhysicalDeviceShaderDrawParametersFeatures,
+}
+
+impl FeatureChain {
+    pub(crate) fn new(
+        supported: &PhysicalDeviceFeatures,
+        enabled: DeviceFeatureSet,
+        api_version: ApiVersion,
+    ) -> Self {
+        let mut chain = Self {
+            api_version,
+            enabled,
+            vulkan11: Default::default(),
+            vulkan12: Default::default(),
+            descriptor_indexing: Default::default(),
+            buffer_device_address: Default::default(),
+            timeline_semaphore: Default::default(),
+            imageless_framebuffer: Default::default(),
+            separate_depth_stencil_layouts: Default::default(),
+            scalar_block_layout: Default::default(),
+            shader_float16_int8: Default::default(),
+            vulkan_memory_model: Default::default(),
+            shader_draw_parameters: Default::default(),
+        };
+
+        for feature in enabled.iter() {
+            match feature {
+                DeviceFeature::DescriptorIndexing => {
+                    chain.descriptor_indexing = supported.descriptor_indexing
+                }
+                DeviceFeature::BufferDeviceAddress => {
+                    chain.buffer_device_address = supported.buffer_device_address
+                }
+                DeviceFeature::TimelineSemaphore => {
+                    chain.timeline_semaphore = supported.timeline_semaphore
+                }
+                DeviceFeature::ImagelessFramebuffer => {
+                    chain.imageless_framebuffer = supported.imageless_framebuffer
+                }
+                DeviceFeature::SeparateDepthStencilLayouts => {
+                    chain.separate_depth_stencil_layouts = supported.separate_depth_stencil_layouts
+                }
+                DeviceFeature::ScalarBlockLayout => {
+                    chain.scalar_block_layout = supported.scalar_block_layout
+                }
+                DeviceFeature::ShaderInt8 => {
+                    chain.shader_float16_int8 = supported.shader_float16_int8
+                }
+                DeviceFeature::VulkanMemoryModel => {
+                    chain.vulkan_memory_model = supported.vulkan_memory_model
+                }
+                DeviceFeature::ShaderDrawParameters => {
+                    chain.shader_draw_parameters = supported.shader_draw_parameters
+                }
+            }
+        }
+
+        if api_version >= ApiVersion::V1_2 {
+            chain.fill_vulkan11_12(supported);
+        }
+
+        chain
+    }
+
+    /// Promoted structs may not be chained along with `Vulkan11Features` and `Vulkan12Features`,
+    /// so their bits are copied over.
+    fn fill_vulkan11_12(&mut self, supported: &PhysicalDeviceFeatures) {
+        self.vulkan11.shader_draw_parameters = self.shader_draw_parameters.shader_draw_parameters;
+
+        let v12 = &mut self.vulkan12;
+
+        if self.enabled.contains(DeviceFeature::DescriptorIndexing) {
+            v12.descriptor_indexing = supported.vulkan12.map_or(0, |f| f.descriptor_indexing);
+        }
+
+        let di = &self.descriptor_indexing;
+        v12.shader_input_attachment_array_dynamic_indexing =
+            di.shader_input_attachment_array_dynamic_indexing;
+        v12.shader_uniform_texel_buffer_array_dynamic_indexing =
+            di.shader_uniform_texel_buffer_array_dynamic_indexing;
+        v12.shader_storage_texel_buffer_array_dynamic_indexing =
+            di.shader_storage_texel_buffer_array_dynamic_indexing;
+        v12.shader_uniform_buffer_array_non_uniform_indexing =
+            di.shader_uniform_buffer_array_non_uniform_indexing;
+        v12.shader_sampled_image_array_non_uniform_indexing =
+            di.shader_sampled_image_array_non_uniform_indexing;
+        v12.shader_storage_buffer_array_non_uniform_indexing =
+            di.shader_storage_buffer_array_non_uniform_indexing;
+        v12.shader_storage_image_array_non_uniform_indexing =
+            di.shader_storage_image_array_non_uniform_indexing;
+        v12.shader_input_attachment_array_non_uniform_indexing =
+            di.shader_input_attachment_array_non_uniform_indexing;
+        v12.shader_uniform_texel_buffer_array_non_uniform_indexing =
+            di.shader_uniform_texel_buffer_array_non_uniform_indexing;
+        v12.shader_storage_texel_buffer_array_non_uniform_indexing =
+            di.shader_storage_texel_buffer_array_non_uniform_indexing;
+        v12.descriptor_binding_uniform_buffer_update_after_bind =
+            di.descriptor_binding_uniform_buffer_update_after_bind;
+        v12.descriptor_binding_sampled_image_update_after_bind =
+            di.descriptor_binding_sampled_image_update_after_bind;
+        v12.descriptor_binding_storage_image_update_after_bind =
+            di.descriptor_binding_storage_image_update_after_bind;
+        v12.descriptor_binding_storage_buffer_update_after_bind =
+            di.descriptor_binding_storage_buffer_update_after_bind;
+        v12.descriptor_binding_uniform_texel_buffer_update_after_bind =
+            di.descriptor_binding_uniform_texel_buffer_update_after_bind;
+        v12.descriptor_binding_storage_texel_buffer_update_after_bind =
+            di.descriptor_binding_storage_texel_buffer_update_after_bind;
+        v12.descriptor_binding_update_unused_while_pending =
+            di.descriptor_binding_update_unused_while_pending;
+        v12.descriptor_binding_partially_bound = di.descriptor_binding_partially_bound;
+        v12.descriptor_binding_variable_descriptor_count =
+            di.descriptor_binding_variable_descriptor_count;
+        v12.runtime_descriptor_array = di.runtime_descriptor_array;
+
+        let bda = &self.buffer_device_address;
+        v12.buffer_device_address = bda.buffer_device_address;
+        v12.buffer_device_address_capture_replay = bda.buffer_device_address_capture_replay;
+        v12.buffer_device_address_multi_device = bda.buffer_device_address_multi_device;
+
+        v12.timeline_semaphore = self.timeline_semaphore.timeline_semaphore;
+        v12.imageless_framebuffer = self.imageless_framebuffer.imageless_framebuffer;
+        v12.separate_depth_stencil_layouts = self
+            .separate_depth_stencil_layouts
+            .separate_depth_stencil_layouts;
+        v12.scalar_block_layout = self.scalar_block_layout.scalar_block_layout;
+        v12.shader_float16 = self.shader_float16_int8.shader_float16;
+        v12.shader_int8 = self.shader_float16_int8.shader_int8;
+
+        let vmm = &self.vulkan_memory_model;
+        v12.vulkan_memory_model = vmm.vulkan_memory_model;
+        v12.vulkan_memory_model_device_scope = vmm.vulkan_memory_model_device_scope;
+        v12.vulkan_memory_model_availability_visibility_chains =
+            vmm.vulkan_memory_model_availability_visibility_chains;
+    }
+
+    pub(crate) fn push_next<'a>(
+        &'a mut self,
+        mut features2: vk::PhysicalDeviceFeatures2Builder<'a>,
+    ) -> vk::PhysicalDeviceFeatures2Builder<'a> {
+        if self.api_version >= ApiVersion::V1_2 {
+            features2 = features2
+                .push_next(&mut self.vulkan11)
+                .push_next(&mut self.vulkan12);
+        } else {
+            let enabled = self.enabled;
+            let has = |feature| enabled.contains(feature);
+
+            if has(DeviceFeature::DescriptorIndexing) {
+                features2 = features2.push_next(&mut self.descriptor_indexing);
+            }
+            if has(DeviceFeature::BufferDeviceAddress) {
+                features2 = features2.push_next(&mut self.buffer_device_address);
+            }
+            if has(DeviceFeature::TimelineSemaphore) {
+                features2 = features2.push_next(&mut self.timeline_semaphore);
+            }
+            if has(DeviceFeature::ImagelessFramebuffer) {
+                features2 = features2.push_next(&mut self.imageless_framebuffer);
+            }
+            if has(DeviceFeature::SeparateDepthStencilLayouts) {
+                features2 = features2.push_next(&mut self.separate_depth_stencil_layouts);
+            }
+            if has(DeviceFeature::ScalarBlockLayout) {
+                features2 = features2.push_next(&mut self.scalar_block_layout);
+            }
+            if has(DeviceFeature::ShaderInt8) {
+                features2 = features2.push_next(&mut self.shader_float16_int8);
+            }
+            if has(DeviceFeature::VulkanMemoryModel) {
+                features2 = features2.push_next(&mut self.vulkan_memory_model);
+            }
+            if has(DeviceFeature::ShaderDrawParameters) {
+                features2 = features2.push_next(&mut self.shader_draw_parameters);
+            }
+        }
+
+        features2
+    }
+}
+
+#[test]
+fn test_feature_chain() {
+    let mut supported = PhysicalDeviceFeatures::default();
+    supported.timeline_semaphore.timeline_semaphore = vk::TRUE;
+    supported.scalar_block_layout.scalar_block_layout = vk::TRUE;
+    supported.vulkan_memory_model.vulkan_memory_model = vk::TRUE;
+    supported
+        .vulkan_memory_model
+        .vulkan_memory_model_device_scope = vk::TRUE;
+
+    assert!(DeviceFeature::TimelineSemaphore.is_supported_by(&supported));
+    assert!(!DeviceFeature::DescriptorIndexing.is_supported_by(&supported));
+    assert!(!DeviceFeature::BufferDeviceAddress.is_supported_by(&supported));
+
+    let enabled: DeviceFeatureSet = [
+        DeviceFeature::TimelineSemaphore,
+        DeviceFeature::VulkanMemoryModel,
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        format!("{:?}", enabled),
+        r#"{"timelineSemaphore", "vulkanMemoryModel"}"#
+    );
+
+    // Supported but not enabled features stay off
+    let chain = FeatureChain::new(&supported, enabled, ApiVersion::V1_2);
+    assert_eq!(chain.vulkan12.timeline_semaphore, vk::TRUE);
+    assert_eq!(chain.vulkan12.vulkan_memory_model, vk::TRUE);
+    assert_eq!(chain.vulkan12.vulkan_memory_model_device_scope, vk::TRUE);
+    assert_eq!(chain.vulkan12.scalar_block_layout, vk::FALSE);
+
+    let chain = FeatureChain::new(&supported, enabled, ApiVersion::V1_1);
+    assert_eq!(chain.timeline_semaphore.timeline_semaphore, vk::TRUE);
+    assert_eq!(chain.scalar_block_layout.scalar_block_layout, vk::FALSE);
+    assert_eq!(chain.vulkan12.timeline_semaphore, vk::FALSE);
+}
@@ -2,7 +2,7 @@
 
 use crate::BackendError;
 
-use super::device::Device;
+use super::{barrier::image_aspect_mask_from_format, device::Device};
 use ash::vk;
 use derive_builder::Builder;
 use gpu_allocator::{AllocationCreateDesc, MemoryLocation};
@@ -30,6 +30,7 @@ pub struct ImageDesc {
     pub tiling: vk::ImageTiling,
     pub mip_levels: u16,
     pub array_elements: u32,
+    pub sample_count: vk::SampleCountFlags,
 }
 
 fn mip_count_1d(extent: u32) -> u16 {
@@ -48,6 +49,7 @@ impl ImageDesc {
             tiling: vk::ImageTiling::OPTIMAL,
             mip_levels: 1,
             array_elements: 1,
+            sample_count: vk::SampleCountFlags::TYPE_1,
         }
     }
 
@@ -74,6 +76,7 @@ impl ImageDesc {
             tiling: vk::ImageTiling::OPTIMAL,
             mip_levels: 1,
             array_elements: 6,
+            sample_count: vk::SampleCountFlags::TYPE_1,
         }
     }
 
@@ -123,6 +126,12 @@ impl ImageDesc {
         self
     }
 
+    /// Multisampled images can only be 2D render targets with a single mip level.
+    pub fn sample_count(mut self, sample_count: vk::SampleCountFlags) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
     pub fn div_up_extent(mut self, div_extent: [u32; 3]) -> Self {
         for (extent, &div_extent) in self.extent.iter_mut().zip(&div_extent) {
             *extent = ((*extent + div_extent - 1) / div_extent).max(1);
@@ -141,6 +150,11 @@ impl ImageDesc {
         self.div_up_extent([2, 2, 2])
     }
 
+    /// Divides all dimensions by `n`, rounding up, e.g. for dispatch or tile counts.
+    pub fn div_round_up(self, n: u32) -> Self {
+        self.div_up_extent([n, n, n])
+    }
+
     pub fn extent_inv_extent_2d(&self) -> [f32; 4] {
         [
             self.extent[0] as f32,
@@ -165,12 +179,20 @@ pub struct Image {
     pub raw: vk::Image,
     pub desc: ImageDesc,
     pub views: Mutex<HashMap<ImageViewDesc, vk::ImageView>>,
-    //allocation: gpu_allocator::SubAllocation,
+    /// `None` for images not owned by the allocator, e.g. swapchain ones
+    pub(crate) allocation: Option<gpu_allocator::SubAllocation>,
 }
 unsafe impl Send for Image {}
 unsafe impl Sync for Image {}
 
 impl Image {
+    /// Bytes of device memory backing the image; zero for swapchain images.
+    pub fn allocation_size(&self) -> u64 {
+        self.allocation
+            .as_ref()
+            .map_or(0, |allocation| allocation.size())
+    }
+
     pub fn view(
         &self,
         device: &Device,
@@ -254,7 +276,15 @@ impl Device {
     ) -> Result<Image, BackendError> {
         log::info!("Creating an image: {:?}", desc);
 
-        let create_info = get_image_create_info(&desc, !initial_data.is_empty());
+        let mut create_info = get_image_create_info(&desc, !initial_data.is_empty());
+        self.validate_image_desc(&ImageDesc {
+            usage: create_info.usage,
+            ..desc
+        })?;
+
+        create_info.sharing_mode = super::device::sharing_mode(&self.concurrent_queue_families);
+        create_info.queue_family_index_count = self.concurrent_queue_families.len() as u32;
+        create_info.p_queue_family_indices = self.concurrent_queue_families.as_ptr();
 
         /*let allocation_info = vk_mem::AllocationCreateInfo {
             usage: vk_mem::MemoryUsage::GpuOnly,
@@ -284,6 +314,9 @@ impl Device {
             .map_err(|err| BackendError::Allocation {
                 inner: err,
                 name: "GpuOnly image".into(),
+                size: requirements.size,
+                memory_type_bits: requirements.memory_type_bits,
+                memory_heaps: self.pdevice.memory_heaps(),
             })?;
 
         // Bind memory to the image
@@ -404,7 +437,7 @@ impl Device {
         ImageHandle(handle)*/
         Ok(Image {
             raw: image,
-            //allocation,
+            allocation: Some(allocation),
             desc,
             views: Default::default(),
         })
@@ -416,12 +449,15 @@ impl Device {
         image_desc: &ImageDesc,
         image_raw: vk::Image,
     ) -> Result<vk::ImageView, BackendError> {
-        if image_desc.format == vk::Format::D32_SFLOAT
-            && !desc.aspect_mask.contains(vk::ImageAspectFlags::DEPTH)
+        let format_aspect_mask = image_aspect_mask_from_format(image_desc.format);
+        if format_aspect_mask != vk::ImageAspectFlags::COLOR
+            && !format_aspect_mask.contains(desc.aspect_mask)
         {
             return Err(BackendError::ResourceAccess {
-                info: "Depth-only resource used without the vk::ImageAspectFlags::DEPTH flag"
-                    .to_owned(),
+                info: format!(
+                    "Depth-stencil resource ({:?}) used with the {:?} aspect; expected a subset of {:?}",
+                    image_desc.format, desc.aspect_mask, format_aspect_mask
+                ),
             });
         }
 
@@ -442,6 +478,31 @@ impl Device {
     }*/
 }
 
+/// Size of a single texel for uncompressed formats, or `None` for formats it doesn't know.
+pub fn try_format_texel_size_bytes(format: vk::Format) -> Option<u32> {
+    let size = match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_UINT => 1,
+        vk::Format::R8G8_UNORM | vk::Format::R16_SFLOAT | vk::Format::R16_UINT => 2,
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::E5B9G9R9_UFLOAT_PACK32
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R32_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::D32_SFLOAT => 4,
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R32G32_SFLOAT => 8,
+        vk::Format::R32G32B32_SFLOAT => 12,
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        _ => return None,
+    };
+
+    Some(size)
+}
+
 pub fn convert_image_type_to_view_type(image_type: ImageType) -> vk::ImageViewType {
     match image_type {
         ImageType::Tex1d => vk::ImageViewType::TYPE_1D,
@@ -534,7 +595,7 @@ pub fn get_image_create_info(desc: &ImageDesc, initial_data: bool) -> vk::ImageC
         extent: image_extent,
         mip_levels: desc.mip_levels as u32,
         array_layers: image_layers as u32,
-        samples: vk::SampleCountFlags::TYPE_1, // TODO: desc.sample_count
+        samples: desc.sample_count,
         tiling: desc.tiling,
         usage: image_usage,
         sharing_mode: vk::SharingMode::EXCLUSIVE,
@@ -545,3 +606,218 @@ pub fn get_image_create_info(desc: &ImageDesc, initial_data: bool) -> vk::ImageC
         ..Default::default()
     }
 }
+
+/// Format features needed for each usage
+fn required_format_features(usage: vk::ImageUsageFlags) -> vk::FormatFeatureFlags {
+    let mut features = vk::FormatFeatureFlags::empty();
+
+    for (usage_flag, feature) in [
+        (
+            vk::ImageUsageFlags::SAMPLED,
+            vk::FormatFeatureFlags::SAMPLED_IMAGE,
+        ),
+        (
+            vk::ImageUsageFlags::STORAGE,
+            vk::FormatFeatureFlags::STORAGE_IMAGE,
+        ),
+        (
+            vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            vk::FormatFeatureFlags::COLOR_ATTACHMENT,
+        ),
+        (
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+        ),
+        (
+            vk::ImageUsageFlags::TRANSFER_SRC,
+            vk::FormatFeatureFlags::TRANSFER_SRC,
+        ),
+        (
+            vk::ImageUsageFlags::TRANSFER_DST,
+            vk::FormatFeatureFlags::TRANSFER_DST,
+        ),
+    ] {
+        if usage.contains(usage_flag) {
+            features |= feature;
+        }
+    }
+
+    features
+}
+
+/// Checks `desc` against the device's limits and the features of its format,
+/// so that impossible images fail with an error naming the problem, rather than in the driver.
+pub fn validate_image_desc(
+    desc: &ImageDesc,
+    limits: &vk::PhysicalDeviceLimits,
+    format_properties: &vk::FormatProperties,
+) -> Result<(), BackendError> {
+    let invalid =
+        |field: &'static str, info: String| Err(BackendError::InvalidImageDesc { field, info });
+    let create_info = get_image_create_info(desc, false);
+    let extent = create_info.extent;
+
+    if extent.width == 0 || extent.height == 0 || extent.depth == 0 {
+        return invalid("extent", format!("{:?} is empty", desc.extent));
+    }
+
+    let (max_dimension, limit_name) = match create_info.image_type {
+        vk::ImageType::TYPE_1D => (limits.max_image_dimension1_d, "maxImageDimension1D"),
+        vk::ImageType::TYPE_3D => (limits.max_image_dimension3_d, "maxImageDimension3D"),
+        _ if desc.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE) => {
+            (limits.max_image_dimension_cube, "maxImageDimensionCube")
+        }
+        _ => (limits.max_image_dimension2_d, "maxImageDimension2D"),
+    };
+
+    if extent.width.max(extent.height).max(extent.depth) > max_dimension {
+        return invalid(
+            "extent",
+            format!(
+                "{:?} exceeds {} of {}",
+                desc.extent, limit_name, max_dimension
+            ),
+        );
+    }
+
+    if create_info.array_layers > limits.max_image_array_layers {
+        return invalid(
+            "array_elements",
+            format!(
+                "{} layers exceed maxImageArrayLayers of {}",
+                create_info.array_layers, limits.max_image_array_layers
+            ),
+        );
+    }
+
+    let max_mip_levels = mip_count_1d(extent.width.max(extent.height).max(extent.depth)) as u32;
+    if create_info.mip_levels == 0 || create_info.mip_levels > max_mip_levels {
+        return invalid(
+            "mip_levels",
+            format!(
+                "{} mips requested, but an extent of {:?} has {}",
+                create_info.mip_levels, desc.extent, max_mip_levels
+            ),
+        );
+    }
+
+    let format_features = match desc.tiling {
+        vk::ImageTiling::LINEAR => format_properties.linear_tiling_features,
+        _ => format_properties.optimal_tiling_features,
+    };
+
+    if format_features.is_empty() {
+        return invalid(
+            "format",
+            format!(
+                "{:?} isn't supported with {:?} tiling",
+                desc.format, desc.tiling
+            ),
+        );
+    }
+
+    let missing_features = required_format_features(desc.usage) & !format_features;
+    if !missing_features.is_empty() {
+        return invalid(
+            "usage",
+            format!(
+                "{:?} needs {:?}, which {:?} doesn't support",
+                desc.usage, missing_features, desc.format
+            ),
+        );
+    }
+
+    if desc.sample_count != vk::SampleCountFlags::TYPE_1 {
+        let is_depth = image_aspect_mask_from_format(desc.format)
+            .intersects(vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL);
+
+        let mut supported_sample_counts = if is_depth {
+            limits.framebuffer_depth_sample_counts
+        } else {
+            limits.framebuffer_color_sample_counts
+        };
+
+        if desc.usage.contains(vk::ImageUsageFlags::SAMPLED) {
+            supported_sample_counts &= if is_depth {
+                limits.sampled_image_depth_sample_counts
+            } else {
+                limits.sampled_image_color_sample_counts
+            };
+        }
+
+        if desc.usage.contains(vk::ImageUsageFlags::STORAGE) {
+            supported_sample_counts &= limits.storage_image_sample_counts;
+        }
+
+        if !supported_sample_counts.contains(desc.sample_count) {
+            return invalid(
+                "sample_count",
+                format!(
+                    "{:?} isn't supported with {:?}; supported counts are {:?}",
+                    desc.sample_count, desc.usage, supported_sample_counts
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_image_desc() {
+    let limits = vk::PhysicalDeviceLimits {
+        max_image_dimension1_d: 16384,
+        max_image_dimension2_d: 8192,
+        max_image_dimension3_d: 2048,
+        max_image_dimension_cube: 4096,
+        max_image_array_layers: 256,
+        framebuffer_color_sample_counts: vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_4,
+        framebuffer_depth_sample_counts: vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_4,
+        storage_image_sample_counts: vk::SampleCountFlags::TYPE_1,
+        ..Default::default()
+    };
+    let format_properties = vk::FormatProperties {
+        optimal_tiling_features: vk::FormatFeatureFlags::SAMPLED_IMAGE
+            | vk::FormatFeatureFlags::COLOR_ATTACHMENT,
+        ..Default::default()
+    };
+
+    let field = |desc: ImageDesc| match validate_image_desc(&desc, &limits, &format_properties) {
+        Ok(()) => None,
+        Err(BackendError::InvalidImageDesc { field, .. }) => Some(field),
+        Err(err) => panic!("{:?}", err),
+    };
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [8192, 1024])
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::COLOR_ATTACHMENT);
+
+    assert_eq!(field(desc), None);
+    assert_eq!(field(desc.all_mip_levels()), None);
+    assert_eq!(field(desc.extent([16384, 16384, 1])), Some("extent"));
+    assert_eq!(field(desc.extent([0, 16, 1])), Some("extent"));
+    assert_eq!(field(desc.mip_levels(15)), Some("mip_levels"));
+    assert_eq!(
+        field(desc.usage(vk::ImageUsageFlags::STORAGE)),
+        Some("usage")
+    );
+    assert_eq!(field(desc.tiling(vk::ImageTiling::LINEAR)), Some("format"));
+    assert_eq!(field(desc.sample_count(vk::SampleCountFlags::TYPE_4)), None);
+    assert_eq!(
+        field(desc.sample_count(vk::SampleCountFlags::TYPE_8)),
+        Some("sample_count")
+    );
+    assert_eq!(
+        field(ImageDesc::new_cube(vk::Format::R8G8B8A8_UNORM, 8192)),
+        Some("extent")
+    );
+    assert_eq!(
+        field(
+            ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4])
+                .image_type(ImageType::Tex2dArray)
+                .array_elements(512)
+        ),
+        Some("array_elements")
+    );
+}
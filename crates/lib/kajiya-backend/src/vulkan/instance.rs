@@ -3,29 +3,168 @@ use ash::{extensions::ext, vk};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use std::{
+    collections::HashSet,
     ffi::{c_void, CStr, CString},
     os::raw::c_char,
     sync::Arc,
 };
 
-#[derive(Default)]
-pub struct DeviceBuilder {
+/// A Vulkan `major.minor` version, as requested from the instance.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct ApiVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ApiVersion {
+    pub const V1_0: Self = Self::new(1, 0);
+    pub const V1_1: Self = Self::new(1, 1);
+    pub const V1_2: Self = Self::new(1, 2);
+
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// Drops the variant and patch version of a packed `u32` version.
+    pub fn from_raw(version: u32) -> Self {
+        Self::new(
+            vk::api_version_major(version),
+            vk::api_version_minor(version),
+        )
+    }
+
+    pub fn raw(self) -> u32 {
+        vk::make_api_version(0, self.major, self.minor, 0)
+    }
+}
+
+impl std::fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+const VALIDATION_LAYER_NAME: &str = "VK_LAYER_KHRONOS_validation";
+
+// `VK_KHR_portability_enumeration` is newer than the headers of our `ash` version
+const ENUMERATE_PORTABILITY_KHR: vk::InstanceCreateFlags = vk::InstanceCreateFlags::from_raw(0x1);
+
+fn portability_enumeration_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_portability_enumeration\0").unwrap()
+}
+
+pub struct InstanceBuilder {
     pub required_extensions: Vec<&'static CStr>,
+    pub optional_extensions: Vec<&'static CStr>,
     pub graphics_debugging: bool,
+    pub validation: bool,
+    pub gpu_assisted_validation: bool,
+    pub synchronization_validation: bool,
+    pub ignored_message_ids: Vec<String>,
+    pub app_name: String,
+    pub app_version: u32,
+    pub api_version: ApiVersion,
+}
+
+impl Default for InstanceBuilder {
+    fn default() -> Self {
+        Self {
+            required_extensions: Vec::new(),
+            optional_extensions: Vec::new(),
+            graphics_debugging: false,
+            validation: validation_default(),
+            gpu_assisted_validation: false,
+            synchronization_validation: false,
+            ignored_message_ids: vec![
+                // Validation layers incorrectly report an error in pushing immutable sampler descriptors.
+                //
+                // https://www.khronos.org/registry/vulkan/specs/1.2-extensions/man/html/vkCmdPushDescriptorSetKHR.html
+                // This documentation claims that it's necessary to push immutable samplers.
+                "VUID-VkWriteDescriptorSet-descriptorType-00322".to_owned(),
+                "VUID-VkWriteDescriptorSet-descriptorType-02752".to_owned(),
+            ],
+            app_name: "kajiya".to_owned(),
+            app_version: 0,
+            api_version: ApiVersion::V1_2,
+        }
+    }
+}
+
+/// e.g. `KAJIYA_VALIDATION=0` to disable validation in debug builds.
+fn validation_default() -> bool {
+    match std::env::var("KAJIYA_VALIDATION") {
+        Ok(value) => !matches!(value.as_str(), "" | "0" | "false"),
+        Err(_) => cfg!(debug_assertions),
+    }
 }
 
-impl DeviceBuilder {
+impl InstanceBuilder {
     pub fn build(self) -> Result<Arc<Instance>> {
         Ok(Arc::new(Instance::create(self)?))
     }
 
+    /// Creating the instance fails if any of these are missing.
     pub fn required_extensions(mut self, required_extensions: Vec<&'static CStr>) -> Self {
         self.required_extensions = required_extensions;
         self
     }
 
+    /// Enabled if available. Query with `Instance::is_extension_enabled`.
+    pub fn optional_extensions(mut self, optional_extensions: Vec<&'static CStr>) -> Self {
+        self.optional_extensions = optional_extensions;
+        self
+    }
+
+    /// Enables debug labels and names for tools, as well as validation.
     pub fn graphics_debugging(mut self, graphics_debugging: bool) -> Self {
         self.graphics_debugging = graphics_debugging;
+        self.validation |= graphics_debugging;
+        self
+    }
+
+    /// Enables `VK_LAYER_KHRONOS_validation`, if it's installed. When it's not,
+    /// the instance is created without it, and a warning is logged.
+    /// On by default in debug builds, unless overridden by the `KAJIYA_VALIDATION` environment variable.
+    pub fn validation(mut self, validation: bool) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Validates shader accesses by instrumenting them. Only takes effect with `validation`.
+    pub fn gpu_assisted_validation(mut self, gpu_assisted_validation: bool) -> Self {
+        self.gpu_assisted_validation = gpu_assisted_validation;
+        self
+    }
+
+    /// Reports hazards caused by missing or insufficient barriers. Only takes effect with `validation`.
+    pub fn synchronization_validation(mut self, synchronization_validation: bool) -> Self {
+        self.synchronization_validation = synchronization_validation;
+        self
+    }
+
+    /// Drops validation messages with this ID name, e.g. `UNASSIGNED-BestPractices-vkCreateDevice-specialuse-extension`,
+    /// for known issues which have been triaged.
+    pub fn ignore_message_id(mut self, message_id_name: &str) -> Self {
+        self.ignored_message_ids.push(message_id_name.to_owned());
+        self
+    }
+
+    /// Reported to drivers and tools in `VkApplicationInfo`.
+    pub fn app_name(mut self, app_name: &str) -> Self {
+        self.app_name = app_name.to_owned();
+        self
+    }
+
+    /// Reported to drivers and tools in `VkApplicationInfo`, e.g. as made by `vk::make_api_version`.
+    pub fn app_version(mut self, app_version: u32) -> Self {
+        self.app_version = app_version;
+        self
+    }
+
+    /// The Vulkan version to use; 1.2 by default. Creating the instance fails
+    /// if the loader doesn't support it.
+    pub fn api_version(mut self, api_version: ApiVersion) -> Self {
+        self.api_version = api_version;
         self
     }
 }
@@ -33,126 +172,355 @@ impl DeviceBuilder {
 pub struct Instance {
     pub(crate) entry: ash::Entry,
     pub raw: ash::Instance,
+    pub(crate) debug_utils: Option<ext::DebugUtils>,
+    /// Routes validation messages to the `log` crate; see `vulkan_debug_callback`.
+    debug_messenger: Option<vk::DebugUtilsMessengerEXT>,
     #[allow(dead_code)]
-    pub(crate) debug_callback: Option<vk::DebugReportCallbackEXT>,
-    #[allow(dead_code)]
-    #[allow(deprecated)]
-    pub(crate) debug_loader: Option<ext::DebugReport>,
-    pub(crate) debug_utils: Option<ash::extensions::ext::DebugUtils>,
+    debug_messenger_filter: Box<DebugMessengerFilter>,
+    api_version: ApiVersion,
+    validation: bool,
+    enabled_extensions: HashSet<CString>,
 }
 
 impl Instance {
-    pub fn builder() -> DeviceBuilder {
-        DeviceBuilder::default()
+    pub fn builder() -> InstanceBuilder {
+        InstanceBuilder::default()
+    }
+
+    /// The version requested with `InstanceBuilder::api_version`.
+    /// Devices may support less; see `PhysicalDevice::api_version`.
+    pub fn api_version(&self) -> ApiVersion {
+        self.api_version
     }
 
-    fn extension_names(builder: &DeviceBuilder) -> Vec<*const i8> {
-        let mut names = vec![vk::KhrGetPhysicalDeviceProperties2Fn::name().as_ptr()];
+    /// Whether the validation layer was requested and found.
+    pub fn validation_enabled(&self) -> bool {
+        self.validation
+    }
+
+    /// Whether the extension was required, or optional and available.
+    pub fn is_extension_enabled(&self, name: &CStr) -> bool {
+        self.enabled_extensions.contains(name)
+    }
+
+    /// The required extensions, and optional ones which are enabled if available.
+    fn extension_names(
+        builder: &InstanceBuilder,
+        validation: bool,
+    ) -> (Vec<&'static CStr>, Vec<&'static CStr>) {
+        let mut required = builder.required_extensions.clone();
+        required.push(vk::KhrGetPhysicalDeviceProperties2Fn::name());
+
+        let mut optional = builder.optional_extensions.clone();
+
+        // Needed to see MoltenVK and other portability implementations with newer loaders
+        optional.push(portability_enumeration_extension_name());
+
+        if builder.graphics_debugging || validation {
+            optional.push(ext::DebugUtils::name());
+        }
 
-        if builder.graphics_debugging {
-            #[allow(deprecated)]
-            names.push(ext::DebugReport::name().as_ptr());
-            names.push(vk::ExtDebugUtilsFn::name().as_ptr());
+        if validation {
+            optional.push(vk::ExtValidationFeaturesFn::name());
         }
 
-        names
+        (required, optional)
+    }
+
+    /// Extensions of the loader and implicit layers, or with `layer_name`, the ones that layer provides.
+    fn available_extensions(
+        entry: &ash::Entry,
+        layer_name: Option<&CStr>,
+    ) -> Result<HashSet<CString>> {
+        // `ash::Entry::enumerate_instance_extension_properties` doesn't take a layer name
+        let layer_name = layer_name.map_or(std::ptr::null(), CStr::as_ptr);
+        let properties = unsafe {
+            let mut count = 0;
+            entry
+                .fp_v1_0()
+                .enumerate_instance_extension_properties(
+                    layer_name,
+                    &mut count,
+                    std::ptr::null_mut(),
+                )
+                .result()?;
+
+            let mut properties: Vec<vk::ExtensionProperties> = Vec::with_capacity(count as usize);
+            entry
+                .fp_v1_0()
+                .enumerate_instance_extension_properties(
+                    layer_name,
+                    &mut count,
+                    properties.as_mut_ptr(),
+                )
+                .result()?;
+            properties.set_len(count as usize);
+            properties
+        };
+
+        Ok(properties
+            .iter()
+            .map(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) }.to_owned())
+            .collect())
     }
 
-    fn layer_names(builder: &DeviceBuilder) -> Vec<CString> {
+    fn layer_names(validation: bool) -> Vec<CString> {
         let mut layer_names = Vec::new();
-        if builder.graphics_debugging {
-            layer_names.push(CString::new("VK_LAYER_KHRONOS_validation").unwrap());
+        if validation {
+            layer_names.push(CString::new(VALIDATION_LAYER_NAME).unwrap());
         }
         layer_names
     }
 
-    fn create(builder: DeviceBuilder) -> Result<Self> {
+    fn validation_layer_available(entry: &ash::Entry) -> Result<bool> {
+        let layers = entry.enumerate_instance_layer_properties()?;
+
+        Ok(layers.iter().any(|layer| {
+            unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) }.to_string_lossy()
+                == VALIDATION_LAYER_NAME
+        }))
+    }
+
+    fn create(builder: InstanceBuilder) -> Result<Self> {
         let entry = unsafe { ash::Entry::new()? };
-        let instance_extensions = builder
-            .required_extensions
+
+        // Vulkan 1.0 loaders don't have `vkEnumerateInstanceVersion`
+        let loader_version = entry
+            .try_enumerate_instance_version()?
+            .map_or(ApiVersion::V1_0, ApiVersion::from_raw);
+        if loader_version < builder.api_version {
+            anyhow::bail!(
+                "Vulkan {} was requested, but the Vulkan loader only supports {}",
+                builder.api_version,
+                loader_version
+            );
+        }
+
+        let validation = builder.validation && {
+            let available = Self::validation_layer_available(&entry)?;
+            if !available {
+                warn!(
+                    "Validation was requested, but {} isn't installed. Continuing without it",
+                    VALIDATION_LAYER_NAME
+                );
+            }
+            available
+        };
+
+        let layer_names = Self::layer_names(validation);
+
+        let mut available_extensions = Self::available_extensions(&entry, None)?;
+        for layer_name in &layer_names {
+            available_extensions.extend(Self::available_extensions(
+                &entry,
+                Some(layer_name.as_c_str()),
+            )?);
+        }
+
+        let (required_extensions, optional_extensions) =
+            Self::extension_names(&builder, validation);
+
+        let missing_extensions: Vec<_> = required_extensions
             .iter()
-            .map(|ext| ext.as_ptr())
-            .chain(Self::extension_names(&builder).into_iter())
-            .collect::<Vec<_>>();
+            .filter(|ext| !available_extensions.contains(**ext))
+            .map(|ext| ext.to_string_lossy())
+            .collect();
+        if !missing_extensions.is_empty() {
+            anyhow::bail!(
+                "Missing required Vulkan instance extensions: {}",
+                missing_extensions.join(", ")
+            );
+        }
+
+        let mut enabled_extensions: HashSet<CString> = HashSet::new();
+        for ext in required_extensions.into_iter().chain(optional_extensions) {
+            if available_extensions.contains(ext) {
+                enabled_extensions.insert(ext.to_owned());
+            } else {
+                info!(
+                    "Optional Vulkan instance extension not available: {}",
+                    ext.to_string_lossy()
+                );
+            }
+        }
+
+        let instance_extensions: Vec<*const i8> =
+            enabled_extensions.iter().map(|ext| ext.as_ptr()).collect();
 
-        let layer_names = Self::layer_names(&builder);
         let layer_names: Vec<*const i8> = layer_names
             .iter()
             .map(|raw_name| raw_name.as_ptr())
             .collect();
 
-        let app_desc = vk::ApplicationInfo::builder().api_version(vk::make_api_version(0, 1, 2, 0));
+        let app_name = CString::new(builder.app_name.as_str())?;
+        let app_desc = vk::ApplicationInfo::builder()
+            .application_name(&app_name)
+            .application_version(builder.app_version)
+            .engine_name(CStr::from_bytes_with_nul(b"kajiya\0").unwrap())
+            .api_version(builder.api_version.raw());
 
-        let instance_desc = vk::InstanceCreateInfo::builder()
+        let mut enabled_validation_features = Vec::new();
+        if builder.gpu_assisted_validation {
+            enabled_validation_features.extend([
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED,
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT,
+            ]);
+        }
+        if builder.synchronization_validation {
+            enabled_validation_features
+                .push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        let mut validation_features = vk::ValidationFeaturesEXT::builder()
+            .enabled_validation_features(&enabled_validation_features);
+
+        let mut instance_desc = vk::InstanceCreateInfo::builder()
             .application_info(&app_desc)
             .enabled_layer_names(&layer_names)
             .enabled_extension_names(&instance_extensions);
 
+        if enabled_extensions.contains(vk::ExtValidationFeaturesFn::name()) {
+            instance_desc = instance_desc.push_next(&mut validation_features);
+        }
+
+        if enabled_extensions.contains(portability_enumeration_extension_name()) {
+            instance_desc = instance_desc.flags(ENUMERATE_PORTABILITY_KHR);
+        }
+
         let instance = unsafe { entry.create_instance(&instance_desc, None)? };
-        info!("Created a Vulkan instance");
-
-        let (debug_loader, debug_callback, debug_utils) = if builder.graphics_debugging {
-            let debug_info = ash::vk::DebugReportCallbackCreateInfoEXT {
-                flags: ash::vk::DebugReportFlagsEXT::ERROR
-                    | ash::vk::DebugReportFlagsEXT::WARNING
-                    | ash::vk::DebugReportFlagsEXT::PERFORMANCE_WARNING,
-                pfn_callback: Some(vulkan_debug_callback),
-                ..Default::default()
-            };
-
-            #[allow(deprecated)]
-            let debug_loader = ext::DebugReport::new(&entry, &instance);
-
-            let debug_callback = unsafe {
-                #[allow(deprecated)]
-                debug_loader
-                    .create_debug_report_callback(&debug_info, None)
-                    .unwrap()
-            };
-
-            let debug_utils = ash::extensions::ext::DebugUtils::new(&entry, &instance);
-
-            (Some(debug_loader), Some(debug_callback), Some(debug_utils))
+        info!(
+            "Created a Vulkan {} instance{}",
+            builder.api_version,
+            if validation { " with validation" } else { "" }
+        );
+
+        // Boxed, since the messenger keeps a pointer to it
+        let debug_messenger_filter = Box::new(DebugMessengerFilter {
+            ignored_message_ids: builder.ignored_message_ids,
+        });
+
+        let (debug_utils, debug_messenger) = if enabled_extensions.contains(ext::DebugUtils::name())
+        {
+            let debug_utils = ext::DebugUtils::new(&entry, &instance);
+
+            let messenger_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+                .message_severity(
+                    vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                        | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                        | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                        | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+                )
+                .message_type(
+                    vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                        | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                        | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+                )
+                .pfn_user_callback(Some(vulkan_debug_callback))
+                .user_data(&*debug_messenger_filter as *const DebugMessengerFilter as *mut c_void);
+
+            let debug_messenger =
+                unsafe { debug_utils.create_debug_utils_messenger(&messenger_info, None)? };
+
+            (Some(debug_utils), Some(debug_messenger))
         } else {
-            (None, None, None)
+            (None, None)
         };
 
         Ok(Self {
             entry,
             raw: instance,
-            debug_callback,
-            debug_loader,
             debug_utils,
+            debug_messenger,
+            debug_messenger_filter,
+            api_version: builder.api_version,
+            validation,
+            enabled_extensions,
         })
     }
 }
 
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // Devices keep the instance alive, so this runs once everything else is gone
+        if let (Some(debug_utils), Some(debug_messenger)) =
+            (&self.debug_utils, self.debug_messenger)
+        {
+            unsafe {
+                debug_utils.destroy_debug_utils_messenger(debug_messenger, None);
+            }
+        }
+    }
+}
+
+struct DebugMessengerFilter {
+    ignored_message_ids: Vec<String>,
+}
+
+unsafe fn cstr_or_empty<'a>(ptr: *const c_char) -> std::borrow::Cow<'a, str> {
+    if ptr.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy()
+    }
+}
+
 unsafe extern "system" fn vulkan_debug_callback(
-    _flags: vk::DebugReportFlagsEXT,
-    _obj_type: vk::DebugReportObjectTypeEXT,
-    _src_obj: u64,
-    _location: usize,
-    _msg_code: i32,
-    _layer_prefix: *const c_char,
-    message: *const c_char,
-    _user_data: *mut c_void,
-) -> u32 {
-    let message = CStr::from_ptr(message).to_str().unwrap();
-
-    #[allow(clippy::if_same_then_else)]
-    if message.starts_with("Validation Error: [ VUID-VkWriteDescriptorSet-descriptorType-00322")
-        || message.starts_with("Validation Error: [ VUID-VkWriteDescriptorSet-descriptorType-02752")
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let callback_data = &*callback_data;
+    let filter = &*(user_data as *const DebugMessengerFilter);
+
+    let message_id_name = cstr_or_empty(callback_data.p_message_id_name);
+    if filter
+        .ignored_message_ids
+        .iter()
+        .any(|ignored| *ignored == message_id_name)
     {
-        // Validation layers incorrectly report an error in pushing immutable sampler descriptors.
-        //
-        // https://www.khronos.org/registry/vulkan/specs/1.2-extensions/man/html/vkCmdPushDescriptorSetKHR.html
-        // This documentation claims that it's necessary to push immutable samplers.
-    } else if message.starts_with("Validation Performance Warning") {
-    } else if message.starts_with("Validation Warning: [ VUID_Undefined ]") {
-        log::warn!("{}\n", message);
+        return vk::FALSE;
+    }
+
+    let mut level = if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        log::Level::Error
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        log::Level::Warn
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        log::Level::Info
+    } else {
+        log::Level::Trace
+    };
+
+    // Performance warnings are too noisy to show by default
+    if message_type == vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE {
+        level = level.max(log::Level::Debug);
+    }
+
+    if !log::log_enabled!(level) {
+        return vk::FALSE;
+    }
+
+    let objects = if callback_data.object_count > 0 {
+        std::slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
     } else {
-        log::error!("{}\n", message);
+        &[]
+    };
+
+    let mut object_names = String::new();
+    for object in objects {
+        object_names += &format!("\n\t{:?} {:#x}", object.object_type, object.object_handle);
+        if !object.p_object_name.is_null() {
+            object_names += &format!(" {:?}", cstr_or_empty(object.p_object_name));
+        }
     }
 
-    ash::vk::FALSE
+    log::log!(
+        level,
+        "[{}] {}{}",
+        message_id_name,
+        cstr_or_empty(callback_data.p_message),
+        object_names
+    );
+
+    vk::FALSE
 }
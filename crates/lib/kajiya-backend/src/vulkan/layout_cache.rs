@@ -0,0 +1,166 @@
+use ash::vk;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct DescriptorSetLayoutBindingKey {
+    binding: u32,
+    ty: vk::DescriptorType,
+    count: u32,
+    stage_flags: vk::ShaderStageFlags,
+    immutable_samplers: Vec<vk::Sampler>,
+    flags: vk::DescriptorBindingFlags,
+}
+
+/// Normalized description of a descriptor set layout. Bindings are sorted by index,
+/// since reflection yields them in hash map order.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct DescriptorSetLayoutKey {
+    flags: vk::DescriptorSetLayoutCreateFlags,
+    bindings: Vec<DescriptorSetLayoutBindingKey>,
+}
+
+impl DescriptorSetLayoutKey {
+    fn new(
+        flags: vk::DescriptorSetLayoutCreateFlags,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        binding_flags: &[vk::DescriptorBindingFlags],
+    ) -> Self {
+        let mut bindings: Vec<DescriptorSetLayoutBindingKey> = bindings
+            .iter()
+            .zip(binding_flags)
+            .map(|(binding, flags)| DescriptorSetLayoutBindingKey {
+                binding: binding.binding,
+                ty: binding.descriptor_type,
+                count: binding.descriptor_count,
+                stage_flags: binding.stage_flags,
+                immutable_samplers: if binding.p_immutable_samplers.is_null() {
+                    Vec::new()
+                } else {
+                    // Immutable samplers are always provided for the whole binding
+                    unsafe {
+                        std::slice::from_raw_parts(
+                            binding.p_immutable_samplers,
+                            binding.descriptor_count as usize,
+                        )
+                    }
+                    .to_vec()
+                },
+                flags: *flags,
+            })
+            .collect();
+
+        bindings.sort_by_key(|binding| binding.binding);
+
+        Self { flags, bindings }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PipelineLayoutKey {
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<(vk::ShaderStageFlags, u32, u32)>,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LayoutCacheStats {
+    /// Unique descriptor set layouts created by pipelines
+    pub descriptor_set_layouts: u32,
+    /// Unique pipeline layouts created by pipelines
+    pub pipeline_layouts: u32,
+}
+
+/// Shares descriptor set layouts and pipeline layouts between pipelines with identical layouts.
+///
+/// Descriptor set layouts are deduplicated by their normalized description, and since equal
+/// descriptions map to the same handle, pipeline layouts can be keyed by the set layout handles.
+/// Everything is owned by the cache, and destroyed together with the device.
+#[derive(Default)]
+pub struct LayoutCache {
+    descriptor_set_layouts: Mutex<HashMap<DescriptorSetLayoutKey, vk::DescriptorSetLayout>>,
+    pipeline_layouts: Mutex<HashMap<PipelineLayoutKey, vk::PipelineLayout>>,
+}
+
+impl LayoutCache {
+    pub fn get_or_create_descriptor_set_layout(
+        &self,
+        device: &ash::Device,
+        flags: vk::DescriptorSetLayoutCreateFlags,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        binding_flags: &[vk::DescriptorBindingFlags],
+    ) -> Result<vk::DescriptorSetLayout, vk::Result> {
+        let key = DescriptorSetLayoutKey::new(flags, bindings, binding_flags);
+        let mut descriptor_set_layouts = self.descriptor_set_layouts.lock();
+
+        if let Some(set_layout) = descriptor_set_layouts.get(&key) {
+            return Ok(*set_layout);
+        }
+
+        let mut binding_flags_create_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder().binding_flags(binding_flags);
+
+        let set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder()
+                    .flags(flags)
+                    .bindings(bindings)
+                    .push_next(&mut binding_flags_create_info),
+                None,
+            )?
+        };
+
+        descriptor_set_layouts.insert(key, set_layout);
+        Ok(set_layout)
+    }
+
+    /// `set_layouts` must come from `get_or_create_descriptor_set_layout`.
+    pub fn get_or_create_pipeline_layout(
+        &self,
+        device: &ash::Device,
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Result<vk::PipelineLayout, vk::Result> {
+        let key = PipelineLayoutKey {
+            set_layouts: set_layouts.to_vec(),
+            push_constant_ranges: push_constant_ranges
+                .iter()
+                .map(|range| (range.stage_flags, range.offset, range.size))
+                .collect(),
+        };
+        let mut pipeline_layouts = self.pipeline_layouts.lock();
+
+        if let Some(pipeline_layout) = pipeline_layouts.get(&key) {
+            return Ok(*pipeline_layout);
+        }
+
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(set_layouts)
+                    .push_constant_ranges(push_constant_ranges),
+                None,
+            )?
+        };
+
+        pipeline_layouts.insert(key, pipeline_layout);
+        Ok(pipeline_layout)
+    }
+
+    pub fn stats(&self) -> LayoutCacheStats {
+        LayoutCacheStats {
+            descriptor_set_layouts: self.descriptor_set_layouts.lock().len() as u32,
+            pipeline_layouts: self.pipeline_layouts.lock().len() as u32,
+        }
+    }
+
+    /// Must only be called once no pipelines using the layouts remain.
+    pub(crate) unsafe fn destroy(&self, device: &ash::Device) {
+        for (_, pipeline_layout) in self.pipeline_layouts.lock().drain() {
+            device.destroy_pipeline_layout(pipeline_layout, None);
+        }
+
+        for (_, set_layout) in self.descriptor_set_layouts.lock().drain() {
+            device.destroy_descriptor_set_layout(set_layout, None);
+        }
+    }
+}
@@ -1,13 +1,18 @@
 pub mod barrier;
+pub mod bindless;
 pub mod buffer;
+pub mod descriptor_allocator;
 pub mod device;
+pub mod device_features;
 pub mod error;
 pub mod image;
 pub mod instance;
+pub mod layout_cache;
 pub mod physical_device;
 pub mod profiler;
 pub mod ray_tracing;
 pub mod shader;
+pub mod shader_module_cache;
 pub mod surface;
 pub mod swapchain;
 
@@ -32,8 +37,10 @@ fn select_surface_format(formats: Vec<vk::SurfaceFormatKHR>) -> Option<vk::Surfa
 
 pub struct RenderBackend {
     pub device: Arc<device::Device>,
-    pub surface: Arc<surface::Surface>,
-    pub swapchain: swapchain::Swapchain,
+    /// `None` for headless backends
+    pub surface: Option<Arc<surface::Surface>>,
+    /// `None` for headless backends
+    pub swapchain: Option<swapchain::Swapchain>,
 }
 
 #[derive(Clone, Copy)]
@@ -58,46 +65,84 @@ impl RenderBackend {
         use physical_device::*;
         let physical_devices =
             enumerate_physical_devices(&instance)?.with_presentation_support(&surface);
+        let device = Self::create_device(physical_devices, &config)?;
+        let swapchain = Self::create_swapchain(&device, &surface, &config)?;
 
-        info!(
-            "Available physical devices: {:#?}",
-            physical_devices
-                .iter()
-                .map(|dev| unsafe {
-                    ::std::ffi::CStr::from_ptr(
-                        dev.properties.device_name.as_ptr() as *const std::os::raw::c_char
-                    )
-                })
-                .collect::<Vec<_>>()
-        );
-
-        let physical_device = Arc::new(if let Some(device_index) = config.device_index {
-            physical_devices.into_iter().nth(device_index).unwrap()
-        } else {
-            physical_devices
-                .into_iter()
-                // If there are multiple devices with the same score, `max_by_key` would choose the last,
-                // and we want to preserve the order of devices from `enumerate_physical_devices`.
-                .rev()
-                .max_by_key(|device| match device.properties.device_type {
-                    vk::PhysicalDeviceType::INTEGRATED_GPU => 200,
-                    vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
-                    vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
-                    _ => 0,
-                })
-                .unwrap()
-        });
-
-        info!("Selected physical device: {:#?}", *physical_device);
-
-        let device = device::Device::create(&physical_device)?;
-        let surface_formats = swapchain::Swapchain::enumerate_surface_formats(&device, &surface)?;
+        Ok(Self {
+            device,
+            surface: Some(surface),
+            swapchain: Some(swapchain),
+        })
+    }
+
+    /// Creates a surface and swapchain for another window, e.g. a detached debug view.
+    /// Each swapchain owns its semaphores, so a frame can render to and present several.
+    /// Only the first window affects device selection; this fails if the device
+    /// can't present to `window`. Only `swapchain_extent` and `vsync` of `config` are used.
+    pub fn create_window_swapchain(
+        &self,
+        window: &impl HasRawWindowHandle,
+        config: RenderBackendConfig,
+    ) -> anyhow::Result<swapchain::Swapchain> {
+        if self.surface.is_none() {
+            anyhow::bail!("Headless backends can't present to windows");
+        }
+
+        let surface = surface::Surface::create(&self.device.instance, window)?;
+        Self::create_swapchain(&self.device, &surface, &config)
+    }
+
+    /// A backend without a window, surface or swapchain, for offscreen rendering.
+    /// Any device can be selected, including ones which can't present.
+    /// `config.swapchain_extent` and `config.vsync` are ignored.
+    pub fn new_headless(config: RenderBackendConfig) -> anyhow::Result<Self> {
+        let instance = instance::Instance::builder()
+            .graphics_debugging(config.graphics_debugging)
+            .build()?;
+
+        let physical_devices = physical_device::enumerate_physical_devices(&instance)?;
+        let device = Self::create_device(physical_devices, &config)?;
+
+        Ok(Self {
+            device,
+            surface: None,
+            swapchain: None,
+        })
+    }
+
+    fn create_device(
+        physical_devices: Vec<physical_device::PhysicalDevice>,
+        config: &RenderBackendConfig,
+    ) -> anyhow::Result<Arc<device::Device>> {
+        // Ray tracing is optional, but preferred
+        let physical_device = Arc::new(physical_device::select_physical_device(
+            physical_devices,
+            config.device_index,
+            &[
+                vk::KhrAccelerationStructureFn::name(),
+                vk::KhrRayTracingPipelineFn::name(),
+            ],
+        )?);
+
+        info!("Selected physical device: {}", *physical_device);
+        debug!("{:#?}", *physical_device);
+        debug!("Memory heaps:\n{}", physical_device.memory_report());
+
+        Ok(device::Device::builder(&physical_device).build()?)
+    }
+
+    fn create_swapchain(
+        device: &Arc<device::Device>,
+        surface: &Arc<surface::Surface>,
+        config: &RenderBackendConfig,
+    ) -> anyhow::Result<swapchain::Swapchain> {
+        let surface_formats = surface.formats(device.physical_device())?;
 
         info!("Available surface formats: {:#?}", surface_formats);
 
-        let swapchain = swapchain::Swapchain::new(
-            &device,
-            &surface,
+        swapchain::Swapchain::new(
+            device,
+            surface,
             swapchain::SwapchainDesc {
                 format: select_surface_format(surface_formats).expect("suitable surface format"),
                 dims: vk::Extent2D {
@@ -105,14 +150,9 @@ impl RenderBackend {
                     height: config.swapchain_extent[1],
                 },
                 vsync: config.vsync,
+                ..Default::default()
             },
-        )?;
-
-        Ok(Self {
-            device,
-            surface,
-            swapchain,
-        })
+        )
     }
 
     /*fn maintain(&mut self) {
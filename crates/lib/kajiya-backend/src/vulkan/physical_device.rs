@@ -1,9 +1,12 @@
-use super::{instance::Instance, surface::Surface};
+use super::{
+    instance::{ApiVersion, Instance},
+    surface::Surface,
+};
 use anyhow::Result;
 use ash::vk::{self, PhysicalDeviceMemoryProperties, PhysicalDeviceProperties};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::sync::Arc;
+use std::{borrow::Cow, collections::HashSet, ffi::CStr, sync::Arc};
 
 /// Properties of the physical device.
 /*#[derive(Clone, Debug)]
@@ -23,6 +26,8 @@ pub struct PhysicalDeviceProperties {
 pub struct QueueFamily {
     pub index: u32,
     pub properties: vk::QueueFamilyProperties,
+    /// Whether the family can present to the surface given to `with_presentation_support`
+    pub present_support: bool,
 }
 
 pub struct PhysicalDevice {
@@ -32,14 +37,468 @@ pub struct PhysicalDevice {
     pub(crate) presentation_requested: bool,
     pub properties: PhysicalDeviceProperties,
     pub memory_properties: PhysicalDeviceMemoryProperties,
+
+    /// Shader group handle size, alignment etc. for building shader binding tables.
+    /// `None` if the device doesn't support `VK_KHR_ray_tracing_pipeline`.
+    pub ray_tracing_pipeline_properties: Option<vk::PhysicalDeviceRayTracingPipelinePropertiesKHR>,
+
+    /// Limits of update-after-bind descriptors, which the bindless set is sized from.
+    /// Zeroed if the device doesn't support descriptor indexing.
+    pub descriptor_indexing_properties: vk::PhysicalDeviceDescriptorIndexingProperties,
+
+    /// Names of the supported device extensions
+    pub(crate) extensions: HashSet<String>,
+    pub features: PhysicalDeviceFeatures,
+}
+
+/// Features reported by `vkGetPhysicalDeviceFeatures2`, queried when enumerating devices.
+/// Structs of extensions which the device doesn't support are left zeroed.
+/// `p_next` pointers are all null.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PhysicalDeviceFeatures {
+    pub core: vk::PhysicalDeviceFeatures,
+    /// `None` on devices older than Vulkan 1.2
+    pub vulkan11: Option<vk::PhysicalDeviceVulkan11Features>,
+    /// `None` on devices older than Vulkan 1.2
+    pub vulkan12: Option<vk::PhysicalDeviceVulkan12Features>,
+    pub scalar_block_layout: vk::PhysicalDeviceScalarBlockLayoutFeatures,
+    pub descriptor_indexing: vk::PhysicalDeviceDescriptorIndexingFeatures,
+    pub imageless_framebuffer: vk::PhysicalDeviceImagelessFramebufferFeatures,
+    pub shader_float16_int8: vk::PhysicalDeviceShaderFloat16Int8Features,
+    pub vulkan_memory_model: vk::PhysicalDeviceVulkanMemoryModelFeatures,
+    pub buffer_device_address: vk::PhysicalDeviceBufferDeviceAddressFeatures,
+    pub timeline_semaphore: vk::PhysicalDeviceTimelineSemaphoreFeatures,
+    pub separate_depth_stencil_layouts: vk::PhysicalDeviceSeparateDepthStencilLayoutsFeatures,
+    pub shader_draw_parameters: vk::PhysicalDeviceShaderDrawParametersFeatures,
+    pub acceleration_structure: vk::PhysicalDeviceAccelerationStructureFeaturesKHR,
+    pub ray_tracing_pipeline: vk::PhysicalDeviceRayTracingPipelineFeaturesKHR,
+    pub ray_query: vk::PhysicalDeviceRayQueryFeaturesKHR,
+    pub conditional_rendering: vk::PhysicalDeviceConditionalRenderingFeaturesEXT,
+    /// What a Vulkan portability implementation such as MoltenVK supports;
+    /// `None` on fully conformant devices.
+    pub portability_subset: Option<vk::PhysicalDevicePortabilitySubsetFeaturesKHR>,
 }
 
+/// One line per device, e.g. for bug reports:
+/// `NVIDIA GeForce RTX 3080 (discrete GPU), driver 531.41.0.0, Vulkan 1.3, 10.0 GiB device-local; ray tracing, ray query, descriptor indexing`
+impl std::fmt::Display for PhysicalDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let device_type = match self.properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => "discrete GPU",
+            vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated GPU",
+            vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual GPU",
+            vk::PhysicalDeviceType::CPU => "CPU",
+            _ => "other",
+        };
+
+        write!(
+            f,
+            "{} ({}), driver {}, Vulkan {}, {:.1} GiB device-local",
+            self.name(),
+            device_type,
+            self.driver_version(),
+            self.api_version(),
+            self.device_local_memory_bytes() as f64 / (1u64 << 30) as f64,
+        )?;
+
+        let capabilities = self.notable_capabilities();
+        if !capabilities.is_empty() {
+            write!(f, "; {}", capabilities.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The one-line `Display` form, or with `{:#?}`, everything queried about the device.
 impl std::fmt::Debug for PhysicalDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "PhysicalDevice {{ {:#?} }}", self.properties)
+        if f.alternate() {
+            self.details().fmt(f)
+        } else {
+            write!(f, "PhysicalDevice({})", self)
+        }
+    }
+}
+
+/// See `PhysicalDevice::details`.
+#[derive(Debug)]
+pub struct PhysicalDeviceDetails<'a> {
+    pub properties: &'a PhysicalDeviceProperties,
+    pub memory_properties: &'a PhysicalDeviceMemoryProperties,
+    pub queue_families: Vec<vk::QueueFamilyProperties>,
+    pub features: &'a PhysicalDeviceFeatures,
+    pub ray_tracing_pipeline_properties:
+        &'a Option<vk::PhysicalDeviceRayTracingPipelinePropertiesKHR>,
+    pub extensions: Vec<&'a str>,
+}
+
+/// A memory heap, and the memory types allocated from it.
+#[derive(Clone, Debug)]
+pub struct MemoryHeapInfo {
+    pub index: u32,
+    pub size: u64,
+    pub flags: vk::MemoryHeapFlags,
+    /// Indices and property flags of the memory types using this heap
+    pub memory_types: Vec<(u32, vk::MemoryPropertyFlags)>,
+}
+
+/// The memory heap layout of a device, e.g. for diagnosing allocation failures.
+/// Formats as one line per heap with `Display`.
+#[derive(Clone, Debug)]
+pub struct MemoryHeapsInfo {
+    pub heaps: Vec<MemoryHeapInfo>,
+}
+
+impl MemoryHeapsInfo {
+    pub fn new(memory_properties: &vk::PhysicalDeviceMemoryProperties) -> Self {
+        let memory_types =
+            &memory_properties.memory_types[..memory_properties.memory_type_count as usize];
+
+        let heaps = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .enumerate()
+            .map(|(heap_idx, heap)| MemoryHeapInfo {
+                index: heap_idx as u32,
+                size: heap.size,
+                flags: heap.flags,
+                memory_types: memory_types
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, ty)| ty.heap_index == heap_idx as u32)
+                    .map(|(type_idx, ty)| (type_idx as u32, ty.property_flags))
+                    .collect(),
+            })
+            .collect();
+
+        Self { heaps }
+    }
+}
+
+impl std::fmt::Display for MemoryHeapsInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for heap in &self.heaps {
+            write!(
+                f,
+                "heap {}: {} MB, {:?}; types",
+                heap.index,
+                heap.size >> 20,
+                heap.flags
+            )?;
+
+            for (type_idx, property_flags) in &heap.memory_types {
+                write!(f, " [{}] {:?}", type_idx, property_flags)?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Driver versions are packed differently by each vendor.
+fn decode_driver_version(vendor_id: u32, driver_version: u32) -> String {
+    const NVIDIA: u32 = 0x10de;
+    const INTEL: u32 = 0x8086;
+
+    match vendor_id {
+        NVIDIA => format!(
+            "{}.{}.{}.{}",
+            driver_version >> 22,
+            (driver_version >> 14) & 0xff,
+            (driver_version >> 6) & 0xff,
+            driver_version & 0x3f
+        ),
+        INTEL if cfg!(windows) => format!("{}.{}", driver_version >> 14, driver_version & 0x3fff),
+        _ => format!(
+            "{}.{}.{}",
+            vk::api_version_major(driver_version),
+            vk::api_version_minor(driver_version),
+            vk::api_version_patch(driver_version)
+        ),
+    }
+}
+
+impl PhysicalDevice {
+    /// The highest Vulkan version the device supports.
+    pub fn api_version(&self) -> ApiVersion {
+        ApiVersion::from_raw(self.properties.api_version)
+    }
+
+    /// Whether this is a Vulkan portability implementation such as MoltenVK,
+    /// which only supports the subset of Vulkan in `features.portability_subset`.
+    pub fn is_portability_subset(&self) -> bool {
+        self.features.portability_subset.is_some()
+    }
+
+    /// Decoded according to the vendor's convention.
+    pub fn driver_version(&self) -> String {
+        decode_driver_version(self.properties.vendor_id, self.properties.driver_version)
+    }
+
+    pub fn memory_heaps(&self) -> MemoryHeapsInfo {
+        MemoryHeapsInfo::new(&self.memory_properties)
+    }
+
+    /// Heap sizes and flags, and the memory types of each heap.
+    pub fn memory_report(&self) -> String {
+        self.memory_heaps().to_string()
+    }
+
+    /// Everything queried about the device, for printing with `{:#?}`.
+    pub fn details(&self) -> PhysicalDeviceDetails {
+        let mut extensions: Vec<&str> = self.extensions.iter().map(String::as_str).collect();
+        extensions.sort_unstable();
+
+        PhysicalDeviceDetails {
+            properties: &self.properties,
+            memory_properties: &self.memory_properties,
+            queue_families: self
+                .queue_families
+                .iter()
+                .map(|family| family.properties)
+                .collect(),
+            features: &self.features,
+            ray_tracing_pipeline_properties: &self.ray_tracing_pipeline_properties,
+            extensions,
+        }
+    }
+
+    fn notable_capabilities(&self) -> Vec<&'static str> {
+        let descriptor_indexing = &self.features.descriptor_indexing;
+
+        [
+            (self.ray_tracing_pipeline_supported(), "ray tracing"),
+            (self.ray_query_supported(), "ray query"),
+            (
+                self.supports_extension("VK_EXT_mesh_shader")
+                    || self.supports_extension("VK_NV_mesh_shader"),
+                "mesh shaders",
+            ),
+            (
+                descriptor_indexing.runtime_descriptor_array != 0
+                    && descriptor_indexing.descriptor_binding_partially_bound != 0,
+                "descriptor indexing",
+            ),
+            (
+                self.conditional_rendering_supported(),
+                "conditional rendering",
+            ),
+            (self.is_portability_subset(), "portability subset"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then(|| name))
+        .collect()
+    }
+
+    pub fn name(&self) -> Cow<str> {
+        unsafe { CStr::from_ptr(self.properties.device_name.as_ptr()) }.to_string_lossy()
+    }
+
+    /// Whether the device supports the extension, e.g. `VK_KHR_ray_query`.
+    /// Extensions promoted to core might only be reported by older drivers.
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.contains(name)
+    }
+
+    pub(crate) fn supports_extension_cstr(&self, name: &CStr) -> bool {
+        self.supports_extension(name.to_string_lossy().as_ref())
+    }
+
+    fn supports_extensions(&self, extensions: &[&CStr]) -> usize {
+        extensions
+            .iter()
+            .filter(|name| self.supports_extension_cstr(name))
+            .count()
+    }
+
+    /// `VK_KHR_acceleration_structure` and `VK_KHR_ray_tracing_pipeline` along with their features.
+    pub fn ray_tracing_pipeline_supported(&self) -> bool {
+        self.supports_extension_cstr(vk::KhrAccelerationStructureFn::name())
+            && self.supports_extension_cstr(vk::KhrRayTracingPipelineFn::name())
+            && self.features.acceleration_structure.acceleration_structure != 0
+            && self.features.ray_tracing_pipeline.ray_tracing_pipeline != 0
+    }
+
+    /// What's missing for the ray tracing `DeviceBuilder` enables, if anything.
+    /// Stricter than `ray_tracing_pipeline_supported`, as it includes the dependencies.
+    pub fn missing_ray_tracing_support(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+
+        for ext in [
+            vk::KhrAccelerationStructureFn::name(),
+            vk::KhrRayTracingPipelineFn::name(),
+            vk::KhrDeferredHostOperationsFn::name(),
+            vk::KhrPipelineLibraryFn::name(),
+        ] {
+            if !self.supports_extension_cstr(ext) {
+                missing.push(ext.to_str().unwrap());
+            }
+        }
+
+        let features = &self.features;
+        let required_features = [
+            (
+                features.acceleration_structure.acceleration_structure,
+                "accelerationStructure",
+            ),
+            (
+                features.ray_tracing_pipeline.ray_tracing_pipeline,
+                "rayTracingPipeline",
+            ),
+            (
+                features
+                    .ray_tracing_pipeline
+                    .ray_tracing_pipeline_trace_rays_indirect,
+                "rayTracingPipelineTraceRaysIndirect",
+            ),
+            (
+                features
+                    .descriptor_indexing
+                    .shader_uniform_buffer_array_non_uniform_indexing,
+                "shaderUniformBufferArrayNonUniformIndexing",
+            ),
+            (
+                features
+                    .descriptor_indexing
+                    .shader_storage_buffer_array_non_uniform_indexing,
+                "shaderStorageBufferArrayNonUniformIndexing",
+            ),
+            (
+                features.buffer_device_address.buffer_device_address,
+                "bufferDeviceAddress",
+            ),
+            (
+                features.vulkan_memory_model.vulkan_memory_model,
+                "vulkanMemoryModel",
+            ),
+        ];
+
+        for (supported, name) in required_features {
+            if supported == 0 {
+                missing.push(name);
+            }
+        }
+
+        missing
+    }
+
+    pub fn ray_query_supported(&self) -> bool {
+        self.supports_extension_cstr(vk::KhrRayQueryFn::name())
+            && self.features.ray_query.ray_query != 0
+    }
+
+    pub fn conditional_rendering_supported(&self) -> bool {
+        self.supports_extension_cstr(vk::ExtConditionalRenderingFn::name())
+            && self.features.conditional_rendering.conditional_rendering != 0
+    }
+
+    /// Total size of the device-local memory heaps
+    fn device_local_memory_bytes(&self) -> u64 {
+        let heaps = &self.memory_properties.memory_heaps
+            [..self.memory_properties.memory_heap_count as usize];
+
+        heaps
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum()
     }
 }
 
+/// Prefers discrete GPUs, then integrated ones. Within those, devices supporting more of
+/// `preferred_extensions` win, and then ones with more device-local memory.
+fn score_physical_device(device: &PhysicalDevice, preferred_extensions: &[&CStr]) -> u64 {
+    let type_score = match device.properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 3,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 2,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+        _ => 0,
+    };
+    let extension_score = device.supports_extensions(preferred_extensions) as u64;
+    let memory_mb = device.device_local_memory_bytes() >> 20;
+
+    (type_score << 48) | (extension_score << 32) | memory_mb.min(u32::MAX as u64)
+}
+
+/// Picks a device to create, typically from those with presentation support.
+///
+/// `device_index`, or failing that the `KAJIYA_GPU_INDEX` environment variable, selects
+/// a device explicitly by its index in `devices`. Otherwise the best one according to
+/// `score_physical_device` is used, with ties going to the one enumerated first.
+pub fn select_physical_device(
+    devices: Vec<PhysicalDevice>,
+    device_index: Option<usize>,
+    preferred_extensions: &[&CStr],
+) -> Result<PhysicalDevice> {
+    if devices.is_empty() {
+        anyhow::bail!("No suitable Vulkan physical devices found");
+    }
+
+    let device_index = match device_index {
+        Some(device_index) => Some(device_index),
+        None => match std::env::var("KAJIYA_GPU_INDEX") {
+            Ok(value) => Some(value.trim().parse::<usize>().map_err(|_| {
+                anyhow::anyhow!("KAJIYA_GPU_INDEX must be a device index, got {:?}", value)
+            })?),
+            Err(_) => None,
+        },
+    };
+
+    let scores: Vec<u64> = devices
+        .iter()
+        .map(|device| score_physical_device(device, preferred_extensions))
+        .collect();
+
+    let selected = if let Some(device_index) = device_index {
+        if device_index >= devices.len() {
+            anyhow::bail!(
+                "GPU index {} requested, but only {} suitable devices were found",
+                device_index,
+                devices.len()
+            );
+        }
+        device_index
+    } else {
+        // `max_by_key` returns the last of equal elements, so iterate in reverse
+        (0..devices.len())
+            .rev()
+            .max_by_key(|&idx| scores[idx])
+            .unwrap()
+    };
+
+    let rejected: Vec<String> = devices
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| *idx != selected)
+        .map(|(idx, device)| {
+            let reason = if device_index.is_some() {
+                "not the requested index"
+            } else {
+                "lower score"
+            };
+            format!("[{}] {} ({})", idx, device.name(), reason)
+        })
+        .collect();
+
+    info!(
+        "Selected GPU [{}] {} ({:?}, {} MB device-local){}{}",
+        selected,
+        devices[selected].name(),
+        devices[selected].properties.device_type,
+        devices[selected].device_local_memory_bytes() >> 20,
+        if rejected.is_empty() {
+            ""
+        } else {
+            "; rejected: "
+        },
+        rejected.join(", ")
+    );
+
+    Ok(devices.into_iter().nth(selected).unwrap())
+}
+
 pub fn enumerate_physical_devices(instance: &Arc<Instance>) -> Result<Vec<PhysicalDevice>> {
     unsafe {
         let pdevices = instance.raw.enumerate_physical_devices()?;
@@ -71,11 +530,45 @@ pub fn enumerate_physical_devices(instance: &Arc<Instance>) -> Result<Vec<Physic
                     .map(|(index, properties)| QueueFamily {
                         index: index as _,
                         properties,
+                        present_support: false,
                     })
                     .collect();
 
                 let memory_properties = instance.raw.get_physical_device_memory_properties(pdevice);
 
+                let extensions: HashSet<String> = instance
+                    .raw
+                    .enumerate_device_extension_properties(pdevice)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|ext| {
+                        CStr::from_ptr(ext.extension_name.as_ptr())
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                    .collect();
+
+                let supports = |name: &CStr| extensions.contains(name.to_string_lossy().as_ref());
+
+                let ray_tracing_pipeline_properties =
+                    if supports(vk::KhrRayTracingPipelineFn::name()) {
+                        Some(get_ray_tracing_pipeline_properties(instance, pdevice))
+                    } else {
+                        None
+                    };
+
+                let api_version = ApiVersion::from_raw(properties.api_version);
+
+                let descriptor_indexing_properties = if api_version >= ApiVersion::V1_2
+                    || supports(vk::ExtDescriptorIndexingFn::name())
+                {
+                    get_descriptor_indexing_properties(instance, pdevice)
+                } else {
+                    Default::default()
+                };
+
+                let features = get_features(instance, pdevice, api_version, &supports);
+
                 PhysicalDevice {
                     raw: pdevice,
                     queue_families,
@@ -83,47 +576,309 @@ pub fn enumerate_physical_devices(instance: &Arc<Instance>) -> Result<Vec<Physic
                     instance: instance.clone(),
                     properties,
                     memory_properties,
+                    ray_tracing_pipeline_properties,
+                    descriptor_indexing_properties,
+                    extensions,
+                    features,
                 }
             })
             .collect())
     }
 }
 
-pub trait PhysicalDeviceList {
+/// Only chains the structs of supported extensions, which are there to fill them in.
+unsafe fn get_features(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+    api_version: ApiVersion,
+    supports: &dyn Fn(&CStr) -> bool,
+) -> PhysicalDeviceFeatures {
+    let mut features = PhysicalDeviceFeatures::default();
+    let ray_tracing = supports(vk::KhrAccelerationStructureFn::name())
+        && supports(vk::KhrRayTracingPipelineFn::name());
+    let portability_subset = supports(vk::KhrPortabilitySubsetFn::name());
+    let mut portability_subset_features = vk::PhysicalDevicePortabilitySubsetFeaturesKHR::default();
+
+    {
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut features.scalar_block_layout)
+            .push_next(&mut features.descriptor_indexing)
+            .push_next(&mut features.imageless_framebuffer)
+            .push_next(&mut features.shader_float16_int8)
+            .push_next(&mut features.vulkan_memory_model)
+            .push_next(&mut features.buffer_device_address)
+            .push_next(&mut features.timeline_semaphore)
+            .push_next(&mut features.separate_depth_stencil_layouts)
+            .push_next(&mut features.shader_draw_parameters);
+
+        if ray_tracing {
+            features2 = features2
+                .push_next(&mut features.acceleration_structure)
+                .push_next(&mut features.ray_tracing_pipeline);
+        }
+
+        if supports(vk::KhrRayQueryFn::name()) {
+            features2 = features2.push_next(&mut features.ray_query);
+        }
+
+        if supports(vk::ExtConditionalRenderingFn::name()) {
+            features2 = features2.push_next(&mut features.conditional_rendering);
+        }
+
+        if portability_subset {
+            features2 = features2.push_next(&mut portability_subset_features);
+        }
+
+        let mut features2 = features2.build();
+        instance
+            .raw
+            .fp_v1_1()
+            .get_physical_device_features2(pdevice, &mut features2);
+
+        features.core = features2.features;
+    }
+
+    if portability_subset {
+        portability_subset_features.p_next = std::ptr::null_mut();
+        features.portability_subset = Some(portability_subset_features);
+    }
+
+    // Queried separately, as they overlap with the structs above
+    if api_version >= ApiVersion::V1_2 {
+        let mut vulkan11 = vk::PhysicalDeviceVulkan11Features::default();
+        let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut vulkan11)
+            .push_next(&mut vulkan12)
+            .build();
+
+        instance
+            .raw
+            .fp_v1_1()
+            .get_physical_device_features2(pdevice, &mut features2);
+
+        features.vulkan11 = Some(vulkan11);
+        features.vulkan12 = Some(vulkan12);
+    }
+
+    // Don't keep pointers into this stack frame around
+    features.scalar_block_layout.p_next = std::ptr::null_mut();
+    features.descriptor_indexing.p_next = std::ptr::null_mut();
+    features.imageless_framebuffer.p_next = std::ptr::null_mut();
+    features.shader_float16_int8.p_next = std::ptr::null_mut();
+    features.vulkan_memory_model.p_next = std::ptr::null_mut();
+    features.buffer_device_address.p_next = std::ptr::null_mut();
+    features.timeline_semaphore.p_next = std::ptr::null_mut();
+    features.separate_depth_stencil_layouts.p_next = std::ptr::null_mut();
+    features.shader_draw_parameters.p_next = std::ptr::null_mut();
+    features.acceleration_structure.p_next = std::ptr::null_mut();
+    features.ray_tracing_pipeline.p_next = std::ptr::null_mut();
+    features.ray_query.p_next = std::ptr::null_mut();
+    features.conditional_rendering.p_next = std::ptr::null_mut();
+    if let Some(vulkan11) = &mut features.vulkan11 {
+        vulkan11.p_next = std::ptr::null_mut();
+    }
+    if let Some(vulkan12) = &mut features.vulkan12 {
+        vulkan12.p_next = std::ptr::null_mut();
+    }
+
+    features
+}
+
+unsafe fn get_ray_tracing_pipeline_properties(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+) -> vk::PhysicalDeviceRayTracingPipelinePropertiesKHR {
+    let mut ray_tracing_pipeline_properties =
+        vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+    let mut properties2 = vk::PhysicalDeviceProperties2::builder()
+        .push_next(&mut ray_tracing_pipeline_properties)
+        .build();
+
+    instance
+        .raw
+        .fp_v1_1()
+        .get_physical_device_properties2(pdevice, &mut properties2);
+
+    // Don't keep a pointer into this stack frame around
+    ray_tracing_pipeline_properties.p_next = std::ptr::null_mut();
+
+    ray_tracing_pipeline_properties
+}
+
+unsafe fn get_descriptor_indexing_properties(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+) -> vk::PhysicalDeviceDescriptorIndexingProperties {
+    let mut descriptor_indexing_properties =
+        vk::PhysicalDeviceDescriptorIndexingProperties::default();
+    let mut properties2 = vk::PhysicalDeviceProperties2::builder()
+        .push_next(&mut descriptor_indexing_properties)
+        .build();
+
+    instance
+        .raw
+        .fp_v1_1()
+        .get_physical_device_properties2(pdevice, &mut properties2);
+
+    // Don't keep a pointer into this stack frame around
+    descriptor_indexing_properties.p_next = std::ptr::null_mut();
+
+    descriptor_indexing_properties
+}
+
+pub trait PhysicalDeviceList: Sized {
     fn with_presentation_support(self, surface: &Surface) -> Self;
+
+    /// Keeps devices which support hardware ray tracing, for builds which require it.
+    /// Fails if there are none, listing what the candidates were missing.
+    fn with_ray_tracing_support(self) -> Result<Self>;
 }
 
 impl PhysicalDeviceList for Vec<PhysicalDevice> {
+    /// Keeps devices which can both render and present, and records which
+    /// of their queue families can present. Those don't need to be the same families.
     fn with_presentation_support(self, surface: &Surface) -> Self {
         self.into_iter()
             .filter_map(|mut pdevice| {
                 pdevice.presentation_requested = true;
 
-                let supports_presentation =
-                    pdevice
-                        .queue_families
-                        .iter()
-                        .enumerate()
-                        .any(|(queue_index, info)| unsafe {
-                            info.properties
-                                .queue_flags
-                                .contains(vk::QueueFlags::GRAPHICS)
-                                && surface
-                                    .fns
-                                    .get_physical_device_surface_support(
-                                        pdevice.raw,
-                                        queue_index as u32,
-                                        surface.raw,
-                                    )
-                                    .unwrap()
-                        });
+                for family in &mut pdevice.queue_families {
+                    family.present_support = unsafe {
+                        surface
+                            .fns
+                            .get_physical_device_surface_support(
+                                pdevice.raw,
+                                family.index,
+                                surface.raw,
+                            )
+                            .unwrap_or(false)
+                    };
+                }
+
+                let supports_presentation = pdevice
+                    .queue_families
+                    .iter()
+                    .any(|family| family.present_support)
+                    && pdevice.queue_families.iter().any(|family| {
+                        family
+                            .properties
+                            .queue_flags
+                            .contains(vk::QueueFlags::GRAPHICS)
+                    });
 
                 if supports_presentation {
                     Some(pdevice)
                 } else {
+                    info!(
+                        "Rejected GPU {}: it can't present to the surface",
+                        pdevice.name()
+                    );
                     None
                 }
             })
             .collect()
     }
+
+    fn with_ray_tracing_support(self) -> Result<Self> {
+        let mut rejected: Vec<String> = Vec::new();
+
+        let devices: Vec<PhysicalDevice> = self
+            .into_iter()
+            .filter(|pdevice| {
+                let missing = pdevice.missing_ray_tracing_support();
+                if missing.is_empty() {
+                    true
+                } else {
+                    rejected.push(format!(
+                        "{} (missing {})",
+                        pdevice.name(),
+                        missing.join(", ")
+                    ));
+                    false
+                }
+            })
+            .collect();
+
+        if devices.is_empty() {
+            anyhow::bail!(
+                "No ray-tracing-capable GPU found; candidates were: {}",
+                if rejected.is_empty() {
+                    "none".to_owned()
+                } else {
+                    rejected.join("; ")
+                }
+            );
+        }
+
+        for rejected in &rejected {
+            info!("Rejected GPU {}", rejected);
+        }
+
+        Ok(devices)
+    }
+}
+
+#[test]
+fn test_decode_driver_version() {
+    assert_eq!(decode_driver_version(0x10de, 0x84d2_4000), "531.73.0.0");
+    assert_eq!(
+        decode_driver_version(0x1002, vk::make_api_version(0, 2, 0, 262)),
+        "2.0.262"
+    );
+
+    if cfg!(windows) {
+        assert_eq!(
+            decode_driver_version(0x8086, (101 << 14) | 4255),
+            "101.4255"
+        );
+    }
+}
+
+#[test]
+fn test_memory_heaps_info() {
+    let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+        memory_type_count: 3,
+        memory_heap_count: 2,
+        ..Default::default()
+    };
+    memory_properties.memory_heaps[0] = vk::MemoryHeap {
+        size: 8 << 30,
+        flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+    };
+    memory_properties.memory_heaps[1] = vk::MemoryHeap {
+        size: 16 << 30,
+        flags: vk::MemoryHeapFlags::empty(),
+    };
+    memory_properties.memory_types[0] = vk::MemoryType {
+        property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        heap_index: 0,
+    };
+    memory_properties.memory_types[1] = vk::MemoryType {
+        property_flags: vk::MemoryPropertyFlags::HOST_VISIBLE
+            | vk::MemoryPropertyFlags::HOST_COHERENT,
+        heap_index: 1,
+    };
+    memory_properties.memory_types[2] = vk::MemoryType {
+        property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL
+            | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        heap_index: 0,
+    };
+
+    let info = MemoryHeapsInfo::new(&memory_properties);
+
+    assert_eq!(info.heaps.len(), 2);
+    assert_eq!(info.heaps[0].size, 8 << 30);
+    assert_eq!(
+        info.heaps[0]
+            .memory_types
+            .iter()
+            .map(|(idx, _)| *idx)
+            .collect::<Vec<_>>(),
+        [0, 2]
+    );
+    assert_eq!(info.heaps[1].memory_types.len(), 1);
+    assert!(info
+        .to_string()
+        .starts_with("heap 0: 8192 MB, DEVICE_LOCAL; types [0]"));
 }
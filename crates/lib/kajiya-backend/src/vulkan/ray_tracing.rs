@@ -5,10 +5,11 @@ use crate::{dynamic_constants::DynamicConstants, BackendError, MAX_DESCRIPTOR_SE
 use super::{
     device::Device,
     shader::{
-        merge_shader_stage_layouts, DescriptorSetLayoutOpts, PipelineShader, ShaderPipelineCommon,
-        ShaderPipelineStage,
+        merge_descriptor_set_layout_flags, merge_shader_stage_layouts, DescriptorBindingInfo,
+        DescriptorSetLayoutOpts, PipelineShader, ShaderPipelineCommon, ShaderPipelineStage,
     },
 };
+use anyhow::Context as _;
 use ash::vk;
 use byte_slice_cast::AsSliceOf;
 use bytes::Bytes;
@@ -687,6 +688,8 @@ impl std::ops::Deref for RayTracingPipeline {
 pub struct RayTracingPipelineDesc {
     pub descriptor_set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
     pub max_pipeline_ray_recursion_depth: u32,
+    /// Resolve unrecognized sampler binding names to a linear-clamp sampler instead of failing
+    pub allow_unknown_samplers: bool,
 }
 
 impl Default for RayTracingPipelineDesc {
@@ -694,6 +697,7 @@ impl Default for RayTracingPipelineDesc {
         Self {
             max_pipeline_ray_recursion_depth: 1,
             descriptor_set_opts: Default::default(),
+            allow_unknown_samplers: false,
         }
     }
 }
@@ -706,6 +710,11 @@ impl RayTracingPipelineDesc {
         self.max_pipeline_ray_recursion_depth = max_pipeline_ray_recursion_depth;
         self
     }
+
+    pub fn allow_unknown_samplers(mut self, allow_unknown_samplers: bool) -> Self {
+        self.allow_unknown_samplers = allow_unknown_samplers;
+        self
+    }
 }
 
 pub fn create_ray_tracing_pipeline(
@@ -725,24 +734,25 @@ pub fn create_ray_tracing_pipeline(
 
     //log::info!("{:#?}", stage_layouts);
 
+    let descriptor_set_opts = merge_descriptor_set_layout_flags(
+        shaders.iter().map(|shader| &shader.desc),
+        &desc.descriptor_set_opts,
+    )?;
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
         &merge_shader_stage_layouts(stage_layouts),
         vk::ShaderStageFlags::ALL,
-        //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
-        &desc.descriptor_set_opts,
-    );
-
-    unsafe {
-        let layout_create_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&descriptor_set_layouts)
-            .build();
+        &descriptor_set_opts,
+        desc.allow_unknown_samplers,
+    )?;
 
-        let pipeline_layout = device
-            .raw
-            .create_pipeline_layout(&layout_create_info, None)
-            .unwrap();
+    let pipeline_layout = device
+        .layout_cache
+        .get_or_create_pipeline_layout(&device.raw, &descriptor_set_layouts, &[])
+        .context("Creating the ray tracing pipeline layout")?;
 
+    unsafe {
         let mut shader_groups: Vec<vk::RayTracingShaderGroupCreateInfoKHR> = Vec::new();
         let mut shader_stages: Vec<vk::PipelineShaderStageCreateInfo> = Vec::new();
 
@@ -753,15 +763,14 @@ pub fn create_ray_tracing_pipeline(
         let mut miss_entry_count = 0;
         let mut hit_entry_count = 0;
 
-        let create_shader_module =
+        let mut shader_modules = Vec::with_capacity(shaders.len());
+        let mut create_shader_module =
             |desc: &PipelineShader<Bytes>| -> (ash::vk::ShaderModule, String) {
-                let shader_info = vk::ShaderModuleCreateInfo::builder()
-                    .code(desc.code.as_slice_of::<u32>().unwrap());
-
-                let shader_module = device
-                    .raw
-                    .create_shader_module(&shader_info, None)
+                let (shader_module_key, shader_module) = device
+                    .shader_module_cache
+                    .get_or_create(&device.raw, desc.code.as_slice_of::<u32>().unwrap())
                     .expect("Shader module error");
+                shader_modules.push(shader_module_key);
 
                 (shader_module, desc.desc.entry.clone())
             };
@@ -869,7 +878,7 @@ pub fn create_ray_tracing_pipeline(
             .ray_tracing_pipeline_ext
             .create_ray_tracing_pipelines(
                 vk::DeferredOperationKHR::null(),
-                vk::PipelineCache::null(),
+                device.pipeline_cache,
                 &[ash::vk::RayTracingPipelineCreateInfoKHR::builder()
                     .stages(&shader_stages)
                     .groups(&shader_groups)
@@ -882,7 +891,7 @@ pub fn create_ray_tracing_pipeline(
 
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
-            for ty in bindings.values() {
+            for DescriptorBindingInfo { ty, .. } in bindings.values() {
                 if let Some(mut dps) = descriptor_pool_sizes.iter_mut().find(|item| item.ty == *ty)
                 {
                     dps.descriptor_count += 1;
@@ -915,6 +924,7 @@ pub fn create_ray_tracing_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::RAY_TRACING_KHR,
+                shader_modules,
             },
             sbt,
         })
@@ -932,6 +942,19 @@ pub struct RayTracingShaderTable {
     pub callable_shader_binding_table: vk::StridedDeviceAddressRegionKHR,
 }
 
+impl RayTracingShaderTable {
+    pub(crate) fn into_buffers(self) -> impl Iterator<Item = super::buffer::Buffer> {
+        [
+            self.raygen_shader_binding_table_buffer,
+            self.miss_shader_binding_table_buffer,
+            self.hit_shader_binding_table_buffer,
+            self.callable_shader_binding_table_buffer,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
 struct GeometryInstance {
@@ -1,10 +1,20 @@
 #![allow(dead_code)]
 
 use super::{
-    device::{Device, SamplerDesc},
+    barrier::image_aspect_mask_from_format,
+    bindless,
+    device::{Device, SamplerDesc, RESERVED_DESCRIPTOR_COUNT},
     image::ImageDesc,
+    shader_module_cache::ShaderModuleKey,
 };
-use crate::{chunky_list::TempList, shader_compiler::get_cs_local_size_from_spirv};
+use crate::{
+    chunky_list::TempList,
+    shader_compiler::{
+        execution_model, get_cs_local_size_from_spirv, get_push_constants_size_from_spirv,
+        validate_entry_point,
+    },
+};
+use anyhow::Context as _;
 use arrayvec::ArrayVec;
 use ash::vk;
 use byte_slice_cast::AsSliceOf as _;
@@ -12,7 +22,8 @@ use bytes::Bytes;
 use derive_builder::Builder;
 use parking_lot::Mutex;
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    convert::TryInto,
     ffi::CString,
     path::PathBuf,
     sync::Arc,
@@ -23,13 +34,23 @@ pub const MAX_DESCRIPTOR_SETS: usize = 4;
 type DescriptorSetLayout = HashMap<u32, rspirv_reflect::DescriptorInfo>;
 type StageDescriptorSetLayouts = HashMap<u32, DescriptorSetLayout>;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DescriptorBindingInfo {
+    pub ty: vk::DescriptorType,
+    /// Number of array elements in the layout; 1 for non-array bindings
+    pub count: u32,
+    pub flags: vk::DescriptorBindingFlags,
+}
+
 pub struct ShaderPipelineCommon {
     pub pipeline_layout: vk::PipelineLayout,
     pub pipeline: vk::Pipeline,
-    pub set_layout_info: Vec<HashMap<u32, vk::DescriptorType>>,
+    pub set_layout_info: Vec<HashMap<u32, DescriptorBindingInfo>>,
     pub descriptor_pool_sizes: Vec<vk::DescriptorPoolSize>,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pub pipeline_bind_point: vk::PipelineBindPoint,
+    /// Released from `Device::shader_module_cache` when the pipeline is replaced
+    pub shader_modules: Vec<ShaderModuleKey>,
 }
 pub struct ComputePipeline {
     pub common: ShaderPipelineCommon,
@@ -56,15 +77,17 @@ impl std::ops::Deref for RasterPipeline {
     }
 }
 
+/// Set layouts are shared through the device's `LayoutCache`, which owns them.
 pub fn create_descriptor_set_layouts(
     device: &Device,
     descriptor_sets: &StageDescriptorSetLayouts,
     stage_flags: vk::ShaderStageFlags,
     set_opts: &[Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
-) -> (
+    allow_unknown_samplers: bool,
+) -> anyhow::Result<(
     Vec<vk::DescriptorSetLayout>,
-    Vec<HashMap<u32, vk::DescriptorType>>,
-) {
+    Vec<HashMap<u32, DescriptorBindingInfo>>,
+)> {
     // dbg!(&descriptor_sets);
 
     // Make a vector of Option<ref> to the original entries
@@ -73,7 +96,7 @@ pub fn create_descriptor_set_layouts(
         .map(|item| item.as_ref())
         .collect::<Vec<_>>();
 
-    let samplers = TempList::new();
+    let immutable_sampler_arrays = TempList::new();
 
     // Find the number of sets in `descriptor_sets`
     let set_count = descriptor_sets
@@ -93,19 +116,10 @@ pub fn create_descriptor_set_layouts(
     );
 
     let mut set_layouts: Vec<vk::DescriptorSetLayout> = Vec::with_capacity(set_count as usize);
-    let mut set_layout_info: Vec<HashMap<u32, vk::DescriptorType>> =
+    let mut set_layout_info: Vec<HashMap<u32, DescriptorBindingInfo>> =
         Vec::with_capacity(set_count as usize);
 
     for set_index in 0..set_count {
-        let stage_flags = if 0 == set_index {
-            stage_flags
-        } else {
-            // Set 0 is for draw params,
-            // Further sets are for pass/frame bindings, and use all stage flags
-            // TODO: pass those as a parameter here?
-            vk::ShaderStageFlags::ALL
-        };
-
         let _set_opts_default = Default::default();
         // Find the descriptor set opts corresponding to the set index, and remove them from the opts list
         let set_opts = {
@@ -121,11 +135,28 @@ pub fn create_descriptor_set_layouts(
             resolved_set_opts
         };
 
-        // Use the specified override, or the layout parsed from the shader if no override was provided
-        let set = set_opts
-            .replace
-            .as_ref()
-            .or_else(|| descriptor_sets.get(&set_index));
+        let stage_flags = set_opts.stage_flags.unwrap_or_else(|| {
+            if 0 == set_index {
+                stage_flags
+            } else {
+                // Set 0 is for draw params,
+                // Further sets are for pass/frame bindings, and use all stage flags the device supports
+                default_descriptor_set_stage_flags(device.ray_tracing_enabled())
+            }
+        });
+
+        // Use the specified override, or the layout parsed from the shader if no override was provided.
+        // Sets which only declare bindings of the bindless set get its full layout,
+        // so that `Device::bindless_descriptor_set` can be bound to them.
+        let set = set_opts.replace.as_ref().or_else(|| {
+            descriptor_sets.get(&set_index).map(|set| {
+                if bindless::is_bindless_descriptor_set_layout(set) {
+                    &*bindless::BINDLESS_DESCRIPTOR_SET_LAYOUT
+                } else {
+                    set
+                }
+            })
+        });
 
         if let Some(set) = set {
             let mut bindings: Vec<vk::DescriptorSetLayoutBinding> = Vec::with_capacity(set.len());
@@ -134,42 +165,104 @@ pub fn create_descriptor_set_layouts(
 
             let mut set_layout_create_flags = vk::DescriptorSetLayoutCreateFlags::empty();
 
+            // Only the highest binding in a set may have a variable descriptor count
+            let last_binding_index = set.keys().copied().max().unwrap_or_default();
+            let bindless_binding_flags = |binding_index: u32| {
+                let flags = vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                    | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
+                    | vk::DescriptorBindingFlags::PARTIALLY_BOUND;
+
+                if binding_index == last_binding_index {
+                    flags | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
+                } else {
+                    flags
+                }
+            };
+
+            // Unsized arrays get the count from the set opts, or as many as the device allows
+            let descriptor_count = |binding: &rspirv_reflect::DescriptorInfo,
+                                    ty: vk::DescriptorType| {
+                reflected_descriptor_count(binding, || match set_opts.runtime_array_count {
+                    Some(count) => Ok(count),
+                    None => max_runtime_array_count(device, ty),
+                })
+                .with_context(|| {
+                    format!(
+                        "Sizing binding {:?} of descriptor set {}",
+                        binding.name, set_index
+                    )
+                })
+            };
+
             for (binding_index, binding) in set.iter() {
                 match binding.ty {
+                    rspirv_reflect::DescriptorType::STORAGE_BUFFER
+                        if matches!(
+                            binding.dimensionality,
+                            rspirv_reflect::DescriptorDimensionality::RuntimeArray
+                        ) =>
+                    {
+                        // Bindless
+                        binding_flags[bindings.len()] = bindless_binding_flags(*binding_index);
+
+                        set_layout_create_flags |=
+                            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
+
+                        bindings.push(
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(*binding_index)
+                                .descriptor_count(descriptor_count(
+                                    binding,
+                                    vk::DescriptorType::STORAGE_BUFFER,
+                                )?)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .stage_flags(stage_flags)
+                                .build(),
+                        );
+                    }
                     rspirv_reflect::DescriptorType::UNIFORM_BUFFER
                     | rspirv_reflect::DescriptorType::UNIFORM_TEXEL_BUFFER
                     | rspirv_reflect::DescriptorType::STORAGE_IMAGE
                     | rspirv_reflect::DescriptorType::STORAGE_BUFFER
-                    | rspirv_reflect::DescriptorType::STORAGE_BUFFER_DYNAMIC => bindings.push(
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(*binding_index)
-                            //.descriptor_count(binding.count)
-                            .descriptor_count(1) // TODO
-                            .descriptor_type(match binding.ty {
-                                rspirv_reflect::DescriptorType::UNIFORM_BUFFER => {
-                                    vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
-                                }
-                                rspirv_reflect::DescriptorType::UNIFORM_TEXEL_BUFFER => {
-                                    vk::DescriptorType::UNIFORM_TEXEL_BUFFER
-                                }
-                                rspirv_reflect::DescriptorType::STORAGE_IMAGE => {
-                                    vk::DescriptorType::STORAGE_IMAGE
-                                }
-                                rspirv_reflect::DescriptorType::STORAGE_BUFFER => {
-                                    if binding.name.ends_with("_dyn") {
-                                        vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
-                                    } else {
-                                        vk::DescriptorType::STORAGE_BUFFER
-                                    }
-                                }
-                                rspirv_reflect::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+                    | rspirv_reflect::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+                        let descriptor_type = match binding.ty {
+                            rspirv_reflect::DescriptorType::UNIFORM_BUFFER => {
+                                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                            }
+                            rspirv_reflect::DescriptorType::UNIFORM_TEXEL_BUFFER => {
+                                vk::DescriptorType::UNIFORM_TEXEL_BUFFER
+                            }
+                            rspirv_reflect::DescriptorType::STORAGE_IMAGE => {
+                                vk::DescriptorType::STORAGE_IMAGE
+                            }
+                            rspirv_reflect::DescriptorType::STORAGE_BUFFER => {
+                                if binding.name.ends_with("_dyn") {
                                     vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
+                                } else {
+                                    vk::DescriptorType::STORAGE_BUFFER
                                 }
-                                _ => unimplemented!("{:?}", binding),
-                            })
-                            .stage_flags(stage_flags)
-                            .build(),
-                    ),
+                            }
+                            rspirv_reflect::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+                                vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
+                            }
+                            ty => anyhow::bail!(
+                                "Binding {} ({:?}) of descriptor set {} has an unsupported descriptor type {:?}",
+                                binding_index,
+                                binding.name,
+                                set_index,
+                                ty
+                            ),
+                        };
+
+                        bindings.push(
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(*binding_index)
+                                .descriptor_count(descriptor_count(binding, descriptor_type)?)
+                                .descriptor_type(descriptor_type)
+                                .stage_flags(stage_flags)
+                                .build(),
+                        )
+                    }
                     rspirv_reflect::DescriptorType::SAMPLED_IMAGE => {
                         if matches!(
                             binding.dimensionality,
@@ -177,132 +270,396 @@ pub fn create_descriptor_set_layouts(
                         ) {
                             // Bindless
 
-                            binding_flags[bindings.len()] =
-                                vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
-                                    | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
-                                    | vk::DescriptorBindingFlags::PARTIALLY_BOUND
-                                    | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT;
+                            binding_flags[bindings.len()] = bindless_binding_flags(*binding_index);
 
                             set_layout_create_flags |=
                                 vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
                         }
 
-                        let descriptor_count = match binding.dimensionality {
-                            rspirv_reflect::DescriptorDimensionality::Single => 1,
-                            rspirv_reflect::DescriptorDimensionality::Array(size) => size,
-                            rspirv_reflect::DescriptorDimensionality::RuntimeArray => {
-                                device.max_bindless_descriptor_count()
-                            }
-                        };
-
                         bindings.push(
                             vk::DescriptorSetLayoutBinding::builder()
                                 .binding(*binding_index)
-                                .descriptor_count(descriptor_count) // TODO
+                                .descriptor_count(descriptor_count(
+                                    binding,
+                                    vk::DescriptorType::SAMPLED_IMAGE,
+                                )?)
                                 .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
                                 .stage_flags(stage_flags)
                                 .build(),
                         );
                     }
                     rspirv_reflect::DescriptorType::SAMPLER => {
-                        let name_prefix = "sampler_";
-                        if let Some(mut spec) = binding.name.strip_prefix(name_prefix) {
-                            let texel_filter = match &spec[..1] {
-                                "n" => vk::Filter::NEAREST,
-                                "l" => vk::Filter::LINEAR,
-                                _ => panic!("{}", &spec[..1]),
-                            };
-                            spec = &spec[1..];
-
-                            let mipmap_mode = match &spec[..1] {
-                                "n" => vk::SamplerMipmapMode::NEAREST,
-                                "l" => vk::SamplerMipmapMode::LINEAR,
-                                _ => panic!("{}", &spec[..1]),
-                            };
-                            spec = &spec[1..];
-
-                            let address_modes = match spec {
-                                "r" => vk::SamplerAddressMode::REPEAT,
-                                "mr" => vk::SamplerAddressMode::MIRRORED_REPEAT,
-                                "c" => vk::SamplerAddressMode::CLAMP_TO_EDGE,
-                                "cb" => vk::SamplerAddressMode::CLAMP_TO_BORDER,
-                                _ => panic!("{}", spec),
-                            };
-
-                            bindings.push(
-                                vk::DescriptorSetLayoutBinding::builder()
-                                    .descriptor_count(1)
-                                    .descriptor_type(vk::DescriptorType::SAMPLER)
-                                    .stage_flags(stage_flags)
-                                    .binding(*binding_index)
-                                    .immutable_samplers(std::slice::from_ref(samplers.add(
-                                        device.get_sampler(SamplerDesc {
-                                            texel_filter,
-                                            mipmap_mode,
-                                            address_modes,
-                                        }),
-                                    )))
-                                    .build(),
+                        let sampler = device.get_sampler(resolve_sampler_desc(
+                            &binding.name,
+                            set_index,
+                            *binding_index,
+                            binding,
+                            allow_unknown_samplers,
+                        )?);
+                        let descriptor_count =
+                            descriptor_count(binding, vk::DescriptorType::SAMPLER)?;
+
+                        bindings.push(
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .descriptor_count(descriptor_count)
+                                .descriptor_type(vk::DescriptorType::SAMPLER)
+                                .stage_flags(stage_flags)
+                                .binding(*binding_index)
+                                .immutable_samplers(
+                                    immutable_sampler_arrays
+                                        .add(vec![sampler; descriptor_count as usize])
+                                        .as_slice(),
+                                )
+                                .build(),
+                        );
+                    }
+                    rspirv_reflect::DescriptorType::COMBINED_IMAGE_SAMPLER => {
+                        let descriptor_count =
+                            descriptor_count(binding, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)?;
+
+                        let mut layout_binding = vk::DescriptorSetLayoutBinding::builder()
+                            .binding(*binding_index)
+                            .descriptor_count(descriptor_count)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .stage_flags(stage_flags);
+
+                        // A `_sampler_*` suffix on the name, e.g. `input_tex_sampler_lnc`,
+                        // bakes in an immutable sampler. Otherwise it's provided when binding.
+                        if let Some(sampler_name) = immutable_sampler_suffix(&binding.name) {
+                            let sampler = device.get_sampler(resolve_sampler_desc(
+                                sampler_name,
+                                set_index,
+                                *binding_index,
+                                binding,
+                                allow_unknown_samplers,
+                            )?);
+                            layout_binding = layout_binding.immutable_samplers(
+                                immutable_sampler_arrays
+                                    .add(vec![sampler; descriptor_count as usize])
+                                    .as_slice(),
                             );
-                        } else {
-                            panic!("{}", binding.name);
                         }
+
+                        bindings.push(layout_binding.build());
                     }
-                    rspirv_reflect::DescriptorType::ACCELERATION_STRUCTURE_KHR => bindings.push(
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(*binding_index)
-                            .descriptor_count(1) // TODO
-                            .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                            .stage_flags(stage_flags)
-                            .build(),
+                    rspirv_reflect::DescriptorType::ACCELERATION_STRUCTURE_KHR => {
+                        // The descriptor type is only valid with the extension enabled
+                        if !device.ray_tracing_enabled() {
+                            let missing = device.pdevice.missing_ray_tracing_support();
+                            anyhow::bail!(
+                                "Binding {} ({:?}) of descriptor set {} is an acceleration structure, but ray tracing is not {}",
+                                binding_index,
+                                binding.name,
+                                set_index,
+                                if missing.is_empty() {
+                                    "enabled on the device".to_owned()
+                                } else {
+                                    format!("supported (missing {})", missing.join(", "))
+                                }
+                            );
+                        }
+
+                        bindings.push(
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(*binding_index)
+                                .descriptor_count(descriptor_count(
+                                    binding,
+                                    vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                                )?)
+                                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                                .stage_flags(stage_flags)
+                                .build(),
+                        )
+                    }
+
+                    _ => anyhow::bail!(
+                        "Binding {} ({:?}) of descriptor set {} has an unsupported descriptor type {:?}",
+                        binding_index,
+                        binding.name,
+                        set_index,
+                        binding.ty
                     ),
+                }
 
-                    _ => unimplemented!("{:?}", binding),
+                // Unsized arrays are partially bound, and the last one in the set can be
+                // allocated with a smaller count. Not allowed for dynamic buffers.
+                if matches!(
+                    binding.dimensionality,
+                    rspirv_reflect::DescriptorDimensionality::RuntimeArray
+                ) && *binding_index == last_binding_index
+                    && !matches!(
+                        bindings.last().map(|b| b.descriptor_type),
+                        Some(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                            | Some(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+                    )
+                {
+                    binding_flags[bindings.len() - 1] |=
+                        vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT;
                 }
-            }
 
-            let mut binding_flags_create_info =
-                vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
-                    .binding_flags(&binding_flags);
+                // Explicitly specified flags win over the ones derived from reflection
+                if let Some(flags) = set_opts
+                    .binding_flags
+                    .as_ref()
+                    .and_then(|flags| flags.get(*binding_index as usize))
+                    .filter(|flags| !flags.is_empty())
+                {
+                    binding_flags[bindings.len() - 1] = *flags;
 
-            let set_layout = unsafe {
-                device
-                    .raw
-                    .create_descriptor_set_layout(
-                        &vk::DescriptorSetLayoutCreateInfo::builder()
-                            .flags(set_opts.flags.unwrap_or_default() | set_layout_create_flags)
-                            .bindings(&bindings)
-                            .push_next(&mut binding_flags_create_info)
-                            .build(),
-                        None,
-                    )
-                    .unwrap()
-            };
+                    if flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND) {
+                        set_layout_create_flags |=
+                            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
+                    }
+                }
+            }
+
+            let set_layout = device
+                .layout_cache
+                .get_or_create_descriptor_set_layout(
+                    &device.raw,
+                    set_opts.flags.unwrap_or_default() | set_layout_create_flags,
+                    &bindings,
+                    &binding_flags,
+                )
+                .with_context(|| format!("Creating the layout of descriptor set {}", set_index))?;
 
             set_layouts.push(set_layout);
             set_layout_info.push(
                 bindings
                     .iter()
-                    .map(|binding| (binding.binding, binding.descriptor_type))
+                    .zip(binding_flags.iter())
+                    .map(|(binding, flags)| {
+                        (
+                            binding.binding,
+                            DescriptorBindingInfo {
+                                ty: binding.descriptor_type,
+                                count: binding.descriptor_count,
+                                flags: *flags,
+                            },
+                        )
+                    })
                     .collect(),
             );
         } else {
-            let set_layout = unsafe {
-                device
-                    .raw
-                    .create_descriptor_set_layout(
-                        &vk::DescriptorSetLayoutCreateInfo::builder().build(),
-                        None,
-                    )
-                    .unwrap()
-            };
+            let set_layout = device
+                .layout_cache
+                .get_or_create_descriptor_set_layout(&device.raw, Default::default(), &[], &[])
+                .with_context(|| format!("Creating the layout of descriptor set {}", set_index))?;
 
             set_layouts.push(set_layout);
             set_layout_info.push(Default::default());
         }
     }
 
-    (set_layouts, set_layout_info)
+    Ok((set_layouts, set_layout_info))
+}
+
+/// Stage flags for descriptor sets shared between pipelines (all sets but the first).
+/// Ray tracing stages are only valid with the extension enabled.
+pub(crate) fn default_descriptor_set_stage_flags(
+    ray_tracing_enabled: bool,
+) -> vk::ShaderStageFlags {
+    let flags = vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::ALL_GRAPHICS;
+
+    if ray_tracing_enabled {
+        flags
+            | vk::ShaderStageFlags::RAYGEN_KHR
+            | vk::ShaderStageFlags::MISS_KHR
+            | vk::ShaderStageFlags::CLOSEST_HIT_KHR
+            | vk::ShaderStageFlags::ANY_HIT_KHR
+            | vk::ShaderStageFlags::INTERSECTION_KHR
+            | vk::ShaderStageFlags::CALLABLE_KHR
+    } else {
+        flags
+    }
+}
+
+/// Layout descriptor count of a reflected binding. `runtime_array_count` is only called for unsized arrays.
+fn reflected_descriptor_count(
+    binding: &rspirv_reflect::DescriptorInfo,
+    runtime_array_count: impl FnOnce() -> anyhow::Result<u32>,
+) -> anyhow::Result<u32> {
+    match binding.dimensionality {
+        rspirv_reflect::DescriptorDimensionality::Single => Ok(1),
+        rspirv_reflect::DescriptorDimensionality::Array(size) => Ok(size),
+        rspirv_reflect::DescriptorDimensionality::RuntimeArray => runtime_array_count(),
+    }
+}
+
+fn max_runtime_array_count(device: &Device, ty: vk::DescriptorType) -> anyhow::Result<u32> {
+    let limits = &device.physical_device().properties.limits;
+    let per_stage_limit = match ty {
+        vk::DescriptorType::SAMPLED_IMAGE
+        | vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        | vk::DescriptorType::UNIFORM_TEXEL_BUFFER => {
+            return Ok(device.max_bindless_descriptor_count())
+        }
+        vk::DescriptorType::STORAGE_BUFFER | vk::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+            return Ok(device.max_bindless_buffer_count())
+        }
+        vk::DescriptorType::STORAGE_IMAGE => limits.max_per_stage_descriptor_storage_images,
+        vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC => {
+            limits.max_per_stage_descriptor_uniform_buffers
+        }
+        vk::DescriptorType::SAMPLER => limits.max_per_stage_descriptor_samplers,
+        _ => anyhow::bail!("Unsized arrays of {:?} descriptors are not supported", ty),
+    };
+
+    Ok((512 * 1024).min(per_stage_limit.saturating_sub(RESERVED_DESCRIPTOR_COUNT)))
+}
+
+const SAMPLER_NAME_GRAMMAR: &str = "sampler_{filter: n|l}{mipmap mode: n|l}{address mode: r|mr|c|cb}[_a8|_a16][_cmp][_bw|_bb], e.g. `sampler_lnc_a8`";
+
+/// Used in place of unrecognized samplers when `allow_unknown_samplers` is set.
+const FALLBACK_SAMPLER_DESC: SamplerDesc = SamplerDesc {
+    texel_filter: vk::Filter::LINEAR,
+    mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+    address_modes: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+    max_anisotropy: 16,
+    compare_op: None,
+    border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+};
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SamplerParseError {
+    #[error("sampler names must start with `sampler_`")]
+    MissingPrefix,
+    #[error("invalid filter {0:?}; expected `n` or `l`")]
+    InvalidFilter(String),
+    #[error("invalid mipmap mode {0:?}; expected `n` or `l`")]
+    InvalidMipmapMode(String),
+    #[error("invalid address mode {0:?}; expected `r`, `mr`, `c` or `cb`")]
+    InvalidAddressMode(String),
+    #[error("unknown suffix `_{0}`; expected `_a8`, `_a16`, `_cmp`, `_bw` or `_bb`")]
+    UnknownSuffix(String),
+    #[error("suffix `_{0}` conflicts with an earlier one")]
+    ConflictingSuffix(String),
+}
+
+/// Parses sampler binding names, e.g. `sampler_lnc` or `sampler_llr_a16_cmp`.
+///
+/// The name is `sampler_` followed by `{filter}{mipmap mode}{address mode}`:
+/// * filter: `n` (nearest), `l` (linear)
+/// * mipmap mode: `n` (nearest), `l` (linear)
+/// * address mode: `r` (repeat), `mr` (mirrored repeat), `c` (clamp to edge), `cb` (clamp to border)
+///
+/// and optionally by any of the `_`-separated suffixes:
+/// * `a8`, `a16`: max anisotropy; linear filters otherwise default to 16, nearest to none
+/// * `cmp`: comparison sampler using `GREATER_OR_EQUAL`, matching the reverse-Z depth convention
+/// * `bw`, `bb`: opaque white or black border color instead of transparent black
+pub fn parse_sampler_name(name: &str) -> Result<SamplerDesc, SamplerParseError> {
+    let mut tokens = name
+        .strip_prefix("sampler_")
+        .ok_or(SamplerParseError::MissingPrefix)?
+        .split('_');
+
+    // `split` always yields at least one token
+    let base = tokens.next().unwrap_or_default();
+    let mut base_chars = base.char_indices();
+
+    let texel_filter = match base_chars.next() {
+        Some((_, 'n')) => vk::Filter::NEAREST,
+        Some((_, 'l')) => vk::Filter::LINEAR,
+        _ => return Err(SamplerParseError::InvalidFilter(base.to_owned())),
+    };
+
+    let mipmap_mode = match base_chars.next() {
+        Some((_, 'n')) => vk::SamplerMipmapMode::NEAREST,
+        Some((_, 'l')) => vk::SamplerMipmapMode::LINEAR,
+        _ => return Err(SamplerParseError::InvalidMipmapMode(base.to_owned())),
+    };
+
+    let address_mode = base_chars.next().map_or("", |(i, _)| &base[i..]);
+    let address_modes = match address_mode {
+        "r" => vk::SamplerAddressMode::REPEAT,
+        "mr" => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        "c" => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        "cb" => vk::SamplerAddressMode::CLAMP_TO_BORDER,
+        _ => {
+            return Err(SamplerParseError::InvalidAddressMode(
+                address_mode.to_owned(),
+            ))
+        }
+    };
+
+    let mut desc = SamplerDesc::new(texel_filter, mipmap_mode, address_modes);
+    let mut anisotropy_set = false;
+    let mut border_color_set = false;
+
+    for token in tokens {
+        let already_set = match token {
+            "a8" | "a16" => {
+                desc.max_anisotropy = if token == "a8" { 8 } else { 16 };
+                std::mem::replace(&mut anisotropy_set, true)
+            }
+            "cmp" => desc
+                .compare_op
+                .replace(vk::CompareOp::GREATER_OR_EQUAL)
+                .is_some(),
+            "bw" | "bb" => {
+                desc.border_color = if token == "bw" {
+                    vk::BorderColor::FLOAT_OPAQUE_WHITE
+                } else {
+                    vk::BorderColor::FLOAT_OPAQUE_BLACK
+                };
+                std::mem::replace(&mut border_color_set, true)
+            }
+            _ => return Err(SamplerParseError::UnknownSuffix(token.to_owned())),
+        };
+
+        if already_set {
+            return Err(SamplerParseError::ConflictingSuffix(token.to_owned()));
+        }
+    }
+
+    Ok(desc)
+}
+
+/// The `sampler_*` part of a combined image sampler name following the `<texture>_sampler_<spec>`
+/// convention. Only suffixes starting with the filter and mipmap mode count, so that names which
+/// merely contain the word, e.g. `shadow_sampler_count`, are left alone.
+fn immutable_sampler_suffix(name: &str) -> Option<&str> {
+    let start = name.rfind("_sampler_")? + 1;
+    let sampler_name = &name[start..];
+
+    let mut spec = sampler_name["sampler_".len()..].chars();
+    match (spec.next(), spec.next()) {
+        (Some('n' | 'l'), Some('n' | 'l')) => Some(sampler_name),
+        _ => None,
+    }
+}
+
+/// Resolves the immutable sampler for a binding from its sampler name.
+/// Unrecognized names are an error, or a linear-clamp sampler if `allow_unknown_samplers` is set.
+fn resolve_sampler_desc(
+    sampler_name: &str,
+    set_index: u32,
+    binding_index: u32,
+    binding: &rspirv_reflect::DescriptorInfo,
+    allow_unknown_samplers: bool,
+) -> anyhow::Result<SamplerDesc> {
+    match parse_sampler_name(sampler_name) {
+        Ok(desc) => Ok(desc),
+        Err(err) if allow_unknown_samplers => {
+            log::warn!(
+                "Invalid sampler {:?} (set {}, binding {}): {}; falling back to linear-clamp. Expected {}",
+                binding.name,
+                set_index,
+                binding_index,
+                err,
+                SAMPLER_NAME_GRAMMAR
+            );
+            Ok(FALLBACK_SAMPLER_DESC)
+        }
+        Err(err) => Err(anyhow::anyhow!(
+            "Invalid sampler {:?} (set {}, binding {}): {}. Expected {}",
+            binding.name,
+            set_index,
+            binding_index,
+            err,
+            SAMPLER_NAME_GRAMMAR
+        )),
+    }
 }
 
 #[derive(Builder, Default, Debug, Clone)]
@@ -312,12 +669,80 @@ pub struct DescriptorSetLayoutOpts {
     pub flags: Option<vk::DescriptorSetLayoutCreateFlags>,
     #[builder(setter(strip_option), default)]
     pub replace: Option<DescriptorSetLayout>,
+    /// Descriptor count for unsized arrays. Defaults to the most the device supports.
+    #[builder(setter(strip_option), default)]
+    pub runtime_array_count: Option<u32>,
+    /// Per-binding flags, indexed by binding number. Empty entries keep the reflected defaults.
+    #[builder(setter(strip_option), default)]
+    pub binding_flags: Option<Vec<vk::DescriptorBindingFlags>>,
+    /// Shader stages the set is visible to. Defaults to the pipeline's stages for set 0,
+    /// and all stages the device supports for the others.
+    #[builder(setter(strip_option), default)]
+    pub stage_flags: Option<vk::ShaderStageFlags>,
 }
 
 impl DescriptorSetLayoutOpts {
     pub fn builder() -> DescriptorSetLayoutOptsBuilder {
         DescriptorSetLayoutOptsBuilder::default()
     }
+
+    fn key(&self) -> DescriptorSetLayoutOptsKey {
+        DescriptorSetLayoutOptsKey {
+            flags: self.flags,
+            // Reflected bindings aren't hashable; their debug form identifies them well enough.
+            replace: self.replace.as_ref().map(|replace| {
+                let mut bindings: Vec<(u32, String)> = replace
+                    .iter()
+                    .map(|(binding, info)| (*binding, format!("{:?}", info)))
+                    .collect();
+                bindings.sort();
+                bindings
+            }),
+            runtime_array_count: self.runtime_array_count,
+            binding_flags: self.binding_flags.clone(),
+            stage_flags: self.stage_flags,
+        }
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+struct DescriptorSetLayoutOptsKey {
+    flags: Option<vk::DescriptorSetLayoutCreateFlags>,
+    replace: Option<Vec<(u32, String)>>,
+    runtime_array_count: Option<u32>,
+    binding_flags: Option<Vec<vk::DescriptorBindingFlags>>,
+    stage_flags: Option<vk::ShaderStageFlags>,
+}
+
+impl DescriptorSetLayoutOptsBuilder {
+    /// Sets the flags of a single binding, leaving others as reflected.
+    pub fn binding_flag(mut self, binding_index: u32, flags: vk::DescriptorBindingFlags) -> Self {
+        let binding_flags = self
+            .binding_flags
+            .get_or_insert(None)
+            .get_or_insert_with(Vec::new);
+
+        if binding_flags.len() <= binding_index as usize {
+            binding_flags.resize(
+                binding_index as usize + 1,
+                vk::DescriptorBindingFlags::empty(),
+            );
+        }
+
+        binding_flags[binding_index as usize] = flags;
+        self
+    }
+
+    /// Marks a binding as an unsized array whose length is chosen when the set is allocated.
+    /// Must be the highest binding in the set.
+    pub fn variable_count_binding(self, binding_index: u32) -> Self {
+        self.binding_flag(
+            binding_index,
+            vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
+                | vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND,
+        )
+    }
 }
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
@@ -345,6 +770,59 @@ impl ShaderSource {
     }
 }
 
+/// Specialization constant values, keyed by constant ID.
+#[derive(Clone, Default, Hash, PartialEq, Eq, Debug)]
+pub struct SpecializationData {
+    constants: BTreeMap<u32, Vec<u8>>,
+}
+
+impl SpecializationData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn constant_bytes(mut self, id: u32, bytes: impl Into<Vec<u8>>) -> Self {
+        self.constants.insert(id, bytes.into());
+        self
+    }
+
+    pub fn constant_u32(self, id: u32, value: u32) -> Self {
+        self.constant_bytes(id, value.to_ne_bytes())
+    }
+
+    pub fn constant_f32(self, id: u32, value: f32) -> Self {
+        self.constant_bytes(id, value.to_ne_bytes())
+    }
+
+    /// Booleans are 32-bit in Vulkan
+    pub fn constant_bool(self, id: u32, value: bool) -> Self {
+        self.constant_u32(id, value as u32)
+    }
+
+    /// The value of a 32-bit constant, if specified
+    pub fn get_u32(&self, id: u32) -> Option<u32> {
+        let bytes = self.constants.get(&id)?;
+        Some(u32::from_ne_bytes(bytes.as_slice().try_into().ok()?))
+    }
+
+    /// Map entries and data for `vk::SpecializationInfo`
+    fn to_vk(&self) -> (Vec<vk::SpecializationMapEntry>, Vec<u8>) {
+        let mut map_entries = Vec::with_capacity(self.constants.len());
+        let mut data = Vec::new();
+
+        for (id, bytes) in &self.constants {
+            map_entries.push(vk::SpecializationMapEntry {
+                constant_id: *id,
+                offset: data.len() as u32,
+                size: bytes.len(),
+            });
+            data.extend_from_slice(bytes);
+        }
+
+        (map_entries, data)
+    }
+}
+
 #[derive(Builder, Clone)]
 #[builder(pattern = "owned", derive(Clone))]
 pub struct ComputePipelineDesc {
@@ -352,6 +830,15 @@ pub struct ComputePipelineDesc {
     pub descriptor_set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
     #[builder(default)]
     pub push_constants_bytes: usize,
+    /// Resolve unrecognized sampler binding names to a linear-clamp sampler instead of failing
+    #[builder(default)]
+    pub allow_unknown_samplers: bool,
+    /// Specialization constant values; pipelines with different values are distinct
+    #[builder(setter(strip_option), default)]
+    pub specialization: Option<SpecializationData>,
+    /// Entry point to use in modules with several of them; defaults to the one of the `source`
+    #[builder(setter(strip_option, into), default)]
+    pub entry_name: Option<String>,
     pub source: ShaderSource,
 }
 
@@ -382,72 +869,148 @@ impl ComputePipelineDesc {
     pub fn builder() -> ComputePipelineDescBuilder {
         ComputePipelineDescBuilder::default()
     }
+
+    pub fn entry(&self) -> &str {
+        self.entry_name
+            .as_deref()
+            .unwrap_or_else(|| self.source.entry())
+    }
+}
+
+/// Pipelines use the push constant block size reflected from their shaders, unless a larger
+/// one is requested explicitly.
+fn push_constants_size(requested: usize, reflected: u32) -> anyhow::Result<u32> {
+    if requested == 0 {
+        Ok(reflected)
+    } else if requested < reflected as usize {
+        anyhow::bail!(
+            "push_constants_bytes is {}, but the shader's push constant block is {} bytes",
+            requested,
+            reflected
+        )
+    } else {
+        Ok(requested as u32)
+    }
 }
 
 pub fn create_compute_pipeline(
     device: &Device,
     spirv: &[u8],
     desc: &ComputePipelineDesc,
-) -> ComputePipeline {
+) -> anyhow::Result<ComputePipeline> {
+    let entry = desc.entry();
+
+    // Validate everything that doesn't create Vulkan objects first, so there's nothing to clean up
+    let spirv_words = spirv
+        .as_slice_of::<u32>()
+        .map_err(|err| anyhow::anyhow!("Compute shader {:?}: invalid SPIR-V: {:?}", entry, err))?;
+
+    validate_entry_point(spirv_words, entry, &[execution_model::GL_COMPUTE])
+        .with_context(|| format!("Compute shader {:?}", entry))?;
+
+    let descriptor_sets = rspirv_reflect::Reflection::new_from_spirv(spirv)
+        .and_then(|reflection| reflection.get_descriptor_sets())
+        .map_err(|err| {
+            anyhow::anyhow!(
+                "Compute shader {:?}: reflecting descriptor sets failed: {:?}",
+                entry,
+                err
+            )
+        })?;
+
+    let push_constants_bytes = get_push_constants_size_from_spirv(spirv_words)
+        .and_then(|reflected| push_constants_size(desc.push_constants_bytes, reflected))
+        .with_context(|| format!("Compute shader {:?}: push constants", entry))?;
+
+    let group_size = get_cs_local_size_from_spirv(spirv_words, entry, |id| {
+        desc.specialization
+            .as_ref()
+            .and_then(|specialization| specialization.get_u32(id))
+    })
+    .with_context(|| format!("Compute shader {:?}: reading the group size", entry))?;
+
+    let entry_name = CString::new(entry)
+        .with_context(|| format!("Compute shader {:?}: invalid entry point name", entry))?;
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &rspirv_reflect::Reflection::new_from_spirv(spirv)
-            .unwrap()
-            .get_descriptor_sets()
-            .unwrap(),
+        &descriptor_sets,
         vk::ShaderStageFlags::COMPUTE,
         &desc.descriptor_set_opts,
-    );
+        desc.allow_unknown_samplers,
+    )
+    .with_context(|| {
+        format!(
+            "Compute shader {:?}: creating descriptor set layouts",
+            entry
+        )
+    })?;
 
     // dbg!(&set_layout_info);
 
-    let mut layout_create_info =
-        vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_set_layouts);
-
-    let push_constant_ranges = vk::PushConstantRange {
-        stage_flags: vk::ShaderStageFlags::COMPUTE,
-        offset: 0,
-        size: desc.push_constants_bytes as _,
+    let push_constant_ranges: Vec<vk::PushConstantRange> = if push_constants_bytes > 0 {
+        vec![vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: push_constants_bytes,
+        }]
+    } else {
+        Vec::new()
     };
 
-    if desc.push_constants_bytes > 0 {
-        layout_create_info =
-            layout_create_info.push_constant_ranges(std::slice::from_ref(&push_constant_ranges));
-    }
+    let pipeline_layout = device
+        .layout_cache
+        .get_or_create_pipeline_layout(&device.raw, &descriptor_set_layouts, &push_constant_ranges)
+        .with_context(|| format!("Compute shader {:?}: creating the pipeline layout", entry))?;
 
     unsafe {
-        let shader_module = device
-            .raw
-            .create_shader_module(
-                &vk::ShaderModuleCreateInfo::builder().code(spirv.as_slice_of::<u32>().unwrap()),
-                None,
-            )
-            .unwrap();
+        let (shader_module_key, shader_module) = device
+            .shader_module_cache
+            .get_or_create(&device.raw, spirv_words)
+            .with_context(|| format!("Compute shader {:?}: creating the shader module", entry))?;
+
+        let (specialization_map_entries, specialization_data) = desc
+            .specialization
+            .as_ref()
+            .map(SpecializationData::to_vk)
+            .unwrap_or_default();
+        let specialization_info = vk::SpecializationInfo::builder()
+            .map_entries(&specialization_map_entries)
+            .data(&specialization_data);
 
-        let entry_name = CString::new(desc.source.entry()).unwrap();
-        let stage_create_info = vk::PipelineShaderStageCreateInfo::builder()
+        let mut stage_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(shader_module)
             .stage(vk::ShaderStageFlags::COMPUTE)
             .name(&entry_name);
 
-        let pipeline_layout = device
-            .raw
-            .create_pipeline_layout(&layout_create_info, None)
-            .unwrap();
+        if desc.specialization.is_some() {
+            stage_create_info = stage_create_info.specialization_info(&specialization_info);
+        }
 
         let pipeline_info = vk::ComputePipelineCreateInfo::builder()
             .stage(stage_create_info.build())
             .layout(pipeline_layout);
 
-        let pipeline = device
-            .raw
-            // TODO: pipeline cache
-            .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info.build()], None)
-            .expect("pipeline")[0];
+        let pipeline = device.raw.create_compute_pipelines(
+            device.pipeline_cache,
+            &[pipeline_info.build()],
+            None,
+        );
+
+        let pipeline = match pipeline {
+            Ok(pipelines) => pipelines[0],
+            Err((_, err)) => {
+                device
+                    .shader_module_cache
+                    .release(&device.raw, &[shader_module_key]);
+                return Err(err)
+                    .with_context(|| format!("Compute shader {:?}: creating the pipeline", entry));
+            }
+        };
 
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
-            for ty in bindings.values() {
+            for DescriptorBindingInfo { ty, .. } in bindings.values() {
                 if let Some(mut dps) = descriptor_pool_sizes.iter_mut().find(|item| item.ty == *ty)
                 {
                     dps.descriptor_count += 1;
@@ -460,7 +1023,7 @@ pub fn create_compute_pipeline(
             }
         }
 
-        ComputePipeline {
+        Ok(ComputePipeline {
             common: ShaderPipelineCommon {
                 pipeline_layout,
                 pipeline,
@@ -468,27 +1031,47 @@ pub fn create_compute_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::COMPUTE,
+                shader_modules: vec![shader_module_key],
             },
-            group_size: get_cs_local_size_from_spirv(spirv.as_slice_of::<u32>().unwrap()).unwrap(),
-        }
+            group_size,
+        })
     }
 }
 
 #[derive(Copy, Clone, Hash, Eq, PartialEq, Debug)]
 pub enum ShaderPipelineStage {
     Vertex,
+    TessControl,
+    TessEval,
+    Geometry,
     Pixel,
     RayGen,
     RayMiss,
     RayClosestHit,
 }
 
+impl ShaderPipelineStage {
+    /// The SPIR-V execution model of entry points usable for this stage
+    pub fn execution_model(self) -> u32 {
+        match self {
+            ShaderPipelineStage::Vertex => execution_model::VERTEX,
+            ShaderPipelineStage::TessControl => execution_model::TESSELLATION_CONTROL,
+            ShaderPipelineStage::TessEval => execution_model::TESSELLATION_EVALUATION,
+            ShaderPipelineStage::Geometry => execution_model::GEOMETRY,
+            ShaderPipelineStage::Pixel => execution_model::FRAGMENT,
+            ShaderPipelineStage::RayGen => execution_model::RAY_GENERATION,
+            ShaderPipelineStage::RayMiss => execution_model::MISS,
+            ShaderPipelineStage::RayClosestHit => execution_model::CLOSEST_HIT,
+        }
+    }
+}
+
 #[derive(Builder, Hash, PartialEq, Eq, Clone, Debug)]
 #[builder(pattern = "owned")]
 pub struct PipelineShaderDesc {
     pub stage: ShaderPipelineStage,
     #[builder(setter(strip_option), default)]
-    pub descriptor_set_layout_flags: Option<Vec<(usize, vk::DescriptorSetLayoutCreateFlags)>>,
+    pub descriptor_set_layout_flags: Option<Vec<(u32, vk::DescriptorSetLayoutCreateFlags)>>,
     #[builder(default)]
     pub push_constants_bytes: usize,
     #[builder(default = "\"main\".to_owned()")]
@@ -509,30 +1092,414 @@ impl PipelineShaderDescBuilder {
         self
     }
 
-    pub fn rust_source(mut self, entry: impl Into<String>) -> Self {
-        self.source = Some(ShaderSource::rust(entry));
+    pub fn rust_source(mut self, entry: impl Into<String>) -> Self {
+        self.source = Some(ShaderSource::rust(entry));
+
+        self
+    }
+}
+
+#[derive(Builder, Clone)]
+#[builder(pattern = "owned", derive(Clone))]
+pub struct RasterPipelineDesc {
+    #[builder(default)]
+    pub descriptor_set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
+    pub render_pass: Arc<RenderPass>,
+    /// Index of the render pass' subpass this pipeline is used in
+    #[builder(default)]
+    pub subpass_index: u32,
+    #[builder(default = "vk::CullModeFlags::NONE")]
+    pub cull: vk::CullModeFlags,
+    #[builder(default = "vk::FrontFace::COUNTER_CLOCKWISE")]
+    pub front_face: vk::FrontFace,
+    /// `LINE` and `POINT` require `DeviceCapabilities::fill_mode_non_solid`
+    #[builder(default = "vk::PolygonMode::FILL")]
+    pub polygon_mode: vk::PolygonMode,
+    #[builder(default = "vk::PrimitiveTopology::TRIANGLE_LIST")]
+    pub topology: vk::PrimitiveTopology,
+    /// Only valid with strip and fan topologies
+    #[builder(default)]
+    pub primitive_restart: bool,
+    /// Vertices per patch with the `PATCH_LIST` topology
+    #[builder(default)]
+    pub patch_control_points: u32,
+    /// Clamped to 1.0 if the device doesn't support wide lines
+    #[builder(default = "1.0")]
+    pub line_width: f32,
+    /// Ignored if the subpass has no depth attachment
+    #[builder(default)]
+    pub depth: DepthStateDesc,
+    /// Requires a subpass depth attachment with a stencil aspect. Disabled by default.
+    #[builder(setter(strip_option), default)]
+    pub stencil: Option<StencilStateDesc>,
+    #[builder(default)]
+    pub push_constants_bytes: usize,
+    /// Resolve unrecognized sampler binding names to a linear-clamp sampler instead of failing
+    #[builder(default)]
+    pub allow_unknown_samplers: bool,
+    /// Blend state of each color attachment of the subpass.
+    /// If empty, all attachments are opaque.
+    #[builder(default)]
+    pub blend: Vec<BlendStateDesc>,
+    /// Classic vertex buffer inputs. By default, there are none, and shaders fetch vertices themselves.
+    #[builder(setter(strip_option), default)]
+    pub vertex_input: Option<VertexInputDesc>,
+}
+
+impl RasterPipelineDesc {
+    pub fn builder() -> RasterPipelineDescBuilder {
+        RasterPipelineDescBuilder::default()
+    }
+
+    /// State which distinguishes pipelines created from the same shaders.
+    ///
+    /// The render pass is identified structurally, so compatible render passes share pipelines,
+    /// and a pipeline stays usable after the render pass it was created with is gone.
+    pub fn state_key(&self) -> RasterPipelineStateKey {
+        RasterPipelineStateKey {
+            descriptor_set_opts: self
+                .descriptor_set_opts
+                .iter()
+                .map(|opts| opts.as_ref().map(|(set, opts)| (*set, opts.key())))
+                .collect(),
+            render_pass: self.render_pass.compatibility_key.clone(),
+            subpass_index: self.subpass_index,
+            cull: self.cull,
+            front_face: self.front_face,
+            polygon_mode: self.polygon_mode,
+            topology: self.topology,
+            primitive_restart: self.primitive_restart,
+            patch_control_points: self.patch_control_points,
+            line_width_bits: self.line_width.to_bits(),
+            depth: self.depth,
+            stencil: self.stencil,
+            push_constants_bytes: self.push_constants_bytes,
+            blend: self.blend.clone(),
+            vertex_input: self.vertex_input.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+pub struct RasterPipelineStateKey {
+    descriptor_set_opts: Vec<Option<(u32, DescriptorSetLayoutOptsKey)>>,
+    render_pass: RenderPassCompatibilityKey,
+    subpass_index: u32,
+    cull: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    polygon_mode: vk::PolygonMode,
+    topology: vk::PrimitiveTopology,
+    primitive_restart: bool,
+    patch_control_points: u32,
+    line_width_bits: u32,
+    depth: DepthStateDesc,
+    stencil: Option<StencilStateDesc>,
+    push_constants_bytes: usize,
+    blend: Vec<BlendStateDesc>,
+    vertex_input: Option<VertexInputDesc>,
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct VertexInputBindingDesc {
+    pub binding: u32,
+    pub stride: u32,
+    pub input_rate: vk::VertexInputRate,
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct VertexInputAttributeDesc {
+    pub location: u32,
+    pub binding: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+#[derive(Clone, Default, Hash, PartialEq, Eq, Debug)]
+pub struct VertexInputDesc {
+    pub bindings: Vec<VertexInputBindingDesc>,
+    pub attributes: Vec<VertexInputAttributeDesc>,
+}
+
+impl VertexInputDesc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a binding advancing once per vertex
+    pub fn per_vertex(self, binding: u32, stride: u32) -> Self {
+        self.binding(binding, stride, vk::VertexInputRate::VERTEX)
+    }
+
+    /// Adds a binding advancing once per instance
+    pub fn per_instance(self, binding: u32, stride: u32) -> Self {
+        self.binding(binding, stride, vk::VertexInputRate::INSTANCE)
+    }
+
+    pub fn binding(mut self, binding: u32, stride: u32, input_rate: vk::VertexInputRate) -> Self {
+        self.bindings.push(VertexInputBindingDesc {
+            binding,
+            stride,
+            input_rate,
+        });
+        self
+    }
+
+    pub fn attribute(
+        mut self,
+        location: u32,
+        binding: u32,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        self.attributes.push(VertexInputAttributeDesc {
+            location,
+            binding,
+            format,
+            offset,
+        });
+        self
+    }
+
+    fn to_vk(
+        &self,
+    ) -> (
+        Vec<vk::VertexInputBindingDescription>,
+        Vec<vk::VertexInputAttributeDescription>,
+    ) {
+        let bindings = self
+            .bindings
+            .iter()
+            .map(|binding| vk::VertexInputBindingDescription {
+                binding: binding.binding,
+                stride: binding.stride,
+                input_rate: binding.input_rate,
+            })
+            .collect();
+
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|attribute| vk::VertexInputAttributeDescription {
+                location: attribute.location,
+                binding: attribute.binding,
+                format: attribute.format,
+                offset: attribute.offset,
+            })
+            .collect();
+
+        (bindings, attributes)
+    }
+}
+
+impl RasterPipelineDescBuilder {
+    /// Culls back faces if `face_cull` is set
+    pub fn face_cull(self, face_cull: bool) -> Self {
+        self.cull(if face_cull {
+            vk::CullModeFlags::BACK
+        } else {
+            vk::CullModeFlags::NONE
+        })
+    }
+
+    pub fn depth_write(mut self, write: bool) -> Self {
+        self.depth.get_or_insert_with(Default::default).write = write;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DepthStateDesc {
+    pub test: bool,
+    pub write: bool,
+    pub compare_op: vk::CompareOp,
+}
+
+impl Default for DepthStateDesc {
+    /// Reverse-Z depth test and write
+    fn default() -> Self {
+        Self {
+            test: true,
+            write: true,
+            compare_op: vk::CompareOp::GREATER_OR_EQUAL,
+        }
+    }
+}
+
+impl DepthStateDesc {
+    pub fn disabled() -> Self {
+        Self {
+            test: false,
+            write: false,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StencilFaceDesc {
+    pub fail_op: vk::StencilOp,
+    pub pass_op: vk::StencilOp,
+    pub depth_fail_op: vk::StencilOp,
+    pub compare_op: vk::CompareOp,
+}
+
+impl Default for StencilFaceDesc {
+    /// Always passes, and leaves the stencil unchanged
+    fn default() -> Self {
+        Self {
+            fail_op: vk::StencilOp::KEEP,
+            pass_op: vk::StencilOp::KEEP,
+            depth_fail_op: vk::StencilOp::KEEP,
+            compare_op: vk::CompareOp::ALWAYS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StencilStateDesc {
+    pub front: StencilFaceDesc,
+    pub back: StencilFaceDesc,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    /// Ignored if `dynamic_reference` is set
+    pub reference: u32,
+    /// Take the reference from `CommandBuffer::set_stencil_reference` instead
+    pub dynamic_reference: bool,
+}
+
+impl Default for StencilStateDesc {
+    fn default() -> Self {
+        Self {
+            front: Default::default(),
+            back: Default::default(),
+            compare_mask: !0,
+            write_mask: !0,
+            reference: 0,
+            dynamic_reference: false,
+        }
+    }
+}
+
+impl StencilStateDesc {
+    /// Writes `reference` wherever geometry passes the depth test
+    pub fn write(reference: u32) -> Self {
+        Self::both_faces(StencilFaceDesc {
+            pass_op: vk::StencilOp::REPLACE,
+            ..Default::default()
+        })
+        .reference(reference)
+    }
+
+    /// Only draws where the stencil equals `reference`
+    pub fn test_equal(reference: u32) -> Self {
+        Self::both_faces(StencilFaceDesc {
+            compare_op: vk::CompareOp::EQUAL,
+            ..Default::default()
+        })
+        .reference(reference)
+    }
+
+    pub fn both_faces(face: StencilFaceDesc) -> Self {
+        Self {
+            front: face,
+            back: face,
+            ..Default::default()
+        }
+    }
+
+    pub fn reference(mut self, reference: u32) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    pub fn dynamic_reference(mut self) -> Self {
+        self.dynamic_reference = true;
+        self
+    }
+
+    pub fn compare_mask(mut self, compare_mask: u32) -> Self {
+        self.compare_mask = compare_mask;
+        self
+    }
+
+    pub fn write_mask(mut self, write_mask: u32) -> Self {
+        self.write_mask = write_mask;
+        self
+    }
+
+    fn face_to_vk(&self, face: &StencilFaceDesc) -> vk::StencilOpState {
+        vk::StencilOpState {
+            fail_op: face.fail_op,
+            pass_op: face.pass_op,
+            depth_fail_op: face.depth_fail_op,
+            compare_op: face.compare_op,
+            compare_mask: self.compare_mask,
+            write_mask: self.write_mask,
+            reference: self.reference,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendStateDesc {
+    /// Overwrites the destination
+    Opaque,
+    /// `src * src.a + dst * (1 - src.a)`
+    AlphaBlend,
+    /// `src + dst`
+    Additive,
+    /// `src + dst * (1 - src.a)`; color is expected to be pre-multiplied by alpha
+    PremultipliedAlpha,
+}
 
-        self
+impl Default for BlendStateDesc {
+    fn default() -> Self {
+        Self::Opaque
     }
 }
 
-#[derive(Builder, Clone)]
-#[builder(pattern = "owned", derive(Clone))]
-pub struct RasterPipelineDesc {
-    #[builder(default)]
-    pub descriptor_set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
-    pub render_pass: Arc<RenderPass>,
-    #[builder(default)]
-    pub face_cull: bool,
-    #[builder(default = "true")]
-    pub depth_write: bool,
-    #[builder(default)]
-    pub push_constants_bytes: usize,
-}
+impl BlendStateDesc {
+    pub fn to_vk(self) -> vk::PipelineColorBlendAttachmentState {
+        let (src_color_blend_factor, dst_color_blend_factor, src_alpha_blend_factor) = match self {
+            BlendStateDesc::Opaque => {
+                return vk::PipelineColorBlendAttachmentState {
+                    blend_enable: 0,
+                    src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
+                    dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
+                    color_blend_op: vk::BlendOp::ADD,
+                    src_alpha_blend_factor: vk::BlendFactor::ZERO,
+                    dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+                    alpha_blend_op: vk::BlendOp::ADD,
+                    color_write_mask: vk::ColorComponentFlags::all(),
+                }
+            }
+            BlendStateDesc::AlphaBlend => (
+                vk::BlendFactor::SRC_ALPHA,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                vk::BlendFactor::ONE,
+            ),
+            BlendStateDesc::Additive => (
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE,
+            ),
+            BlendStateDesc::PremultipliedAlpha => (
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                vk::BlendFactor::ONE,
+            ),
+        };
 
-impl RasterPipelineDesc {
-    pub fn builder() -> RasterPipelineDescBuilder {
-        RasterPipelineDescBuilder::default()
+        vk::PipelineColorBlendAttachmentState {
+            blend_enable: 1,
+            src_color_blend_factor,
+            dst_color_blend_factor,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor,
+            dst_alpha_blend_factor: dst_color_blend_factor,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }
     }
 }
 
@@ -550,7 +1517,12 @@ pub struct RenderPassAttachmentDesc {
     pub format: vk::Format,
     pub load_op: vk::AttachmentLoadOp,
     pub store_op: vk::AttachmentStoreOp,
+    /// Only used by formats with a stencil aspect
+    pub stencil_load_op: vk::AttachmentLoadOp,
+    pub stencil_store_op: vk::AttachmentStoreOp,
     pub samples: vk::SampleCountFlags,
+    /// Only meaningful for depth attachments; see `read_only`
+    pub read_only: bool,
 }
 
 impl RenderPassAttachmentDesc {
@@ -559,7 +1531,10 @@ impl RenderPassAttachmentDesc {
             format,
             load_op: vk::AttachmentLoadOp::LOAD,
             store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::LOAD,
+            stencil_store_op: vk::AttachmentStoreOp::STORE,
             samples: vk::SampleCountFlags::TYPE_1,
+            read_only: false,
         }
     }
 
@@ -578,18 +1553,65 @@ impl RenderPassAttachmentDesc {
         self
     }
 
-    fn to_vk(
-        self,
-        initial_layout: vk::ImageLayout,
-        final_layout: vk::ImageLayout,
-    ) -> vk::AttachmentDescription {
+    pub fn garbage_stencil_input(mut self) -> Self {
+        self.stencil_load_op = vk::AttachmentLoadOp::DONT_CARE;
+        self
+    }
+
+    pub fn clear_stencil_input(mut self) -> Self {
+        self.stencil_load_op = vk::AttachmentLoadOp::CLEAR;
+        self
+    }
+
+    pub fn discard_stencil_output(mut self) -> Self {
+        self.stencil_store_op = vk::AttachmentStoreOp::DONT_CARE;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Depth attachment which is only tested against, e.g. while also being sampled by shaders.
+    /// Pipelines used with it must not write depth.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    fn is_depth_stencil(&self) -> bool {
+        image_aspect_mask_from_format(self.format) != vk::ImageAspectFlags::COLOR
+    }
+
+    /// The layout the attachment is in during the render pass, as well as before and after it.
+    /// Must match what the render graph transitions the image to for the access type used, e.g.
+    /// `DepthAttachmentWriteStencilReadOnly` for depth-only formats.
+    fn layout(&self) -> vk::ImageLayout {
+        if !self.is_depth_stencil() {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        } else if self.read_only {
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        } else if image_aspect_mask_from_format(self.format).contains(vk::ImageAspectFlags::STENCIL)
+        {
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        } else {
+            vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL
+        }
+    }
+
+    fn to_vk(self) -> vk::AttachmentDescription {
+        let layout = self.layout();
+
         vk::AttachmentDescription {
             format: self.format,
             samples: self.samples,
             load_op: self.load_op,
             store_op: self.store_op,
-            initial_layout,
-            final_layout,
+            stencil_load_op: self.stencil_load_op,
+            stencil_store_op: self.stencil_store_op,
+            initial_layout: layout,
+            final_layout: layout,
             ..Default::default()
         }
     }
@@ -597,11 +1619,23 @@ impl RenderPassAttachmentDesc {
 
 pub const MAX_COLOR_ATTACHMENTS: usize = 8;
 
+/// Color attachments, depth, and a resolve target for each color attachment
+pub const MAX_ATTACHMENTS: usize = MAX_COLOR_ATTACHMENTS * 2 + 1;
+
 #[derive(Eq, PartialEq, Hash)]
 pub struct FramebufferCacheKey {
     pub dims: [u32; 2],
-    pub attachments:
-        ArrayVec<[(vk::ImageUsageFlags, vk::ImageCreateFlags); MAX_COLOR_ATTACHMENTS + 1]>,
+    pub attachments: ArrayVec<[FramebufferAttachmentKey; MAX_ATTACHMENTS]>,
+}
+
+/// The properties of an image bound to an imageless framebuffer attachment
+/// which need to match the framebuffer's attachment image info.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct FramebufferAttachmentKey {
+    pub usage: vk::ImageUsageFlags,
+    pub flags: vk::ImageCreateFlags,
+    pub format: vk::Format,
+    pub samples: vk::SampleCountFlags,
 }
 
 impl FramebufferCacheKey {
@@ -609,11 +1643,17 @@ impl FramebufferCacheKey {
         dims: [u32; 2],
         color_attachments: impl Iterator<Item = &'a ImageDesc>,
         depth_stencil_attachment: Option<&'a ImageDesc>,
+        resolve_attachments: impl Iterator<Item = &'a ImageDesc>,
     ) -> Self {
         let color_attachments = color_attachments
             .chain(depth_stencil_attachment.into_iter())
-            .copied()
-            .map(|attachment| (attachment.usage, attachment.flags))
+            .chain(resolve_attachments)
+            .map(|attachment| FramebufferAttachmentKey {
+                usage: attachment.usage,
+                flags: attachment.flags,
+                format: attachment.format,
+                samples: attachment.sample_count,
+            })
             .collect();
 
         Self {
@@ -623,12 +1663,12 @@ impl FramebufferCacheKey {
     }
 }
 
-// TODO: nuke when resizing
+/// Imageless framebuffers of a render pass, keyed by their dimensions and attachment properties.
+/// Cleared via `Device::clear_framebuffer_caches` when the swapchain is recreated.
 pub struct FramebufferCache {
     entries: Mutex<HashMap<FramebufferCacheKey, vk::Framebuffer>>,
-    attachment_desc: ArrayVec<[RenderPassAttachmentDesc; MAX_COLOR_ATTACHMENTS + 1]>,
+    attachment_desc: ArrayVec<[RenderPassAttachmentDesc; MAX_ATTACHMENTS]>,
     render_pass: vk::RenderPass,
-    color_attachment_count: usize,
 }
 
 impl FramebufferCache {
@@ -636,6 +1676,7 @@ impl FramebufferCache {
         render_pass: vk::RenderPass,
         color_attachments: &[RenderPassAttachmentDesc],
         depth_attachment: Option<RenderPassAttachmentDesc>,
+        resolve_attachments: &[RenderPassAttachmentDesc],
     ) -> Self {
         let mut attachment_desc = ArrayVec::new();
 
@@ -647,11 +1688,21 @@ impl FramebufferCache {
             attachment_desc.push(depth_attachment)
         }
 
+        attachment_desc
+            .try_extend_from_slice(resolve_attachments)
+            .unwrap();
+
         Self {
             entries: Default::default(),
             attachment_desc,
             render_pass,
-            color_attachment_count: color_attachments.len(),
+        }
+    }
+
+    /// Releases all cached framebuffers once the GPU is done with the current frame.
+    pub fn clear(&self, device: &Device) {
+        for (_, framebuffer) in self.entries.lock().drain() {
+            device.defer_release(framebuffer);
         }
     }
 
@@ -669,21 +1720,29 @@ impl FramebufferCache {
                 let color_formats = TempList::new();
                 let [width, height] = key.dims;
 
-                let attachments = self
-                    .attachment_desc
+                anyhow::ensure!(
+                    key.attachments.len() == self.attachment_desc.len(),
+                    "The render pass has {} attachments, but {} were provided",
+                    self.attachment_desc.len(),
+                    key.attachments.len()
+                );
+
+                let attachments = key
+                    .attachments
                     .iter()
-                    .zip(key.attachments.iter())
-                    .map(|(desc, (usage, flags))| {
+                    .map(|attachment| {
                         vk::FramebufferAttachmentImageInfoKHR::builder()
                             .width(width as _)
                             .height(height as _)
-                            .flags(*flags)
+                            .flags(attachment.flags)
                             .layer_count(1)
-                            .view_formats(std::slice::from_ref(color_formats.add(desc.format)))
-                            .usage(*usage)
+                            .view_formats(std::slice::from_ref(
+                                color_formats.add(attachment.format),
+                            ))
+                            .usage(attachment.usage)
                             .build()
                     })
-                    .collect::<ArrayVec<[_; MAX_COLOR_ATTACHMENTS + 1]>>();
+                    .collect::<ArrayVec<[_; MAX_ATTACHMENTS]>>();
 
                 let mut imageless_desc = vk::FramebufferAttachmentsCreateInfoKHR::builder()
                     .attachment_image_infos(&attachments);
@@ -707,73 +1766,237 @@ impl FramebufferCache {
     }
 }
 
+/// Attachments used by one subpass of a render pass. Indices refer to the render pass' attachments:
+/// the color attachments in order, followed by the depth attachment, followed by the resolve attachments.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SubpassDesc<'a> {
+    pub color_attachments: &'a [u32],
+    pub depth_attachment: Option<u32>,
+    /// Attachments written by earlier subpasses, read via `subpassLoad`
+    pub input_attachments: &'a [u32],
+    /// Either empty, or one per color attachment
+    pub resolve_attachments: &'a [u32],
+}
+
+#[derive(Default)]
 pub struct RenderPassDesc<'a> {
     pub color_attachments: &'a [RenderPassAttachmentDesc],
     pub depth_attachment: Option<RenderPassAttachmentDesc>,
+    /// Single-sampled targets which multisampled color attachments get resolved into.
+    /// Either empty, or one per color attachment.
+    pub resolve_attachments: &'a [RenderPassAttachmentDesc],
+    /// If empty, there's a single subpass using all the attachments
+    pub subpasses: &'a [SubpassDesc<'a>],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SubpassInfo {
+    pub color_attachment_count: usize,
+    pub has_depth_attachment: bool,
+    /// The depth attachment was created with `RenderPassAttachmentDesc::read_only`
+    pub depth_read_only: bool,
+    /// The depth attachment has a stencil aspect
+    pub has_stencil_attachment: bool,
+    /// Sample count shared by all the color and depth attachments of the subpass
+    pub samples: vk::SampleCountFlags,
+}
+
+/// Everything that makes two render passes compatible, as far as pipelines are concerned:
+/// attachment formats and sample counts, and how the subpasses reference the attachments.
+/// Load/store ops are deliberately left out.
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+pub struct RenderPassCompatibilityKey {
+    /// Format, sample count, and read-only-ness of each attachment
+    attachments: Vec<(vk::Format, vk::SampleCountFlags, bool)>,
+    /// Color, depth, input, and resolve attachment indices of each subpass
+    subpasses: Vec<(Vec<u32>, Option<u32>, Vec<u32>, Vec<u32>)>,
 }
 
 pub struct RenderPass {
     pub raw: vk::RenderPass,
     pub framebuffer_cache: FramebufferCache,
+    pub subpasses: Vec<SubpassInfo>,
+    pub compatibility_key: RenderPassCompatibilityKey,
+    device: ash::Device,
+}
+
+impl Drop for RenderPass {
+    // Render passes live as long as the renderers which create them, so this only
+    // happens at teardown, when the GPU is idle.
+    fn drop(&mut self) {
+        unsafe {
+            for (_, framebuffer) in self.framebuffer_cache.entries.get_mut().drain() {
+                self.device.destroy_framebuffer(framebuffer, None);
+            }
+
+            self.device.destroy_render_pass(self.raw, None);
+        }
+    }
 }
 
 pub fn create_render_pass(device: &Device, desc: RenderPassDesc<'_>) -> Arc<RenderPass> {
+    assert!(
+        desc.color_attachments
+            .iter()
+            .chain(desc.resolve_attachments)
+            .all(|a| !a.is_depth_stencil()),
+        "Depth-stencil formats can only be used for the depth attachment"
+    );
+    assert!(
+        desc.depth_attachment.map_or(true, |a| a.is_depth_stencil()),
+        "The depth attachment must have a depth-stencil format"
+    );
+
     let renderpass_attachments = desc
         .color_attachments
         .iter()
-        .map(|a| {
-            a.to_vk(
-                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-            )
-        })
-        .chain(desc.depth_attachment.as_ref().map(|a| {
-            a.to_vk(
-                vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
-                vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
-            )
-        }))
+        .chain(desc.depth_attachment.as_ref())
+        .chain(desc.resolve_attachments)
+        .map(|a| a.to_vk())
         .collect::<Vec<_>>();
 
-    let color_attachment_refs = (0..desc.color_attachments.len() as u32)
-        .map(|attachment| vk::AttachmentReference {
-            attachment,
-            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        })
-        .collect::<Vec<_>>();
+    assert!(
+        desc.resolve_attachments.is_empty()
+            || desc.resolve_attachments.len() == desc.color_attachments.len(),
+        "A render pass needs either no resolve attachments, or one per color attachment"
+    );
+    assert!(
+        desc.resolve_attachments
+            .iter()
+            .all(|a| a.samples == vk::SampleCountFlags::TYPE_1),
+        "Resolve attachments must be single-sampled"
+    );
 
-    let depth_attachment_ref = vk::AttachmentReference {
-        attachment: desc.color_attachments.len() as u32,
-        layout: vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
+    let depth_attachment_index = desc.color_attachments.len() as u32;
+    let first_resolve_attachment_index =
+        depth_attachment_index + desc.depth_attachment.is_some() as u32;
+
+    let default_color_attachments: Vec<u32> = (0..desc.color_attachments.len() as u32).collect();
+    let default_resolve_attachments: Vec<u32> = (0..desc.resolve_attachments.len() as u32)
+        .map(|i| first_resolve_attachment_index + i)
+        .collect();
+    let default_subpass = [SubpassDesc {
+        color_attachments: &default_color_attachments,
+        depth_attachment: desc.depth_attachment.map(|_| depth_attachment_index),
+        input_attachments: &[],
+        resolve_attachments: &default_resolve_attachments,
+    }];
+
+    let subpasses = if desc.subpasses.is_empty() {
+        &default_subpass[..]
+    } else {
+        desc.subpasses
     };
 
-    // TODO: Calculate optimal dependencies. using implicit dependencies for now.
-    /*let dependencies = [vk::SubpassDependency {
-        src_subpass: vk::SUBPASS_EXTERNAL,
-        src_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
-            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
-            | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
-            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
-            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        ..Default::default()
-    }];*/
-
-    let mut subpass_description = vk::SubpassDescription::builder()
-        .color_attachments(&color_attachment_refs)
-        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
-
-    if desc.depth_attachment.is_some() {
-        subpass_description = subpass_description.depth_stencil_attachment(&depth_attachment_ref);
-    }
-    let subpass_description = subpass_description.build();
-
-    let subpasses = [subpass_description];
+    let attachment_refs = TempList::new();
+    let subpass_descriptions: Vec<vk::SubpassDescription> = subpasses
+        .iter()
+        .map(|subpass| {
+            for &attachment in subpass
+                .color_attachments
+                .iter()
+                .chain(subpass.input_attachments)
+                .chain(subpass.depth_attachment.as_ref())
+                .chain(subpass.resolve_attachments)
+            {
+                assert!(
+                    (attachment as usize) < renderpass_attachments.len(),
+                    "Subpass attachment index {} out of range",
+                    attachment
+                );
+            }
+
+            assert!(
+                subpass.resolve_attachments.is_empty()
+                    || subpass.resolve_attachments.len() == subpass.color_attachments.len(),
+                "A subpass needs either no resolve attachments, or one per color attachment"
+            );
+
+            let color_attachment_refs = attachment_refs.add(
+                subpass
+                    .color_attachments
+                    .iter()
+                    .map(|&attachment| vk::AttachmentReference {
+                        attachment,
+                        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                    })
+                    .collect::<Vec<_>>(),
+            );
+
+            let input_attachment_refs = attachment_refs.add(
+                subpass
+                    .input_attachments
+                    .iter()
+                    .map(|&attachment| vk::AttachmentReference {
+                        attachment,
+                        layout: if attachment == depth_attachment_index {
+                            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                        } else {
+                            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                        },
+                    })
+                    .collect::<Vec<_>>(),
+            );
+
+            let mut subpass_description = vk::SubpassDescription::builder()
+                .color_attachments(color_attachment_refs)
+                .input_attachments(input_attachment_refs)
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+
+            if !subpass.resolve_attachments.is_empty() {
+                subpass_description = subpass_description.resolve_attachments(
+                    attachment_refs.add(
+                        subpass
+                            .resolve_attachments
+                            .iter()
+                            .map(|&attachment| vk::AttachmentReference {
+                                attachment,
+                                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                            })
+                            .collect::<Vec<_>>(),
+                    ),
+                );
+            }
+
+            if let Some(attachment) = subpass.depth_attachment {
+                subpass_description = subpass_description.depth_stencil_attachment(
+                    &attachment_refs.add(vec![vk::AttachmentReference {
+                        attachment,
+                        layout: renderpass_attachments[attachment as usize].initial_layout,
+                    }])[0],
+                );
+            }
+
+            subpass_description.build()
+        })
+        .collect();
+
+    // External dependencies are implicit. Chain the subpasses so that each one sees
+    // the attachment writes of the previous one.
+    let dependencies: Vec<vk::SubpassDependency> = (1..subpasses.len() as u32)
+        .map(|dst_subpass| vk::SubpassDependency {
+            src_subpass: dst_subpass - 1,
+            dst_subpass,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER
+                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::INPUT_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dependency_flags: vk::DependencyFlags::BY_REGION,
+        })
+        .collect();
+
     let render_pass_create_info = vk::RenderPassCreateInfo::builder()
         .attachments(&renderpass_attachments)
-        .subpasses(&subpasses);
+        .subpasses(&subpass_descriptions)
+        .dependencies(&dependencies);
 
     let render_pass = unsafe {
         device
@@ -782,14 +2005,80 @@ pub fn create_render_pass(device: &Device, desc: RenderPassDesc<'_>) -> Arc<Rend
             .unwrap()
     };
 
-    Arc::new(RenderPass {
+    let render_pass = Arc::new(RenderPass {
         raw: render_pass,
         framebuffer_cache: FramebufferCache::new(
             render_pass,
             desc.color_attachments,
             desc.depth_attachment,
+            desc.resolve_attachments,
         ),
-    })
+        subpasses: subpasses
+            .iter()
+            .map(|subpass| SubpassInfo {
+                color_attachment_count: subpass.color_attachments.len(),
+                has_depth_attachment: subpass.depth_attachment.is_some(),
+                has_stencil_attachment: subpass.depth_attachment.map_or(false, |attachment| {
+                    image_aspect_mask_from_format(
+                        renderpass_attachments[attachment as usize].format,
+                    )
+                    .contains(vk::ImageAspectFlags::STENCIL)
+                }),
+                depth_read_only: subpass.depth_attachment.map_or(false, |attachment| {
+                    renderpass_attachments[attachment as usize].initial_layout
+                        == vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                }),
+                samples: subpass_samples(&renderpass_attachments, subpass),
+            })
+            .collect(),
+        compatibility_key: RenderPassCompatibilityKey {
+            attachments: desc
+                .color_attachments
+                .iter()
+                .chain(desc.depth_attachment.as_ref())
+                .chain(desc.resolve_attachments)
+                .map(|a| (a.format, a.samples, a.read_only))
+                .collect(),
+            subpasses: subpasses
+                .iter()
+                .map(|subpass| {
+                    (
+                        subpass.color_attachments.to_vec(),
+                        subpass.depth_attachment,
+                        subpass.input_attachments.to_vec(),
+                        subpass.resolve_attachments.to_vec(),
+                    )
+                })
+                .collect(),
+        },
+        device: device.raw.clone(),
+    });
+
+    device
+        .render_passes
+        .lock()
+        .push(Arc::downgrade(&render_pass));
+
+    render_pass
+}
+
+fn subpass_samples(
+    attachments: &[vk::AttachmentDescription],
+    subpass: &SubpassDesc,
+) -> vk::SampleCountFlags {
+    let mut samples = subpass
+        .color_attachments
+        .iter()
+        .chain(subpass.depth_attachment.as_ref())
+        .map(|&attachment| attachments[attachment as usize].samples);
+
+    let first = samples.next().unwrap_or(vk::SampleCountFlags::TYPE_1);
+    assert!(
+        samples.all(|s| s == first),
+        "All color and depth attachments of a subpass must have the same sample count"
+    );
+
+    first
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -825,6 +2114,119 @@ pub fn create_raster_pipeline(
     shaders: &[PipelineShader<Bytes>],
     desc: &RasterPipelineDesc,
 ) -> anyhow::Result<RasterPipeline> {
+    let subpass = *desc
+        .render_pass
+        .subpasses
+        .get(desc.subpass_index as usize)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "The raster pipeline uses subpass {}, but its render pass has {}",
+                desc.subpass_index,
+                desc.render_pass.subpasses.len()
+            )
+        })?;
+
+    if desc.stencil.is_some() && !subpass.has_stencil_attachment {
+        anyhow::bail!(
+            "The raster pipeline uses a stencil state, but its subpass has no stencil attachment"
+        );
+    }
+
+    if subpass.depth_read_only && desc.depth.write {
+        anyhow::bail!(
+            "The raster pipeline writes depth, but the depth attachment of its subpass is read-only"
+        );
+    }
+
+    let color_attachment_count = subpass.color_attachment_count;
+    if !desc.blend.is_empty() && desc.blend.len() != color_attachment_count {
+        anyhow::bail!(
+            "The raster pipeline specifies {} blend states, but its subpass has {} color attachments",
+            desc.blend.len(),
+            color_attachment_count
+        );
+    }
+
+    if desc.polygon_mode != vk::PolygonMode::FILL && !device.capabilities().fill_mode_non_solid {
+        anyhow::bail!(
+            "The raster pipeline uses polygon mode {:?}, but the device doesn't support fillModeNonSolid",
+            desc.polygon_mode
+        );
+    }
+
+    if shaders
+        .iter()
+        .any(|shader| shader.desc.stage == ShaderPipelineStage::Geometry)
+        && !device.capabilities().geometry_shader
+    {
+        anyhow::bail!(
+            "The raster pipeline has a geometry shader, but the device doesn't support them"
+        );
+    }
+
+    let tessellated = shaders.iter().any(|shader| {
+        matches!(
+            shader.desc.stage,
+            ShaderPipelineStage::TessControl | ShaderPipelineStage::TessEval
+        )
+    });
+
+    if tessellated {
+        if !device.capabilities().tessellation_shader {
+            anyhow::bail!(
+                "The raster pipeline has tessellation shaders, but the device doesn't support them"
+            );
+        }
+
+        if desc.topology != vk::PrimitiveTopology::PATCH_LIST || desc.patch_control_points == 0 {
+            anyhow::bail!(
+                "Tessellation requires the PATCH_LIST topology and a non-zero patch_control_points"
+            );
+        }
+    }
+
+    if desc.primitive_restart
+        && !matches!(
+            desc.topology,
+            vk::PrimitiveTopology::LINE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_FAN
+                | vk::PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+                | vk::PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+        )
+    {
+        anyhow::bail!(
+            "Primitive restart can't be used with topology {:?}",
+            desc.topology
+        );
+    }
+
+    let line_width = if device.capabilities().wide_lines {
+        let [min_width, max_width] = device.physical_device().properties.limits.line_width_range;
+        desc.line_width.max(min_width).min(max_width)
+    } else {
+        if desc.line_width != 1.0 {
+            log::warn!(
+                "Line width {} requested, but the device doesn't support wide lines. Using 1.0",
+                desc.line_width
+            );
+        }
+        1.0
+    };
+
+    for shader in shaders {
+        let spirv = shader
+            .code
+            .as_slice_of::<u32>()
+            .map_err(|err| anyhow::anyhow!("Invalid SPIR-V: {:?}", err))?;
+        validate_entry_point(
+            spirv,
+            &shader.desc.entry,
+            &[shader.desc.stage.execution_model()],
+        )
+        .with_context(|| format!("{:?} shader {:?}", shader.desc.stage, shader.desc.entry))?;
+    }
+
     let stage_layouts = shaders
         .iter()
         .map(|shader| {
@@ -835,48 +2237,65 @@ pub fn create_raster_pipeline(
         })
         .collect::<Vec<_>>();
 
+    let descriptor_set_opts = merge_descriptor_set_layout_flags(
+        shaders.iter().map(|shader| &shader.desc),
+        &desc.descriptor_set_opts,
+    )?;
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
         &merge_shader_stage_layouts(stage_layouts),
         vk::ShaderStageFlags::ALL_GRAPHICS,
-        //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
-        &desc.descriptor_set_opts,
-    );
+        &descriptor_set_opts,
+        desc.allow_unknown_samplers,
+    )?;
+
+    let mut reflected_push_constants_bytes = 0;
+    for shader in shaders {
+        let spirv = shader
+            .code
+            .as_slice_of::<u32>()
+            .map_err(|err| anyhow::anyhow!("Invalid SPIR-V: {:?}", err))?;
+        reflected_push_constants_bytes =
+            reflected_push_constants_bytes.max(get_push_constants_size_from_spirv(spirv)?);
+    }
 
-    unsafe {
-        let mut layout_create_info =
-            vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_set_layouts);
+    let push_constants_bytes =
+        push_constants_size(desc.push_constants_bytes, reflected_push_constants_bytes)
+            .context("Raster pipeline push constants")?;
 
-        let push_constant_ranges = vk::PushConstantRange {
+    let push_constant_ranges: Vec<vk::PushConstantRange> = if push_constants_bytes > 0 {
+        vec![vk::PushConstantRange {
             stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
             offset: 0,
-            size: desc.push_constants_bytes as _,
-        };
-
-        if desc.push_constants_bytes > 0 {
-            layout_create_info = layout_create_info
-                .push_constant_ranges(std::slice::from_ref(&push_constant_ranges));
-        }
+            size: push_constants_bytes,
+        }]
+    } else {
+        Vec::new()
+    };
 
-        let pipeline_layout = device
-            .raw
-            .create_pipeline_layout(&layout_create_info, None)
-            .unwrap();
+    let pipeline_layout = device
+        .layout_cache
+        .get_or_create_pipeline_layout(&device.raw, &descriptor_set_layouts, &push_constant_ranges)
+        .context("Creating the raster pipeline layout")?;
 
+    unsafe {
         let entry_names = TempList::new();
+        let mut shader_modules = Vec::with_capacity(shaders.len());
         let shader_stage_create_infos: Vec<_> = shaders
             .iter()
             .map(|desc| {
-                let shader_info = vk::ShaderModuleCreateInfo::builder()
-                    .code(desc.code.as_slice_of::<u32>().unwrap());
-
-                let shader_module = device
-                    .raw
-                    .create_shader_module(&shader_info, None)
+                let (shader_module_key, shader_module) = device
+                    .shader_module_cache
+                    .get_or_create(&device.raw, desc.code.as_slice_of::<u32>().unwrap())
                     .expect("Shader module error");
+                shader_modules.push(shader_module_key);
 
                 let stage = match desc.desc.stage {
                     ShaderPipelineStage::Vertex => vk::ShaderStageFlags::VERTEX,
+                    ShaderPipelineStage::TessControl => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+                    ShaderPipelineStage::TessEval => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+                    ShaderPipelineStage::Geometry => vk::ShaderStageFlags::GEOMETRY,
                     ShaderPipelineStage::Pixel => vk::ShaderStageFlags::FRAGMENT,
                     _ => unimplemented!(),
                 };
@@ -889,93 +2308,96 @@ pub fn create_raster_pipeline(
             })
             .collect();
 
-        let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
-            vertex_attribute_description_count: 0,
-            p_vertex_attribute_descriptions: std::ptr::null(),
-            vertex_binding_description_count: 0,
-            p_vertex_binding_descriptions: std::ptr::null(),
-            ..Default::default()
-        };
+        let (vertex_bindings, vertex_attributes) = desc
+            .vertex_input
+            .as_ref()
+            .map(VertexInputDesc::to_vk)
+            .unwrap_or_default();
+        let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&vertex_attributes);
         let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
-            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            topology: desc.topology,
+            primitive_restart_enable: desc.primitive_restart as u32,
             ..Default::default()
         };
 
+        let tessellation_state_info = vk::PipelineTessellationStateCreateInfo::builder()
+            .patch_control_points(desc.patch_control_points);
+
         let viewport_state_info = vk::PipelineViewportStateCreateInfo::builder()
             .viewport_count(1)
             .scissor_count(1);
 
         let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
-            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
-            line_width: 1.0,
-            polygon_mode: vk::PolygonMode::FILL,
-            cull_mode: if desc.face_cull {
-                ash::vk::CullModeFlags::BACK
-            } else {
-                ash::vk::CullModeFlags::NONE
-            },
+            front_face: desc.front_face,
+            line_width,
+            polygon_mode: desc.polygon_mode,
+            cull_mode: desc.cull,
             ..Default::default()
         };
         let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
-            rasterization_samples: vk::SampleCountFlags::TYPE_1,
-            ..Default::default()
-        };
-        let noop_stencil_state = vk::StencilOpState {
-            fail_op: vk::StencilOp::KEEP,
-            pass_op: vk::StencilOp::KEEP,
-            depth_fail_op: vk::StencilOp::KEEP,
-            compare_op: vk::CompareOp::ALWAYS,
+            rasterization_samples: subpass.samples,
             ..Default::default()
         };
+        let stencil = desc.stencil.unwrap_or_default();
         let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
-            depth_test_enable: 1,
-            depth_write_enable: if desc.depth_write { 1 } else { 0 },
-            depth_compare_op: vk::CompareOp::GREATER_OR_EQUAL,
-            front: noop_stencil_state,
-            back: noop_stencil_state,
+            depth_test_enable: desc.depth.test as u32,
+            depth_write_enable: desc.depth.write as u32,
+            depth_compare_op: desc.depth.compare_op,
+            stencil_test_enable: desc.stencil.is_some() as u32,
+            front: stencil.face_to_vk(&stencil.front),
+            back: stencil.face_to_vk(&stencil.back),
             max_depth_bounds: 1.0,
             ..Default::default()
         };
 
-        let color_attachment_count = desc.render_pass.framebuffer_cache.color_attachment_count;
-
-        let color_blend_attachment_states = vec![
-            vk::PipelineColorBlendAttachmentState {
-                blend_enable: 0,
-                src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
-                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
-                color_blend_op: vk::BlendOp::ADD,
-                src_alpha_blend_factor: vk::BlendFactor::ZERO,
-                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
-                alpha_blend_op: vk::BlendOp::ADD,
-                color_write_mask: vk::ColorComponentFlags::all(),
-            };
-            color_attachment_count
-        ];
+        let color_blend_attachment_states: Vec<_> = if desc.blend.is_empty() {
+            vec![BlendStateDesc::Opaque.to_vk(); color_attachment_count]
+        } else {
+            desc.blend.iter().map(|blend| blend.to_vk()).collect()
+        };
         let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
             .attachments(&color_blend_attachment_states);
 
-        let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let mut dynamic_state = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        if desc
+            .stencil
+            .map_or(false, |stencil| stencil.dynamic_reference)
+        {
+            dynamic_state.push(vk::DynamicState::STENCIL_REFERENCE);
+        }
+
         let dynamic_state_info =
             vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_state);
 
-        let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+        let mut graphic_pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stage_create_infos)
             .vertex_input_state(&vertex_input_state_info)
             .input_assembly_state(&vertex_input_assembly_state_info)
             .viewport_state(&viewport_state_info)
             .rasterization_state(&rasterization_info)
             .multisample_state(&multisample_state_info)
-            .depth_stencil_state(&depth_state_info)
             .color_blend_state(&color_blend_state)
             .dynamic_state(&dynamic_state_info)
             .layout(pipeline_layout)
-            .render_pass(desc.render_pass.raw);
+            .render_pass(desc.render_pass.raw)
+            .subpass(desc.subpass_index);
+
+        if tessellated {
+            graphic_pipeline_info =
+                graphic_pipeline_info.tessellation_state(&tessellation_state_info);
+        }
+
+        // Color-only subpasses don't get depth-stencil state
+        if subpass.has_depth_attachment {
+            graphic_pipeline_info = graphic_pipeline_info.depth_stencil_state(&depth_state_info);
+        }
 
         let pipeline = device
             .raw
             .create_graphics_pipelines(
-                vk::PipelineCache::null(),
+                device.pipeline_cache,
                 &[graphic_pipeline_info.build()],
                 None,
             )
@@ -983,7 +2405,7 @@ pub fn create_raster_pipeline(
 
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
-            for ty in bindings.values() {
+            for DescriptorBindingInfo { ty, .. } in bindings.values() {
                 if let Some(mut dps) = descriptor_pool_sizes.iter_mut().find(|item| item.ty == *ty)
                 {
                     dps.descriptor_count += 1;
@@ -1005,6 +2427,7 @@ pub fn create_raster_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+                shader_modules,
             },
         })
     }
@@ -1046,6 +2469,72 @@ fn merge_shader_stage_layout_pair(
     }
 }
 
+/// Folds the `descriptor_set_layout_flags` of all stages into the pipeline's set opts.
+/// Stages (and the pipeline) must agree on the flags of any set they all specify.
+pub(crate) fn merge_descriptor_set_layout_flags<'a>(
+    stages: impl IntoIterator<Item = &'a PipelineShaderDesc>,
+    set_opts: &[Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
+) -> anyhow::Result<[Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS]> {
+    let mut merged_flags: BTreeMap<u32, (vk::DescriptorSetLayoutCreateFlags, ShaderPipelineStage)> =
+        BTreeMap::new();
+
+    for stage in stages {
+        for (set_index, flags) in stage.descriptor_set_layout_flags.iter().flatten() {
+            match merged_flags.get(set_index) {
+                Some((existing, existing_stage)) if existing != flags => anyhow::bail!(
+                    "Conflicting layout flags for descriptor set {}: {:?} in the {:?} shader, {:?} in the {:?} shader",
+                    set_index,
+                    existing,
+                    existing_stage,
+                    flags,
+                    stage.stage
+                ),
+                Some(_) => {}
+                None => {
+                    merged_flags.insert(*set_index, (*flags, stage.stage));
+                }
+            }
+        }
+    }
+
+    let mut set_opts = set_opts.clone();
+
+    for (set_index, (flags, stage)) in merged_flags {
+        if let Some((_, opts)) = set_opts
+            .iter_mut()
+            .flatten()
+            .find(|(opt_set, _)| *opt_set == set_index)
+        {
+            match opts.flags {
+                Some(existing) if existing != flags => anyhow::bail!(
+                    "Conflicting layout flags for descriptor set {}: {:?} in the pipeline's set opts, {:?} in the {:?} shader",
+                    set_index,
+                    existing,
+                    flags,
+                    stage
+                ),
+                _ => opts.flags = Some(flags),
+            }
+        } else if let Some(free_slot) = set_opts.iter_mut().find(|opt| opt.is_none()) {
+            *free_slot = Some((
+                set_index,
+                DescriptorSetLayoutOpts {
+                    flags: Some(flags),
+                    ..Default::default()
+                },
+            ));
+        } else {
+            anyhow::bail!(
+                "No room for the layout flags of descriptor set {}: all {} set opts are in use",
+                set_index,
+                MAX_DESCRIPTOR_SETS
+            );
+        }
+    }
+
+    Ok(set_opts)
+}
+
 pub(crate) fn merge_shader_stage_layouts(
     stages: Vec<StageDescriptorSetLayouts>,
 ) -> StageDescriptorSetLayouts {
@@ -1058,3 +2547,286 @@ pub(crate) fn merge_shader_stage_layouts(
 
     result
 }
+
+#[test]
+fn test_reflected_sized_array_count() {
+    // Hand-assembled module declaring `Texture2D textures[4]` at set 0, binding 0
+    #[rustfmt::skip]
+    let spirv: &[u32] = &[
+        0x0723_0203, 0x0001_0000, 0, 8, 0,
+        // OpCapability Shader
+        (2 << 16) | 17, 1,
+        // OpMemoryModel Logical GLSL450
+        (3 << 16) | 14, 0, 1,
+        // OpName %7 "textures"
+        (5 << 16) | 5, 7, 0x7478_6574, 0x7365_7275, 0,
+        // OpDecorate %7 DescriptorSet 0
+        (4 << 16) | 71, 7, 34, 0,
+        // OpDecorate %7 Binding 0
+        (4 << 16) | 71, 7, 33, 0,
+        // %1 = OpTypeFloat 32
+        (3 << 16) | 22, 1, 32,
+        // %2 = OpTypeImage %1 2D 0 0 0 1 Unknown
+        (9 << 16) | 25, 2, 1, 1, 0, 0, 0, 1, 0,
+        // %3 = OpTypeInt 32 0
+        (4 << 16) | 21, 3, 32, 0,
+        // %4 = OpConstant %3 4
+        (4 << 16) | 43, 3, 4, 4,
+        // %5 = OpTypeArray %2 %4
+        (4 << 16) | 28, 5, 2, 4,
+        // %6 = OpTypePointer UniformConstant %5
+        (4 << 16) | 32, 6, 0, 5,
+        // %7 = OpVariable %6 UniformConstant
+        (4 << 16) | 59, 6, 7, 0,
+    ];
+    let spirv: Vec<u8> = spirv.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    let sets = rspirv_reflect::Reflection::new_from_spirv(&spirv)
+        .unwrap()
+        .get_descriptor_sets()
+        .unwrap();
+    let binding = &sets[&0][&0];
+
+    assert_eq!(binding.ty, rspirv_reflect::DescriptorType::SAMPLED_IMAGE);
+    assert_eq!(
+        reflected_descriptor_count(binding, || unreachable!("not an unsized array")).unwrap(),
+        4
+    );
+}
+
+#[test]
+fn test_parse_sampler_name() {
+    use vk::{BorderColor, CompareOp, Filter, SamplerAddressMode, SamplerMipmapMode};
+
+    let base = |texel_filter, mipmap_mode, address_modes| {
+        SamplerDesc::new(texel_filter, mipmap_mode, address_modes)
+    };
+
+    let valid = [
+        (
+            "sampler_lnc",
+            base(
+                Filter::LINEAR,
+                SamplerMipmapMode::NEAREST,
+                SamplerAddressMode::CLAMP_TO_EDGE,
+            ),
+        ),
+        (
+            "sampler_nnr",
+            base(
+                Filter::NEAREST,
+                SamplerMipmapMode::NEAREST,
+                SamplerAddressMode::REPEAT,
+            ),
+        ),
+        (
+            "sampler_llmr",
+            base(
+                Filter::LINEAR,
+                SamplerMipmapMode::LINEAR,
+                SamplerAddressMode::MIRRORED_REPEAT,
+            ),
+        ),
+        (
+            "sampler_nlcb",
+            base(
+                Filter::NEAREST,
+                SamplerMipmapMode::LINEAR,
+                SamplerAddressMode::CLAMP_TO_BORDER,
+            ),
+        ),
+        (
+            "sampler_llr_a8",
+            SamplerDesc {
+                max_anisotropy: 8,
+                ..base(
+                    Filter::LINEAR,
+                    SamplerMipmapMode::LINEAR,
+                    SamplerAddressMode::REPEAT,
+                )
+            },
+        ),
+        (
+            "sampler_nnc_a16",
+            SamplerDesc {
+                max_anisotropy: 16,
+                ..base(
+                    Filter::NEAREST,
+                    SamplerMipmapMode::NEAREST,
+                    SamplerAddressMode::CLAMP_TO_EDGE,
+                )
+            },
+        ),
+        (
+            "sampler_llcb_cmp_bw",
+            SamplerDesc {
+                compare_op: Some(CompareOp::GREATER_OR_EQUAL),
+                border_color: BorderColor::FLOAT_OPAQUE_WHITE,
+                ..base(
+                    Filter::LINEAR,
+                    SamplerMipmapMode::LINEAR,
+                    SamplerAddressMode::CLAMP_TO_BORDER,
+                )
+            },
+        ),
+        (
+            "sampler_lncb_bb_a8",
+            SamplerDesc {
+                max_anisotropy: 8,
+                border_color: BorderColor::FLOAT_OPAQUE_BLACK,
+                ..base(
+                    Filter::LINEAR,
+                    SamplerMipmapMode::NEAREST,
+                    SamplerAddressMode::CLAMP_TO_BORDER,
+                )
+            },
+        ),
+    ];
+
+    for (name, expected) in valid.iter() {
+        assert_eq!(parse_sampler_name(name), Ok(*expected), "{}", name);
+    }
+
+    let malformed = [
+        ("lnc", SamplerParseError::MissingPrefix),
+        ("samplerlnc", SamplerParseError::MissingPrefix),
+        ("sampler_", SamplerParseError::InvalidFilter("".into())),
+        (
+            "sampler_xnc",
+            SamplerParseError::InvalidFilter("xnc".into()),
+        ),
+        (
+            "sampler_l",
+            SamplerParseError::InvalidMipmapMode("l".into()),
+        ),
+        (
+            "sampler_lxc",
+            SamplerParseError::InvalidMipmapMode("lxc".into()),
+        ),
+        (
+            "sampler_ln",
+            SamplerParseError::InvalidAddressMode("".into()),
+        ),
+        (
+            "sampler_lnx",
+            SamplerParseError::InvalidAddressMode("x".into()),
+        ),
+        (
+            "sampler_lncc",
+            SamplerParseError::InvalidAddressMode("cc".into()),
+        ),
+        ("sampler_lnc_", SamplerParseError::UnknownSuffix("".into())),
+        (
+            "sampler_lnc_a4",
+            SamplerParseError::UnknownSuffix("a4".into()),
+        ),
+        (
+            "sampler_lnc_CMP",
+            SamplerParseError::UnknownSuffix("CMP".into()),
+        ),
+        (
+            "sampler_lnc_a8_a16",
+            SamplerParseError::ConflictingSuffix("a16".into()),
+        ),
+        (
+            "sampler_lnc_cmp_cmp",
+            SamplerParseError::ConflictingSuffix("cmp".into()),
+        ),
+        (
+            "sampler_lncb_bw_bb",
+            SamplerParseError::ConflictingSuffix("bb".into()),
+        ),
+    ];
+
+    for (name, expected) in malformed.iter() {
+        assert_eq!(parse_sampler_name(name).as_ref(), Err(expected), "{}", name);
+    }
+}
+
+#[test]
+fn test_immutable_sampler_suffix() {
+    assert_eq!(
+        immutable_sampler_suffix("input_tex_sampler_lnc"),
+        Some("sampler_lnc")
+    );
+    assert_eq!(
+        immutable_sampler_suffix("a_sampler_b_sampler_nlr_a8"),
+        Some("sampler_nlr_a8")
+    );
+    // Invalid address modes still get reported by `parse_sampler_name`
+    assert_eq!(
+        immutable_sampler_suffix("input_tex_sampler_llx"),
+        Some("sampler_llx")
+    );
+
+    assert_eq!(immutable_sampler_suffix("input_tex"), None);
+    assert_eq!(immutable_sampler_suffix("sampler_lnc"), None);
+    assert_eq!(immutable_sampler_suffix("supersampler_lnc"), None);
+    assert_eq!(immutable_sampler_suffix("shadow_sampler_count"), None);
+    assert_eq!(immutable_sampler_suffix("shadow_sampler_"), None);
+}
+
+#[test]
+fn test_framebuffer_cache_key_includes_format() {
+    let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+    let rgba8 = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [64, 64]).usage(usage);
+    let rgba16f = rgba8.format(vk::Format::R16G16B16A16_SFLOAT);
+    let rgba8_msaa = rgba8.sample_count(vk::SampleCountFlags::TYPE_4);
+
+    let keys = [
+        FramebufferCacheKey::new([64, 64], std::iter::once(&rgba8), None, std::iter::empty()),
+        FramebufferCacheKey::new(
+            [64, 64],
+            std::iter::once(&rgba16f),
+            None,
+            std::iter::empty(),
+        ),
+        FramebufferCacheKey::new(
+            [64, 64],
+            std::iter::once(&rgba8_msaa),
+            None,
+            std::iter::empty(),
+        ),
+    ];
+
+    let unique: std::collections::HashSet<&FramebufferCacheKey> = keys.iter().collect();
+    assert_eq!(unique.len(), keys.len());
+}
+
+#[test]
+fn test_merge_descriptor_set_layout_flags() {
+    let uab = vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
+    let push = vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR;
+
+    let stage = |stage, flags: Vec<(u32, vk::DescriptorSetLayoutCreateFlags)>| {
+        PipelineShaderDesc::builder(stage)
+            .hlsl_source("/shaders/test.hlsl")
+            .descriptor_set_layout_flags(flags)
+            .build()
+            .unwrap()
+    };
+
+    let vs = stage(ShaderPipelineStage::Vertex, vec![(1, uab)]);
+    let ps = stage(ShaderPipelineStage::Pixel, vec![(1, uab), (2, push)]);
+    let set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS] = [
+        Some((2, Default::default())),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    ];
+
+    let merged = merge_descriptor_set_layout_flags([&vs, &ps], &set_opts).unwrap();
+    let flags_of = |set_index| {
+        merged
+            .iter()
+            .flatten()
+            .find(|(opt_set, _)| *opt_set == set_index)
+            .and_then(|(_, opts)| opts.flags)
+    };
+    assert_eq!(flags_of(1), Some(uab));
+    assert_eq!(flags_of(2), Some(push));
+
+    let conflicting = stage(ShaderPipelineStage::Pixel, vec![(1, push)]);
+    let err = merge_descriptor_set_layout_flags([&vs, &conflicting], &set_opts).unwrap_err();
+    assert!(err.to_string().contains("descriptor set 1"));
+}
@@ -0,0 +1,170 @@
+use ash::vk;
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+/// Identifies a cached module by a hash of its SPIR-V. Modules whose hashes collide
+/// get the next free key.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ShaderModuleKey(u64);
+
+impl ShaderModuleKey {
+    fn new(spirv: &[u32]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        spirv.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+struct CachedShaderModule {
+    module: vk::ShaderModule,
+    /// Compared on lookup, so that colliding hashes don't share a module
+    spirv: Vec<u32>,
+    /// Pipelines created from the module which haven't been released yet
+    users: usize,
+}
+
+/// Shares `vk::ShaderModule`s between pipelines created from identical SPIR-V,
+/// e.g. a vertex shader used with several pixel shaders.
+///
+/// Every `get_or_create` must be paired with a `release` once the pipeline is replaced;
+/// modules without users are destroyed. The rest go together with the device.
+#[derive(Default)]
+pub struct ShaderModuleCache {
+    modules: Mutex<HashMap<ShaderModuleKey, CachedShaderModule>>,
+}
+
+impl ShaderModuleCache {
+    pub fn get_or_create(
+        &self,
+        device: &ash::Device,
+        spirv: &[u32],
+    ) -> Result<(ShaderModuleKey, vk::ShaderModule), vk::Result> {
+        self.get_or_create_with(spirv, |spirv| unsafe {
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(spirv), None)
+        })
+    }
+
+    fn get_or_create_with(
+        &self,
+        spirv: &[u32],
+        create: impl FnOnce(&[u32]) -> Result<vk::ShaderModule, vk::Result>,
+    ) -> Result<(ShaderModuleKey, vk::ShaderModule), vk::Result> {
+        let mut key = ShaderModuleKey::new(spirv);
+        let mut modules = self.modules.lock();
+
+        loop {
+            match modules.get_mut(&key) {
+                Some(cached) if cached.spirv == spirv => {
+                    cached.users += 1;
+                    return Ok((key, cached.module));
+                }
+                Some(_) => key.0 = key.0.wrapping_add(1),
+                None => break,
+            }
+        }
+
+        let module = create(spirv)?;
+        modules.insert(
+            key,
+            CachedShaderModule {
+                module,
+                spirv: spirv.to_vec(),
+                users: 1,
+            },
+        );
+        Ok((key, module))
+    }
+
+    /// Drops a use of each of the modules, destroying the ones no pipeline uses anymore.
+    /// Pipelines don't need their modules after creation, so this doesn't wait for the GPU.
+    pub(crate) fn release(&self, device: &ash::Device, keys: &[ShaderModuleKey]) {
+        self.release_with(keys, |module| unsafe {
+            device.destroy_shader_module(module, None)
+        })
+    }
+
+    fn release_with(&self, keys: &[ShaderModuleKey], mut destroy: impl FnMut(vk::ShaderModule)) {
+        let mut modules = self.modules.lock();
+
+        for key in keys {
+            let cached = match modules.get_mut(key) {
+                Some(cached) => cached,
+                None => continue,
+            };
+
+            cached.users -= 1;
+            if cached.users == 0 {
+                destroy(cached.module);
+                modules.remove(key);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Must only be called once no pipelines are being created from the modules.
+    pub(crate) unsafe fn destroy(&self, device: &ash::Device) {
+        for (_, cached) in self.modules.lock().drain() {
+            device.destroy_shader_module(cached.module, None);
+        }
+    }
+}
+
+#[test]
+fn test_shader_module_cache_dedup() {
+    use ash::vk::Handle as _;
+
+    let cache = ShaderModuleCache::default();
+    let created = std::cell::Cell::new(0u64);
+    let create = |_: &[u32]| {
+        created.set(created.get() + 1);
+        Ok(vk::ShaderModule::from_raw(created.get()))
+    };
+
+    let vs = [0x07230203u32, 1, 2, 3];
+    let ps = [0x07230203u32, 4, 5, 6];
+
+    let (vs_key, first) = cache.get_or_create_with(&vs, create).unwrap();
+    let (_, second) = cache.get_or_create_with(&vs, create).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(created.get(), 1);
+
+    let (ps_key, third) = cache.get_or_create_with(&ps, create).unwrap();
+    assert_ne!(first, third);
+    assert_eq!(created.get(), 2);
+    assert_eq!(cache.len(), 2);
+
+    // The vertex shader has two users, so it's only destroyed with the second release
+    let mut destroyed = Vec::new();
+    cache.release_with(&[vs_key, ps_key], |module| destroyed.push(module));
+    assert_eq!(destroyed, [third]);
+    assert_eq!(cache.len(), 1);
+
+    cache.release_with(&[vs_key], |module| destroyed.push(module));
+    assert_eq!(destroyed, [third, first]);
+    assert!(cache.is_empty());
+
+    // A module whose hash collides with the vertex shader's isn't handed out for it
+    let colliding = vk::ShaderModule::from_raw(100);
+    cache.modules.lock().insert(
+        ShaderModuleKey::new(&vs),
+        CachedShaderModule {
+            module: colliding,
+            spirv: ps.to_vec(),
+            users: 1,
+        },
+    );
+    let (key, module) = cache.get_or_create_with(&vs, create).unwrap();
+    assert_ne!(module, colliding);
+    assert_ne!(key, ShaderModuleKey::new(&vs));
+    assert_eq!(cache.len(), 2);
+}
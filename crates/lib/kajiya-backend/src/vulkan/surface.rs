@@ -1,10 +1,15 @@
+use super::physical_device::PhysicalDevice;
 use anyhow::Result;
 use ash::{extensions::khr, vk};
-use std::sync::Arc;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
 
 pub struct Surface {
     pub(crate) raw: vk::SurfaceKHR,
     pub(crate) fns: khr::Surface,
+    /// Per physical device; these don't change over the surface's lifetime
+    formats: Mutex<HashMap<vk::PhysicalDevice, Vec<vk::SurfaceFormatKHR>>>,
+    present_modes: Mutex<HashMap<vk::PhysicalDevice, Vec<vk::PresentModeKHR>>>,
 }
 
 impl Surface {
@@ -19,6 +24,64 @@ impl Surface {
         Ok(Arc::new(Self {
             raw: surface,
             fns: surface_loader,
+            formats: Default::default(),
+            present_modes: Default::default(),
         }))
     }
+
+    /// Whether `queue_family_index` of `pdevice` can present to this surface.
+    /// Checked when creating swapchains, as only the first surface affects device selection.
+    pub fn supports_queue_family(
+        &self,
+        pdevice: &PhysicalDevice,
+        queue_family_index: u32,
+    ) -> Result<bool> {
+        Ok(unsafe {
+            self.fns
+                .get_physical_device_surface_support(pdevice.raw, queue_family_index, self.raw)
+        }?)
+    }
+
+    /// Not cached, as the current extent changes as the window is resized.
+    pub fn capabilities(&self, pdevice: &PhysicalDevice) -> Result<vk::SurfaceCapabilitiesKHR> {
+        Ok(unsafe {
+            self.fns
+                .get_physical_device_surface_capabilities(pdevice.raw, self.raw)
+        }?)
+    }
+
+    pub fn formats(&self, pdevice: &PhysicalDevice) -> Result<Vec<vk::SurfaceFormatKHR>> {
+        let mut formats = self.formats.lock();
+        if let Some(formats) = formats.get(&pdevice.raw) {
+            return Ok(formats.clone());
+        }
+
+        let queried = unsafe {
+            self.fns
+                .get_physical_device_surface_formats(pdevice.raw, self.raw)
+        }?;
+        Ok(formats.entry(pdevice.raw).or_insert(queried).clone())
+    }
+
+    pub fn present_modes(&self, pdevice: &PhysicalDevice) -> Result<Vec<vk::PresentModeKHR>> {
+        let mut present_modes = self.present_modes.lock();
+        if let Some(present_modes) = present_modes.get(&pdevice.raw) {
+            return Ok(present_modes.clone());
+        }
+
+        let queried = unsafe {
+            self.fns
+                .get_physical_device_surface_present_modes(pdevice.raw, self.raw)
+        }?;
+        Ok(present_modes.entry(pdevice.raw).or_insert(queried).clone())
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        // Swapchains keep their surface alive until they're destroyed
+        unsafe {
+            self.fns.destroy_surface(self.raw, None);
+        }
+    }
 }
@@ -1,17 +1,37 @@
-use super::{device::Device, surface::Surface};
+use super::{
+    device::{Device, PendingResourceReleases, RetiredSwapchain},
+    surface::Surface,
+};
 use anyhow::Result;
 use ash::{extensions::khr, vk};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy)]
 pub struct SwapchainDesc {
     pub format: vk::SurfaceFormatKHR,
     pub dims: vk::Extent2D,
     pub vsync: bool,
+    /// Flags the surface doesn't support are dropped with a warning. `STORAGE` by default,
+    /// for the compute passes writing to the swapchain.
+    pub usage: vk::ImageUsageFlags,
 }
 
+impl Default for SwapchainDesc {
+    fn default() -> Self {
+        Self {
+            format: Default::default(),
+            dims: Default::default(),
+            vsync: false,
+            usage: vk::ImageUsageFlags::STORAGE,
+        }
+    }
+}
+
+/// Owns its images and the semaphores used to acquire and present them, so that several
+/// swapchains, e.g. one per window, can be used with the same `Device` within a frame.
+/// Dropping one destroys it once the GPU is done with the current frame.
 pub struct Swapchain {
     pub(crate) fns: khr::Swapchain,
     pub(crate) raw: vk::SwapchainKHR,
@@ -24,80 +44,136 @@ pub struct Swapchain {
     pub next_semaphore: usize,
 
     // Keep a reference in order not to drop after the device
-    #[allow(dead_code)]
     pub(crate) device: Arc<Device>,
 
-    // Ditto
-    #[allow(dead_code)]
+    // Ditto; also released along with the swapchain
     surface: Arc<Surface>,
 }
 
+// Triple-buffer so that acquiring an image doesn't stall for >16.6ms at 60Hz on AMD
+// when frames take >16.6ms to render. Also allows MAILBOX to work.
+fn select_image_count(capabilities: &vk::SurfaceCapabilitiesKHR) -> u32 {
+    let image_count = 3.max(capabilities.min_image_count);
+
+    if capabilities.max_image_count != 0 {
+        image_count.min(capabilities.max_image_count)
+    } else {
+        image_count
+    }
+}
+
+/// The surface dictates the extent, unless it reports a current width of `u32::MAX`.
+fn select_extent(capabilities: &vk::SurfaceCapabilitiesKHR, dims: vk::Extent2D) -> vk::Extent2D {
+    match capabilities.current_extent.width {
+        std::u32::MAX => dims,
+        _ => capabilities.current_extent,
+    }
+}
+
+/// FIFO is the fallback, as it's the only mode guaranteed to be supported.
+pub fn select_present_mode(
+    present_modes: &[vk::PresentModeKHR],
+    vsync: bool,
+) -> vk::PresentModeKHR {
+    let present_mode_preference = if vsync {
+        [vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO]
+    } else {
+        [vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
+    };
+
+    present_mode_preference
+        .into_iter()
+        .find(|mode| present_modes.contains(mode))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+/// An image acquired by `Swapchain::acquire_next_image`, to be handed back via `Swapchain::present`.
 pub struct SwapchainImage {
+    /// Can be imported into the render graph
     pub image: Arc<crate::Image>,
     pub image_index: u32,
+    /// Signaled once the image can be written; wait on it before the first access
     pub acquire_semaphore: vk::Semaphore,
+    /// One per image, for submissions which the presentation of this image should wait on
     pub rendering_finished_semaphore: vk::Semaphore,
+    /// The swapchain doesn't match the surface exactly anymore. The image can still be
+    /// drawn to and presented, but the swapchain should be recreated.
+    pub suboptimal: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SwapchainError {
+    #[error("Timed out waiting for a swapchain image")]
+    Timeout,
+
+    /// The swapchain must be recreated before it can be used again.
+    #[error("The swapchain is out of date")]
+    OutOfDate,
+
+    /// Returned by `Swapchain::present` when the swapchain doesn't match the surface
+    /// exactly anymore, and should be recreated. Acquiring reports it via `SwapchainImage::suboptimal`.
+    #[error("The swapchain is suboptimal")]
+    Suboptimal,
+
+    #[error("Vulkan error: {0:?}")]
+    Vulkan(vk::Result),
 }
 
-pub enum SwapchainAcquireImageErr {
-    RecreateFramebuffer,
+impl From<vk::Result> for SwapchainError {
+    fn from(err: vk::Result) -> Self {
+        match err {
+            vk::Result::TIMEOUT | vk::Result::NOT_READY => Self::Timeout,
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Self::OutOfDate,
+            vk::Result::SUBOPTIMAL_KHR => Self::Suboptimal,
+            err => Self::Vulkan(err),
+        }
+    }
 }
 
 impl Swapchain {
-    pub fn enumerate_surface_formats(
+    pub fn new(
         device: &Arc<Device>,
-        surface: &Surface,
-    ) -> Result<Vec<vk::SurfaceFormatKHR>> {
-        unsafe {
-            Ok(surface
-                .fns
-                .get_physical_device_surface_formats(device.pdevice.raw, surface.raw)?)
+        surface: &Arc<Surface>,
+        mut desc: SwapchainDesc,
+    ) -> Result<Self> {
+        // When recreating the swapchain (e.g. on resize), framebuffers cached for the old size are stale
+        device.clear_framebuffer_caches();
+
+        let pdevice = device.physical_device();
+
+        // Devices are only selected for presenting to the first surface
+        let present_family = device.present_queue().family.index;
+        if !surface.supports_queue_family(pdevice, present_family)? {
+            anyhow::bail!(
+                "{} can't present to this surface from queue family {}",
+                pdevice.name(),
+                present_family
+            );
         }
-    }
 
-    pub fn new(device: &Arc<Device>, surface: &Arc<Surface>, desc: SwapchainDesc) -> Result<Self> {
-        let surface_capabilities = unsafe {
-            surface
-                .fns
-                .get_physical_device_surface_capabilities(device.pdevice.raw, surface.raw)
-        }?;
-
-        // Triple-buffer so that acquiring an image doesn't stall for >16.6ms at 60Hz on AMD
-        // when frames take >16.6ms to render. Also allows MAILBOX to work.
-        let mut desired_image_count = 3.max(surface_capabilities.min_image_count);
-
-        if surface_capabilities.max_image_count != 0 {
-            desired_image_count = desired_image_count.min(surface_capabilities.max_image_count);
-        }
+        let surface_capabilities = surface.capabilities(pdevice)?;
 
+        let desired_image_count = select_image_count(&surface_capabilities);
         log::info!("Swapchain image count: {}", desired_image_count);
 
-        //dbg!(&surface_capabilities);
-        let surface_resolution = match surface_capabilities.current_extent.width {
-            std::u32::MAX => desc.dims,
-            _ => surface_capabilities.current_extent,
-        };
-
+        let surface_resolution = select_extent(&surface_capabilities, desc.dims);
         if 0 == surface_resolution.width || 0 == surface_resolution.height {
             anyhow::bail!("Swapchain resolution cannot be zero");
         }
 
-        let present_mode_preference = if desc.vsync {
-            vec![vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO]
-        } else {
-            vec![vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
-        };
-
-        let present_modes = unsafe {
-            surface
-                .fns
-                .get_physical_device_surface_present_modes(device.pdevice.raw, surface.raw)
-        }?;
+        let unsupported_usage = desc.usage & !surface_capabilities.supported_usage_flags;
+        if !unsupported_usage.is_empty() {
+            log::warn!(
+                "Swapchain usage {:?} isn't supported by the surface, and won't be available",
+                unsupported_usage
+            );
+            desc.usage &= surface_capabilities.supported_usage_flags;
+        }
+        if desc.usage.is_empty() {
+            anyhow::bail!("None of the requested swapchain usage flags are supported");
+        }
 
-        let present_mode = present_mode_preference
-            .into_iter()
-            .find(|mode| present_modes.contains(mode))
-            .unwrap_or(vk::PresentModeKHR::FIFO);
+        let present_mode = select_present_mode(&surface.present_modes(pdevice)?, desc.vsync);
         log::info!("Presentation mode: {:?}", present_mode);
 
         let pre_transform = if surface_capabilities
@@ -109,14 +185,24 @@ impl Swapchain {
             surface_capabilities.current_transform
         };
 
+        // Shared with the present queue if rendering happens on another family
+        let queue_family_indices: Vec<u32> = match &device.present_queue {
+            Some(present_queue) => vec![
+                device.universal_queue.family.index,
+                present_queue.family.index,
+            ],
+            None => Vec::new(),
+        };
+
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
             .surface(surface.raw)
             .min_image_count(desired_image_count)
             .image_color_space(desc.format.color_space)
             .image_format(desc.format.format)
             .image_extent(surface_resolution)
-            .image_usage(vk::ImageUsageFlags::STORAGE)
-            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .image_usage(desc.usage)
+            .image_sharing_mode(super::device::sharing_mode(&queue_family_indices))
+            .queue_family_indices(&queue_family_indices)
             .pre_transform(pre_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(present_mode)
@@ -165,15 +251,17 @@ impl Swapchain {
                     raw: vk_image,
                     desc: crate::ImageDesc {
                         image_type: crate::ImageType::Tex2d,
-                        usage: vk::ImageUsageFlags::STORAGE,
+                        usage: desc.usage,
                         flags: vk::ImageCreateFlags::empty(),
                         format: vk::Format::B8G8R8A8_UNORM,
                         extent: [desc.dims.width, desc.dims.height, 0],
                         tiling: vk::ImageTiling::OPTIMAL,
                         mip_levels: 1,
                         array_elements: 1,
+                        sample_count: vk::SampleCountFlags::TYPE_1,
                     },
                     views: Default::default(),
+                    allocation: None,
                 })
             })
             .collect();
@@ -219,80 +307,151 @@ impl Swapchain {
         [self.desc.dims.width, self.desc.dims.height]
     }
 
+    /// Waits up to `timeout` for the next image. Acquire semaphores are used round-robin,
+    /// as the image which gets returned isn't known ahead of time.
     pub fn acquire_next_image(
         &mut self,
-    ) -> std::result::Result<SwapchainImage, SwapchainAcquireImageErr> {
+        timeout: Duration,
+    ) -> std::result::Result<SwapchainImage, SwapchainError> {
         puffin::profile_function!();
 
         let acquire_semaphore = self.acquire_semaphores[self.next_semaphore];
-        let rendering_finished_semaphore = self.rendering_finished_semaphores[self.next_semaphore];
-
-        let present_index = unsafe {
-            self.fns.acquire_next_image(
-                self.raw,
-                std::u64::MAX,
-                acquire_semaphore,
-                vk::Fence::null(),
-            )
-        }
-        .map(|(val, _)| val as usize);
-
-        match present_index {
-            Ok(present_index) => {
-                assert_eq!(present_index, self.next_semaphore);
-
-                self.next_semaphore = (self.next_semaphore + 1) % self.images.len();
-                Ok(SwapchainImage {
-                    image: self.images[present_index].clone(),
-                    image_index: present_index as u32,
-                    acquire_semaphore,
-                    rendering_finished_semaphore,
-                })
-            }
-            Err(err)
-                if err == vk::Result::ERROR_OUT_OF_DATE_KHR
-                    || err == vk::Result::SUBOPTIMAL_KHR =>
-            {
-                Err(SwapchainAcquireImageErr::RecreateFramebuffer)
-            }
-            err => {
-                panic!("Could not acquire swapchain image: {:?}", err);
-            }
-        }
+        let timeout_ns = timeout.as_nanos().min(std::u64::MAX as u128) as u64;
+
+        let (image_index, suboptimal) = unsafe {
+            self.fns
+                .acquire_next_image(self.raw, timeout_ns, acquire_semaphore, vk::Fence::null())
+        }?;
+
+        // The semaphore is pending now, so the next acquire must use another one
+        self.next_semaphore = (self.next_semaphore + 1) % self.acquire_semaphores.len();
+
+        Ok(SwapchainImage {
+            image: self.images[image_index as usize].clone(),
+            image_index,
+            acquire_semaphore,
+            rendering_finished_semaphore: self.rendering_finished_semaphores[image_index as usize],
+            suboptimal,
+        })
     }
 
-    pub fn present_image(&self, image: SwapchainImage) {
+    /// Queues `image` for presentation once `wait_semaphore` is signaled.
+    pub fn present(
+        &self,
+        image: SwapchainImage,
+        wait_semaphore: vk::Semaphore,
+    ) -> std::result::Result<(), SwapchainError> {
         puffin::profile_function!();
 
         let present_info = vk::PresentInfoKHR::builder()
-            .wait_semaphores(std::slice::from_ref(&image.rendering_finished_semaphore))
+            .wait_semaphores(std::slice::from_ref(&wait_semaphore))
             .swapchains(std::slice::from_ref(&self.raw))
             .image_indices(std::slice::from_ref(&image.image_index));
 
-        unsafe {
-            match self
-                .fns
-                .queue_present(self.device.universal_queue.raw, &present_info)
-            {
-                Ok(_) => (),
-                Err(err)
-                    if err == vk::Result::ERROR_OUT_OF_DATE_KHR
-                        || err == vk::Result::SUBOPTIMAL_KHR =>
-                {
-                    // Handled in the next frame
-                }
-                err => {
-                    panic!("Could not present image: {:?}", err);
-                }
-            }
+        let suboptimal = unsafe {
+            self.device
+                .present_queue()
+                .present(&self.fns, &present_info)
+        }?;
+
+        if suboptimal {
+            Err(SwapchainError::Suboptimal)
+        } else {
+            Ok(())
         }
     }
 }
 
 impl Drop for Swapchain {
     fn drop(&mut self) {
-        unsafe {
-            self.fns.destroy_swapchain(self.raw, None);
-        }
+        // The last frame may still be writing to, or presenting, the images
+        let image_views = self
+            .images
+            .iter()
+            .flat_map(|image| {
+                image
+                    .views
+                    .lock()
+                    .drain()
+                    .map(|(_, view)| view)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        self.device
+            .defer_release_swapchain(PendingResourceReleases {
+                semaphores: std::mem::take(&mut self.acquire_semaphores)
+                    .into_iter()
+                    .chain(std::mem::take(&mut self.rendering_finished_semaphores))
+                    .collect(),
+                image_views,
+                swapchains: vec![RetiredSwapchain {
+                    fns: self.fns.clone(),
+                    raw: self.raw,
+                    surface: self.surface.clone(),
+                }],
+                ..Default::default()
+            });
     }
 }
+
+#[test]
+fn test_swapchain_selection() {
+    // Captured from a Windows desktop with an NVIDIA GPU
+    let capabilities = vk::SurfaceCapabilitiesKHR {
+        min_image_count: 2,
+        max_image_count: 8,
+        current_extent: vk::Extent2D {
+            width: 1920,
+            height: 1080,
+        },
+        ..Default::default()
+    };
+    let present_modes = [
+        vk::PresentModeKHR::FIFO,
+        vk::PresentModeKHR::FIFO_RELAXED,
+        vk::PresentModeKHR::MAILBOX,
+        vk::PresentModeKHR::IMMEDIATE,
+    ];
+    let dims = vk::Extent2D {
+        width: 1280,
+        height: 720,
+    };
+
+    assert_eq!(select_image_count(&capabilities), 3);
+    assert_eq!(
+        select_extent(&capabilities, dims),
+        capabilities.current_extent
+    );
+    assert_eq!(
+        select_present_mode(&present_modes, true),
+        vk::PresentModeKHR::FIFO_RELAXED
+    );
+    assert_eq!(
+        select_present_mode(&present_modes, false),
+        vk::PresentModeKHR::MAILBOX
+    );
+
+    // Captured from Wayland, which leaves the extent and image count up to the app
+    let capabilities = vk::SurfaceCapabilitiesKHR {
+        min_image_count: 4,
+        max_image_count: 0,
+        current_extent: vk::Extent2D {
+            width: u32::MAX,
+            height: u32::MAX,
+        },
+        ..Default::default()
+    };
+    let present_modes = [vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::FIFO];
+
+    assert_eq!(select_image_count(&capabilities), 4);
+    assert_eq!(select_extent(&capabilities, dims), dims);
+    assert_eq!(
+        select_present_mode(&present_modes, true),
+        vk::PresentModeKHR::FIFO
+    );
+    assert_eq!(
+        select_present_mode(&[vk::PresentModeKHR::FIFO], false),
+        vk::PresentModeKHR::FIFO
+    );
+}
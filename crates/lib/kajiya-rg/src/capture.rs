@@ -0,0 +1,179 @@
+use crate::{
+    graph::{GraphResourceCreateInfo, GraphResourceImportInfo, GraphResourceInfo},
+    resource::GraphRawResourceHandle,
+    GraphResourceDesc, Handle, Image, ImageDesc, RenderGraph,
+};
+use kajiya_backend::{ash::vk, vulkan::image::try_format_texel_size_bytes};
+use parking_lot::Mutex;
+use std::{marker::PhantomData, sync::Arc};
+
+/// An image read back by `RenderGraph::capture_images`.
+#[derive(Clone, Debug)]
+pub struct CapturedImageInfo {
+    /// As used in logs, e.g. `ssgi.raw#12`
+    pub name: String,
+    pub desc: ImageDesc,
+    /// The pass which last wrote the image, or `None` for imported images the graph only reads
+    pub last_written_by: Option<String>,
+}
+
+impl RenderGraph {
+    /// Reads back every image in the graph after its final write, for frame captures.
+    /// Call once all other passes have been added.
+    ///
+    /// Returns the captured images. `callback` receives the index of each into them
+    /// along with its data, in the layout delivered by `readback`. Images whose extents are
+    /// relative to the output, whose formats can't be read back, which are multisampled,
+    /// only written by conditional passes, or imported without `TRANSFER_SRC` usage
+    /// are skipped with a warning.
+    pub fn capture_images(
+        &mut self,
+        callback: impl FnMut(usize, &[u8]) + Send + 'static,
+    ) -> Vec<CapturedImageInfo> {
+        // The pass and version of each resource's last write
+        let mut last_writes: Vec<Option<(usize, u32)>> = vec![None; self.resources.len()];
+        let mut unconditionally_written: Vec<bool> = vec![false; self.resources.len()];
+
+        for (pass_idx, pass) in self.passes.iter().enumerate() {
+            for res in &pass.write {
+                let id = res.handle.id as usize;
+                last_writes[id] = Some((pass_idx, res.handle.version + 1));
+                unconditionally_written[id] |= pass.predicate.is_none();
+            }
+        }
+
+        let mut captured: Vec<(Handle<Image>, CapturedImageInfo)> = Vec::new();
+
+        for (id, resource) in self.resources.iter().enumerate() {
+            let name = self.resource_debug_name(id as u32);
+
+            let desc = match resource {
+                GraphResourceInfo::Created(GraphResourceCreateInfo {
+                    desc: GraphResourceDesc::Image(desc),
+                    extent,
+                    ..
+                }) => {
+                    if last_writes[id].is_none() {
+                        continue;
+                    }
+
+                    if extent.is_some() {
+                        log::warn!(
+                            "Frame capture skips {}: its extent is relative to the output",
+                            name
+                        );
+                        continue;
+                    }
+
+                    if !unconditionally_written[id] {
+                        log::warn!(
+                            "Frame capture skips {}: it's only written by conditional passes",
+                            name
+                        );
+                        continue;
+                    }
+
+                    *desc
+                }
+                GraphResourceInfo::Imported(GraphResourceImportInfo::Image {
+                    resource, ..
+                }) => {
+                    if !resource
+                        .desc
+                        .usage
+                        .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+                    {
+                        log::warn!(
+                            "Frame capture skips {}: it's imported without TRANSFER_SRC usage",
+                            name
+                        );
+                        continue;
+                    }
+
+                    resource.desc
+                }
+                _ => continue,
+            };
+
+            if try_format_texel_size_bytes(desc.format).is_none() {
+                log::warn!(
+                    "Frame capture skips {}: {:?} can't be read back",
+                    name,
+                    desc.format
+                );
+                continue;
+            }
+
+            if desc.sample_count != vk::SampleCountFlags::TYPE_1 {
+                log::warn!("Frame capture skips {}: it's multisampled", name);
+                continue;
+            }
+
+            let handle = Handle {
+                raw: GraphRawResourceHandle {
+                    id: id as u32,
+                    version: last_writes[id].map_or(0, |(_, version)| version),
+                },
+                desc,
+                marker: PhantomData,
+                graph_id: self.graph_id,
+            };
+
+            captured.push((
+                handle,
+                CapturedImageInfo {
+                    name,
+                    desc,
+                    last_written_by: last_writes[id]
+                        .map(|(pass_idx, _)| self.passes[pass_idx].name.clone()),
+                },
+            ));
+        }
+
+        let callback = Arc::new(Mutex::new(callback));
+
+        captured
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (handle, info))| {
+                let callback = callback.clone();
+                self.readback(&handle, move |data| (*callback.lock())(idx, data));
+                info
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_capture_images() {
+    use crate::ImageExtent;
+    use kajiya_backend::vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut lit = rg.create_named(desc, "lit");
+    let _unwritten = rg.create(desc);
+    let mut relative = rg.create_image(desc, ImageExtent::RelativeToOutput { scale: [0.5, 0.5] });
+    let mut depth = rg.create_named(desc.format(vk::Format::D24_UNORM_S8_UINT), "depth");
+
+    {
+        let mut pass = rg.add_pass("draw");
+        pass.write(&mut depth, AccessType::General);
+        pass.write(&mut lit, AccessType::ComputeShaderWrite);
+    }
+    {
+        let mut pass = rg.add_pass("post");
+        pass.write(&mut lit, AccessType::ComputeShaderWrite);
+        pass.write(&mut relative, AccessType::ComputeShaderWrite);
+    }
+
+    let captured = rg.capture_images(|_, _| {});
+
+    assert_eq!(captured.len(), 1);
+    assert_eq!(captured[0].name, "lit#0");
+    assert_eq!(captured[0].last_written_by.as_deref(), Some("post"));
+
+    // The readback reads the last version
+    assert!(rg.validation_errors().is_empty());
+    assert_eq!(rg.passes.last().unwrap().read[0].handle, lit.raw);
+}
@@ -0,0 +1,197 @@
+use crate::{Handle, RenderGraph};
+use kajiya_backend::{vk_sync::AccessType, Device, Image};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// An image kept alive after the frame it was rendered in, e.g. as temporal history.
+/// Created by `TemporalRenderGraph::export_image`, filled in once that frame's graph retires,
+/// and imported into later graphs with `RenderGraph::import_exported_image`.
+///
+/// Dropping it without importing it again releases the image via `Device::defer_release_image`,
+/// as soon as no graph which imported it is around anymore.
+pub struct ExportedImage(pub(crate) Arc<ExportedImageState>);
+
+impl ExportedImage {
+    /// The image, and the access type it was left in; `None` until the frame has retired.
+    pub fn image(&self) -> Option<(Arc<Image>, AccessType)> {
+        self.0.image.lock().clone()
+    }
+}
+
+/// Shared by the `ExportedImage` and the graphs exporting or importing it.
+pub(crate) struct ExportedImageState {
+    device: Arc<Device>,
+    image: Mutex<Option<(Arc<Image>, AccessType)>>,
+}
+
+impl ExportedImageState {
+    pub(crate) fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            image: Mutex::new(None),
+        }
+    }
+
+    /// Called when the graph which exported the image retires.
+    pub(crate) fn set(&self, image: Arc<Image>, access_type: AccessType) {
+        let previous = self.image.lock().replace((image, access_type));
+
+        // Re-executions of retained graphs export a new image every time
+        if let Some((previous, _)) = previous {
+            self.release(previous);
+        }
+    }
+
+    fn release(&self, image: Arc<Image>) {
+        match Arc::try_unwrap(image) {
+            // Deferred, as frames in flight may still use it
+            Ok(image) => self.device.defer_release_image(image),
+            Err(image) => log::warn!(
+                "Leaking exported image {:?}, which is still in use",
+                image.desc
+            ),
+        }
+    }
+}
+
+impl Drop for ExportedImageState {
+    fn drop(&mut self) {
+        if let Some((image, _)) = self.image.get_mut().take() {
+            self.release(image);
+        }
+    }
+}
+
+impl RenderGraph {
+    /// Imports an image exported by an earlier frame, in the access type that frame left it in.
+    /// Panics if the frame hasn't retired yet.
+    pub fn import_exported_image(&mut self, exported: &ExportedImage) -> Handle<Image> {
+        let (image, access_type) = exported
+            .image()
+            .expect("The frame which exported the image hasn't retired yet");
+
+        self.imported_exported_images.push(exported.0.clone());
+        self.import(image, access_type)
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn test_exported_image_ping_pong() {
+    use crate::{
+        imageops,
+        renderer::{FrameConstantsLayout, Renderer},
+        ImageDesc,
+    };
+    use kajiya_backend::{
+        ash::vk,
+        vulkan::{RenderBackend, RenderBackendConfig},
+    };
+
+    const FRAME_COUNT: usize = 10;
+    const WIDTH: u32 = 16;
+
+    let backend = RenderBackend::new_headless(RenderBackendConfig {
+        swapchain_extent: [WIDTH, 1],
+        vsync: false,
+        graphics_debugging: false,
+        device_index: None,
+    })
+    .unwrap();
+    let mut renderer = Renderer::new(&backend).unwrap();
+
+    let desc = ImageDesc::new_2d(vk::Format::R32_UINT, [WIDTH, 1]);
+    let mut history: Option<ExportedImage> = None;
+    let result: Arc<Mutex<Option<Vec<u8>>>> = Default::default();
+
+    // Every frame copies the previous one's output, and sets the texel at its own index
+    for frame in 0..FRAME_COUNT {
+        renderer
+            .prepare_frame(|rg| {
+                let mut output = rg.create(desc);
+                match &history {
+                    Some(history) => {
+                        let previous = rg.import_exported_image(history);
+                        imageops::copy_image(rg, &previous, &mut output);
+                    }
+                    None => imageops::clear_color(rg, &mut output, [0.0; 4]),
+                }
+
+                let marker =
+                    rg.upload_buffer(&1u32.to_ne_bytes(), vk::BufferUsageFlags::TRANSFER_SRC);
+
+                let mut pass = rg.add_pass("mark frame");
+                let marker_ref = pass.read(&marker, AccessType::TransferRead);
+                let output_ref = pass.write(&mut output, AccessType::TransferWrite);
+                pass.render(move |api| {
+                    let marker = api.resources.buffer(marker_ref);
+                    let output = api.resources.image(output_ref);
+
+                    unsafe {
+                        api.device().raw.cmd_copy_buffer_to_image(
+                            api.cb.raw,
+                            marker.raw,
+                            output.raw,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            &[vk::BufferImageCopy {
+                                image_subresource: vk::ImageSubresourceLayers {
+                                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                                    mip_level: 0,
+                                    base_array_layer: 0,
+                                    layer_count: 1,
+                                },
+                                image_offset: vk::Offset3D {
+                                    x: frame as i32,
+                                    y: 0,
+                                    z: 0,
+                                },
+                                image_extent: vk::Extent3D {
+                                    width: 1,
+                                    height: 1,
+                                    depth: 1,
+                                },
+                                ..Default::default()
+                            }],
+                        );
+                    }
+
+                    Ok(())
+                });
+
+                if frame + 1 == FRAME_COUNT {
+                    let result = result.clone();
+                    rg.readback(&output, move |data| {
+                        *result.lock() = Some(data.to_vec());
+                    });
+                }
+
+                // Replaces last frame's image, which is released once this frame's graph is done
+                history = Some(rg.export_image(output, AccessType::TransferRead));
+            })
+            .unwrap();
+
+        renderer.draw_frame_offscreen(
+            |_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            },
+            [WIDTH, 1],
+        );
+    }
+
+    renderer.flush_readbacks();
+    assert!(history.unwrap().image().is_some());
+
+    let texels: Vec<u32> = result
+        .lock()
+        .take()
+        .unwrap()
+        .chunks_exact(4)
+        .map(|texel| u32::from_ne_bytes(texel.try_into().unwrap()))
+        .collect();
+
+    let mut expected = vec![0u32; WIDTH as usize];
+    expected[..FRAME_COUNT].fill(1);
+    assert_eq!(texels, expected);
+}
@@ -1,6 +1,10 @@
 #![allow(unused_imports)]
 
-use crate::{renderer::FrameConstantsLayout, resource_registry::PendingRenderResourceInfo};
+use crate::{
+    exported_image::ExportedImageState, renderer::FrameConstantsLayout,
+    resource_registry::PendingRenderResourceInfo, ReadbackQueue, TransientMemoryStatistics,
+    TransientResourceMemory,
+};
 
 use super::{
     pass_builder::PassBuilder,
@@ -11,6 +15,7 @@ use super::{
     RenderPassApi,
 };
 
+use anyhow::Context as _;
 use kajiya_backend::{
     ash::{
         extensions::khr::Swapchain,
@@ -26,10 +31,10 @@ use kajiya_backend::{
     vk_sync,
     vulkan::{
         barrier::{
-            get_access_info, image_aspect_mask_from_access_type_and_format, record_image_barrier,
-            ImageBarrier,
+            get_access_info, get_combined_access_info,
+            image_aspect_mask_from_access_type_and_format,
         },
-        device::{CommandBuffer, Device},
+        device::{AsyncComputeFrame, CommandBuffer, Device},
         image::ImageViewDesc,
         profiler::VkProfilerData,
         ray_tracing::{RayTracingAcceleration, RayTracingPipelineDesc},
@@ -39,17 +44,24 @@ use kajiya_backend::{
 };
 use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     ffi::CString,
     hash::Hash,
     marker::PhantomData,
     path::{Path, PathBuf},
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+    },
 };
 
 #[derive(Clone)]
 pub(crate) struct GraphResourceCreateInfo {
     pub desc: GraphResourceDesc,
+    /// Debug name, applied to the Vulkan object. Doesn't need to be unique.
+    pub name: Option<String>,
+    /// Replaces the extent of an image desc when the graph executes. See `RenderGraph::create_image`.
+    pub extent: Option<ImageExtent>,
 }
 
 #[derive(Clone)]
@@ -66,7 +78,8 @@ pub(crate) enum GraphResourceImportInfo {
         resource: Arc<RayTracingAcceleration>,
         access_type: vk_sync::AccessType,
     },
-    SwapchainImage,
+    /// Index into the swapchains the frame is drawn to
+    SwapchainImage { output: usize },
 }
 
 #[derive(Clone)]
@@ -78,13 +91,15 @@ pub(crate) enum GraphResourceInfo {
 pub(crate) enum ExportableGraphResource {
     Image(Handle<Image>),
     Buffer(Handle<Buffer>),
+    RayTracingAcceleration(Handle<RayTracingAcceleration>),
 }
 
 impl ExportableGraphResource {
-    fn raw(&self) -> GraphRawResourceHandle {
+    pub(crate) fn raw(&self) -> GraphRawResourceHandle {
         match self {
             ExportableGraphResource::Image(h) => h.raw,
             ExportableGraphResource::Buffer(h) => h.raw,
+            ExportableGraphResource::RayTracingAcceleration(h) => h.raw,
         }
     }
 }
@@ -128,9 +143,9 @@ pub struct GraphDebugHook {
 }
 
 pub struct RenderGraph {
-    passes: Vec<RecordedPass>,
-    resources: Vec<GraphResourceInfo>,
-    exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    pub(crate) passes: Vec<RecordedPass>,
+    pub(crate) resources: Vec<GraphResourceInfo>,
+    pub(crate) exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
     pub(crate) compute_pipelines: Vec<RgComputePipeline>,
     pub(crate) raster_pipelines: Vec<RgRasterPipeline>,
     pub(crate) rt_pipelines: Vec<RgRtPipeline>,
@@ -138,8 +153,22 @@ pub struct RenderGraph {
 
     pub debug_hook: Option<GraphDebugHook>,
     pub debugged_resource: Option<Handle<Image>>,
+    /// When set, the graph is written to this file in the DOT format when compiled
+    pub graphviz_dump_path: Option<PathBuf>,
+    /// Check resource accesses with `validation_errors` when compiled, panicking on misuse.
+    /// Enabled by default in debug builds.
+    pub validate: bool,
+    /// Unique per graph, so that handles from other graphs can be caught
+    pub(crate) graph_id: u32,
+    /// Ids of images exported with `TemporalRenderGraph::export_image`, filled in on retirement
+    pub(crate) exported_images: Vec<(u32, Arc<ExportedImageState>)>,
+    /// Kept alive while the graph refers to their images. Declared after `resources`,
+    /// so that those references are gone by the time the images may get released.
+    pub(crate) imported_exported_images: Vec<Arc<ExportedImageState>>,
 }
 
+static NEXT_GRAPH_ID: AtomicU32 = AtomicU32::new(0);
+
 pub trait ImportExportToRenderGraph
 where
     Self: Resource + Sized,
@@ -181,6 +210,7 @@ impl ImportExportToRenderGraph for Image {
             raw: res,
             desc,
             marker: PhantomData,
+            graph_id: rg.graph_id,
         }
     }
 
@@ -223,6 +253,7 @@ impl ImportExportToRenderGraph for Buffer {
             raw: res,
             desc,
             marker: PhantomData,
+            graph_id: rg.graph_id,
         }
     }
 
@@ -265,15 +296,24 @@ impl ImportExportToRenderGraph for RayTracingAcceleration {
             raw: res,
             desc,
             marker: PhantomData,
+            graph_id: rg.graph_id,
         }
     }
 
     fn export(
-        _resource: Handle<Self>,
-        _rg: &mut RenderGraph,
-        _access_type: vk_sync::AccessType,
+        resource: Handle<Self>,
+        rg: &mut RenderGraph,
+        access_type: vk_sync::AccessType,
     ) -> ExportedHandle<Self> {
-        todo!()
+        let res = ExportedHandle {
+            raw: resource.raw,
+            marker: PhantomData,
+        };
+        rg.exported_resources.push((
+            ExportableGraphResource::RayTracingAcceleration(resource),
+            access_type,
+        ));
+        res
     }
 }
 
@@ -307,6 +347,11 @@ impl RenderGraph {
             predefined_descriptor_set_layouts: HashMap::new(),
             debug_hook: None,
             debugged_resource: None,
+            graphviz_dump_path: None,
+            validate: cfg!(debug_assertions),
+            graph_id: NEXT_GRAPH_ID.fetch_add(1, Ordering::Relaxed),
+            exported_images: Vec::new(),
+            imported_exported_images: Vec::new(),
         }
     }
 
@@ -314,20 +359,79 @@ impl RenderGraph {
         &mut self,
         desc: Desc,
     ) -> Handle<<Desc as ResourceDesc>::Resource>
+    where
+        Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>,
+    {
+        self.create_impl(desc, None)
+    }
+
+    /// Like `create`, with a name shown in debuggers and render graph logs.
+    pub fn create_named<Desc: ResourceDesc>(
+        &mut self,
+        desc: Desc,
+        name: impl Into<String>,
+    ) -> Handle<<Desc as ResourceDesc>::Resource>
+    where
+        Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>,
+    {
+        self.create_impl(desc, Some(name.into()))
+    }
+
+    fn create_impl<Desc: ResourceDesc>(
+        &mut self,
+        desc: Desc,
+        name: Option<String>,
+    ) -> Handle<<Desc as ResourceDesc>::Resource>
     where
         Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>,
     {
         let handle: Handle<<Desc as ResourceDesc>::Resource> = Handle {
             raw: self.create_raw_resource(GraphResourceCreateInfo {
                 desc: desc.clone().into(),
+                name,
+                extent: None,
             }),
             desc: TypeEquals::same(desc),
             marker: PhantomData,
+            graph_id: self.graph_id,
         };
 
         handle
     }
 
+    /// Creates an image whose extent may be relative to the output resolution, replacing
+    /// that of `desc`. Relative extents are only known once the graph executes; the handle's desc
+    /// keeps the extent of `desc`, so passes should use the image's actual desc instead,
+    /// e.g. through `SimpleRenderPass::dispatch_image_extent` or `ResourceRegistry::image_desc`.
+    pub fn create_image(&mut self, desc: ImageDesc, extent: ImageExtent) -> Handle<Image> {
+        Handle {
+            raw: self.create_raw_resource(GraphResourceCreateInfo {
+                desc: desc.into(),
+                name: None,
+                extent: Some(extent),
+            }),
+            desc,
+            marker: PhantomData,
+            graph_id: self.graph_id,
+        }
+    }
+
+    /// Resource name for logs, e.g. `ssgi.raw#12`, or just `#12` for unnamed resources.
+    pub(crate) fn resource_debug_name(&self, id: u32) -> String {
+        match &self.resources[id as usize] {
+            GraphResourceInfo::Created(GraphResourceCreateInfo {
+                name: Some(name), ..
+            }) => format!("{}#{}", name, id),
+            GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage { output: 0 }) => {
+                format!("swapchain#{}", id)
+            }
+            GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage { output }) => {
+                format!("swapchain{}#{}", output, id)
+            }
+            _ => format!("#{}", id),
+        }
+    }
+
     pub(crate) fn create_raw_resource(
         &mut self,
         info: GraphResourceCreateInfo,
@@ -349,6 +453,29 @@ impl RenderGraph {
         ImportExportToRenderGraph::import(resource, self, access_type_at_import_time)
     }
 
+    /// Imports a buffer owned by the application, such as a vertex buffer or the bindless
+    /// material table. Nothing is allocated, and the buffer isn't aliased with transients.
+    /// Passes using it are synchronized against `access_type_at_import_time`; the access type
+    /// it's left in can be retrieved by exporting it, to be passed to the next import.
+    pub fn import_buffer(
+        &mut self,
+        buffer: Arc<Buffer>,
+        access_type_at_import_time: vk_sync::AccessType,
+    ) -> Handle<Buffer> {
+        self.import(buffer, access_type_at_import_time)
+    }
+
+    /// Imports an acceleration structure built outside of the graph, e.g. a TLAS, so that passes
+    /// tracing against it are ordered after its build. Having no layouts, it's only synchronized
+    /// with memory barriers.
+    pub fn import_acceleration_structure(
+        &mut self,
+        acceleration_structure: Arc<RayTracingAcceleration>,
+        access_type_at_import_time: vk_sync::AccessType,
+    ) -> Handle<RayTracingAcceleration> {
+        self.import(acceleration_structure, access_type_at_import_time)
+    }
+
     pub fn export<Res: ImportExportToRenderGraph>(
         &mut self,
         resource: Handle<Res>,
@@ -357,14 +484,45 @@ impl RenderGraph {
         ImportExportToRenderGraph::export(resource, self, access_type)
     }
 
+    /// The desc a resource was created or imported with.
+    pub fn resource_desc<Res: Resource>(&self, handle: &Handle<Res>) -> GraphResourceDesc {
+        self.check_handle(handle);
+
+        match &self.resources[handle.raw.id as usize] {
+            GraphResourceInfo::Created(create_info) => create_info.desc,
+            GraphResourceInfo::Imported(GraphResourceImportInfo::Image { resource, .. }) => {
+                resource.desc.into()
+            }
+            GraphResourceInfo::Imported(GraphResourceImportInfo::Buffer { resource, .. }) => {
+                resource.desc.into()
+            }
+            GraphResourceInfo::Imported(_) => handle.desc.clone().into(),
+        }
+    }
+
+    /// Catches handles from other graphs, e.g. ones kept around from the previous frame.
+    pub(crate) fn check_handle<Res: Resource>(&self, handle: &Handle<Res>) {
+        debug_assert_eq!(
+            handle.graph_id, self.graph_id,
+            "Handle to resource #{} belongs to a different render graph",
+            handle.raw.id
+        );
+    }
+
     pub fn get_swap_chain(&mut self) -> Handle<Image> {
+        self.get_swap_chain_output(0)
+    }
+
+    /// The image of the `output`-th swapchain passed to `Renderer::draw_frame_to_swapchains`,
+    /// e.g. for a second window. Output 0 is the one `get_swap_chain` returns.
+    pub fn get_swap_chain_output(&mut self, output: usize) -> Handle<Image> {
         let res = GraphRawResourceHandle {
             id: self.resources.len() as u32,
             version: 0,
         };
 
         self.resources.push(GraphResourceInfo::Imported(
-            GraphResourceImportInfo::SwapchainImage,
+            GraphResourceImportInfo::SwapchainImage { output },
         ));
 
         Handle {
@@ -372,20 +530,21 @@ impl RenderGraph {
             // TODO: size
             desc: ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [1, 1]),
             marker: PhantomData,
+            graph_id: self.graph_id,
         }
     }
 }
 
 #[derive(Debug)]
-struct ResourceLifetime {
-    //first_access: Option<usize>,
-    last_access: Option<usize>,
+pub(crate) struct ResourceLifetime {
+    pub first_access: Option<usize>,
+    pub last_access: Option<usize>,
 }
 
-struct ResourceInfo {
-    _lifetimes: Vec<ResourceLifetime>,
-    image_usage_flags: Vec<vk::ImageUsageFlags>,
-    buffer_usage_flags: Vec<vk::BufferUsageFlags>,
+pub(crate) struct ResourceInfo {
+    pub lifetimes: Vec<ResourceLifetime>,
+    pub image_usage_flags: Vec<vk::ImageUsageFlags>,
+    pub buffer_usage_flags: Vec<vk::BufferUsageFlags>,
 }
 
 pub struct RenderGraphExecutionParams<'a> {
@@ -394,6 +553,9 @@ pub struct RenderGraphExecutionParams<'a> {
     pub frame_descriptor_set: vk::DescriptorSet,
     pub frame_constants_layout: FrameConstantsLayout,
     pub profiler_data: &'a VkProfilerData,
+    pub readbacks: &'a Mutex<ReadbackQueue>,
+    /// Resolution which `ImageExtent::RelativeToOutput` is relative to
+    pub output_extent: [u32; 2],
 }
 
 pub struct RenderGraphPipelines {
@@ -413,27 +575,28 @@ struct PendingDebugPass {
 }
 
 impl RenderGraph {
-    pub fn add_pass<'s>(&'s mut self, name: &str) -> PassBuilder<'s> {
+    pub fn add_pass<'s>(&'s mut self, name: impl Into<String>) -> PassBuilder<'s> {
         let pass_idx = self.passes.len();
 
         PassBuilder {
             rg: self,
             pass_idx,
-            pass: Some(RecordedPass::new(name, pass_idx)),
+            pass: Some(RecordedPass::new(name.into(), pass_idx)),
         }
     }
 
-    fn calculate_resource_info(&self) -> ResourceInfo {
+    /// Lifetimes are in indices into `passes`.
+    pub(crate) fn calculate_resource_info(&self, passes: &[&RecordedPass]) -> ResourceInfo {
         let mut lifetimes: Vec<ResourceLifetime> = self
             .resources
             .iter()
             .map(|res| match res {
                 GraphResourceInfo::Created(_) => ResourceLifetime {
-                    //first_access: None,
+                    first_access: None,
                     last_access: None,
                 },
                 GraphResourceInfo::Imported(_) => ResourceLifetime {
-                    //first_access: Some(0),
+                    first_access: Some(0),
                     last_access: Some(0),
                 },
             })
@@ -463,17 +626,23 @@ impl RenderGraph {
             }
         }
 
-        for (pass_idx, pass) in self.passes.iter().enumerate() {
+        for (pass_idx, pass) in passes.iter().enumerate() {
             for res_access in pass.read.iter().chain(pass.write.iter()) {
                 let resource_index = res_access.handle.id as usize;
                 let res = &mut lifetimes[resource_index];
+                res.first_access.get_or_insert(pass_idx);
                 res.last_access = Some(
                     res.last_access
                         .map(|last_access| last_access.max(pass_idx))
                         .unwrap_or(pass_idx),
                 );
 
-                let access_mask = get_access_info(res_access.access.access_type).access_mask;
+                let access_masks = res_access
+                    .access
+                    .access_types
+                    .as_slice()
+                    .iter()
+                    .map(|access_type| get_access_info(*access_type).access_mask);
 
                 match &self.resources[resource_index] {
                     // Images
@@ -482,9 +651,12 @@ impl RenderGraph {
                         ..
                     })
                     | GraphResourceInfo::Imported(GraphResourceImportInfo::Image { .. })
-                    | GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage) => {
-                        let image_usage: vk::ImageUsageFlags =
-                            image_access_mask_to_usage_flags(access_mask);
+                    | GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage {
+                        ..
+                    }) => {
+                        let image_usage: vk::ImageUsageFlags = access_masks
+                            .map(image_access_mask_to_usage_flags)
+                            .fold(vk::ImageUsageFlags::empty(), |a, b| a | b);
 
                         image_usage_flags[res_access.handle.id as usize] |= image_usage;
                     }
@@ -495,8 +667,9 @@ impl RenderGraph {
                         ..
                     })
                     | GraphResourceInfo::Imported(GraphResourceImportInfo::Buffer { .. }) => {
-                        let buffer_usage: vk::BufferUsageFlags =
-                            buffer_access_mask_to_usage_flags(access_mask);
+                        let buffer_usage: vk::BufferUsageFlags = access_masks
+                            .map(buffer_access_mask_to_usage_flags)
+                            .fold(vk::BufferUsageFlags::empty(), |a, b| a | b);
 
                         buffer_usage_flags[res_access.handle.id as usize] |= buffer_usage;
                     }
@@ -519,7 +692,8 @@ impl RenderGraph {
 
         for (res, access_type) in &self.exported_resources {
             let raw_id = res.raw().id as usize;
-            lifetimes[raw_id].last_access = Some(self.passes.len().saturating_sub(1));
+            lifetimes[raw_id].last_access = Some(passes.len().saturating_sub(1));
+            lifetimes[raw_id].first_access.get_or_insert(0);
 
             if *access_type != vk_sync::AccessType::Nothing {
                 let access_mask = get_access_info(*access_type).access_mask;
@@ -532,29 +706,311 @@ impl RenderGraph {
                         buffer_usage_flags[raw_id] |=
                             buffer_access_mask_to_usage_flags(access_mask);
                     }
+                    ExportableGraphResource::RayTracingAcceleration(_) => {}
                 }
             }
         }
 
         ResourceInfo {
-            _lifetimes: lifetimes,
+            lifetimes,
             image_usage_flags,
             buffer_usage_flags,
         }
     }
 
-    pub fn compile(self, pipeline_cache: &mut PipelineCache) -> CompiledRenderGraph {
-        let resource_info = self.calculate_resource_info();
+    /// Removes passes which don't contribute to any exported resource, the swapchain,
+    /// or other imported resources. Passes which don't write anything are kept,
+    /// as are ones marked with `PassBuilder::no_cull`, and ones which kept passes
+    /// are ordered after with `PassBuilder::after`.
+    fn cull_passes(&mut self) {
+        let mut live_passes = self.live_passes().into_iter();
+        self.passes.retain(|pass| {
+            let live = live_passes.next().unwrap();
+            if !live {
+                log::debug!("Culling render pass {:?}", pass.name);
+            }
+            live
+        });
+    }
+
+    /// For each pass, whether `cull_passes` keeps it.
+    pub(crate) fn live_passes(&self) -> Vec<bool> {
+        let mut needed_resources: HashSet<u32> = self
+            .exported_resources
+            .iter()
+            .map(|(res, _)| res.raw().id)
+            .collect();
+
+        // `RecordedPass::idx` of passes which live passes are ordered after
+        let mut needed_passes: HashSet<usize> = HashSet::new();
+
+        let mut live_passes = vec![false; self.passes.len()];
+
+        for (pass_idx, pass) in self.passes.iter().enumerate().rev() {
+            let live = pass.no_cull
+                || pass.write.is_empty()
+                || needed_passes.contains(&pass.idx)
+                || pass.write.iter().any(|res| {
+                    needed_resources.contains(&res.handle.id)
+                        || matches!(
+                            self.resources[res.handle.id as usize],
+                            GraphResourceInfo::Imported(_)
+                        )
+                });
+
+            if live {
+                live_passes[pass_idx] = true;
+
+                // Writes don't remove resources from the needed set, since earlier passes
+                // may produce the parts this pass doesn't overwrite.
+                needed_resources.extend(
+                    pass.read
+                        .iter()
+                        .chain(pass.write.iter())
+                        .map(|res| res.handle.id),
+                );
+                needed_passes.extend(pass.after.iter().copied());
+            }
+        }
+
+        live_passes
+    }
+
+    /// Finds reads and writes which don't follow the order in which passes were recorded:
+    /// transient resources read before being written, several passes writing the same version
+    /// of a resource, and reads of versions which a later write has already replaced.
+    /// Also reports async compute passes using graphics-only access types, passes which
+    /// always run reading resources only written by conditional ones, and cycles
+    /// in the order given by `PassBuilder::after`.
+    pub fn validation_errors(&self) -> Vec<GraphValidationError> {
+        let mut errors = Vec::new();
+
+        // For each resource, the passes which produced its versions 1, 2, ...
+        let mut writers: Vec<Vec<usize>> = vec![Vec::new(); self.resources.len()];
+
+        // Whether a pass which always runs has written the resource yet
+        let mut unconditionally_written: Vec<bool> = vec![false; self.resources.len()];
+
+        for (pass_idx, pass) in self.passes.iter().enumerate() {
+            for res in &pass.read {
+                let id = res.handle.id as usize;
+                let version = res.handle.version;
+
+                if let Some(&writer) = writers[id].get(version as usize) {
+                    errors.push(GraphValidationError::StaleRead {
+                        pass: pass.name.clone(),
+                        writer_pass: self.passes[writer].name.clone(),
+                        resource: self.resource_debug_name(id as u32),
+                        version,
+                    });
+                } else if version == 0
+                    && matches!(self.resources[id], GraphResourceInfo::Created(_))
+                {
+                    errors.push(GraphValidationError::UnwrittenRead {
+                        pass: pass.name.clone(),
+                        resource: self.resource_debug_name(id as u32),
+                    });
+                } else if version > 0
+                    && pass.predicate.is_none()
+                    && !unconditionally_written[id]
+                    && matches!(self.resources[id], GraphResourceInfo::Created(_))
+                {
+                    errors.push(GraphValidationError::ConditionallyWrittenRead {
+                        pass: pass.name.clone(),
+                        writer_pass: self.passes[writers[id][version as usize - 1]].name.clone(),
+                        resource: self.resource_debug_name(id as u32),
+                    });
+                }
+            }
+
+            for res in &pass.write {
+                let id = res.handle.id as usize;
+                let version = res.handle.version;
+
+                if let Some(&writer) = writers[id].get(version as usize) {
+                    errors.push(GraphValidationError::ConflictingWrite {
+                        pass: pass.name.clone(),
+                        previous_pass: self.passes[writer].name.clone(),
+                        resource: self.resource_debug_name(id as u32),
+                        version,
+                    });
+                }
+
+                writers[id].push(pass_idx);
+                unconditionally_written[id] |= pass.predicate.is_none();
+            }
+
+            // Passes run in the order they're added in, so only earlier passes can precede this one
+            for &after_idx in &pass.after {
+                if after_idx >= pass.idx {
+                    let after_pass = self
+                        .passes
+                        .iter()
+                        .find(|pass| pass.idx == after_idx)
+                        .map_or_else(|| format!("#{}", after_idx), |pass| pass.name.clone());
+
+                    errors.push(GraphValidationError::OrderingCycle {
+                        pass: pass.name.clone(),
+                        after_pass,
+                    });
+                }
+            }
+
+            if pass.async_compute {
+                for res in pass.read.iter().chain(pass.write.iter()) {
+                    if !res
+                        .access
+                        .access_types
+                        .as_slice()
+                        .iter()
+                        .all(|&access_type| is_async_compute_access(access_type))
+                    {
+                        errors.push(GraphValidationError::AsyncComputeAccess {
+                            pass: pass.name.clone(),
+                            resource: self.resource_debug_name(res.handle.id),
+                        });
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Writes the graph in the DOT format, with a node per pass and resource version.
+    /// Imported resources are filled, exported ones get a double border,
+    /// and passes which would be culled are dashed.
+    pub fn dump_graphviz(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut versions = vec![0u32; self.resources.len()];
+        let mut declared: HashSet<(u32, u32)> = HashSet::new();
+
+        let mut declare_resource =
+            |out: &mut dyn std::io::Write, id: u32, version: u32| -> std::io::Result<()> {
+                if declared.insert((id, version)) {
+                    let style = match self.resources[id as usize] {
+                        GraphResourceInfo::Created(_) => "",
+                        GraphResourceInfo::Imported(_) => ", style=filled, fillcolor=lightblue",
+                    };
+                    writeln!(
+                        out,
+                        "    r{}_{} [label=\"{} v{}\", shape=ellipse{}];",
+                        id,
+                        version,
+                        escape(&self.resource_debug_name(id)),
+                        version,
+                        style
+                    )?;
+                }
+                Ok(())
+            };
+
+        writeln!(out, "digraph render_graph {{")?;
+        writeln!(out, "    rankdir=LR;")?;
+
+        for (pass, live) in self.passes.iter().zip(self.live_passes()) {
+            let style = if live {
+                ""
+            } else {
+                ", style=dashed, color=gray"
+            };
+            writeln!(
+                out,
+                "    p{} [label=\"{}\", shape=box{}];",
+                pass.idx,
+                escape(&pass.name),
+                style
+            )?;
+
+            for res in &pass.read {
+                let id = res.handle.id;
+                declare_resource(out, id, versions[id as usize])?;
+                writeln!(
+                    out,
+                    "    r{}_{} -> p{} [label=\"{:?}\"];",
+                    id, versions[id as usize], pass.idx, res.access.access_types
+                )?;
+            }
+
+            for res in &pass.write {
+                let id = res.handle.id;
+                versions[id as usize] += 1;
+                declare_resource(out, id, versions[id as usize])?;
+                writeln!(
+                    out,
+                    "    p{} -> r{}_{} [label=\"{:?}\"];",
+                    pass.idx, id, versions[id as usize], res.access.access_types
+                )?;
+            }
+
+            for after_idx in &pass.after {
+                writeln!(out, "    p{} -> p{} [style=dotted];", after_idx, pass.idx)?;
+            }
+        }
+
+        for (res, access_type) in &self.exported_resources {
+            let id = res.raw().id;
+            declare_resource(out, id, versions[id as usize])?;
+            writeln!(
+                out,
+                "    r{}_{} [peripheries=2, xlabel=\"export: {:?}\"];",
+                id, versions[id as usize], access_type
+            )?;
+        }
+
+        writeln!(out, "}}")
+    }
+
+    fn dump_graphviz_to_file(&self, path: &Path) {
+        let result = std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            self.dump_graphviz(&mut out)?;
+            std::io::Write::flush(&mut out)
+        });
+
+        match result {
+            Ok(()) => log::info!("Dumped the render graph to {:?}", path),
+            Err(err) => log::error!("Failed to dump the render graph to {:?}: {}", path, err),
+        }
+    }
+
+    pub fn compile(mut self, pipeline_cache: &mut PipelineCache) -> CompiledRenderGraph {
+        if let Some(path) = self.graphviz_dump_path.take() {
+            self.dump_graphviz_to_file(&path);
+        }
+
+        if self.validate {
+            let errors = self.validation_errors();
+            if !errors.is_empty() {
+                panic!(
+                    "Invalid render graph:\n{}",
+                    errors
+                        .iter()
+                        .map(|err| format!("  {}", err))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+            }
+        }
+
+        self.cull_passes();
+
+        let resource_info = self.calculate_resource_info(&self.passes.iter().collect::<Vec<_>>());
         // TODO: alias resources
 
-        /* println!(
-            "Resources: {:#?}",
-            self.resources
-                .iter()
-                .map(|info| info.desc)
-                .zip(resource_lifetimes.iter())
-                .collect::<Vec<_>>()
-        ); */
+        if log::log_enabled!(log::Level::Trace) {
+            for (id, lifetime) in resource_info.lifetimes.iter().enumerate() {
+                log::trace!(
+                    "Resource {}: used by passes {:?} to {:?}",
+                    self.resource_debug_name(id as u32),
+                    lifetime.first_access,
+                    lifetime.last_access
+                );
+            }
+        }
 
         let compute_pipelines = self
             .compute_pipelines
@@ -622,6 +1078,9 @@ impl RenderGraph {
                     // Resources created by the render graph can be used as-is, as long as they have a color aspect
                     GraphResourceInfo::Created(GraphResourceCreateInfo {
                         desc: GraphResourceDesc::Image(img_desc),
+                        // Relative extents aren't known until the graph executes
+                        extent: None,
+                        ..
                     }) if is_debug_compatible(img_desc) => Some((src_ref.handle, *img_desc)),
 
                     // Imported resources must also support vk::ImageUsageFlags::SAMPLED because their
@@ -638,12 +1097,22 @@ impl RenderGraph {
                 }
             })?;
 
+            // The debug pass runs after this one, so it must see the last version written here
+            let src_handle = pass
+                .write
+                .iter()
+                .filter(|res| res.handle.id == src_handle.id)
+                .map(|res| res.handle.next_version())
+                .last()
+                .unwrap();
+
             let src_handle: Handle<Image> = Handle {
                 raw: src_handle,
                 desc: TypeEquals::same(src_desc)
                     .mip_levels(1)
                     .format(vk::Format::B10G11R11_UFLOAT_PACK32),
                 marker: PhantomData,
+                graph_id: self.graph_id,
             };
 
             Some(PendingDebugPass { img: src_handle })
@@ -709,6 +1178,17 @@ impl CompiledRenderGraph {
         dynamic_constants: &'constants mut DynamicConstants,
     ) -> ExecutingRenderGraph<'exec_params, 'constants> {
         let device = params.device;
+
+        let predicate_buffers: HashSet<u32> = self
+            .rg
+            .passes
+            .iter()
+            .filter_map(|pass| match &pass.predicate {
+                Some(PassPredicate::Buffer(handle)) => Some(handle.id),
+                _ => None,
+            })
+            .collect();
+
         let resources: Vec<RegistryResource> = self
             .rg
             .resources
@@ -717,30 +1197,50 @@ impl CompiledRenderGraph {
             .map(|(resource_idx, resource)| match resource {
                 GraphResourceInfo::Created(create_info) => match create_info.desc {
                     GraphResourceDesc::Image(mut desc) => {
+                        if let Some(extent) = create_info.extent {
+                            desc.extent = extent.resolve(params.output_extent);
+                        }
                         desc.usage = self.resource_info.image_usage_flags[resource_idx];
+                        self.validate_transient_image(device, resource_idx, &desc);
 
                         let image = transient_resource_cache
-                            .get_image(&desc)
-                            .unwrap_or_else(|| device.create_image(desc, vec![]).unwrap());
+                            .get_or_create_image(device, desc)
+                            .unwrap();
+
+                        // Transient images are reused, so they get renamed every time
+                        if let Some(name) = &create_info.name {
+                            device.set_debug_name(image.raw, name);
+                        }
 
                         RegistryResource {
-                            access_type: vk_sync::AccessType::Nothing,
+                            mip_access_types: Vec::new(),
+                            resolved: Default::default(),
+                            access_types: vk_sync::AccessType::Nothing.into(),
                             resource: AnyRenderResource::OwnedImage(image),
                         }
                     }
                     GraphResourceDesc::Buffer(mut desc) => {
                         desc.usage = self.resource_info.buffer_usage_flags[resource_idx];
 
-                        let buffer =
-                            transient_resource_cache
-                                .get_buffer(&desc)
-                                .unwrap_or_else(|| {
-                                    device.create_buffer(desc, "rg buffer", None).unwrap()
-                                });
+                        if device.capabilities().conditional_rendering
+                            && predicate_buffers.contains(&(resource_idx as u32))
+                        {
+                            desc.usage |= vk::BufferUsageFlags::CONDITIONAL_RENDERING_EXT;
+                        }
+
+                        let buffer = transient_resource_cache
+                            .get_or_create_buffer(device, desc, "rg buffer")
+                            .unwrap();
+
+                        if let Some(name) = &create_info.name {
+                            device.set_debug_name(buffer.raw, name);
+                        }
 
                         RegistryResource {
+                            mip_access_types: Vec::new(),
+                            resolved: Default::default(),
                             resource: AnyRenderResource::OwnedBuffer(buffer),
-                            access_type: vk_sync::AccessType::Nothing,
+                            access_types: vk_sync::AccessType::Nothing.into(),
                         }
                     }
                     GraphResourceDesc::RayTracingAcceleration(_) => {
@@ -752,35 +1252,45 @@ impl CompiledRenderGraph {
                         resource,
                         access_type,
                     } => RegistryResource {
+                        mip_access_types: Vec::new(),
+                        resolved: Default::default(),
                         resource: AnyRenderResource::ImportedImage(resource.clone()),
-                        access_type: *access_type,
+                        access_types: (*access_type).into(),
                     },
                     GraphResourceImportInfo::Buffer {
                         resource,
                         access_type,
                     } => RegistryResource {
+                        mip_access_types: Vec::new(),
+                        resolved: Default::default(),
                         resource: AnyRenderResource::ImportedBuffer(resource.clone()),
-                        access_type: *access_type,
+                        access_types: (*access_type).into(),
                     },
                     GraphResourceImportInfo::RayTracingAcceleration {
                         resource,
                         access_type,
                     } => RegistryResource {
+                        mip_access_types: Vec::new(),
+                        resolved: Default::default(),
                         resource: AnyRenderResource::ImportedRayTracingAcceleration(
                             resource.clone(),
                         ),
-                        access_type: *access_type,
+                        access_types: (*access_type).into(),
                     },
-                    GraphResourceImportInfo::SwapchainImage => RegistryResource {
+                    GraphResourceImportInfo::SwapchainImage { .. } => RegistryResource {
+                        mip_access_types: Vec::new(),
+                        resolved: Default::default(),
                         resource: AnyRenderResource::Pending(PendingRenderResourceInfo {
                             resource: resource.clone(),
                         }),
-                        access_type: vk_sync::AccessType::ComputeShaderWrite,
+                        access_types: vk_sync::AccessType::ComputeShaderWrite.into(),
                     },
                 },
             })
             .collect();
 
+        self.transient_memory(&resources).publish();
+
         let resource_registry = ResourceRegistry {
             execution_params: params,
             resources,
@@ -788,32 +1298,146 @@ impl CompiledRenderGraph {
             pipelines: self.pipelines,
         };
 
+        let mut rg = self.rg;
+
         ExecutingRenderGraph {
             resource_registry,
-            passes: self.rg.passes.into(),
-            resources: self.rg.resources,
-            exported_resources: self.rg.exported_resources,
+            passes: std::mem::take(&mut rg.passes).into(),
+            recorded_passes: Vec::new(),
+            rg,
+            resource_info: self.resource_info,
+        }
+    }
+}
+
+impl CompiledRenderGraph {
+    /// Checks the desc a transient image is about to be created with against the device.
+    /// On failure, panics naming the pass whose access first made the desc invalid.
+    fn validate_transient_image(&self, device: &Device, resource_idx: usize, desc: &ImageDesc) {
+        let err = match device.validate_image_desc(desc) {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+
+        let is_valid = |usage| {
+            device
+                .validate_image_desc(&ImageDesc { usage, ..*desc })
+                .is_ok()
+        };
+
+        let mut usage = match &self.rg.resources[resource_idx] {
+            GraphResourceInfo::Created(GraphResourceCreateInfo {
+                desc: GraphResourceDesc::Image(desc),
+                ..
+            }) => desc.usage,
+            _ => unreachable!(),
+        };
+
+        let mut culprit = "its desc".to_owned();
+
+        if is_valid(usage) {
+            culprit = "an export".to_owned();
+
+            for pass in &self.rg.passes {
+                for res_access in pass.read.iter().chain(pass.write.iter()) {
+                    if res_access.handle.id as usize == resource_idx {
+                        usage |= res_access
+                            .access
+                            .access_types
+                            .as_slice()
+                            .iter()
+                            .map(|access_type| {
+                                image_access_mask_to_usage_flags(
+                                    get_access_info(*access_type).access_mask,
+                                )
+                            })
+                            .fold(vk::ImageUsageFlags::empty(), |a, b| a | b);
+                    }
+                }
+
+                if !is_valid(usage) {
+                    culprit = format!("pass {:?}", pass.name);
+                    break;
+                }
+            }
         }
+
+        panic!(
+            "Render graph image {} is invalid due to {}: {}",
+            self.rg.resource_debug_name(resource_idx as u32),
+            culprit,
+            err
+        );
+    }
+
+    fn transient_memory(&self, resources: &[RegistryResource]) -> TransientMemoryStatistics {
+        let transients = self
+            .rg
+            .resources
+            .iter()
+            .zip(resources)
+            .enumerate()
+            .filter_map(|(resource_idx, (info, resource))| {
+                if !matches!(info, GraphResourceInfo::Created(_)) {
+                    return None;
+                }
+
+                // Descs of the created resources, including the usage flags the graph added
+                let (desc, size_bytes) = match &resource.resource {
+                    AnyRenderResource::OwnedImage(image) => (
+                        GraphResourceDesc::Image(image.desc),
+                        image.allocation_size(),
+                    ),
+                    AnyRenderResource::OwnedBuffer(buffer) => (
+                        GraphResourceDesc::Buffer(buffer.desc),
+                        buffer.allocation.size(),
+                    ),
+                    _ => return None,
+                };
+
+                let lifetime = &self.resource_info.lifetimes[resource_idx];
+
+                Some(TransientResourceMemory {
+                    name: self.rg.resource_debug_name(resource_idx as u32),
+                    desc,
+                    size_bytes,
+                    passes: lifetime.first_access.zip(lifetime.last_access),
+                })
+            })
+            .collect();
+
+        TransientMemoryStatistics::new(transients)
     }
 }
 
 pub struct ExecutingRenderGraph<'exec_params, 'constants> {
     passes: VecDeque<RecordedPass>,
-    resources: Vec<GraphResourceInfo>,
-    exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    /// Passes already recorded, kept for `RetiredRenderGraph::retain`
+    recorded_passes: Vec<RecordedPass>,
+    /// The compiled graph, with its passes moved out
+    rg: RenderGraph,
+    resource_info: ResourceInfo,
     resource_registry: ResourceRegistry<'exec_params, 'constants>,
 }
 
 impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
-    pub fn record_main_cb(&mut self, cb: &CommandBuffer) {
+    /// Records the passes which don't touch the swapchain. With `async_compute`, passes marked
+    /// with `PassBuilder::on_async_compute` can also be recorded into its command buffers,
+    /// in which case `true` is returned, and all of them must be submitted
+    /// as described in `AsyncComputeFrame`.
+    pub fn record_main_cb(
+        &mut self,
+        cb: &CommandBuffer,
+        async_compute: Option<&AsyncComputeFrame>,
+    ) -> bool {
         let mut first_presentation_pass: usize = self.passes.len();
 
         for (pass_idx, pass) in self.passes.iter().enumerate() {
             for res in &pass.write {
-                let res = &self.resources[res.handle.id as usize];
+                let res = &self.rg.resources[res.handle.id as usize];
                 if matches!(
                     res,
-                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage)
+                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage { .. })
                 ) {
                     first_presentation_pass = pass_idx;
                     break;
@@ -827,28 +1451,42 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
         // While we don't have split barriers yet, this will remove some bubbles
         // which would otherwise occur with temporal resources.
         {
-            let mut resource_first_access_states: HashMap<u32, &mut PassResourceAccessType> =
-                HashMap::with_capacity(self.resources.len());
+            // Resources first used with per-mip access are left to be transitioned by their passes
+            let mut resource_first_access_states: HashMap<
+                u32,
+                Option<&mut PassResourceAccessType>,
+            > = HashMap::with_capacity(self.rg.resources.len());
 
             for pass in &mut passes[0..first_presentation_pass] {
                 for resource_ref in pass.read.iter_mut().chain(pass.write.iter_mut()) {
-                    resource_first_access_states
-                        .entry(resource_ref.handle.id)
-                        .or_insert(&mut resource_ref.access);
+                    let entry = resource_first_access_states.entry(resource_ref.handle.id);
+                    if resource_ref.mip_range.is_none() {
+                        entry.or_insert(Some(&mut resource_ref.access));
+                    } else {
+                        entry.or_insert(None);
+                    }
                 }
             }
 
             let params = &self.resource_registry.execution_params;
+            let mut barriers = PassBarriers::default();
+
             for (resource_idx, access) in resource_first_access_states {
+                let access = match access {
+                    Some(access) => access,
+                    None => continue,
+                };
+
                 let resource = &mut self.resource_registry.resources[resource_idx as usize];
                 Self::transition_resource(
-                    params.device,
-                    cb,
+                    &mut barriers,
+                    resource_idx as usize,
                     resource,
                     PassResourceAccessType {
-                        access_type: access.access_type,
+                        access_types: access.access_types,
                         sync_type: PassResourceAccessSyncType::SkipSyncIfSameAccessType,
                     },
+                    None,
                     false,
                     "",
                 );
@@ -856,46 +1494,143 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
                 // Skip the sync when this pass is encountered later.
                 access.sync_type = PassResourceAccessSyncType::SkipSyncIfSameAccessType;
             }
-        }
 
-        for pass in passes.drain(..first_presentation_pass) {
-            Self::record_pass_cb(pass, &mut self.resource_registry, cb);
+            barriers.record(params.device, cb);
         }
 
+        let schedule =
+            schedule_async_compute(&passes[..first_presentation_pass], async_compute.is_some());
+        let (main_passes, mut async_compute_passes): (Vec<_>, Vec<_>) = passes
+            .drain(..first_presentation_pass)
+            .zip(schedule)
+            .partition(|(_, pass_cb)| *pass_cb == PassCommandBuffer::Main);
         self.passes = passes.into();
+
+        for (mut pass, _) in main_passes {
+            Self::record_pass_cb(&mut pass, &self.rg, &mut self.resource_registry, cb);
+            self.recorded_passes.push(pass);
+        }
+
+        let async_compute = match async_compute {
+            Some(async_compute) if !async_compute_passes.is_empty() => async_compute,
+            _ => return false,
+        };
+
+        // Barriers on the compute-only queue can't wait on graphics stages, so resources
+        // are transitioned to their first async compute access types while still
+        // on the universal queue. The semaphore between the queues takes care of the rest.
+        {
+            let params = &self.resource_registry.execution_params;
+            let mut barriers = PassBarriers::default();
+            let mut transitioned: HashSet<u32> = HashSet::new();
+
+            for (pass, _) in async_compute_passes
+                .iter_mut()
+                .filter(|(_, pass_cb)| *pass_cb == PassCommandBuffer::AsyncCompute)
+            {
+                for resource_ref in pass.read.iter_mut().chain(pass.write.iter_mut()) {
+                    if !transitioned.insert(resource_ref.handle.id) {
+                        continue;
+                    }
+
+                    let resource_idx = resource_ref.handle.id as usize;
+                    let resource = &mut self.resource_registry.resources[resource_idx];
+                    Self::transition_resource(
+                        &mut barriers,
+                        resource_idx,
+                        resource,
+                        PassResourceAccessType {
+                            access_types: resource_ref.access.access_types,
+                            sync_type: PassResourceAccessSyncType::AlwaysSync,
+                        },
+                        None,
+                        false,
+                        "",
+                    );
+
+                    if resource_ref.mip_range.is_none() {
+                        resource_ref.access.sync_type =
+                            PassResourceAccessSyncType::SkipSyncIfSameAccessType;
+                    }
+                }
+            }
+
+            barriers.record(params.device, cb);
+        }
+
+        for (mut pass, pass_cb) in async_compute_passes {
+            let cb = match pass_cb {
+                PassCommandBuffer::Main => unreachable!(),
+                PassCommandBuffer::AsyncCompute => &async_compute.command_buffer,
+                PassCommandBuffer::Overlap => &async_compute.overlap_command_buffer,
+                PassCommandBuffer::Join => &async_compute.join_command_buffer,
+            };
+            Self::record_pass_cb(&mut pass, &self.rg, &mut self.resource_registry, cb);
+            self.recorded_passes.push(pass);
+        }
+
+        true
     }
 
+    /// `swapchain_images` holds an image of each swapchain output, and is empty
+    /// for frames drawn offscreen.
     #[must_use]
     pub fn record_presentation_cb(
         mut self,
         cb: &CommandBuffer,
-        swapchain_image: Arc<Image>,
+        swapchain_images: &[Arc<Image>],
     ) -> RetiredRenderGraph {
         let params = &self.resource_registry.execution_params;
 
         // Transition exported images to the requested access types
-        for (resource_idx, access_type) in self.exported_resources {
+        let mut barriers = PassBarriers::default();
+        for (resource_idx, access_type) in &self.rg.exported_resources {
+            let access_type = *access_type;
             if access_type != vk_sync::AccessType::Nothing {
-                let resource =
-                    &mut self.resource_registry.resources[resource_idx.raw().id as usize];
+                let resource_idx = resource_idx.raw().id as usize;
+                let resource = &mut self.resource_registry.resources[resource_idx];
                 Self::transition_resource(
-                    params.device,
-                    cb,
+                    &mut barriers,
+                    resource_idx,
                     resource,
-                    PassResourceAccessType {
+                    PassResourceAccessType::new(
                         access_type,
-                        sync_type: PassResourceAccessSyncType::AlwaysSync,
-                    },
+                        PassResourceAccessSyncType::AlwaysSync,
+                    ),
+                    None,
                     false,
                     "",
                 );
             }
         }
+        barriers.record(params.device, cb);
 
-        for res in &mut self.resource_registry.resources {
+        for (resource_idx, res) in self.resource_registry.resources.iter_mut().enumerate() {
             if let AnyRenderResource::Pending(pending) = &mut res.resource {
                 match pending.resource {
-                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage) => {
+                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage {
+                        output,
+                    }) => {
+                        let swapchain_image = swapchain_images.get(output).unwrap_or_else(|| {
+                            panic!(
+                                "The render graph uses swapchain output {}, but the frame is drawn to {} swapchains",
+                                output,
+                                swapchain_images.len()
+                            )
+                        });
+
+                        // Only known now, as the swapchain's usage depends on the surface
+                        let missing_usage = self.resource_info.image_usage_flags[resource_idx]
+                            & !swapchain_image.desc.usage;
+                        assert!(
+                            missing_usage.is_empty(),
+                            "{}",
+                            GraphValidationError::IncompatibleUsage {
+                                resource: self.rg.resource_debug_name(resource_idx as u32),
+                                usage: format!("{:?}", missing_usage),
+                            }
+                        );
+
                         res.resource = AnyRenderResource::ImportedImage(swapchain_image.clone());
                     }
                     _ => panic!("Only swapchain can be currently pending"),
@@ -903,18 +1638,102 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
             }
         }
 
-        let passes = self.passes;
-        for pass in passes {
-            Self::record_pass_cb(pass, &mut self.resource_registry, cb);
+        for mut pass in std::mem::take(&mut self.passes) {
+            Self::record_pass_cb(&mut pass, &self.rg, &mut self.resource_registry, cb);
+            self.recorded_passes.push(pass);
+        }
+
+        // Exported and imported resources are tracked with a single access type between
+        // frames, so images left with mips in different states, and resources last used
+        // with several access types, are brought to a common one.
+        let tracked_resources: HashSet<usize> = self
+            .rg
+            .exported_resources
+            .iter()
+            .map(|(res, _)| res.raw().id as usize)
+            .chain(
+                self.rg
+                    .resources
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, info)| {
+                        matches!(
+                            info,
+                            GraphResourceInfo::Imported(
+                                GraphResourceImportInfo::Image { .. }
+                                    | GraphResourceImportInfo::Buffer { .. }
+                                    | GraphResourceImportInfo::RayTracingAcceleration { .. }
+                            )
+                        )
+                    })
+                    .map(|(resource_idx, _)| resource_idx),
+            )
+            .collect();
+
+        let params = &self.resource_registry.execution_params;
+        let mut barriers = PassBarriers::default();
+        for resource_idx in tracked_resources {
+            let resource = &mut self.resource_registry.resources[resource_idx];
+            let access_type = match resource.access_types.single() {
+                Some(_) if resource.mip_access_types.is_empty() => continue,
+                Some(access_type) => access_type,
+                None => vk_sync::AccessType::General,
+            };
+
+            Self::transition_resource(
+                &mut barriers,
+                resource_idx,
+                resource,
+                PassResourceAccessType::new(access_type, PassResourceAccessSyncType::AlwaysSync),
+                None,
+                false,
+                "",
+            );
+        }
+        barriers.record(params.device, cb);
+
+        let mut rg = self.rg;
+        rg.passes = self.recorded_passes;
+        rg.passes.sort_by_key(|pass| pass.idx);
+
+        let ResourceRegistry {
+            mut resources,
+            pipelines,
+            ..
+        } = self.resource_registry;
+
+        // Moved out of the registry, so that they don't go back to the transient resource cache
+        for (resource_idx, exported) in &rg.exported_images {
+            let resource_idx = *resource_idx as usize;
+            let resource = &mut resources[resource_idx];
+            let image = match std::mem::replace(
+                &mut resource.resource,
+                AnyRenderResource::Pending(PendingRenderResourceInfo {
+                    resource: rg.resources[resource_idx].clone(),
+                }),
+            ) {
+                AnyRenderResource::OwnedImage(image) => Arc::new(image),
+                AnyRenderResource::ImportedImage(image) => image,
+                _ => unreachable!("Only images are exported with `export_image`"),
+            };
+
+            resource.resource = AnyRenderResource::ImportedImage(image.clone());
+            exported.set(image, resource.access_types.single().unwrap());
         }
 
         RetiredRenderGraph {
-            resources: self.resource_registry.resources,
+            resources,
+            compiled: CompiledRenderGraph {
+                rg,
+                resource_info: self.resource_info,
+                pipelines,
+            },
         }
     }
 
     fn record_pass_cb(
-        pass: RecordedPass,
+        pass: &mut RecordedPass,
+        rg: &RenderGraph,
         resource_registry: &mut ResourceRegistry,
         cb: &CommandBuffer,
     ) {
@@ -933,87 +1752,142 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
             }
         }
 
-        let vk_query_idx = {
-            let query_id = gpu_profiler::create_gpu_query(
-                gpu_profiler::RenderScopeDesc {
-                    name: pass.name.clone(),
-                    id: pass.idx as _,
-                },
-                pass.idx,
-            );
-            let vk_query_idx = params.profiler_data.get_query_id(query_id);
-
-            unsafe {
-                params.device.raw.cmd_write_timestamp(
-                    cb.raw,
-                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                    params.profiler_data.query_pool,
-                    vk_query_idx * 2,
-                );
-            }
-
-            vk_query_idx
-        };
-
         {
             let params = &resource_registry.execution_params;
 
-            let mut transitions: Vec<(usize, PassResourceAccessType)> = Vec::new();
-            for resource_ref in pass.read.iter() {
-                transitions.push((
-                    resource_ref.handle.id as usize,
-                    resource_ref.access,
-                    //format!("read {i}"),
-                ));
-            }
+            // Barriers within one command must not overlap, so if the pass accesses
+            // a whole resource, any of its per-mip accesses are widened to the whole resource.
+            let whole_resources: HashSet<u32> = pass
+                .read
+                .iter()
+                .chain(pass.write.iter())
+                .filter(|resource_ref| resource_ref.mip_range.is_none())
+                .map(|resource_ref| resource_ref.handle.id)
+                .collect();
+
+            let mut transitions: Vec<(usize, PassResourceAccessType, Option<(u32, u32)>)> =
+                Vec::new();
+            for resource_ref in pass.read.iter().chain(pass.write.iter()) {
+                let mip_range = resource_ref
+                    .mip_range
+                    .filter(|_| !whole_resources.contains(&resource_ref.handle.id));
 
-            for resource_ref in pass.write.iter() {
                 transitions.push((
                     resource_ref.handle.id as usize,
                     resource_ref.access,
-                    //format!("write {i}"),
+                    mip_range,
                 ));
             }
 
-            // TODO: optimize the barriers
+            // All of the pass's transitions go into a single barrier
+            let mut barriers = PassBarriers::default();
 
-            for (resource_idx, access) in transitions {
+            for (resource_idx, access, mip_range) in transitions {
                 let resource = &mut resource_registry.resources[resource_idx];
 
                 Self::transition_resource(
-                    params.device,
-                    cb,
+                    &mut barriers,
+                    resource_idx,
                     resource,
                     access,
+                    mip_range,
                     //pass.name == "raster simple",
                     false,
                     "",
                 );
             }
+
+            // Timed separately, so that pass timings only cover the pass itself
+            if !barriers.is_empty() {
+                let vk_query_idx = begin_gpu_query(
+                    params,
+                    cb,
+                    gpu_profiler::RenderScopeDesc {
+                        name: BARRIERS_SCOPE_NAME.to_owned(),
+                        id: pass.idx as _,
+                    },
+                    pass.idx,
+                );
+                barriers.record(params.device, cb);
+                end_gpu_query(params, cb, vk_query_idx);
+            }
+        }
+
+        let vk_query_idx = begin_gpu_query(
+            &resource_registry.execution_params,
+            cb,
+            gpu_profiler::RenderScopeDesc {
+                name: pass.name.clone(),
+                id: pass.idx as _,
+            },
+            pass.idx,
+        );
+
+        // Evaluated after the barriers, so that resources end up in the same state
+        // whether the pass runs or not.
+        let mut conditional_rendering = false;
+        let run = match &mut pass.predicate {
+            None => true,
+            Some(PassPredicate::Cpu(predicate)) => predicate(),
+            Some(PassPredicate::Buffer(handle)) => {
+                let buffer = resource_registry
+                    .buffer_from_raw_handle::<GpuSrv>(*handle)
+                    .raw;
+                conditional_rendering = resource_registry
+                    .execution_params
+                    .device
+                    .cmd_begin_conditional_rendering(cb.raw, buffer);
+                true
+            }
+        };
+
+        if !run {
+            let params = &resource_registry.execution_params;
+            let skipped_query_idx = begin_gpu_query(
+                params,
+                cb,
+                gpu_profiler::RenderScopeDesc {
+                    name: SKIPPED_PASS_SCOPE_NAME.to_owned(),
+                    id: pass.idx as _,
+                },
+                pass.idx,
+            );
+            end_gpu_query(params, cb, skipped_query_idx);
         }
 
         let mut api = RenderPassApi {
             cb,
             resources: resource_registry,
+            pass_constants: Default::default(),
         };
 
-        if let Some(render_fn) = pass.render_fn {
-            if let Err(err) = render_fn(&mut api) {
-                panic!("Pass {:?} failed to render: {:#}", pass.name, err);
+        if let Some(render_fn) = pass.render_fn.as_mut().filter(|_| run) {
+            let check_unused = cfg!(debug_assertions) && !pass.allow_unused_resources;
+            if check_unused {
+                for resource_ref in pass.read.iter().chain(pass.write.iter()) {
+                    api.resources.resources[resource_ref.handle.id as usize]
+                        .resolved
+                        .store(false, Ordering::Relaxed);
+                }
+            }
+
+            if let Err(err) = with_pass_context(render_fn(&mut api), pass, rg) {
+                panic!("Render graph pass failed: {:#}", err);
+            }
+
+            if check_unused {
+                warn_about_unused_resources(pass, rg, api.resources);
             }
         }
 
         let params = &resource_registry.execution_params;
 
-        unsafe {
-            params.device.raw.cmd_write_timestamp(
-                cb.raw,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                params.profiler_data.query_pool,
-                vk_query_idx * 2 + 1,
-            );
+        if conditional_rendering {
+            params.device.cmd_end_conditional_rendering(cb.raw);
         }
 
+        end_gpu_query(params, cb, vk_query_idx);
+
         if let Some(debug_utils) = params.device.debug_utils() {
             unsafe {
                 debug_utils.cmd_end_debug_utils_label(cb.raw);
@@ -1026,29 +1900,40 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
             .record_crash_marker(cb, format!("end render pass {:?}", pass.name));
     }
 
+    /// `mip_range` is `(base, count)`; `None` transitions all subresources.
     fn transition_resource(
-        device: &Device,
-        cb: &CommandBuffer,
+        barriers: &mut PassBarriers,
+        resource_idx: usize,
         resource: &mut RegistryResource,
         access: PassResourceAccessType,
+        mip_range: Option<(u32, u32)>,
         debug: bool,
         dbg_str: &str,
     ) {
-        if unsafe { RG_ALLOW_PASS_OVERLAP }
-            && resource.access_type == access.access_type
-            && matches!(
-                access.sync_type,
-                PassResourceAccessSyncType::SkipSyncIfSameAccessType
-            )
-        {
+        let prev_access_types = resource.access_types;
+        // Pending resources can't be borrowed yet
+        let mip_levels = match &resource.resource {
+            AnyRenderResource::OwnedImage(image) => image.desc.mip_levels as u32,
+            AnyRenderResource::ImportedImage(image) => image.desc.mip_levels as u32,
+            _ => 1,
+        };
+
+        let transitions = plan_transitions(
+            &mut resource.access_types,
+            &mut resource.mip_access_types,
+            mip_levels,
+            access,
+            mip_range,
+        );
+        if transitions.is_empty() {
             return;
         }
 
         if debug {
             log::info!(
                 "\t{dbg_str}: {:?} -> {:?}",
-                resource.access_type,
-                access.access_type
+                prev_access_types,
+                access.access_types
             );
         }
 
@@ -1058,68 +1943,561 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
                     log::info!("\t(image {:?})", image.desc);
                 }
 
-                record_image_barrier(
-                    device,
-                    cb.raw,
-                    ImageBarrier::new(
-                        image.raw,
-                        resource.access_type,
-                        access.access_type,
+                let aspect_mask = access
+                    .access_types
+                    .as_slice()
+                    .iter()
+                    .map(|access_type| {
                         image_aspect_mask_from_access_type_and_format(
-                            access.access_type,
+                            *access_type,
                             image.desc.format,
                         )
                         .unwrap_or_else(|| {
-                            panic!(
-                                "Invalid image access {:?} :: {:?}",
-                                access.access_type, image.desc
-                            )
-                        }),
-                    ),
-                );
+                            panic!("Invalid image access {:?} :: {:?}", access_type, image.desc)
+                        })
+                    })
+                    .fold(vk::ImageAspectFlags::empty(), |a, b| a | b);
 
-                resource.access_type = access.access_type;
+                for (mip, prev_access) in transitions {
+                    barriers.image(
+                        resource_idx,
+                        image.raw,
+                        aspect_mask,
+                        mip,
+                        prev_access,
+                        access.access_types,
+                    );
+                }
             }
             AnyRenderResourceRef::Buffer(buffer) => {
                 if debug {
                     log::info!("\t(buffer {:?})", buffer.desc);
                 }
-                //global_barrier(device, cb, &[resource.access_type], &[access.access_type]);
-
-                vk_sync::cmd::pipeline_barrier(
-                    device.raw.fp_v1_0(),
-                    cb.raw,
-                    None,
-                    &[vk_sync::BufferBarrier {
-                        previous_accesses: &[resource.access_type],
-                        next_accesses: &[access.access_type],
-                        src_queue_family_index: device.universal_queue.family.index,
-                        dst_queue_family_index: device.universal_queue.family.index,
-                        buffer: buffer.raw,
-                        offset: 0,
-                        size: buffer.desc.size,
-                    }],
-                    &[],
-                );
 
-                resource.access_type = access.access_type;
+                for (_, prev_access) in transitions {
+                    barriers.buffer(
+                        resource_idx,
+                        buffer.raw,
+                        buffer.desc.size,
+                        prev_access,
+                        access.access_types,
+                    );
+                }
             }
             AnyRenderResourceRef::RayTracingAcceleration(_) => {
                 if debug {
                     log::info!("\t(bvh)");
                 }
-                /*global_barrier(
-                    device,
-                    cb,
-                    &[resource.access_type],
-                    &[access_type],
-                );*/
-                // TODO
 
-                resource.access_type = access.access_type;
+                for (_, prev_access) in transitions {
+                    barriers.global(prev_access, access.access_types);
+                }
+            }
+        }
+    }
+}
+
+/// Which of the frame's command buffers a pass is recorded into.
+/// See `AsyncComputeFrame` for how they're submitted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PassCommandBuffer {
+    Main,
+    AsyncCompute,
+    Overlap,
+    Join,
+}
+
+/// Splits passes between the queues, without changing the order in which
+/// any single resource is accessed, or that of passes ordered with `PassBuilder::after`.
+///
+/// Universal passes after the first async compute pass run alongside it, until one of them
+/// touches a resource used by async compute, or is ordered after an async compute pass;
+/// that one, and all the following universal passes, wait for async compute to finish.
+/// Async compute passes which need the results of those concurrent or waiting passes stay
+/// on the universal queue instead, as do all passes when the device has no async compute queue.
+fn schedule_async_compute(
+    passes: &[RecordedPass],
+    async_compute_available: bool,
+) -> Vec<PassCommandBuffer> {
+    let mut async_compute_resources: HashSet<u32> = HashSet::new();
+    // Used by the `Overlap` and `Join` passes
+    let mut universal_resources: HashSet<u32> = HashSet::new();
+    // `RecordedPass::idx` of passes on either side of the split
+    let mut async_compute_passes: HashSet<usize> = HashSet::new();
+    let mut universal_passes: HashSet<usize> = HashSet::new();
+    let mut joined = false;
+
+    passes
+        .iter()
+        .map(|pass| {
+            let resources = || {
+                pass.read
+                    .iter()
+                    .chain(pass.write.iter())
+                    .map(|res| res.handle.id)
+            };
+
+            let async_compute = async_compute_available
+                && pass.async_compute
+                && pass.read.iter().chain(pass.write.iter()).all(|res| {
+                    res.access
+                        .access_types
+                        .as_slice()
+                        .iter()
+                        .all(|&access_type| is_async_compute_access(access_type))
+                })
+                && !resources().any(|id| universal_resources.contains(&id))
+                && !pass.after.iter().any(|idx| universal_passes.contains(idx));
+
+            if async_compute_available && pass.async_compute && !async_compute {
+                log::debug!(
+                    "Pass {:?} can't run on async compute; recording it on the universal queue",
+                    pass.name
+                );
+            }
+
+            if async_compute {
+                async_compute_resources.extend(resources());
+                async_compute_passes.insert(pass.idx);
+                PassCommandBuffer::AsyncCompute
+            } else if async_compute_resources.is_empty() {
+                PassCommandBuffer::Main
+            } else {
+                joined = joined
+                    || resources().any(|id| async_compute_resources.contains(&id))
+                    || pass
+                        .after
+                        .iter()
+                        .any(|idx| async_compute_passes.contains(idx));
+                universal_resources.extend(resources());
+                universal_passes.insert(pass.idx);
+
+                if joined {
+                    PassCommandBuffer::Join
+                } else {
+                    PassCommandBuffer::Overlap
+                }
             }
+        })
+        .collect()
+}
+
+/// Access types whose pipeline stages exist on compute-only queues.
+fn is_async_compute_access(access_type: vk_sync::AccessType) -> bool {
+    use vk_sync::AccessType::*;
+
+    matches!(
+        access_type,
+        Nothing
+            | IndirectBuffer
+            | ComputeShaderReadUniformBuffer
+            | ComputeShaderReadSampledImageOrUniformTexelBuffer
+            | ComputeShaderReadOther
+            | ComputeShaderWrite
+            | AnyShaderReadUniformBuffer
+            | AnyShaderReadSampledImageOrUniformTexelBuffer
+            | AnyShaderReadOther
+            | AnyShaderWrite
+            | TransferRead
+            | TransferWrite
+            | General
+    )
+}
+
+/// GPU profiler scope of the barriers recorded before each pass.
+pub const BARRIERS_SCOPE_NAME: &str = "_barriers";
+
+/// GPU profiler scope marking passes whose CPU predicate skipped them.
+/// Shares its id with the skipped pass's own scope.
+pub const SKIPPED_PASS_SCOPE_NAME: &str = "_skipped";
+
+fn begin_gpu_query(
+    params: &RenderGraphExecutionParams,
+    cb: &CommandBuffer,
+    scope: gpu_profiler::RenderScopeDesc,
+    user_id: usize,
+) -> u32 {
+    let query_id = gpu_profiler::create_gpu_query(scope, user_id);
+    let vk_query_idx = params.profiler_data.get_query_id(query_id);
+
+    unsafe {
+        params.device.raw.cmd_write_timestamp(
+            cb.raw,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            params.profiler_data.query_pool,
+            vk_query_idx * 2,
+        );
+    }
+
+    vk_query_idx
+}
+
+fn end_gpu_query(params: &RenderGraphExecutionParams, cb: &CommandBuffer, vk_query_idx: u32) {
+    unsafe {
+        params.device.raw.cmd_write_timestamp(
+            cb.raw,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            params.profiler_data.query_pool,
+            vk_query_idx * 2 + 1,
+        );
+    }
+}
+
+/// Names the pass, and the resources it declared, in errors from its render function.
+fn with_pass_context(
+    result: Result<(), BackendError>,
+    pass: &RecordedPass,
+    rg: &RenderGraph,
+) -> anyhow::Result<()> {
+    result.with_context(|| {
+        let mut resources: Vec<u32> = pass
+            .read
+            .iter()
+            .chain(pass.write.iter())
+            .map(|resource_ref| resource_ref.handle.id)
+            .collect();
+        resources.sort_unstable();
+        resources.dedup();
+
+        let mut context = format!("in render graph pass {:?} (index {})", pass.name, pass.idx);
+        if !resources.is_empty() {
+            context += &format!(
+                " using {}",
+                resources
+                    .iter()
+                    .map(|id| rg.resource_debug_name(*id))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
         }
+        context
+    })
+}
+
+/// Logs resources the pass declared, but didn't look up in the registry while rendering.
+/// Each pass name is only reported once.
+fn warn_about_unused_resources(pass: &RecordedPass, rg: &RenderGraph, registry: &ResourceRegistry) {
+    lazy_static::lazy_static! {
+        static ref REPORTED_PASSES: Mutex<HashSet<String>> = Default::default();
     }
+
+    // Used by the graph itself rather than the render function
+    let predicate_buffer = match &pass.predicate {
+        Some(PassPredicate::Buffer(handle)) => Some(handle.id),
+        _ => None,
+    };
+
+    let mut unused: Vec<u32> = pass
+        .read
+        .iter()
+        .chain(pass.write.iter())
+        .map(|resource_ref| resource_ref.handle.id)
+        .filter(|id| Some(*id) != predicate_buffer)
+        .filter(|id| {
+            !registry.resources[*id as usize]
+                .resolved
+                .load(Ordering::Relaxed)
+        })
+        .collect();
+    unused.sort_unstable();
+    unused.dedup();
+
+    if unused.is_empty() || !REPORTED_PASSES.lock().insert(pass.name.clone()) {
+        return;
+    }
+
+    log::warn!(
+        "Pass {:?} declared resources it never used: {}. If it only uses them conditionally, \
+        call `PassBuilder::allow_unused_resources`",
+        pass.name,
+        unused
+            .iter()
+            .map(|id| rg.resource_debug_name(*id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+/// Moves the tracked access types of a resource to `access`, returning the mip (or `None` for
+/// the whole resource) and previous access types of each barrier this needs.
+///
+/// Per-mip tracking kicks in on the first per-mip access of an image,
+/// and ends once all mips are in the same state again.
+pub(crate) fn plan_transitions(
+    access_types: &mut AccessTypes,
+    mip_access_types: &mut Vec<AccessTypes>,
+    mip_levels: u32,
+    access: PassResourceAccessType,
+    mip_range: Option<(u32, u32)>,
+) -> Vec<(Option<u32>, AccessTypes)> {
+    let prev_access = std::mem::replace(access_types, access.access_types);
+
+    if mip_range.is_none() && mip_access_types.is_empty() {
+        return if can_skip_sync(prev_access, access) {
+            Vec::new()
+        } else {
+            vec![(None, prev_access)]
+        };
+    }
+
+    if mip_access_types.is_empty() {
+        *mip_access_types = vec![prev_access; mip_levels as usize];
+    }
+
+    let mut transitions = Vec::new();
+    let (base_mip, mip_count) = mip_range.unwrap_or((0, mip_levels));
+    for mip in base_mip..base_mip.saturating_add(mip_count).min(mip_levels) {
+        let prev_access = mip_access_types[mip as usize];
+        if can_skip_sync(prev_access, access) {
+            continue;
+        }
+
+        transitions.push((Some(mip), prev_access));
+        mip_access_types[mip as usize] = access.access_types;
+    }
+
+    let first = mip_access_types[0];
+    if mip_access_types
+        .iter()
+        .all(|access_types| *access_types == first)
+    {
+        mip_access_types.clear();
+    }
+
+    transitions
+}
+
+pub(crate) fn can_skip_sync(prev_access: AccessTypes, access: PassResourceAccessType) -> bool {
+    prev_access == access.access_types
+        && (access.access_types.is_read_only()
+            || (unsafe { RG_ALLOW_PASS_OVERLAP }
+                && matches!(
+                    access.sync_type,
+                    PassResourceAccessSyncType::SkipSyncIfSameAccessType
+                )))
+}
+
+fn is_read_only_access(access_type: vk_sync::AccessType) -> bool {
+    const WRITE_ACCESS: vk::AccessFlags = vk::AccessFlags::from_raw(
+        vk::AccessFlags::SHADER_WRITE.as_raw()
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw()
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw()
+            | vk::AccessFlags::TRANSFER_WRITE.as_raw()
+            | vk::AccessFlags::HOST_WRITE.as_raw()
+            | vk::AccessFlags::MEMORY_WRITE.as_raw()
+            | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR.as_raw(),
+    );
+
+    access_type != vk_sync::AccessType::Nothing
+        && !get_access_info(access_type)
+            .access_mask
+            .intersects(WRITE_ACCESS)
+}
+
+struct PendingImageBarrier {
+    resource_idx: usize,
+    /// A single mip level, or all of them
+    mip: Option<u32>,
+    image: vk::Image,
+    aspect_mask: vk::ImageAspectFlags,
+    prev_access: AccessTypes,
+    next_access: AccessTypes,
+}
+
+struct PendingBufferBarrier {
+    resource_idx: usize,
+    buffer: vk::Buffer,
+    size: usize,
+    prev_access: AccessTypes,
+    next_access: AccessTypes,
+}
+
+/// Transitions gathered for a pass, and recorded with a single `vkCmdPipelineBarrier`.
+///
+/// Barriers within one command are unordered, so a resource referenced multiple times
+/// by the pass gets one barrier from its access before the pass to the last one requested.
+#[derive(Default)]
+struct PassBarriers {
+    images: Vec<PendingImageBarrier>,
+    buffers: Vec<PendingBufferBarrier>,
+    global_prev_accesses: Vec<vk_sync::AccessType>,
+    global_next_accesses: Vec<vk_sync::AccessType>,
+}
+
+impl PassBarriers {
+    fn image(
+        &mut self,
+        resource_idx: usize,
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+        mip: Option<u32>,
+        prev_access: AccessTypes,
+        next_access: AccessTypes,
+    ) {
+        if let Some(existing) = self
+            .images
+            .iter_mut()
+            .find(|barrier| barrier.resource_idx == resource_idx && barrier.mip == mip)
+        {
+            existing.next_access = next_access;
+            existing.aspect_mask |= aspect_mask;
+        } else {
+            self.images.push(PendingImageBarrier {
+                resource_idx,
+                mip,
+                image,
+                aspect_mask,
+                prev_access,
+                next_access,
+            });
+        }
+    }
+
+    fn buffer(
+        &mut self,
+        resource_idx: usize,
+        buffer: vk::Buffer,
+        size: usize,
+        prev_access: AccessTypes,
+        next_access: AccessTypes,
+    ) {
+        if let Some(existing) = self
+            .buffers
+            .iter_mut()
+            .find(|barrier| barrier.resource_idx == resource_idx)
+        {
+            existing.next_access = next_access;
+        } else {
+            self.buffers.push(PendingBufferBarrier {
+                resource_idx,
+                buffer,
+                size,
+                prev_access,
+                next_access,
+            });
+        }
+    }
+
+    fn global(&mut self, prev_access: AccessTypes, next_access: AccessTypes) {
+        for prev_access in prev_access.as_slice() {
+            if !self.global_prev_accesses.contains(prev_access) {
+                self.global_prev_accesses.push(*prev_access);
+            }
+        }
+        for next_access in next_access.as_slice() {
+            if !self.global_next_accesses.contains(next_access) {
+                self.global_next_accesses.push(*next_access);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.images.is_empty() && self.buffers.is_empty() && self.global_next_accesses.is_empty()
+    }
+
+    fn record(self, device: &Device, cb: &CommandBuffer) {
+        if self.is_empty() {
+            return;
+        }
+
+        let queue_family_index = device.universal_queue.family.index;
+
+        let image_accesses: Vec<_> = self
+            .images
+            .iter()
+            .map(|barrier| {
+                (
+                    vk_sync_image_accesses(barrier.prev_access),
+                    vk_sync_image_accesses(barrier.next_access),
+                )
+            })
+            .collect();
+
+        let image_barriers: Vec<vk_sync::ImageBarrier> = self
+            .images
+            .iter()
+            .zip(&image_accesses)
+            .map(|(barrier, (prev, next))| vk_sync::ImageBarrier {
+                previous_accesses: prev.0.as_slice(),
+                next_accesses: next.0.as_slice(),
+                previous_layout: prev.1,
+                next_layout: next.1,
+                discard_contents: false,
+                src_queue_family_index: queue_family_index,
+                dst_queue_family_index: queue_family_index,
+                image: barrier.image,
+                range: vk::ImageSubresourceRange {
+                    aspect_mask: barrier.aspect_mask,
+                    base_mip_level: barrier.mip.unwrap_or(0),
+                    level_count: if barrier.mip.is_some() {
+                        1
+                    } else {
+                        vk::REMAINING_MIP_LEVELS
+                    },
+                    base_array_layer: 0,
+                    layer_count: vk::REMAINING_ARRAY_LAYERS,
+                },
+            })
+            .collect();
+
+        let buffer_barriers: Vec<vk_sync::BufferBarrier> = self
+            .buffers
+            .iter()
+            .map(|barrier| vk_sync::BufferBarrier {
+                previous_accesses: barrier.prev_access.as_slice(),
+                next_accesses: barrier.next_access.as_slice(),
+                src_queue_family_index: queue_family_index,
+                dst_queue_family_index: queue_family_index,
+                buffer: barrier.buffer,
+                offset: 0,
+                size: barrier.size,
+            })
+            .collect();
+
+        let global_barrier = if self.global_next_accesses.is_empty() {
+            None
+        } else {
+            Some(vk_sync::GlobalBarrier {
+                previous_accesses: &self.global_prev_accesses,
+                next_accesses: &self.global_next_accesses,
+            })
+        };
+
+        vk_sync::cmd::pipeline_barrier(
+            device.raw.fp_v1_0(),
+            cb.raw,
+            global_barrier,
+            &buffer_barriers,
+            &image_barriers,
+        );
+    }
+}
+
+/// Orders image accesses for `vk_sync`, which takes the layout of the last access
+/// when using optimal layouts. Combinations which need `GENERAL` use it explicitly.
+fn vk_sync_image_accesses(access_types: AccessTypes) -> (AccessTypes, vk_sync::ImageLayout) {
+    if access_types.single().is_some() {
+        return (access_types, vk_sync::ImageLayout::Optimal);
+    }
+
+    let layout = get_combined_access_info(access_types.as_slice())
+        .unwrap_or_else(|err| panic!("Invalid image access {:?}: {:#}", access_types, err))
+        .image_layout;
+
+    if layout == vk::ImageLayout::GENERAL {
+        return (access_types, vk_sync::ImageLayout::General);
+    }
+
+    let mut ordered = access_types;
+    let len = ordered.len as usize;
+    if let Some(idx) = ordered.types[..len]
+        .iter()
+        .rposition(|access_type| get_access_info(*access_type).image_layout == layout)
+    {
+        ordered.types.swap(idx, len - 1);
+    }
+
+    (ordered, vk_sync::ImageLayout::Optimal)
 }
 
 #[allow(dead_code)]
@@ -1143,6 +2521,7 @@ fn global_barrier(
 
 pub struct RetiredRenderGraph {
     resources: Vec<RegistryResource>,
+    compiled: CompiledRenderGraph,
 }
 
 impl RetiredRenderGraph {
@@ -1153,12 +2532,56 @@ impl RetiredRenderGraph {
         let reg_resource = &self.resources[handle.raw.id as usize];
         (
             <Res as Resource>::borrow_resource(&reg_resource.resource),
-            reg_resource.access_type,
+            // Brought to a single access type at the end of `record_presentation_cb`
+            reg_resource.access_types.single().unwrap(),
         )
     }
 
     pub fn release_resources(self, transient_resource_cache: &mut TransientResourceCache) {
-        for resource in self.resources {
+        Self::release_registry_resources(self.resources, transient_resource_cache);
+    }
+
+    /// Releases the resources like `release_resources`, and returns the graph so that it can
+    /// execute again, without being rebuilt. Handles stay valid, and transient resources are
+    /// bound anew from the cache. Imported resources start in the state this execution left them in.
+    ///
+    /// Pass closures and predicates run again in every execution. Data they captured when the graph
+    /// was built stays the same, so anything varying per frame should be read at execution time,
+    /// e.g. from `RenderGraphExecutionParams` and the frame constants.
+    pub fn retain(
+        self,
+        transient_resource_cache: &mut TransientResourceCache,
+    ) -> CompiledRenderGraph {
+        let Self {
+            resources,
+            mut compiled,
+        } = self;
+
+        for (info, resource) in compiled.rg.resources.iter_mut().zip(&resources) {
+            if let GraphResourceInfo::Imported(
+                GraphResourceImportInfo::Image { access_type, .. }
+                | GraphResourceImportInfo::Buffer { access_type, .. }
+                | GraphResourceImportInfo::RayTracingAcceleration { access_type, .. },
+            ) = info
+            {
+                // Images and buffers are brought to a single access type
+                // at the end of `record_presentation_cb`
+                *access_type = resource
+                    .access_types
+                    .single()
+                    .unwrap_or(vk_sync::AccessType::General);
+            }
+        }
+
+        Self::release_registry_resources(resources, transient_resource_cache);
+        compiled
+    }
+
+    fn release_registry_resources(
+        resources: Vec<RegistryResource>,
+        transient_resource_cache: &mut TransientResourceCache,
+    ) {
+        for resource in resources {
             match resource.resource {
                 AnyRenderResource::OwnedImage(image) => {
                     transient_resource_cache.insert_image(image)
@@ -1175,7 +2598,8 @@ impl RetiredRenderGraph {
     }
 }
 
-type DynRenderFn = dyn FnOnce(&mut RenderPassApi) -> Result<(), BackendError>;
+/// Called every time the graph executes; see `RetiredRenderGraph::retain`.
+type DynRenderFn = dyn FnMut(&mut RenderPassApi) -> Result<(), BackendError>;
 
 #[derive(Copy, Clone)]
 pub enum PassResourceAccessSyncType {
@@ -1183,17 +2607,115 @@ pub enum PassResourceAccessSyncType {
     SkipSyncIfSameAccessType,
 }
 
+/// Maximum number of access types a pass can use a single resource with.
+pub const MAX_ACCESS_TYPES_PER_RESOURCE: usize = 4;
+
+/// Access types a resource is used with at the same time, e.g. a depth buffer sampled
+/// while also bound as a read-only attachment. Compares equal regardless of order.
+#[derive(Copy, Clone)]
+pub struct AccessTypes {
+    types: [vk_sync::AccessType; MAX_ACCESS_TYPES_PER_RESOURCE],
+    len: u8,
+}
+
+impl AccessTypes {
+    pub fn new(access_types: &[vk_sync::AccessType]) -> Self {
+        let mut res = Self {
+            types: [vk_sync::AccessType::Nothing; MAX_ACCESS_TYPES_PER_RESOURCE],
+            len: 0,
+        };
+
+        for access_type in access_types {
+            if !res.contains(*access_type) {
+                assert!(
+                    (res.len as usize) < MAX_ACCESS_TYPES_PER_RESOURCE,
+                    "At most {} access types can be used with one resource: {:?}",
+                    MAX_ACCESS_TYPES_PER_RESOURCE,
+                    access_types
+                );
+                res.types[res.len as usize] = *access_type;
+                res.len += 1;
+            }
+        }
+
+        assert!(res.len > 0, "At least one access type is needed");
+        res
+    }
+
+    pub fn as_slice(&self) -> &[vk_sync::AccessType] {
+        &self.types[..self.len as usize]
+    }
+
+    pub fn contains(&self, access_type: vk_sync::AccessType) -> bool {
+        self.as_slice().contains(&access_type)
+    }
+
+    pub fn single(&self) -> Option<vk_sync::AccessType> {
+        match self.as_slice() {
+            [access_type] => Some(*access_type),
+            _ => None,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.as_slice()
+            .iter()
+            .all(|access_type| is_read_only_access(*access_type))
+    }
+}
+
+impl PartialEq for AccessTypes {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .as_slice()
+                .iter()
+                .all(|access_type| other.contains(*access_type))
+    }
+}
+
+impl Eq for AccessTypes {}
+
+impl std::fmt::Debug for AccessTypes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.single() {
+            Some(access_type) => std::fmt::Debug::fmt(&access_type, f),
+            None => f.debug_list().entries(self.as_slice()).finish(),
+        }
+    }
+}
+
+impl From<vk_sync::AccessType> for AccessTypes {
+    fn from(access_type: vk_sync::AccessType) -> Self {
+        Self::new(&[access_type])
+    }
+}
+
+impl From<&[vk_sync::AccessType]> for AccessTypes {
+    fn from(access_types: &[vk_sync::AccessType]) -> Self {
+        Self::new(access_types)
+    }
+}
+
+impl<const N: usize> From<[vk_sync::AccessType; N]> for AccessTypes {
+    fn from(access_types: [vk_sync::AccessType; N]) -> Self {
+        Self::new(&access_types)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct PassResourceAccessType {
-    // TODO: multiple
-    access_type: vk_sync::AccessType,
+    access_types: AccessTypes,
     sync_type: PassResourceAccessSyncType,
 }
 
 impl PassResourceAccessType {
-    pub fn new(access_type: vk_sync::AccessType, sync_type: PassResourceAccessSyncType) -> Self {
+    pub fn new(
+        access_types: impl Into<AccessTypes>,
+        sync_type: PassResourceAccessSyncType,
+    ) -> Self {
         Self {
-            access_type,
+            access_types: access_types.into(),
             sync_type,
         }
     }
@@ -1202,6 +2724,8 @@ impl PassResourceAccessType {
 pub(crate) struct PassResourceRef {
     pub handle: GraphRawResourceHandle,
     pub access: PassResourceAccessType,
+    /// `(base, count)` of the mip levels accessed, or `None` for the whole resource
+    pub mip_range: Option<(u32, u32)>,
 }
 
 pub(crate) struct RecordedPass {
@@ -1210,18 +2734,725 @@ pub(crate) struct RecordedPass {
     pub render_fn: Option<Box<DynRenderFn>>,
     pub name: String,
     pub idx: usize,
+    /// Keep the pass even if nothing consumes its outputs
+    pub no_cull: bool,
+    /// Run on the async compute queue if possible. See `PassBuilder::on_async_compute`.
+    pub async_compute: bool,
+    /// Decides at execution time whether the pass runs. See `PassBuilder::executes_if`.
+    pub predicate: Option<PassPredicate>,
+    /// Don't warn about declared resources the pass doesn't use.
+    /// See `PassBuilder::allow_unused_resources`.
+    pub allow_unused_resources: bool,
+    /// `idx` of the passes which must run before this one. See `PassBuilder::after`.
+    pub after: Vec<usize>,
+}
+
+/// Refers to a pass from later ones. See `PassBuilder::handle`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PassHandle {
+    pub(crate) idx: usize,
+    pub(crate) graph_id: u32,
+}
+
+pub(crate) enum PassPredicate {
+    Cpu(Box<dyn FnMut() -> bool>),
+    Buffer(GraphRawResourceHandle),
 }
 
 impl RecordedPass {
-    fn new(name: &str, idx: usize) -> Self {
+    fn new(name: String, idx: usize) -> Self {
         Self {
             read: Default::default(),
             write: Default::default(),
             render_fn: Default::default(),
-            name: name.to_owned(),
+            name,
             idx,
+            no_cull: false,
+            async_compute: false,
+            predicate: None,
+            allow_unused_resources: false,
+            after: Vec::new(),
+        }
+    }
+}
+
+/// Resource misuse found by `RenderGraph::validation_errors` and `RenderGraph::validate`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum GraphValidationError {
+    /// A resource created by the graph is read before any pass writes it
+    UnwrittenRead { pass: String, resource: String },
+    /// A pass which always runs reads a resource created by the graph,
+    /// but only passes with `PassBuilder::executes_if` have written it
+    ConditionallyWrittenRead {
+        pass: String,
+        writer_pass: String,
+        resource: String,
+    },
+    /// A pass marked with `PassBuilder::on_async_compute` accesses a resource
+    /// in a way which can't be synchronized on a compute-only queue
+    AsyncComputeAccess { pass: String, resource: String },
+    /// Two passes write the same version of a resource, leaving their order undefined
+    ConflictingWrite {
+        pass: String,
+        previous_pass: String,
+        resource: String,
+        version: u32,
+    },
+    /// A pass reads a version of a resource which an earlier pass has already overwritten
+    StaleRead {
+        pass: String,
+        writer_pass: String,
+        resource: String,
+        version: u32,
+    },
+    /// A pass is ordered after itself, or after a pass which runs after it
+    OrderingCycle { pass: String, after_pass: String },
+    /// The access types of a resource need usage flags which its desc can't have, like
+    /// attachment usage of the wrong aspect, or which an imported resource wasn't created with.
+    /// Only reported by `RenderGraph::validate`.
+    IncompatibleUsage { resource: String, usage: String },
+}
+
+impl std::fmt::Display for GraphValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnwrittenRead { pass, resource } => write!(
+                f,
+                "pass {:?} reads {}, which no pass has written",
+                pass, resource
+            ),
+            Self::ConditionallyWrittenRead {
+                pass,
+                writer_pass,
+                resource,
+            } => write!(
+                f,
+                "pass {:?} reads {}, which is only written by the conditional pass {:?}",
+                pass, resource, writer_pass
+            ),
+            Self::AsyncComputeAccess { pass, resource } => write!(
+                f,
+                "async compute pass {:?} uses {} with a graphics-only access type",
+                pass, resource
+            ),
+            Self::ConflictingWrite {
+                pass,
+                previous_pass,
+                resource,
+                version,
+            } => write!(
+                f,
+                "passes {:?} and {:?} both write version {} of {}",
+                previous_pass, pass, version, resource
+            ),
+            Self::StaleRead {
+                pass,
+                writer_pass,
+                resource,
+                version,
+            } => write!(
+                f,
+                "pass {:?} reads version {} of {}, which pass {:?} has since overwritten",
+                pass, version, resource, writer_pass
+            ),
+            Self::OrderingCycle { pass, after_pass } => write!(
+                f,
+                "pass {:?} is ordered after {:?}, which can't run before it",
+                pass, after_pass
+            ),
+            Self::IncompatibleUsage { resource, usage } => write!(
+                f,
+                "{} is used as {}, which its desc doesn't allow",
+                resource, usage
+            ),
         }
     }
 }
 
 pub static mut RG_ALLOW_PASS_OVERLAP: bool = true;
+
+#[test]
+fn test_cull_unused_passes() {
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut used = rg.create(desc);
+    let mut dead = rg.create(desc);
+    let mut output = rg.create(desc);
+
+    let add_pass = |rg: &mut RenderGraph,
+                    name: &str,
+                    src: Option<&Handle<Image>>,
+                    dst: &mut Handle<Image>,
+                    no_cull: bool| {
+        let mut pass = rg.add_pass(name);
+        if let Some(src) = src {
+            pass.read(
+                src,
+                vk_sync::AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+            );
+        }
+        pass.write(dst, vk_sync::AccessType::ComputeShaderWrite);
+        if no_cull {
+            pass.no_cull();
+        }
+        pass.render(|_| Ok(()));
+    };
+
+    add_pass(&mut rg, "used", None, &mut used, false);
+    add_pass(&mut rg, "dead", None, &mut dead, false);
+    add_pass(&mut rg, "kept", Some(&used), &mut dead, true);
+    add_pass(&mut rg, "output", Some(&used), &mut output, false);
+    rg.export(output, vk_sync::AccessType::Nothing);
+
+    rg.cull_passes();
+
+    let names: Vec<&str> = rg.passes.iter().map(|pass| pass.name.as_str()).collect();
+    assert_eq!(names, ["used", "dead", "kept", "output"][..]);
+
+    // Without the `no_cull` marker, the dead branch goes away
+    rg.passes[2].no_cull = false;
+    rg.cull_passes();
+
+    let names: Vec<&str> = rg.passes.iter().map(|pass| pass.name.as_str()).collect();
+    assert_eq!(names, ["used", "output"][..]);
+}
+
+#[test]
+fn test_swap_chain_outputs() {
+    let mut rg = RenderGraph::new();
+    let mut main_view = rg.get_swap_chain();
+    let mut debug_view = rg.get_swap_chain_output(1);
+
+    for (name, output) in [("main", &mut main_view), ("debug", &mut debug_view)] {
+        rg.add_pass(name)
+            .write(output, vk_sync::AccessType::ComputeShaderWrite);
+    }
+
+    rg.cull_passes();
+
+    let names: Vec<&str> = rg.passes.iter().map(|pass| pass.name.as_str()).collect();
+    assert_eq!(names, ["main", "debug"][..]);
+    assert_eq!(rg.resource_debug_name(main_view.raw.id), "swapchain#0");
+    assert_eq!(rg.resource_debug_name(debug_view.raw.id), "swapchain1#1");
+}
+
+#[test]
+fn test_output_relative_image() {
+    use vk_sync::AccessType;
+
+    // Half-res AO, as `ssgi` would create it if it followed the output size
+    let desc = ImageDesc::new_2d(vk::Format::R16_SFLOAT, [1, 1]);
+    let mut rg = RenderGraph::new();
+    let mut ssao = rg.create_image(desc, ImageExtent::RelativeToOutput { scale: [0.5, 0.5] });
+    let absolute = rg.create(desc.extent([7, 5, 1]));
+
+    {
+        let mut pass = rg.add_pass("ssao");
+        pass.read(
+            &absolute,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut ssao, AccessType::ComputeShaderWrite);
+    }
+
+    let extent = match &rg.resources[ssao.raw.id as usize] {
+        GraphResourceInfo::Created(create_info) => create_info.extent.unwrap(),
+        _ => unreachable!(),
+    };
+    assert!(matches!(
+        &rg.resources[absolute.raw.id as usize],
+        GraphResourceInfo::Created(GraphResourceCreateInfo { extent: None, .. })
+    ));
+
+    let before_resize = desc.extent(extent.resolve([1920, 1080]));
+    let after_resize = desc.extent(extent.resolve([1280, 721]));
+    assert_eq!(before_resize.extent, [960, 540, 1]);
+    assert_eq!(after_resize.extent, [640, 361, 1]);
+
+    // Different descs, so the transient cache hands out a new image after the resize
+    assert_ne!(before_resize, after_resize);
+}
+
+#[test]
+fn test_dump_graphviz() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut lighting = rg.create_named(desc, "lighting");
+    let mut scratch = rg.create(desc);
+    let mut swapchain = rg.get_swap_chain();
+
+    {
+        let mut pass = rg.add_pass("light");
+        pass.write(&mut lighting, AccessType::ComputeShaderWrite);
+    }
+    {
+        let mut pass = rg.add_pass("dead");
+        pass.write(&mut scratch, AccessType::ComputeShaderWrite);
+    }
+    {
+        let mut pass = rg.add_pass("blit");
+        pass.read(
+            &lighting,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut swapchain, AccessType::ComputeShaderWrite);
+    }
+    rg.export(
+        lighting,
+        AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+    );
+
+    let mut dot = Vec::new();
+    rg.dump_graphviz(&mut dot).unwrap();
+
+    let expected = r##"digraph render_graph {
+    rankdir=LR;
+    p0 [label="light", shape=box];
+    r0_1 [label="lighting#0 v1", shape=ellipse];
+    p0 -> r0_1 [label="ComputeShaderWrite"];
+    p1 [label="dead", shape=box, style=dashed, color=gray];
+    r1_1 [label="#1 v1", shape=ellipse];
+    p1 -> r1_1 [label="ComputeShaderWrite"];
+    p2 [label="blit", shape=box];
+    r0_1 -> p2 [label="ComputeShaderReadSampledImageOrUniformTexelBuffer"];
+    r2_1 [label="swapchain#2 v1", shape=ellipse, style=filled, fillcolor=lightblue];
+    p2 -> r2_1 [label="ComputeShaderWrite"];
+    r0_1 [peripheries=2, xlabel="export: AnyShaderReadSampledImageOrUniformTexelBuffer"];
+}
+"##;
+    assert_eq!(String::from_utf8(dot).unwrap(), expected);
+}
+
+#[test]
+fn test_validate_unwritten_read() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut written = rg.create_named(desc, "written");
+    let unwritten = rg.create_named(desc, "unwritten");
+    let mut output = rg.create(desc);
+
+    {
+        let mut pass = rg.add_pass("produce");
+        pass.write(&mut written, AccessType::ComputeShaderWrite);
+    }
+    {
+        let mut pass = rg.add_pass("consume");
+        pass.read(
+            &written,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.read(
+            &unwritten,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut output, AccessType::ComputeShaderWrite);
+    }
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::UnwrittenRead {
+            pass: "consume".to_owned(),
+            resource: "unwritten#1".to_owned(),
+        }]
+    );
+}
+
+#[test]
+fn test_validate_conditionally_written_read() {
+    use crate::RgPredicate;
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut denoised = rg.create_named(desc, "denoised");
+    let mut history = rg.create_named(desc, "history");
+    let mut output = rg.create(desc);
+    let mut overlay = rg.create(desc);
+
+    {
+        let mut pass = rg.add_pass("clear history");
+        pass.write(&mut history, AccessType::ComputeShaderWrite);
+    }
+    {
+        let mut pass = rg.add_pass("denoise");
+        pass.write(&mut denoised, AccessType::ComputeShaderWrite);
+        pass.write(&mut history, AccessType::ComputeShaderWrite);
+        pass.executes_if(RgPredicate::cpu(|| false));
+    }
+    {
+        let mut pass = rg.add_pass("composite");
+        pass.read(
+            &denoised,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.read(
+            &history,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut output, AccessType::ComputeShaderWrite);
+    }
+    {
+        // Conditional readers are assumed to share the writer's predicate
+        let mut pass = rg.add_pass("overlay");
+        pass.read(
+            &denoised,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut overlay, AccessType::ComputeShaderWrite);
+        pass.executes_if(RgPredicate::cpu(|| false));
+    }
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::ConditionallyWrittenRead {
+            pass: "composite".to_owned(),
+            writer_pass: "denoise".to_owned(),
+            resource: "denoised#0".to_owned(),
+        }]
+    );
+}
+
+#[test]
+fn test_validate_conflicting_write() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut img = rg.create_named(desc, "img");
+    let mut stale_copy = img.clone_unchecked();
+
+    {
+        let mut pass = rg.add_pass("first");
+        pass.write(&mut img, AccessType::ComputeShaderWrite);
+    }
+    {
+        // Writing twice through the up-to-date handle is fine
+        let mut pass = rg.add_pass("second");
+        pass.write(&mut img, AccessType::ComputeShaderWrite);
+    }
+    assert!(rg.validation_errors().is_empty());
+
+    {
+        let mut pass = rg.add_pass("third");
+        pass.write(&mut stale_copy, AccessType::ComputeShaderWrite);
+    }
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::ConflictingWrite {
+            pass: "third".to_owned(),
+            previous_pass: "first".to_owned(),
+            resource: "img#0".to_owned(),
+            version: 0,
+        }]
+    );
+}
+
+#[test]
+fn test_validate_stale_read() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut img = rg.create_named(desc, "img");
+    let mut output = rg.create(desc);
+
+    {
+        let mut pass = rg.add_pass("produce");
+        pass.write(&mut img, AccessType::ComputeShaderWrite);
+    }
+    let stale_copy = img.clone_unchecked();
+    {
+        // Reading and writing in the same pass only consumes the version before the write
+        let mut pass = rg.add_pass("update");
+        pass.read(
+            &img,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut img, AccessType::ComputeShaderWrite);
+    }
+    assert!(rg.validation_errors().is_empty());
+
+    {
+        let mut pass = rg.add_pass("consume");
+        pass.read(
+            &stale_copy,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut output, AccessType::ComputeShaderWrite);
+    }
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::StaleRead {
+            pass: "consume".to_owned(),
+            writer_pass: "update".to_owned(),
+            resource: "img#0".to_owned(),
+            version: 1,
+        }]
+    );
+}
+
+#[test]
+fn test_access_types() {
+    use vk_sync::AccessType;
+
+    let sampled = AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer;
+    let attachment = AccessType::DepthStencilAttachmentRead;
+
+    let combined = AccessTypes::from([sampled, attachment, sampled]);
+    assert_eq!(combined.as_slice(), &[sampled, attachment][..]);
+    assert_eq!(combined, AccessTypes::from(&[attachment, sampled][..]));
+    assert_ne!(combined, AccessTypes::from(sampled));
+    assert_eq!(combined.single(), None);
+    assert!(combined.is_read_only());
+
+    let (ordered, layout) = vk_sync_image_accesses(combined);
+    assert_eq!(ordered.as_slice().last(), Some(&attachment));
+    assert!(matches!(layout, vk_sync::ImageLayout::Optimal));
+
+    let (_, layout) = vk_sync_image_accesses(AccessTypes::from([
+        AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        AccessType::ComputeShaderReadOther,
+    ]));
+    assert!(matches!(layout, vk_sync::ImageLayout::General));
+}
+
+#[test]
+fn test_schedule_async_compute() {
+    use PassCommandBuffer::*;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut gbuffer = rg.create(desc);
+    let mut ao = rg.create(desc);
+    let mut shadows = rg.create(desc);
+    let mut shadows_blurred = rg.create(desc);
+    let mut output = rg.create(desc);
+    let mut late = rg.create(desc);
+
+    let compute_read = vk_sync::AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer;
+    let fragment_read = vk_sync::AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer;
+
+    let add_pass = |rg: &mut RenderGraph,
+                    name: &str,
+                    src: Option<(&Handle<Image>, vk_sync::AccessType)>,
+                    dst: &mut Handle<Image>,
+                    async_compute: bool| {
+        let mut pass = rg.add_pass(name);
+        if let Some((src, access_type)) = src {
+            pass.read(src, access_type);
+        }
+        pass.write(dst, vk_sync::AccessType::ComputeShaderWrite);
+        if async_compute {
+            pass.on_async_compute();
+        }
+        pass.render(|_| Ok(()));
+    };
+
+    add_pass(&mut rg, "gbuffer", None, &mut gbuffer, false);
+    add_pass(&mut rg, "ao", Some((&gbuffer, compute_read)), &mut ao, true);
+    add_pass(&mut rg, "shadows", None, &mut shadows, false);
+    // Needs the output of a concurrent universal pass
+    add_pass(
+        &mut rg,
+        "blur",
+        Some((&shadows, compute_read)),
+        &mut shadows_blurred,
+        true,
+    );
+    add_pass(
+        &mut rg,
+        "composite",
+        Some((&ao, fragment_read)),
+        &mut output,
+        false,
+    );
+    add_pass(&mut rg, "late", None, &mut late, true);
+    // Graphics-only access
+    add_pass(
+        &mut rg,
+        "misplaced",
+        Some((&gbuffer, fragment_read)),
+        &mut late,
+        true,
+    );
+
+    assert_eq!(
+        schedule_async_compute(&rg.passes, true),
+        vec![
+            Main,
+            AsyncCompute,
+            Overlap,
+            Overlap,
+            Join,
+            AsyncCompute,
+            Join
+        ]
+    );
+    assert_eq!(
+        schedule_async_compute(&rg.passes, false),
+        vec![Main; rg.passes.len()]
+    );
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::AsyncComputeAccess {
+            pass: "misplaced".to_owned(),
+            resource: rg.resource_debug_name(0),
+        }]
+    );
+}
+
+#[test]
+fn test_pass_ordering() {
+    use PassCommandBuffer::*;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut queries = rg.create(desc);
+    let mut ao = rg.create(desc);
+    let mut shadows = rg.create(desc);
+    let mut output = rg.create(desc);
+
+    let mut add_pass = |name: &str,
+                        dst: &mut Handle<Image>,
+                        async_compute: bool,
+                        after: &[PassHandle]|
+     -> PassHandle {
+        let mut pass = rg.add_pass(name);
+        pass.write(dst, vk_sync::AccessType::ComputeShaderWrite);
+        if async_compute {
+            pass.on_async_compute();
+        }
+        for &other in after {
+            pass.after(other);
+        }
+        let handle = pass.handle();
+        pass.render(|_| Ok(()));
+        handle
+    };
+
+    // Nothing reads its output, but the ordering keeps it alive
+    let reset_queries = add_pass("reset queries", &mut queries, false, &[]);
+    let ao_pass = add_pass("ao", &mut ao, true, &[reset_queries]);
+    // No resources shared with async compute, but must wait for it
+    let shadows_pass = add_pass("shadows", &mut shadows, false, &[ao_pass]);
+    add_pass("output", &mut output, false, &[shadows_pass]);
+    rg.export(output, vk_sync::AccessType::Nothing);
+
+    assert_eq!(
+        schedule_async_compute(&rg.passes, true),
+        vec![Main, AsyncCompute, Join, Join]
+    );
+
+    rg.cull_passes();
+    assert_eq!(rg.passes.len(), 4);
+    assert!(rg.validation_errors().is_empty());
+
+    // Ordered after itself
+    let mut pass = rg.add_pass("cycle");
+    pass.after(pass.handle());
+    drop(pass);
+
+    assert_eq!(
+        rg.validation_errors(),
+        vec![GraphValidationError::OrderingCycle {
+            pass: "cycle".to_owned(),
+            after_pass: "cycle".to_owned(),
+        }]
+    );
+}
+
+#[test]
+fn test_render_fn_error_context() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let input = rg.create_named(desc, "gbuffer");
+    let mut output = rg.create(desc);
+
+    rg.add_pass("unrelated");
+    {
+        let mut pass = rg.add_pass("shadow denoise");
+        pass.read(
+            &input,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut output, AccessType::ComputeShaderWrite);
+    }
+
+    // Render functions can't run without a device, so fail the way one would
+    let result = Err(BackendError::ResourceAccess {
+        info: "invalid descriptor".to_owned(),
+    });
+    let err = with_pass_context(result, &rg.passes[1], &rg).unwrap_err();
+
+    assert_eq!(
+        format!("{:#}", err),
+        "in render graph pass \"shadow denoise\" (index 1) using gbuffer#0, #1: \
+        Invalid resource access: \"invalid descriptor\""
+    );
+    assert!(err.downcast_ref::<BackendError>().is_some());
+}
+
+#[test]
+#[should_panic(expected = "belongs to a different render graph")]
+fn test_handle_from_other_graph() {
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut previous_rg = RenderGraph::new();
+    let stale = previous_rg.create(desc);
+
+    let mut rg = RenderGraph::new();
+    let own = rg.create(desc);
+    assert!(matches!(
+        rg.resource_desc(&own),
+        GraphResourceDesc::Image(own_desc) if own_desc.extent == desc.extent
+    ));
+
+    let mut pass = rg.add_pass("read");
+    pass.read(
+        &stale,
+        AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+    );
+}
+
+#[test]
+fn test_raster_depth() {
+    use vk_sync::AccessType;
+
+    let mut rg = RenderGraph::new();
+    let mut depth = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, [4, 4]));
+
+    {
+        let mut pass = rg.add_pass("geometry");
+        pass.raster_depth(&mut depth, DepthAccess::ReadWrite);
+    }
+    {
+        let mut pass = rg.add_pass("decals");
+        pass.raster_depth(&mut depth, DepthAccess::ReadOnly);
+    }
+
+    assert_eq!(
+        rg.passes[0].write[0].access.access_types,
+        AccessTypes::from(AccessType::DepthStencilAttachmentWrite)
+    );
+    assert!(rg.passes[1].write.is_empty());
+
+    // Sampled in the layout of the read-only attachment, without a transition in between
+    let (_, layout) = vk_sync_image_accesses(rg.passes[1].read[0].access.access_types);
+    assert!(matches!(layout, vk_sync::ImageLayout::Optimal));
+    assert_eq!(
+        get_combined_access_info(rg.passes[1].read[0].access.access_types.as_slice())
+            .unwrap()
+            .image_layout,
+        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+    );
+}
@@ -12,26 +12,20 @@ use kajiya_backend::{
 use crate::Image;
 
 use super::{
-    BindRgRef, Buffer, GpuSrv, GpuUav, Handle, PassBuilder, Ref, RenderPassApi, RenderPassBinding,
-    Resource, RgComputePipelineHandle, RgRtPipelineHandle,
+    BindRgRef, Buffer, GpuSrv, GpuUav, Handle, PassBuilder, Ref, RenderGraph, RenderPassApi,
+    RenderPassBinding, Resource, RgComputePipelineHandle, RgRtPipelineHandle,
 };
 
 pub trait ConstBlob {
-    fn push_self(
-        self: Box<Self>,
-        dynamic_constants: &mut dynamic_constants::DynamicConstants,
-    ) -> u32;
+    fn push_self(&self, dynamic_constants: &mut dynamic_constants::DynamicConstants) -> u32;
 }
 
 impl<T> ConstBlob for T
 where
     T: Copy + 'static,
 {
-    fn push_self(
-        self: Box<Self>,
-        dynamic_constants: &mut dynamic_constants::DynamicConstants,
-    ) -> u32 {
-        dynamic_constants.push(self.as_ref())
+    fn push_self(&self, dynamic_constants: &mut dynamic_constants::DynamicConstants) -> u32 {
+        dynamic_constants.push(self)
     }
 }
 
@@ -41,11 +35,8 @@ impl<T> ConstBlob for VecBlob<T>
 where
     T: Copy + 'static,
 {
-    fn push_self(
-        self: Box<Self>,
-        dynamic_constants: &mut dynamic_constants::DynamicConstants,
-    ) -> u32 {
-        dynamic_constants.push_from_iter(self.0.into_iter())
+    fn push_self(&self, dynamic_constants: &mut dynamic_constants::DynamicConstants) -> u32 {
+        dynamic_constants.push_from_iter(self.0.iter().copied())
     }
 }
 
@@ -69,13 +60,13 @@ where
         }
     }
 
+    /// Pushes the constants anew every time the pass executes.
     fn patch_const_blobs(&mut self, api: &mut RenderPassApi) {
         let dynamic_constants = api.dynamic_constants();
 
-        let const_blobs = std::mem::take(&mut self.const_blobs);
-        for (binding_idx, blob) in const_blobs {
-            let dynamic_constants_offset = ConstBlob::push_self(blob, dynamic_constants);
-            match &mut self.bindings[binding_idx] {
+        for (binding_idx, blob) in &self.const_blobs {
+            let dynamic_constants_offset = blob.push_self(dynamic_constants);
+            match &mut self.bindings[*binding_idx] {
                 RenderPassBinding::DynamicConstants(offset)
                 | RenderPassBinding::DynamicConstantsStorageBuffer(offset) => {
                     *offset = dynamic_constants_offset;
@@ -106,7 +97,23 @@ pub struct SimpleRenderPass<'rg, RgPipelineHandle> {
     state: SimpleRenderPassState<RgPipelineHandle>,
 }
 
+/// A compute pass whose bindings are declared in the order of the shader's set 0,
+/// e.g. `SimpleComputePass::new(rg, "/shaders/blur.hlsl").read(&input).write(&mut output).dispatch(extent)`.
+///
+/// Bindings which don't match the reflected layout fail the pass with an error naming the binding.
+pub type SimpleComputePass<'rg> = SimpleRenderPass<'rg, RgComputePipelineHandle>;
+
 impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
+    /// Adds a pass named after the shader file.
+    pub fn new(rg: &'rg mut RenderGraph, pipeline_path: &str) -> Self {
+        let name = std::path::Path::new(pipeline_path).file_stem().map_or_else(
+            || pipeline_path.to_owned(),
+            |stem| stem.to_string_lossy().into_owned(),
+        );
+
+        Self::new_compute(rg.add_pass(name), pipeline_path)
+    }
+
     pub fn new_compute(mut pass: PassBuilder<'rg>, pipeline_path: &str) -> Self {
         let pipeline = pass.register_compute_pipeline(pipeline_path);
 
@@ -130,6 +137,8 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
         }
     }
 
+    /// `extent` is in threads, e.g. the extent of the output image. It's divided into groups
+    /// according to the shader's workgroup size.
     pub fn dispatch(self, extent: [u32; 3]) {
         let mut state = self.state;
 
@@ -144,6 +153,28 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
         });
     }
 
+    /// Like `dispatch`, over the extent `image` has when the pass executes.
+    /// Needed for images created with relative extents; see `RenderGraph::create_image`.
+    pub fn dispatch_image_extent(self, image: &Handle<Image>) {
+        let image = image.raw;
+        let mut state = self.state;
+
+        self.pass.render(move |api| {
+            state.patch_const_blobs(api);
+
+            let extent = api
+                .resources
+                .image_from_raw_handle::<GpuSrv>(image)
+                .desc
+                .extent;
+            let pipeline = api.bind_compute_pipeline(state.create_pipeline_binding())?;
+
+            pipeline.dispatch(extent);
+
+            Ok(())
+        });
+    }
+
     pub fn dispatch_indirect(mut self, args_buffer: &Handle<Buffer>, args_buffer_offset: u64) {
         let args_buffer_ref = self.pass.read(args_buffer, AccessType::IndirectBuffer);
         let mut state = self.state;
@@ -287,10 +318,11 @@ impl<'rg, RgPipelineHandle> SimpleRenderPass<'rg, RgPipelineHandle> {
     }
 
     pub fn read_view(mut self, handle: &Handle<Image>, view_desc: ImageViewDescBuilder) -> Self {
-        let handle_ref = self.pass.read(
-            handle,
-            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
-        );
+        let access_type = AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer;
+        let handle_ref = match view_mip_range(&view_desc) {
+            Some(mip_range) => self.pass.read_mips(handle, access_type, mip_range),
+            None => self.pass.read(handle, access_type),
+        };
 
         self.state.bindings.push(handle_ref.bind_view(view_desc));
 
@@ -343,7 +375,12 @@ impl<'rg, RgPipelineHandle> SimpleRenderPass<'rg, RgPipelineHandle> {
         handle: &mut Handle<Image>,
         view_desc: ImageViewDescBuilder,
     ) -> Self {
-        let handle_ref = self.pass.write(handle, AccessType::AnyShaderWrite);
+        let handle_ref = match view_mip_range(&view_desc) {
+            Some(mip_range) => self
+                .pass
+                .write_mips(handle, AccessType::AnyShaderWrite, mip_range),
+            None => self.pass.write(handle, AccessType::AnyShaderWrite),
+        };
 
         self.state.bindings.push(handle_ref.bind_view(view_desc));
 
@@ -418,3 +455,9 @@ pub trait BindMutToSimpleRenderPass<'rg, RgPipelineHandle> {
         pass: SimpleRenderPass<'rg, RgPipelineHandle>,
     ) -> SimpleRenderPass<'rg, RgPipelineHandle>;
 }
+
+/// Views of a limited number of mip levels only need those mips synchronized.
+fn view_mip_range(view_desc: &ImageViewDescBuilder) -> Option<(u32, u32)> {
+    let view_desc = view_desc.clone().build().ok()?;
+    Some((view_desc.base_mip_level, view_desc.level_count?))
+}
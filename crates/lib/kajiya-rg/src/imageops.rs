@@ -1,5 +1,18 @@
-use crate::{self as rg, RenderGraph};
-use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*};
+use crate::{self as rg, BindRgRef, IntoRenderPassPipelineBinding, RenderGraph, SimpleRenderPass};
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{
+        barrier::image_aspect_mask_from_format,
+        image::*,
+        shader::{
+            create_render_pass, PipelineShaderDesc, RasterPipelineDesc, RenderPass,
+            RenderPassAttachmentDesc, RenderPassDesc, ShaderPipelineStage,
+        },
+    },
+    BackendError, Device,
+};
+use std::sync::Arc;
 
 pub fn clear_depth(rg: &mut RenderGraph, img: &mut rg::Handle<Image>) {
     let mut pass = rg.add_pass("clear depth");
@@ -21,7 +34,8 @@ pub fn clear_depth(rg: &mut RenderGraph, img: &mut rg::Handle<Image>) {
                     stencil: 0,
                 },
                 std::slice::from_ref(&vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    // Clears stencil too for combined depth-stencil formats
+                    aspect_mask: image_aspect_mask_from_format(image.desc.format),
                     level_count: 1,
                     layer_count: 1,
                     ..Default::default()
@@ -63,3 +77,355 @@ pub fn clear_color(rg: &mut RenderGraph, img: &mut rg::Handle<Image>, clear_colo
         Ok(())
     });
 }
+
+/// Copies all of `src` into `dst`. The images must have the same extent and layer count,
+/// and formats with the same aspects and texel size; mips present in both are copied.
+/// Use `blit_image` to resize or convert between formats.
+pub fn copy_image(rg: &mut RenderGraph, src: &rg::Handle<Image>, dst: &mut rg::Handle<Image>) {
+    let src_desc = *src.desc();
+    let dst_desc = *dst.desc();
+
+    let aspect_mask = image_aspect_mask_from_format(src_desc.format);
+    assert!(
+        src_desc.format == dst_desc.format
+            || (aspect_mask == vk::ImageAspectFlags::COLOR
+                && image_aspect_mask_from_format(dst_desc.format) == vk::ImageAspectFlags::COLOR
+                && try_format_texel_size_bytes(src_desc.format).map_or(false, |size| {
+                    try_format_texel_size_bytes(dst_desc.format) == Some(size)
+                })),
+        "Can't copy between {:?} and {:?}; use blit_image to convert formats",
+        src_desc.format,
+        dst_desc.format
+    );
+    assert_eq!(
+        src_desc.extent, dst_desc.extent,
+        "Can't copy between images of different sizes; use blit_image to resize"
+    );
+
+    let layer_count = image_layer_count(&src_desc);
+    assert_eq!(
+        layer_count,
+        image_layer_count(&dst_desc),
+        "Can't copy between images with different layer counts"
+    );
+
+    let mut pass = rg.add_pass("copy image");
+    let src_ref = pass.read(src, AccessType::TransferRead);
+    let dst_ref = pass.write(dst, AccessType::TransferWrite);
+
+    pass.render(move |api| {
+        let raw_device = &api.device().raw;
+        let cb = api.cb;
+
+        let src = api.resources.image(src_ref);
+        let dst = api.resources.image(dst_ref);
+
+        let subresource = |mip_level| vk::ImageSubresourceLayers {
+            aspect_mask,
+            mip_level,
+            base_array_layer: 0,
+            layer_count,
+        };
+
+        let regions: Vec<vk::ImageCopy> = (0..src_desc.mip_levels.min(dst_desc.mip_levels))
+            .map(|mip| {
+                let extent = mip_extent(src_desc.extent, mip as u32);
+                vk::ImageCopy {
+                    src_subresource: subresource(mip as u32),
+                    dst_subresource: subresource(mip as u32),
+                    extent: vk::Extent3D {
+                        width: extent[0],
+                        height: extent[1],
+                        depth: extent[2],
+                    },
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        unsafe {
+            raw_device.cmd_copy_image(
+                cb.raw,
+                src.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.raw,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            );
+        }
+
+        Ok(())
+    });
+}
+
+/// Scales the top mip of `src` over the top mip of `dst`, converting between formats.
+///
+/// Depth and stencil images can only be blitted between identical formats,
+/// and with `vk::Filter::NEAREST`.
+pub fn blit_image(
+    rg: &mut RenderGraph,
+    src: &rg::Handle<Image>,
+    dst: &mut rg::Handle<Image>,
+    filter: vk::Filter,
+) {
+    let src_desc = *src.desc();
+    let dst_desc = *dst.desc();
+
+    let aspect_mask = image_aspect_mask_from_format(src_desc.format);
+    if aspect_mask != vk::ImageAspectFlags::COLOR {
+        assert_eq!(
+            src_desc.format, dst_desc.format,
+            "Depth and stencil images can only be blitted to the same format"
+        );
+        assert_eq!(
+            filter,
+            vk::Filter::NEAREST,
+            "Depth and stencil images can't be blitted with filtering"
+        );
+    } else {
+        assert_eq!(
+            image_aspect_mask_from_format(dst_desc.format),
+            vk::ImageAspectFlags::COLOR,
+            "Can't blit a color image into a depth or stencil one"
+        );
+    }
+
+    let layer_count = image_layer_count(&src_desc);
+    assert_eq!(
+        layer_count,
+        image_layer_count(&dst_desc),
+        "Can't blit between images with different layer counts"
+    );
+
+    let mut pass = rg.add_pass("blit image");
+    let src_ref = pass.read(src, AccessType::TransferRead);
+    let dst_ref = pass.write(dst, AccessType::TransferWrite);
+
+    pass.render(move |api| {
+        let device = api.device();
+        let cb = api.cb;
+
+        let required_features = [
+            (src_desc.format, vk::FormatFeatureFlags::BLIT_SRC),
+            (dst_desc.format, vk::FormatFeatureFlags::BLIT_DST),
+            (
+                src_desc.format,
+                if filter == vk::Filter::LINEAR {
+                    vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR
+                } else {
+                    vk::FormatFeatureFlags::empty()
+                },
+            ),
+        ];
+        for &(format, feature) in required_features.iter() {
+            if !device.format_features(format).contains(feature) {
+                return Err(BackendError::ResourceAccess {
+                    info: format!("{:?} doesn't support {:?}", format, feature),
+                });
+            }
+        }
+
+        let src = api.resources.image(src_ref);
+        let dst = api.resources.image(dst_ref);
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count,
+        };
+        let offsets = |extent: [u32; 3]| {
+            [
+                vk::Offset3D::default(),
+                vk::Offset3D {
+                    x: extent[0] as i32,
+                    y: extent[1] as i32,
+                    z: extent[2] as i32,
+                },
+            ]
+        };
+
+        unsafe {
+            device.raw.cmd_blit_image(
+                cb.raw,
+                src.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.raw,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::ImageBlit {
+                    src_subresource: subresource,
+                    src_offsets: offsets(src_desc.extent),
+                    dst_subresource: subresource,
+                    dst_offsets: offsets(dst_desc.extent),
+                }],
+                filter,
+            );
+        }
+
+        Ok(())
+    });
+}
+
+/// How `generate_mips` reduces each 2x2 (or 3x3 at odd edges) footprint.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MipFilter {
+    Average,
+    Min,
+    Max,
+}
+
+/// Fills mips `1..mip_levels` of a 2D image from its top mip, one pass per level.
+///
+/// Each pass only synchronizes the two levels it touches. Mip extents are floored,
+/// with odd source rows and columns folded into the last texel of the next level.
+pub fn generate_mips(rg: &mut RenderGraph, img: &mut rg::Handle<Image>, filter: MipFilter) {
+    let desc = *img.desc();
+
+    assert_eq!(
+        desc.image_type,
+        ImageType::Tex2d,
+        "generate_mips only supports 2D images"
+    );
+    assert!(
+        desc.mip_levels > 1,
+        "generate_mips needs an image with more than one mip level"
+    );
+    assert!(
+        desc.usage
+            .contains(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED),
+        "generate_mips needs STORAGE and SAMPLED usage; got {:?}",
+        desc.usage
+    );
+
+    let filter_mode: u32 = match filter {
+        MipFilter::Average => 0,
+        MipFilter::Min => 1,
+        MipFilter::Max => 2,
+    };
+
+    for target_mip in 1..desc.mip_levels as u32 {
+        let input_extent = mip_extent(desc.extent, target_mip - 1);
+        let output_extent = mip_extent(desc.extent, target_mip);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass(&format!("generate mip {}", target_mip)),
+            "/shaders/generate_mip.hlsl",
+        )
+        .read_view(
+            img,
+            ImageViewDesc::builder()
+                .base_mip_level(target_mip - 1)
+                .level_count(Some(1)),
+        )
+        .write_view(
+            img,
+            ImageViewDesc::builder()
+                .base_mip_level(target_mip)
+                .level_count(Some(1)),
+        )
+        .constants((
+            input_extent[0],
+            input_extent[1],
+            output_extent[0],
+            output_extent[1],
+            filter_mode,
+        ))
+        .dispatch(output_extent);
+    }
+}
+
+/// A render pass with a single color attachment of `format`, for use with `fullscreen_pass`.
+/// Its previous contents are discarded.
+pub fn create_fullscreen_render_pass(device: &Device, format: vk::Format) -> Arc<RenderPass> {
+    create_render_pass(
+        device,
+        RenderPassDesc {
+            color_attachments: &[RenderPassAttachmentDesc::new(format).garbage_input()],
+            depth_attachment: None,
+            resolve_attachments: &[],
+            subpasses: &[],
+        },
+    )
+}
+
+/// Runs `pixel_shader_path` over all of `output`, with `inputs` bound as sampled images
+/// in set 0, in order. The vertex shader provides `uv` in `TEXCOORD0`.
+///
+/// `render_pass` must come from `create_fullscreen_render_pass` with the format of `output`.
+pub fn fullscreen_pass(
+    rg: &mut RenderGraph,
+    render_pass: &Arc<RenderPass>,
+    pixel_shader_path: &str,
+    inputs: &[&rg::Handle<Image>],
+    output: &mut rg::Handle<Image>,
+) {
+    let name = std::path::Path::new(pixel_shader_path)
+        .file_stem()
+        .map_or_else(
+            || pixel_shader_path.to_owned(),
+            |stem| stem.to_string_lossy().into_owned(),
+        );
+    let mut pass = rg.add_pass(name);
+
+    let pipeline = pass.register_raster_pipeline(
+        &[
+            PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                .hlsl_source("/shaders/fullscreen_vs.hlsl")
+                .build()
+                .unwrap(),
+            PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                .hlsl_source(pixel_shader_path)
+                .build()
+                .unwrap(),
+        ],
+        RasterPipelineDesc::builder().render_pass(render_pass.clone()),
+    );
+
+    let input_refs: Vec<_> = inputs
+        .iter()
+        .map(|input| {
+            pass.read(
+                *input,
+                AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+            )
+        })
+        .collect();
+    let output_ref = pass.raster(output, AccessType::ColorAttachmentWrite);
+
+    let render_pass = render_pass.clone();
+    pass.render(move |api| {
+        let [width, height, _] = output_ref.desc().extent;
+
+        api.begin_render_pass(
+            &*render_pass,
+            [width, height],
+            &[(output_ref, &ImageViewDesc::default())],
+            None,
+        )?;
+
+        api.set_default_view_and_scissor([width, height]);
+
+        let bindings: Vec<_> = input_refs.iter().map(|input| input.bind()).collect();
+        api.bind_raster_pipeline(pipeline.into_binding().descriptor_set(0, &bindings))?;
+
+        unsafe {
+            api.device().raw.cmd_draw(api.cb.raw, 3, 1, 0, 0);
+        }
+
+        api.end_render_pass();
+
+        Ok(())
+    });
+}
+
+fn image_layer_count(desc: &ImageDesc) -> u32 {
+    get_image_create_info(desc, false).array_layers
+}
+
+fn mip_extent(extent: [u32; 3], mip: u32) -> [u32; 3] {
+    [
+        (extent[0] >> mip).max(1),
+        (extent[1] >> mip).max(1),
+        (extent[2] >> mip).max(1),
+    ]
+}
@@ -1,18 +1,29 @@
+mod capture;
+mod exported_image;
 mod graph;
 mod hl;
 mod pass_api;
 mod pass_builder;
+mod readback;
 mod resource;
 mod resource_registry;
+mod statistics;
 mod temporal;
+mod upload;
+mod validate;
 
 pub mod imageops;
 pub mod renderer;
 
+pub use capture::*;
+pub use exported_image::ExportedImage;
 pub use graph::*;
 pub use hl::*;
 pub use pass_api::*;
 pub use pass_builder::*;
+pub use readback::*;
 pub use resource::*;
 pub use resource_registry::ResourceRegistry;
+pub use statistics::*;
 pub use temporal::*;
+pub use validate::*;
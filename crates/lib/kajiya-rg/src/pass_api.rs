@@ -1,4 +1,8 @@
-use std::{cell::UnsafeCell, sync::Arc};
+use std::{
+    cell::{Cell, UnsafeCell},
+    collections::HashMap,
+    sync::Arc,
+};
 
 use arrayvec::ArrayVec;
 
@@ -15,12 +19,13 @@ use kajiya_backend::{
         MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES,
     },
     vulkan::{
-        device::{CommandBuffer, Device},
+        descriptor_allocator::FrameDescriptorSetDesc,
+        device::{CommandBuffer, Device, SamplerDesc},
         image::*,
         ray_tracing::{RayTracingAcceleration, RayTracingPipeline},
         shader::{
-            ComputePipeline, FramebufferCacheKey, RasterPipeline, ShaderPipelineCommon,
-            MAX_COLOR_ATTACHMENTS,
+            ComputePipeline, DescriptorBindingInfo, FramebufferCacheKey, RasterPipeline,
+            ShaderPipelineCommon, MAX_ATTACHMENTS,
         },
     },
     BackendError,
@@ -29,12 +34,23 @@ use kajiya_backend::{
 pub struct RenderPassApi<'a, 'exec_params, 'constants> {
     pub cb: &'a CommandBuffer,
     pub resources: &'a mut ResourceRegistry<'exec_params, 'constants>,
+    /// Pushed by `constants`, and taken by the next pipeline bind
+    pub(crate) pass_constants: Cell<Vec<PassConstants>>,
+}
+
+/// Constants in the dynamic constants buffer, waiting to be bound to a constant buffer binding.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PassConstants {
+    offset: u32,
+    size: usize,
 }
 
 pub enum DescriptorSetBinding {
     Image(vk::DescriptorImageInfo),
+    CombinedImageSampler(vk::DescriptorImageInfo),
     ImageArray(Vec<vk::DescriptorImageInfo>),
     Buffer(vk::DescriptorBufferInfo),
+    BufferArray(Vec<vk::DescriptorBufferInfo>),
     RayTracingAcceleration(vk::AccelerationStructureKHR),
     DynamicBuffer {
         buffer: vk::DescriptorBufferInfo,
@@ -108,6 +124,22 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
         self.resources.dynamic_constants
     }
 
+    /// Pushes `value` into the dynamic constants buffer, to be bound by the next pipeline bind.
+    /// Returns its offset in the buffer.
+    ///
+    /// Constants fill the pipeline's `UNIFORM_BUFFER_DYNAMIC` bindings which directly follow
+    /// the ones passed to `descriptor_set`, in set and binding order, with their offsets supplied
+    /// as dynamic offsets. Sets which only hold constant buffers don't need `descriptor_set`.
+    pub fn constants<T: Copy>(&mut self, value: T) -> u32 {
+        let offset = self.resources.dynamic_constants.push(&value);
+        self.pass_constants.get_mut().push(PassConstants {
+            offset,
+            size: std::mem::size_of::<T>(),
+        });
+
+        offset
+    }
+
     pub fn bind_compute_pipeline<'s>(
         &'s mut self,
         binding: RenderPassPipelineBinding<'_, RgComputePipelineHandle>,
@@ -199,8 +231,40 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
             }
         }
 
-        for (set_idx, bindings) in &binding.bindings {
-            let set_idx = *set_idx;
+        let mut pass_constants = self.pass_constants.take().into_iter();
+        let min_constants_alignment = device
+            .physical_device()
+            .properties
+            .limits
+            .min_uniform_buffer_offset_alignment;
+
+        let mut sets: Vec<(u32, &[RenderPassBinding])> = binding.bindings.clone();
+        if pass_constants.len() > 0 {
+            // Sets starting with a constant buffer can be bound without explicit bindings
+            for set_idx in 0..pipeline.set_layout_info.len() as u32 {
+                let is_bound = set_idx == 2
+                    || sets.iter().any(|(idx, _)| *idx == set_idx)
+                    || binding.raw_bindings.iter().any(|(idx, _)| *idx == set_idx);
+
+                if !is_bound
+                    && pipeline.set_layout_info[set_idx as usize]
+                        .get(&0)
+                        .map_or(false, |info| {
+                            info.ty == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                        })
+                {
+                    sets.push((set_idx, &[]));
+                }
+            }
+        }
+
+        // Constants are assigned in set order
+        sets.sort_by_key(|(set_idx, _)| *set_idx);
+
+        // Where constants which don't fit would go, for errors
+        let mut next_constants_slot = (0, 0);
+
+        for (set_idx, bindings) in sets {
             if pipeline.set_layout_info.get(set_idx as usize).is_none() {
                 continue;
             }
@@ -217,6 +281,18 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                                 )
                                 .build(),
                         ),
+                        RenderPassBinding::CombinedImageSampler { image, sampler } => {
+                            DescriptorSetBinding::CombinedImageSampler(
+                                vk::DescriptorImageInfo::builder()
+                                    .image_layout(image.image_layout)
+                                    .image_view(
+                                        self.resources
+                                            .image_view(image.handle, &image.view_desc)?,
+                                    )
+                                    .sampler(device.get_sampler(*sampler))
+                                    .build(),
+                            )
+                        }
                         RenderPassBinding::ImageArray(images) => DescriptorSetBinding::ImageArray(
                             images
                                 .iter()
@@ -241,6 +317,23 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                                 .range(vk::WHOLE_SIZE)
                                 .build(),
                         ),
+                        RenderPassBinding::BufferArray(buffers) => {
+                            DescriptorSetBinding::BufferArray(
+                                buffers
+                                    .iter()
+                                    .map(|buffer| {
+                                        vk::DescriptorBufferInfo::builder()
+                                            .buffer(
+                                                self.resources
+                                                    .buffer_from_raw_handle::<GpuSrv>(buffer.handle)
+                                                    .raw,
+                                            )
+                                            .range(vk::WHOLE_SIZE)
+                                            .build()
+                                    })
+                                    .collect(),
+                            )
+                        }
                         RenderPassBinding::RayTracingAcceleration(acc) => {
                             DescriptorSetBinding::RayTracingAcceleration(
                                 self.resources
@@ -269,7 +362,23 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                     })
                 })
                 .collect();
-            let bindings = bindings?;
+            let mut bindings = bindings?;
+
+            append_pass_constants(
+                &pipeline.set_layout_info[set_idx as usize],
+                set_idx,
+                &mut bindings,
+                &mut pass_constants,
+                self.resources.dynamic_constants.buffer.raw,
+                min_constants_alignment,
+            )?;
+            next_constants_slot = (set_idx, bindings.len() as u32);
+
+            check_descriptor_set_bindings(
+                &pipeline.set_layout_info[set_idx as usize],
+                set_idx,
+                &bindings,
+            )?;
 
             bind_descriptor_set(
                 &*self.resources.execution_params.device,
@@ -280,6 +389,18 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
             );
         }
 
+        if pass_constants.len() > 0 {
+            let (set, binding) = next_constants_slot;
+            return Err(BackendError::DescriptorBinding {
+                set,
+                binding,
+                info: format!(
+                    "{} more constant buffers were pushed with `constants` than the shader declares",
+                    pass_constants.len()
+                ),
+            });
+        }
+
         for (set_idx, binding) in &binding.raw_bindings {
             let set_idx = *set_idx;
             if pipeline.set_layout_info.get(set_idx as usize).is_none() {
@@ -311,6 +432,25 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
         dims: [u32; 2],
         color_attachments: &[(Ref<Image, GpuRt>, &ImageViewDesc)],
         depth_attachment: Option<(Ref<Image, GpuRt>, &ImageViewDesc)>,
+    ) -> Result<(), BackendError> {
+        self.begin_render_pass_with_resolve(
+            render_pass,
+            dims,
+            color_attachments,
+            depth_attachment,
+            &[],
+        )
+    }
+
+    /// Like `begin_render_pass`, but for render passes created with `resolve_attachments`.
+    /// The multisampled color attachments get resolved into `resolve_attachments` at the end of the subpass.
+    pub fn begin_render_pass_with_resolve(
+        &mut self,
+        render_pass: &kajiya_backend::vulkan::shader::RenderPass,
+        dims: [u32; 2],
+        color_attachments: &[(Ref<Image, GpuRt>, &ImageViewDesc)],
+        depth_attachment: Option<(Ref<Image, GpuRt>, &ImageViewDesc)>,
+        resolve_attachments: &[(Ref<Image, GpuRt>, &ImageViewDesc)],
     ) -> Result<(), BackendError> {
         let device = self.resources.execution_params.device;
 
@@ -326,19 +466,21 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                     depth_attachment.as_ref().map(|(a, _)| {
                         &self.resources.image_from_raw_handle::<GpuRt>(a.handle).desc
                     }),
+                    resolve_attachments.iter().map(|(a, _)| {
+                        &self.resources.image_from_raw_handle::<GpuRt>(a.handle).desc
+                    }),
                 ),
             )
             .unwrap();
 
         // Bind images to the imageless framebuffer
-        let image_attachments: Result<
-            ArrayVec<[vk::ImageView; MAX_COLOR_ATTACHMENTS + 1]>,
-            BackendError,
-        > = color_attachments
-            .iter()
-            .chain(depth_attachment.as_ref().into_iter())
-            .map(|(img, view)| self.resources.image_view(img.handle, view))
-            .collect();
+        let image_attachments: Result<ArrayVec<[vk::ImageView; MAX_ATTACHMENTS]>, BackendError> =
+            color_attachments
+                .iter()
+                .chain(depth_attachment.as_ref().into_iter())
+                .chain(resolve_attachments.iter())
+                .map(|(img, view)| self.resources.image_view(img.handle, view))
+                .collect();
         let image_attachments = image_attachments?;
 
         let mut pass_attachment_desc =
@@ -370,6 +512,16 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
         Ok(())
     }
 
+    /// Advances to the next subpass of the current render pass
+    pub fn next_subpass(&mut self) {
+        let device = self.resources.execution_params.device;
+        unsafe {
+            device
+                .raw
+                .cmd_next_subpass(self.cb.raw, vk::SubpassContents::INLINE);
+        }
+    }
+
     pub fn end_render_pass(&mut self) {
         let device = self.resources.execution_params.device;
         unsafe {
@@ -410,22 +562,28 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
     }
 }
 
+/// Number of groups of `group_size` needed to cover `threads`, rounding up.
+pub fn dispatch_group_count(threads: [u32; 3], group_size: [u32; 3]) -> [u32; 3] {
+    [
+        (threads[0] + group_size[0] - 1) / group_size[0],
+        (threads[1] + group_size[1] - 1) / group_size[1],
+        (threads[2] + group_size[2] - 1) / group_size[2],
+    ]
+}
+
 pub struct BoundComputePipeline<'api, 'a, 'exec_params, 'constants> {
     api: &'api mut RenderPassApi<'a, 'exec_params, 'constants>,
     pipeline: Arc<ComputePipeline>,
 }
 
 impl<'api, 'a, 'exec_params, 'constants> BoundComputePipeline<'api, 'a, 'exec_params, 'constants> {
+    /// Dispatches enough groups to cover `threads`, e.g. an image extent, using the group size
+    /// reflected from the shader; see `dispatch_group_count`.
     pub fn dispatch(&self, threads: [u32; 3]) {
-        let group_size = self.pipeline.group_size;
+        let [x, y, z] = dispatch_group_count(threads, self.pipeline.group_size);
 
         unsafe {
-            self.api.device().raw.cmd_dispatch(
-                self.api.cb.raw,
-                (threads[0] + group_size[0] - 1) / group_size[0],
-                (threads[1] + group_size[1] - 1) / group_size[1],
-                (threads[2] + group_size[2] - 1) / group_size[2],
-            );
+            self.api.device().raw.cmd_dispatch(self.api.cb.raw, x, y, z);
         }
     }
 
@@ -503,8 +661,13 @@ pub struct RenderPassRayTracingAccelerationBinding {
 
 pub enum RenderPassBinding {
     Image(RenderPassImageBinding),
+    CombinedImageSampler {
+        image: RenderPassImageBinding,
+        sampler: SamplerDesc,
+    },
     ImageArray(Vec<RenderPassImageBinding>),
     Buffer(RenderPassBufferBinding),
+    BufferArray(Vec<RenderPassBufferBinding>),
     RayTracingAcceleration(RenderPassRayTracingAccelerationBinding),
     DynamicConstants(u32),
     DynamicConstantsStorageBuffer(u32),
@@ -575,6 +738,34 @@ impl Ref<Image, GpuSrv> {
             image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
         })
     }
+
+    /// Binds to a `COMBINED_IMAGE_SAMPLER` slot. The sampler is ignored
+    /// if the shader's binding has an immutable one.
+    pub fn bind_with_sampler(&self, sampler: SamplerDesc) -> RenderPassBinding {
+        RenderPassBinding::CombinedImageSampler {
+            image: RenderPassImageBinding {
+                handle: self.handle,
+                view_desc: ImageViewDesc::default(),
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            sampler,
+        }
+    }
+}
+
+impl Ref<Image, GpuRt> {
+    /// Binds a depth attachment declared with `DepthAccess::ReadOnly` for sampling
+    /// in the same pass, in the layout the render pass keeps it in.
+    pub fn bind_read_only_depth(&self) -> RenderPassBinding {
+        RenderPassBinding::Image(RenderPassImageBinding {
+            handle: self.handle,
+            view_desc: ImageViewDesc::builder()
+                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                .build()
+                .unwrap(),
+            image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        })
+    }
 }
 
 impl BindRgRef for Vec<Ref<Image, GpuSrv>> {
@@ -626,6 +817,18 @@ impl BindRgRef for Ref<Buffer, GpuUav> {
     }
 }
 
+impl BindRgRef for Vec<Ref<Buffer, GpuSrv>> {
+    fn bind(&self) -> RenderPassBinding {
+        RenderPassBinding::BufferArray(
+            self.iter()
+                .map(|buffer| RenderPassBufferBinding {
+                    handle: buffer.handle,
+                })
+                .collect(),
+        )
+    }
+}
+
 impl BindRgRef for Ref<RayTracingAcceleration, GpuSrv> {
     fn bind(&self) -> RenderPassBinding {
         RenderPassBinding::RayTracingAcceleration(RenderPassRayTracingAccelerationBinding {
@@ -656,26 +859,55 @@ fn bind_descriptor_set(
     let accel_info: TempList<UnsafeCell<vk::WriteDescriptorSetAccelerationStructureKHR>> =
         TempList::new();
 
-    let raw_device = &device.raw;
-
-    let descriptor_pool = {
-        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
-            .max_sets(1)
-            .pool_sizes(&pipeline.descriptor_pool_sizes);
+    // The variable-count binding, if any, is sized by what's actually bound to it
+    let variable_descriptor_count = shader_set_info
+        .iter()
+        .find(|(_, info)| {
+            info.flags
+                .contains(vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT)
+        })
+        .map(
+            |(binding_idx, _)| match bindings.get(*binding_idx as usize) {
+                Some(DescriptorSetBinding::ImageArray(images)) => images.len() as u32,
+                Some(DescriptorSetBinding::BufferArray(buffers)) => buffers.len() as u32,
+                Some(_) => 1,
+                None => 0,
+            },
+        );
 
-        unsafe { raw_device.create_descriptor_pool(&descriptor_pool_create_info, None) }.unwrap()
-    };
-    device.defer_release(descriptor_pool);
+    let mut descriptor_counts: Vec<vk::DescriptorPoolSize> = Vec::new();
+    for info in shader_set_info.values() {
+        let count = if info
+            .flags
+            .contains(vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT)
+        {
+            variable_descriptor_count.unwrap_or_default()
+        } else {
+            info.count
+        };
+
+        if let Some(dps) = descriptor_counts.iter_mut().find(|item| item.ty == info.ty) {
+            dps.descriptor_count += count;
+        } else {
+            descriptor_counts.push(vk::DescriptorPoolSize {
+                ty: info.ty,
+                descriptor_count: count,
+            });
+        }
+    }
 
-    let descriptor_set = {
-        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(std::slice::from_ref(
-                &pipeline.descriptor_set_layouts[set_index as usize],
-            ));
+    // Pool sizes can't be zero
+    descriptor_counts.retain(|dps| dps.descriptor_count > 0);
 
-        unsafe { raw_device.allocate_descriptor_sets(&descriptor_set_allocate_info) }.unwrap()[0]
-    };
+    let descriptor_set = device.allocate_frame_descriptor_set(&FrameDescriptorSetDesc {
+        layout: pipeline.descriptor_set_layouts[set_index as usize],
+        descriptor_counts: &descriptor_counts,
+        update_after_bind: shader_set_info.values().any(|info| {
+            info.flags
+                .contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND)
+        }),
+        variable_descriptor_count,
+    });
 
     unsafe {
         let mut dynamic_offsets: Vec<u32> = Vec::new();
@@ -693,7 +925,8 @@ fn bind_descriptor_set(
                     match binding {
                         DescriptorSetBinding::Image(image) => write
                             .descriptor_type(match image.image_layout {
-                                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => {
+                                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                                | vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL => {
                                     vk::DescriptorType::SAMPLED_IMAGE
                                 }
                                 vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
@@ -701,12 +934,18 @@ fn bind_descriptor_set(
                             })
                             .image_info(std::slice::from_ref(image_info.add(*image)))
                             .build(),
+                        DescriptorSetBinding::CombinedImageSampler(image) => write
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .image_info(std::slice::from_ref(image_info.add(*image)))
+                            .build(),
                         DescriptorSetBinding::ImageArray(images) => {
                             assert!(!images.is_empty());
+                            assert_array_fits_layout(shader_set_info, binding_idx, images.len());
 
                             write
                                 .descriptor_type(match images[0].image_layout {
-                                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => {
+                                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                                    | vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL => {
                                         vk::DescriptorType::SAMPLED_IMAGE
                                     }
                                     vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
@@ -719,6 +958,15 @@ fn bind_descriptor_set(
                             .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                             .buffer_info(std::slice::from_ref(buffer_info.add(*buffer)))
                             .build(),
+                        DescriptorSetBinding::BufferArray(buffers) => {
+                            assert!(!buffers.is_empty());
+                            assert_array_fits_layout(shader_set_info, binding_idx, buffers.len());
+
+                            write
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .buffer_info(buffers.as_slice())
+                                .build()
+                        }
                         DescriptorSetBinding::DynamicBuffer { buffer, offset } => {
                             dynamic_offsets.push(*offset);
                             write
@@ -765,3 +1013,224 @@ fn bind_descriptor_set(
         );
     }
 }
+
+/// Binds `pass_constants` to the constant buffers which directly follow `bindings` in the set,
+/// for as long as both last.
+fn append_pass_constants(
+    shader_set_info: &HashMap<u32, DescriptorBindingInfo>,
+    set_index: u32,
+    bindings: &mut Vec<DescriptorSetBinding>,
+    pass_constants: &mut impl Iterator<Item = PassConstants>,
+    buffer: vk::Buffer,
+    min_alignment: vk::DeviceSize,
+) -> Result<(), BackendError> {
+    loop {
+        let binding_idx = bindings.len() as u32;
+        let is_constant_buffer = shader_set_info.get(&binding_idx).map_or(false, |info| {
+            info.ty == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+        });
+
+        if !is_constant_buffer {
+            return Ok(());
+        }
+
+        // Leftover constants are reported once all sets are bound
+        let constants = match pass_constants.next() {
+            Some(constants) => constants,
+            None => return Ok(()),
+        };
+
+        let mismatch = |info: String| BackendError::DescriptorBinding {
+            set: set_index,
+            binding: binding_idx,
+            info,
+        };
+
+        if constants.offset as vk::DeviceSize % min_alignment != 0 {
+            return Err(mismatch(format!(
+                "constants at offset {} aren't aligned to the device's {} bytes",
+                constants.offset, min_alignment
+            )));
+        }
+
+        if constants.size > MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH {
+            return Err(mismatch(format!(
+                "{} bytes of constants don't fit the {} bytes bound per dispatch",
+                constants.size, MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH
+            )));
+        }
+
+        bindings.push(DescriptorSetBinding::DynamicBuffer {
+            buffer: vk::DescriptorBufferInfo::builder()
+                .buffer(buffer)
+                .range(MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH as u64)
+                .build(),
+            offset: constants.offset,
+        });
+    }
+}
+
+/// Bindings are matched to the shader by their index in the set. Ones the shader doesn't declare
+/// are ignored, since reflection omits bindings which the compiler optimized out.
+fn check_descriptor_set_bindings(
+    shader_set_info: &HashMap<u32, DescriptorBindingInfo>,
+    set_index: u32,
+    bindings: &[DescriptorSetBinding],
+) -> Result<(), BackendError> {
+    let mismatch = |binding_idx: u32, info: String| BackendError::DescriptorBinding {
+        set: set_index,
+        binding: binding_idx,
+        info,
+    };
+
+    for (&binding_idx, info) in shader_set_info {
+        let binding = match bindings.get(binding_idx as usize) {
+            Some(binding) => binding,
+            None if info
+                .flags
+                .contains(vk::DescriptorBindingFlags::PARTIALLY_BOUND) =>
+            {
+                continue
+            }
+            None => {
+                return Err(mismatch(
+                    binding_idx,
+                    format!(
+                        "the shader expects a {:?}, but only {} bindings were provided",
+                        info.ty,
+                        bindings.len()
+                    ),
+                ))
+            }
+        };
+
+        let image_type = |layout: vk::ImageLayout| match layout {
+            vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
+            _ => vk::DescriptorType::SAMPLED_IMAGE,
+        };
+
+        let ty = match binding {
+            DescriptorSetBinding::Image(image) => image_type(image.image_layout),
+            DescriptorSetBinding::ImageArray(images) => images
+                .first()
+                .map_or(info.ty, |image| image_type(image.image_layout)),
+            DescriptorSetBinding::CombinedImageSampler(_) => {
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            }
+            DescriptorSetBinding::Buffer(_) | DescriptorSetBinding::BufferArray(_) => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            DescriptorSetBinding::DynamicBuffer { .. } => {
+                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+            }
+            DescriptorSetBinding::DynamicStorageBuffer { .. } => {
+                vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
+            }
+            DescriptorSetBinding::RayTracingAcceleration(_) => {
+                vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+            }
+        };
+
+        if ty != info.ty {
+            return Err(mismatch(
+                binding_idx,
+                format!(
+                    "the shader expects a {:?}, but a {:?} was provided",
+                    info.ty, ty
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn assert_array_fits_layout(
+    shader_set_info: &HashMap<u32, DescriptorBindingInfo>,
+    binding_idx: usize,
+    count: usize,
+) {
+    let layout_count = shader_set_info[&(binding_idx as u32)].count;
+    assert!(
+        count <= layout_count as usize,
+        "Binding {} was given {} descriptors, but the shader only declares {}",
+        binding_idx,
+        count,
+        layout_count
+    );
+}
+
+#[test]
+fn test_dispatch_group_count() {
+    // The same extent is covered whichever group size the shader declares
+    let extent = [1920, 1081, 1];
+    for group_size in [[8, 8, 1], [16, 4, 1], [64, 1, 1]].iter() {
+        let groups = dispatch_group_count(extent, *group_size);
+        for ((groups, group_size), extent) in groups.iter().zip(group_size).zip(&extent) {
+            assert!(groups * group_size >= *extent);
+            assert!((groups - 1) * group_size < *extent);
+        }
+    }
+}
+
+#[test]
+fn test_append_pass_constants() {
+    let info = |ty| DescriptorBindingInfo {
+        ty,
+        count: 1,
+        flags: vk::DescriptorBindingFlags::empty(),
+    };
+    let set_info: HashMap<u32, DescriptorBindingInfo> = [
+        (0, info(vk::DescriptorType::SAMPLED_IMAGE)),
+        (1, info(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)),
+        (2, info(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)),
+    ]
+    .into_iter()
+    .collect();
+
+    let constants = |offset| PassConstants { offset, size: 16 };
+    let image = || DescriptorSetBinding::Image(vk::DescriptorImageInfo::default());
+
+    // Constants fill the constant buffers after the explicit bindings, leaving the rest for other sets
+    let mut bindings = vec![image()];
+    let mut pass_constants = vec![constants(0), constants(256), constants(512)].into_iter();
+    append_pass_constants(
+        &set_info,
+        0,
+        &mut bindings,
+        &mut pass_constants,
+        vk::Buffer::null(),
+        256,
+    )
+    .unwrap();
+
+    assert_eq!(bindings.len(), 3);
+    assert!(matches!(
+        bindings[2],
+        DescriptorSetBinding::DynamicBuffer { offset: 256, .. }
+    ));
+    assert_eq!(pass_constants.len(), 1);
+    assert!(check_descriptor_set_bindings(&set_info, 0, &bindings).is_ok());
+
+    // Misaligned constants name their binding
+    let mut bindings = vec![image()];
+    let mut pass_constants = vec![constants(64)].into_iter();
+    let err = append_pass_constants(
+        &set_info,
+        3,
+        &mut bindings,
+        &mut pass_constants,
+        vk::Buffer::null(),
+        256,
+    )
+    .unwrap_err();
+
+    assert!(matches!(
+        err,
+        BackendError::DescriptorBinding {
+            set: 3,
+            binding: 1,
+            ..
+        }
+    ));
+}
@@ -2,23 +2,53 @@ use crate::{PassResourceAccessSyncType, RenderPassApi};
 
 use super::{
     graph::{
-        PassResourceAccessType, PassResourceRef, RecordedPass, RenderGraph, RgComputePipeline,
-        RgComputePipelineHandle, RgRasterPipeline, RgRasterPipelineHandle, RgRtPipeline,
-        RgRtPipelineHandle, TypeEquals,
+        AccessTypes, PassHandle, PassPredicate, PassResourceAccessType, PassResourceRef,
+        RecordedPass, RenderGraph, RgComputePipeline, RgComputePipelineHandle, RgRasterPipeline,
+        RgRasterPipelineHandle, RgRtPipeline, RgRtPipelineHandle, TypeEquals,
     },
     resource::*,
 };
 
 use kajiya_backend::{
-    vk_sync::{self, AccessType},
-    vulkan::{ray_tracing::RayTracingPipelineDesc, shader::*},
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{
+        barrier::{get_combined_access_info, image_aspect_mask_from_format},
+        ray_tracing::RayTracingPipelineDesc,
+        shader::*,
+    },
     BackendError,
 };
 use std::{marker::PhantomData, path::Path};
 
+/// Decides at execution time whether a pass runs. See `PassBuilder::executes_if`.
+pub enum RgPredicate<'a> {
+    /// Evaluated on the CPU just before the pass is recorded
+    Cpu(Box<dyn FnMut() -> bool>),
+    /// The pass runs if the first 32 bits of the buffer are non-zero. This uses
+    /// `VK_EXT_conditional_rendering`, which only skips draws, dispatches and clears;
+    /// without it, the pass always runs.
+    Buffer(&'a Handle<Buffer>),
+}
+
+impl RgPredicate<'static> {
+    pub fn cpu(predicate: impl FnMut() -> bool + 'static) -> Self {
+        Self::Cpu(Box::new(predicate))
+    }
+}
+
+/// How a raster pass uses its depth attachment. See `PassBuilder::raster_depth`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DepthAccess {
+    /// Depth testing and writing
+    ReadWrite,
+    /// Depth testing only. The image stays in the read-only depth layout,
+    /// so the pass's fragment shaders can sample it too.
+    ReadOnly,
+}
+
 pub struct PassBuilder<'rg> {
     pub(crate) rg: &'rg mut RenderGraph,
-    #[allow(dead_code)]
     pub(crate) pass_idx: usize,
     pub(crate) pass: Option<RecordedPass>,
 }
@@ -40,12 +70,27 @@ impl<'rg> PassBuilder<'rg> {
         self.rg.create(desc)
     }
 
+    pub fn create_named<Desc: ResourceDesc>(
+        &mut self,
+        desc: Desc,
+        name: impl Into<String>,
+    ) -> Handle<<Desc as ResourceDesc>::Resource>
+    where
+        Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>,
+    {
+        self.rg.create_named(desc, name)
+    }
+
     pub fn write_impl<Res: Resource, ViewType: GpuViewType>(
         &mut self,
         handle: &mut Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
         sync_type: PassResourceAccessSyncType,
     ) -> Ref<Res, ViewType> {
+        let access_types = access_types.into();
+        check_access_types(&handle.desc, access_types);
+        self.rg.check_handle(handle);
+
         let pass = self.pass.as_mut().unwrap();
 
         // CHECK DISABLED: multiple writes or mixing of reads and writes is valid with non-overlapping views
@@ -62,12 +107,15 @@ impl<'rg> PassBuilder<'rg> {
 
         pass.write.push(PassResourceRef {
             handle: handle.raw,
-            access: PassResourceAccessType::new(access_type, sync_type),
+            access: PassResourceAccessType::new(access_types, sync_type),
+            mip_range: None,
         });
 
+        handle.raw = handle.raw.next_version();
+
         Ref {
             desc: handle.desc.clone(),
-            handle: handle.raw.next_version(),
+            handle: handle.raw,
             marker: PhantomData,
         }
     }
@@ -75,55 +123,61 @@ impl<'rg> PassBuilder<'rg> {
     pub fn write<Res: Resource>(
         &mut self,
         handle: &mut Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
     ) -> Ref<Res, GpuUav> {
-        match access_type {
-            AccessType::CommandBufferWriteNVX
-            | AccessType::VertexShaderWrite
-            | AccessType::TessellationControlShaderWrite
-            | AccessType::TessellationEvaluationShaderWrite
-            | AccessType::GeometryShaderWrite
-            | AccessType::FragmentShaderWrite
-            | AccessType::ComputeShaderWrite
-            | AccessType::AnyShaderWrite
-            | AccessType::TransferWrite
-            | AccessType::HostWrite
-            | AccessType::ColorAttachmentReadWrite
-            | AccessType::General => {}
-            _ => {
-                panic!("Invalid access type: {:?}", access_type);
+        let access_types = access_types.into();
+        for access_type in access_types.as_slice() {
+            match access_type {
+                AccessType::CommandBufferWriteNVX
+                | AccessType::VertexShaderWrite
+                | AccessType::TessellationControlShaderWrite
+                | AccessType::TessellationEvaluationShaderWrite
+                | AccessType::GeometryShaderWrite
+                | AccessType::FragmentShaderWrite
+                | AccessType::ComputeShaderWrite
+                | AccessType::AnyShaderWrite
+                | AccessType::TransferWrite
+                | AccessType::HostWrite
+                | AccessType::ColorAttachmentReadWrite
+                | AccessType::General => {}
+                _ => {
+                    panic!("Invalid access type: {:?}", access_type);
+                }
             }
         }
 
-        self.write_impl(handle, access_type, PassResourceAccessSyncType::AlwaysSync)
+        self.write_impl(handle, access_types, PassResourceAccessSyncType::AlwaysSync)
     }
 
     pub fn write_no_sync<Res: Resource>(
         &mut self,
         handle: &mut Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
     ) -> Ref<Res, GpuUav> {
-        match access_type {
-            AccessType::CommandBufferWriteNVX
-            | AccessType::VertexShaderWrite
-            | AccessType::TessellationControlShaderWrite
-            | AccessType::TessellationEvaluationShaderWrite
-            | AccessType::GeometryShaderWrite
-            | AccessType::FragmentShaderWrite
-            | AccessType::ComputeShaderWrite
-            | AccessType::AnyShaderWrite
-            | AccessType::TransferWrite
-            | AccessType::HostWrite
-            | AccessType::ColorAttachmentReadWrite
-            | AccessType::General => {}
-            _ => {
-                panic!("Invalid access type: {:?}", access_type);
+        let access_types = access_types.into();
+        for access_type in access_types.as_slice() {
+            match access_type {
+                AccessType::CommandBufferWriteNVX
+                | AccessType::VertexShaderWrite
+                | AccessType::TessellationControlShaderWrite
+                | AccessType::TessellationEvaluationShaderWrite
+                | AccessType::GeometryShaderWrite
+                | AccessType::FragmentShaderWrite
+                | AccessType::ComputeShaderWrite
+                | AccessType::AnyShaderWrite
+                | AccessType::TransferWrite
+                | AccessType::HostWrite
+                | AccessType::ColorAttachmentReadWrite
+                | AccessType::General => {}
+                _ => {
+                    panic!("Invalid access type: {:?}", access_type);
+                }
             }
         }
 
         self.write_impl(
             handle,
-            access_type,
+            access_types,
             PassResourceAccessSyncType::SkipSyncIfSameAccessType,
         )
     }
@@ -131,65 +185,74 @@ impl<'rg> PassBuilder<'rg> {
     pub fn raster<Res: Resource>(
         &mut self,
         handle: &mut Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
     ) -> Ref<Res, GpuRt> {
-        match access_type {
-            AccessType::ColorAttachmentWrite
-            | AccessType::DepthStencilAttachmentWrite
-            | AccessType::DepthAttachmentWriteStencilReadOnly
-            | AccessType::StencilAttachmentWriteDepthReadOnly => {}
-            _ => {
-                panic!("Invalid access type: {:?}", access_type);
+        let access_types = access_types.into();
+        for access_type in access_types.as_slice() {
+            match access_type {
+                AccessType::ColorAttachmentWrite
+                | AccessType::DepthStencilAttachmentWrite
+                | AccessType::DepthAttachmentWriteStencilReadOnly
+                | AccessType::StencilAttachmentWriteDepthReadOnly => {}
+                _ => {
+                    panic!("Invalid access type: {:?}", access_type);
+                }
             }
         }
 
-        self.write_impl(handle, access_type, PassResourceAccessSyncType::AlwaysSync)
+        self.write_impl(handle, access_types, PassResourceAccessSyncType::AlwaysSync)
     }
 
     pub fn read<Res: Resource>(
         &mut self,
         handle: &Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
     ) -> Ref<Res, GpuSrv> {
-        match access_type {
-            AccessType::CommandBufferReadNVX
-            | AccessType::IndirectBuffer
-            | AccessType::IndexBuffer
-            | AccessType::VertexBuffer
-            | AccessType::VertexShaderReadUniformBuffer
-            | AccessType::VertexShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::VertexShaderReadOther
-            | AccessType::TessellationControlShaderReadUniformBuffer
-            | AccessType::TessellationControlShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::TessellationControlShaderReadOther
-            | AccessType::TessellationEvaluationShaderReadUniformBuffer
-            | AccessType::TessellationEvaluationShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::TessellationEvaluationShaderReadOther
-            | AccessType::GeometryShaderReadUniformBuffer
-            | AccessType::GeometryShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::GeometryShaderReadOther
-            | AccessType::FragmentShaderReadUniformBuffer
-            | AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::FragmentShaderReadColorInputAttachment
-            | AccessType::FragmentShaderReadDepthStencilInputAttachment
-            | AccessType::FragmentShaderReadOther
-            | AccessType::ColorAttachmentRead
-            | AccessType::DepthStencilAttachmentRead
-            | AccessType::ComputeShaderReadUniformBuffer
-            | AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::ComputeShaderReadOther
-            | AccessType::AnyShaderReadUniformBuffer
-            | AccessType::AnyShaderReadUniformBufferOrVertexBuffer
-            | AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer
-            | AccessType::AnyShaderReadOther
-            | AccessType::TransferRead
-            | AccessType::HostRead
-            | AccessType::Present => {}
-            _ => {
-                panic!("Invalid access type: {:?}", access_type);
+        let access_types = access_types.into();
+        for access_type in access_types.as_slice() {
+            match access_type {
+                AccessType::CommandBufferReadNVX
+                | AccessType::IndirectBuffer
+                | AccessType::IndexBuffer
+                | AccessType::VertexBuffer
+                | AccessType::VertexShaderReadUniformBuffer
+                | AccessType::VertexShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::VertexShaderReadOther
+                | AccessType::TessellationControlShaderReadUniformBuffer
+                | AccessType::TessellationControlShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::TessellationControlShaderReadOther
+                | AccessType::TessellationEvaluationShaderReadUniformBuffer
+                | AccessType::TessellationEvaluationShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::TessellationEvaluationShaderReadOther
+                | AccessType::GeometryShaderReadUniformBuffer
+                | AccessType::GeometryShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::GeometryShaderReadOther
+                | AccessType::FragmentShaderReadUniformBuffer
+                | AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::FragmentShaderReadColorInputAttachment
+                | AccessType::FragmentShaderReadDepthStencilInputAttachment
+                | AccessType::FragmentShaderReadOther
+                | AccessType::ColorAttachmentRead
+                | AccessType::DepthStencilAttachmentRead
+                | AccessType::ComputeShaderReadUniformBuffer
+                | AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::ComputeShaderReadOther
+                | AccessType::AnyShaderReadUniformBuffer
+                | AccessType::AnyShaderReadUniformBufferOrVertexBuffer
+                | AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::AnyShaderReadOther
+                | AccessType::TransferRead
+                | AccessType::HostRead
+                | AccessType::Present => {}
+                _ => {
+                    panic!("Invalid access type: {:?}", access_type);
+                }
             }
         }
 
+        check_access_types(&handle.desc, access_types);
+        self.rg.check_handle(handle);
+
         let pass = self.pass.as_mut().unwrap();
 
         // CHECK DISABLED: multiple writes or mixing of reads and writes is valid with non-overlapping views
@@ -201,9 +264,10 @@ impl<'rg> PassBuilder<'rg> {
         pass.read.push(PassResourceRef {
             handle: handle.raw,
             access: PassResourceAccessType::new(
-                access_type,
+                access_types,
                 PassResourceAccessSyncType::SkipSyncIfSameAccessType,
             ),
+            mip_range: None,
         });
 
         Ref {
@@ -213,26 +277,89 @@ impl<'rg> PassBuilder<'rg> {
         }
     }
 
+    /// Like `read`, but only the `(base, count)` mip levels of the image are synchronized,
+    /// so that other mips can be written by the same pass.
+    pub fn read_mips(
+        &mut self,
+        handle: &Handle<Image>,
+        access_types: impl Into<AccessTypes>,
+        mip_range: (u32, u32),
+    ) -> Ref<Image, GpuSrv> {
+        let res = self.read(handle, access_types);
+        self.pass
+            .as_mut()
+            .unwrap()
+            .read
+            .last_mut()
+            .unwrap()
+            .mip_range = Some(mip_range);
+        res
+    }
+
+    /// Like `write`, but only the `(base, count)` mip levels of the image are synchronized,
+    /// e.g. for downsampling chains which read the previous mip in the same pass.
+    pub fn write_mips(
+        &mut self,
+        handle: &mut Handle<Image>,
+        access_types: impl Into<AccessTypes>,
+        mip_range: (u32, u32),
+    ) -> Ref<Image, GpuUav> {
+        let res = self.write(handle, access_types);
+        self.pass
+            .as_mut()
+            .unwrap()
+            .write
+            .last_mut()
+            .unwrap()
+            .mip_range = Some(mip_range);
+        res
+    }
+
+    pub fn write_mip(
+        &mut self,
+        handle: &mut Handle<Image>,
+        access_types: impl Into<AccessTypes>,
+        mip: u32,
+    ) -> Ref<Image, GpuUav> {
+        self.write_mips(handle, access_types, (mip, 1))
+    }
+
     pub fn raster_read<Res: Resource>(
         &mut self,
         handle: &Handle<Res>,
-        access_type: vk_sync::AccessType,
+        access_types: impl Into<AccessTypes>,
     ) -> Ref<Res, GpuRt> {
-        match access_type {
-            AccessType::ColorAttachmentRead | AccessType::DepthStencilAttachmentRead => {}
-            _ => {
-                panic!("Invalid access type: {:?}", access_type);
+        let access_types = access_types.into();
+        let reads_attachment = access_types.contains(AccessType::ColorAttachmentRead)
+            || access_types.contains(AccessType::DepthStencilAttachmentRead);
+
+        for access_type in access_types.as_slice() {
+            match access_type {
+                AccessType::ColorAttachmentRead | AccessType::DepthStencilAttachmentRead => {}
+                // Read-only attachments can also be read by shaders in the same pass
+                AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer
+                | AccessType::FragmentShaderReadColorInputAttachment
+                | AccessType::FragmentShaderReadDepthStencilInputAttachment
+                | AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer
+                    if reads_attachment => {}
+                _ => {
+                    panic!("Invalid access type: {:?}", access_type);
+                }
             }
         }
 
+        check_access_types(&handle.desc, access_types);
+        self.rg.check_handle(handle);
+
         let pass = self.pass.as_mut().unwrap();
 
         pass.read.push(PassResourceRef {
             handle: handle.raw,
             access: PassResourceAccessType::new(
-                access_type,
+                access_types,
                 PassResourceAccessSyncType::SkipSyncIfSameAccessType,
             ),
+            mip_range: None,
         });
 
         Ref {
@@ -242,6 +369,33 @@ impl<'rg> PassBuilder<'rg> {
         }
     }
 
+    /// Declares the depth attachment of a raster pass.
+    ///
+    /// With `DepthAccess::ReadOnly`, the returned ref can also be bound to the pass's
+    /// shaders via `bind_read_only_depth`, e.g. for soft particles or decals.
+    pub fn raster_depth(
+        &mut self,
+        handle: &mut Handle<Image>,
+        access: DepthAccess,
+    ) -> Ref<Image, GpuRt> {
+        assert!(
+            image_aspect_mask_from_format(handle.desc.format).contains(vk::ImageAspectFlags::DEPTH),
+            "raster_depth used with a non-depth format {:?}",
+            handle.desc.format
+        );
+
+        match access {
+            DepthAccess::ReadWrite => self.raster(handle, AccessType::DepthStencilAttachmentWrite),
+            DepthAccess::ReadOnly => self.raster_read(
+                handle,
+                [
+                    AccessType::DepthStencilAttachmentRead,
+                    AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+                ],
+            ),
+        }
+    }
+
     pub fn register_compute_pipeline(&mut self, path: impl AsRef<Path>) -> RgComputePipelineHandle {
         let desc = ComputePipelineDesc::builder()
             .compute_hlsl(path.as_ref().to_owned())
@@ -322,9 +476,77 @@ impl<'rg> PassBuilder<'rg> {
         RgRtPipelineHandle { id }
     }
 
+    /// Refers to this pass in `after` constraints of later passes.
+    pub fn handle(&self) -> PassHandle {
+        PassHandle {
+            idx: self.pass_idx,
+            graph_id: self.rg.graph_id,
+        }
+    }
+
+    /// Makes the pass run after `other`, even when no resource connects them, e.g. to reset
+    /// queries before the pass using them. Like a resource dependency, this keeps `other`
+    /// from being culled while this pass is live, and holds when passes move to async compute.
+    pub fn after(&mut self, other: PassHandle) {
+        assert_eq!(
+            other.graph_id, self.rg.graph_id,
+            "Pass handle belongs to a different render graph"
+        );
+        self.pass.as_mut().unwrap().after.push(other.idx);
+    }
+
+    /// Silences the debug build warning about declared resources which the pass's render function
+    /// never looks up, for passes which only bind some of their resources conditionally.
+    pub fn allow_unused_resources(&mut self) {
+        self.pass.as_mut().unwrap().allow_unused_resources = true;
+    }
+
+    /// Keeps the pass even when none of its outputs are used, e.g. for readbacks.
+    pub fn no_cull(&mut self) {
+        self.pass.as_mut().unwrap().no_cull = true;
+    }
+
+    /// Runs the pass on the async compute queue, concurrently with the universal queue passes
+    /// around it which don't depend on it. Only compute and transfer commands may be recorded.
+    ///
+    /// Falls back to the universal queue if the device has no async compute queue,
+    /// or when the pass needs the results of universal queue passes it would overlap with.
+    pub fn on_async_compute(&mut self) {
+        self.pass.as_mut().unwrap().async_compute = true;
+    }
+
+    /// Decides whether the pass runs when the graph is executed, rather than when it's built.
+    ///
+    /// Skipped passes still get their barriers, so resources end up in the same state either way;
+    /// their writes simply don't happen. Reading a resource which only conditional passes write
+    /// is a validation error, unless the reading pass is conditional too.
+    pub fn executes_if(&mut self, predicate: RgPredicate) {
+        let pass = self.pass.as_mut().unwrap();
+        assert!(
+            pass.predicate.is_none(),
+            "A pass can only have one predicate"
+        );
+
+        pass.predicate = Some(match predicate {
+            RgPredicate::Cpu(predicate) => PassPredicate::Cpu(predicate),
+            RgPredicate::Buffer(handle) => {
+                // `General` covers the conditional rendering stage and access
+                pass.read.push(PassResourceRef {
+                    handle: handle.raw,
+                    access: PassResourceAccessType::new(
+                        AccessType::General,
+                        PassResourceAccessSyncType::SkipSyncIfSameAccessType,
+                    ),
+                    mip_range: None,
+                });
+                PassPredicate::Buffer(handle.raw)
+            }
+        });
+    }
+
     pub fn render(
         mut self,
-        render: impl (FnOnce(&mut RenderPassApi) -> Result<(), BackendError>) + 'static,
+        render: impl (FnMut(&mut RenderPassApi) -> Result<(), BackendError>) + 'static,
     ) {
         let prev = self
             .pass
@@ -336,3 +558,13 @@ impl<'rg> PassBuilder<'rg> {
         assert!(prev.is_none());
     }
 }
+
+/// Images can only be used with several access types at once if they share a layout.
+fn check_access_types<Desc: ResourceDesc>(desc: &Desc, access_types: AccessTypes) {
+    if access_types.single().is_none() && matches!(desc.clone().into(), GraphResourceDesc::Image(_))
+    {
+        if let Err(err) = get_combined_access_info(access_types.as_slice()) {
+            panic!("Invalid access types {:?}: {:#}", access_types, err);
+        }
+    }
+}
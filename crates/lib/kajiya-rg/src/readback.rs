@@ -0,0 +1,264 @@
+use crate::{Buffer, BufferDesc, GpuSrv, Handle, Image, Ref, RenderGraph, RenderPassApi, Resource};
+use kajiya_backend::{
+    ash::vk, vk_sync::AccessType, vulkan::device::ImageReadbackLayout, BackendError, Device,
+};
+use std::borrow::Cow;
+
+type ReadbackCallback = Box<dyn FnOnce(&[u8]) + Send>;
+
+/// Pooled readback buffers which go unused for this many frames are released,
+/// so that large one-off readbacks such as frame captures don't keep their memory.
+const MAX_UNUSED_FRAMES: u64 = 8;
+
+/// Resources which `RenderGraph::readback` can copy to the CPU.
+pub trait ReadbackSource: Resource + Sized {
+    fn readback_size(desc: &Self::Desc) -> Result<usize, BackendError>;
+
+    /// Records a copy of `src` into `buffer`, returning how to unpack image data.
+    fn record_readback_copy(
+        api: &RenderPassApi,
+        src: Ref<Self, GpuSrv>,
+        buffer: &Buffer,
+    ) -> Result<Option<PackedImageLayout>, BackendError>;
+}
+
+/// Image data is copied with padded rows, and repacked before reaching the callback.
+#[derive(Clone, Copy, Debug)]
+pub struct PackedImageLayout {
+    pub layout: ImageReadbackLayout,
+    /// Bytes in a tightly packed row
+    pub row_bytes: usize,
+}
+
+impl ReadbackSource for Image {
+    fn readback_size(desc: &Self::Desc) -> Result<usize, BackendError> {
+        Ok(ImageReadbackLayout::new(desc, 0)?.size_bytes)
+    }
+
+    fn record_readback_copy(
+        api: &RenderPassApi,
+        src: Ref<Self, GpuSrv>,
+        buffer: &Buffer,
+    ) -> Result<Option<PackedImageLayout>, BackendError> {
+        let image = api.resources.image(src);
+        let layout = api
+            .cb
+            .copy_image_to_buffer(api.device(), image, buffer, 0)?;
+
+        Ok(Some(PackedImageLayout {
+            layout,
+            row_bytes: layout.extent[0] as usize * layout.texel_size_bytes,
+        }))
+    }
+}
+
+impl ReadbackSource for Buffer {
+    fn readback_size(desc: &Self::Desc) -> Result<usize, BackendError> {
+        Ok(desc.size)
+    }
+
+    fn record_readback_copy(
+        api: &RenderPassApi,
+        src: Ref<Self, GpuSrv>,
+        buffer: &Buffer,
+    ) -> Result<Option<PackedImageLayout>, BackendError> {
+        let device = api.device();
+        let src = api.resources.buffer(src);
+
+        unsafe {
+            device.raw.cmd_copy_buffer(
+                api.cb.raw,
+                src.raw,
+                buffer.raw,
+                &[vk::BufferCopy {
+                    src_offset: 0,
+                    dst_offset: 0,
+                    size: src.desc.size as u64,
+                }],
+            );
+        }
+        buffer.mark_gpu_write(device);
+
+        Ok(None)
+    }
+}
+
+struct PendingReadback {
+    buffer: Buffer,
+    size: usize,
+    frame_index: u64,
+    image_layout: Option<PackedImageLayout>,
+    callback: ReadbackCallback,
+}
+
+/// Host-visible buffers written by readback passes, waiting for their frames to retire.
+/// Owned by the `Renderer`, which delivers finished readbacks at the start of each frame.
+#[derive(Default)]
+pub struct ReadbackQueue {
+    pending: Vec<PendingReadback>,
+    /// Buffers of delivered readbacks, reused by later ones, and the frame count they were returned at
+    pool: Vec<(Buffer, u64)>,
+}
+
+impl ReadbackQueue {
+    fn get_buffer(&mut self, device: &Device, size: usize) -> Result<Buffer, BackendError> {
+        if let Some(idx) = self
+            .pool
+            .iter()
+            .position(|(buffer, _)| buffer.desc.size >= size)
+        {
+            Ok(self.pool.swap_remove(idx).0)
+        } else {
+            device.create_buffer(
+                BufferDesc::new_gpu_to_cpu(size, vk::BufferUsageFlags::TRANSFER_DST),
+                "readback buffer",
+                None,
+            )
+        }
+    }
+
+    /// Invokes the callbacks of readbacks whose frames the GPU has finished.
+    /// Runs on the thread calling `Renderer::draw_frame`, right after `Device::begin_frame`.
+    pub(crate) fn deliver_retired(&mut self, device: &Device) {
+        self.deliver(device, device.retired_frame_count());
+    }
+
+    /// Invokes the callbacks of readbacks recorded before `retired_frame_count`,
+    /// which the caller guarantees the GPU has finished.
+    pub(crate) fn deliver(&mut self, device: &Device, retired_frame_count: u64) {
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            if self.pending[idx].frame_index >= retired_frame_count {
+                idx += 1;
+                continue;
+            }
+
+            let readback = self.pending.remove(idx);
+            let bytes = &readback.buffer.mapped_slice(device)[..readback.size];
+
+            match readback.image_layout {
+                Some(image_layout) => (readback.callback)(&pack_image_rows(bytes, &image_layout)),
+                None => (readback.callback)(bytes),
+            }
+
+            self.pool.push((readback.buffer, retired_frame_count));
+        }
+
+        let (stale, pool): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pool)
+                .into_iter()
+                .partition(|(_, returned_at)| {
+                    // `Renderer::flush_readbacks` can return buffers ahead of the device's count
+                    retired_frame_count.saturating_sub(*returned_at) > MAX_UNUSED_FRAMES
+                });
+
+        self.pool = pool;
+        for (buffer, _) in stale {
+            device.defer_release_buffer(buffer);
+        }
+    }
+}
+
+/// Strips the row padding of `ImageReadbackLayout`, unless there's none.
+fn pack_image_rows<'a>(bytes: &'a [u8], image_layout: &PackedImageLayout) -> Cow<'a, [u8]> {
+    let PackedImageLayout { layout, row_bytes } = *image_layout;
+
+    if layout.row_pitch_bytes == row_bytes {
+        return Cow::Borrowed(&bytes[..layout.size_bytes]);
+    }
+
+    let row_count = layout.extent[1] as usize * layout.extent[2] as usize;
+    let mut packed = Vec::with_capacity(row_bytes * row_count);
+    for row in bytes[..layout.size_bytes].chunks_exact(layout.row_pitch_bytes) {
+        packed.extend_from_slice(&row[..row_bytes]);
+    }
+
+    Cow::Owned(packed)
+}
+
+impl RenderGraph {
+    /// Copies the contents of `handle` to the CPU, and calls `callback` with them once
+    /// the frame has retired on the GPU, typically two frames later.
+    ///
+    /// Images have their top mip and first layer copied, with tightly packed rows.
+    /// The callback runs on the thread calling `Renderer::draw_frame`, before it records the frame.
+    /// Retained graphs only read back in their first execution.
+    /// Images in formats which can't be copied to buffers fail the pass.
+    pub fn readback<Res: ReadbackSource + 'static>(
+        &mut self,
+        handle: &Handle<Res>,
+        callback: impl FnOnce(&[u8]) + Send + 'static,
+    ) {
+        let desc = handle.desc().clone();
+
+        let mut pass = self.add_pass("readback");
+        pass.no_cull();
+        // Re-executions of retained graphs skip the copy
+        pass.allow_unused_resources();
+
+        let src_ref = pass.read(handle, AccessType::TransferRead);
+        let mut callback: Option<ReadbackCallback> = Some(Box::new(callback));
+
+        pass.render(move |api| {
+            let callback = match callback.take() {
+                Some(callback) => callback,
+                None => return Ok(()),
+            };
+
+            let size = Res::readback_size(&desc)?;
+            let device = api.device();
+            let readbacks = api.resources.execution_params.readbacks;
+
+            let buffer = readbacks.lock().get_buffer(device, size)?;
+            let image_layout = match Res::record_readback_copy(api, src_ref, &buffer) {
+                Ok(image_layout) => image_layout,
+                Err(err) => {
+                    readbacks.lock().pool.push((buffer, device.frame_index()));
+                    return Err(err);
+                }
+            };
+
+            readbacks.lock().pending.push(PendingReadback {
+                buffer,
+                size,
+                frame_index: device.frame_index(),
+                image_layout,
+                callback,
+            });
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn test_pack_image_rows() {
+    let desc = crate::ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [3, 2]);
+    let layout = ImageReadbackLayout::new(&desc, 0).unwrap();
+    assert_eq!(layout.row_pitch_bytes, 256);
+
+    // Each texel holds its row and column index; padding is garbage
+    let mut bytes = vec![0xffu8; layout.size_bytes];
+    for y in 0..2 {
+        for x in 0..3 {
+            let offset = y * layout.row_pitch_bytes + x * 4;
+            bytes[offset..offset + 4].copy_from_slice(&[y as u8, x as u8, 0, 0]);
+        }
+    }
+
+    let packed = pack_image_rows(
+        &bytes,
+        &PackedImageLayout {
+            layout,
+            row_bytes: 3 * 4,
+        },
+    );
+
+    assert_eq!(
+        &*packed,
+        &[
+            0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, //
+            1, 0, 0, 0, 1, 1, 0, 0, 1, 2, 0, 0,
+        ][..]
+    );
+}
@@ -1,6 +1,6 @@
 use crate::{
     CompiledRenderGraph, ExecutingRenderGraph, ExportedTemporalRenderGraphState,
-    PredefinedDescriptorSet, RenderGraphExecutionParams, TemporalRenderGraph,
+    PredefinedDescriptorSet, ReadbackQueue, RenderGraphExecutionParams, TemporalRenderGraph,
     TemporalRenderGraphState, TemporalResourceState,
 };
 use kajiya_backend::{
@@ -8,14 +8,24 @@ use kajiya_backend::{
     dynamic_constants::*,
     pipeline_cache::*,
     rspirv_reflect,
-    transient_resource_cache::TransientResourceCache,
+    transient_resource_cache::{TransientResourceCache, TransientResourceCacheStats},
     vk_sync,
-    vulkan::{self, swapchain::Swapchain, RenderBackend},
-    Device,
+    vulkan::{
+        self,
+        device::{AsyncComputeFrame, CommandBuffer, Queue, QueueKind},
+        swapchain::{Swapchain, SwapchainError, SwapchainImage},
+        RenderBackend,
+    },
+    Device, Image,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{collections::HashMap, sync::Arc};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use turbosloth::*;
 use vulkan::buffer::{Buffer, BufferDesc};
 
@@ -30,6 +40,34 @@ impl Default for TemporalRg {
     }
 }
 
+/// A drawn frame's graph, kept for `Renderer::prepare_retained_frame`.
+struct RetainedRenderGraph {
+    rg: CompiledRenderGraph,
+    temporal_rg_state: ExportedTemporalRenderGraphState,
+}
+
+/// CPU cost of preparing render graphs, to tell what retaining them saves.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RenderGraphCpuStats {
+    /// Time spent building and compiling the most recently built graph, in milliseconds
+    pub build_ms: f32,
+    /// Time spent preparing the last frame, in milliseconds
+    pub prepare_ms: f32,
+    /// Whether the last frame re-executed a retained graph instead of building one
+    pub retained: bool,
+}
+
+impl RenderGraphCpuStats {
+    /// CPU time the last frame saved by re-executing a retained graph.
+    pub fn saved_ms(&self) -> f32 {
+        if self.retained {
+            (self.build_ms - self.prepare_ms).max(0.0)
+        } else {
+            0.0
+        }
+    }
+}
+
 pub struct Renderer {
     device: Arc<Device>,
 
@@ -40,6 +78,11 @@ pub struct Renderer {
 
     compiled_rg: Option<CompiledRenderGraph>,
     temporal_rg_state: TemporalRg,
+    readbacks: Mutex<ReadbackQueue>,
+
+    retain_render_graph: bool,
+    retained_rg: Option<RetainedRenderGraph>,
+    cpu_stats: RenderGraphCpuStats,
 }
 
 lazy_static::lazy_static! {
@@ -91,7 +134,9 @@ impl Renderer {
                     DYNAMIC_CONSTANTS_SIZE_BYTES * DYNAMIC_CONSTANTS_BUFFER_COUNT,
                     vk::BufferUsageFlags::UNIFORM_BUFFER
                         | vk::BufferUsageFlags::STORAGE_BUFFER
-                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                        // Staging for `RenderGraph::upload_buffer`
+                        | vk::BufferUsageFlags::TRANSFER_SRC,
                 ),
                 "dynamic constants buffer",
                 None,
@@ -110,6 +155,11 @@ impl Renderer {
 
             compiled_rg: None,
             temporal_rg_state: Default::default(),
+            readbacks: Default::default(),
+
+            retain_render_graph: false,
+            retained_rg: None,
+            cpu_stats: Default::default(),
         })
     }
 
@@ -119,6 +169,58 @@ impl Renderer {
         swapchain: &mut Swapchain,
     ) where
         PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        let output_extent = swapchain.extent();
+        self.draw_frame_impl(prepare_frame_constants, &mut [swapchain], output_extent);
+    }
+
+    /// Draws the frame to several swapchains, e.g. one per window, and presents all of them.
+    /// The graph writes to each via `get_swap_chain_output`, with its index in `swapchains`.
+    /// Their images are acquired and presented together, with a single submission waiting on
+    /// all of them. Extents relative to the output are relative to the first swapchain.
+    pub fn draw_frame_to_swapchains<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        swapchains: &mut [&mut Swapchain],
+    ) where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        let output_extent = swapchains
+            .first()
+            .expect("draw_frame_to_swapchains needs at least one swapchain")
+            .extent();
+        self.draw_frame_impl(prepare_frame_constants, swapchains, output_extent);
+    }
+
+    /// Draws the frame without presenting it, for headless rendering. The graph can't use
+    /// `get_swap_chain`; it should write to an imported image instead, and `readback` that.
+    /// `output_extent` resolves extents relative to the output.
+    pub fn draw_frame_offscreen<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        output_extent: [u32; 2],
+    ) where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        self.draw_frame_impl(prepare_frame_constants, &mut [], output_extent);
+    }
+
+    /// Waits for the GPU to go idle, and delivers all pending readbacks.
+    /// Lets headless rendering get its results without drawing further frames.
+    pub fn flush_readbacks(&mut self) {
+        unsafe { self.device.raw.device_wait_idle() }.expect("device_wait_idle");
+        self.readbacks
+            .get_mut()
+            .deliver(&self.device, self.device.frame_index() + 1);
+    }
+
+    fn draw_frame_impl<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        swapchains: &mut [&mut Swapchain],
+        output_extent: [u32; 2],
+    ) where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
     {
         let rg = if let Some(rg) = self.compiled_rg.take() {
             rg
@@ -131,11 +233,19 @@ impl Renderer {
 
         let current_frame = self.device.begin_frame();
 
-        // Both command buffers are accessible now, so begin recording.
-        for cb in [
+        // Frames which wrote readbacks may have retired now
+        self.readbacks.get_mut().deliver_retired(device);
+
+        // All command buffers are accessible now, so begin recording.
+        let mut command_buffers = vec![
             &current_frame.main_command_buffer,
             &current_frame.presentation_command_buffer,
-        ] {
+        ];
+        if let Some(async_compute) = &current_frame.async_compute {
+            command_buffers.extend(async_compute.command_buffers().iter().copied());
+        }
+
+        for cb in command_buffers {
             unsafe {
                 raw_device
                     .reset_command_buffer(cb.raw, vk::CommandBufferResetFlags::default())
@@ -172,6 +282,8 @@ impl Renderer {
                         frame_descriptor_set: self.frame_descriptor_set,
                         frame_constants_layout,
                         profiler_data: &current_frame.profiler_data,
+                        readbacks: &self.readbacks,
+                        output_extent,
                     },
                     &mut self.transient_resource_cache,
                     &mut self.dynamic_constants,
@@ -182,15 +294,27 @@ impl Renderer {
             unsafe {
                 puffin::profile_scope!("main cb");
 
-                {
+                let async_compute = {
                     puffin::profile_scope!("rg::record_main_cb");
-                    executing_rg.record_main_cb(main_cb)
-                }
+                    let async_compute = current_frame.async_compute.as_ref();
+                    if executing_rg.record_main_cb(main_cb, async_compute) {
+                        async_compute
+                    } else {
+                        None
+                    }
+                };
 
                 raw_device.end_command_buffer(main_cb.raw).unwrap();
 
                 let submit_info = [vk::SubmitInfo::builder()
                     .command_buffers(std::slice::from_ref(&main_cb.raw))
+                    .signal_semaphores(
+                        async_compute
+                            .map(|async_compute| {
+                                std::slice::from_ref(&async_compute.fork_semaphore)
+                            })
+                            .unwrap_or_default(),
+                    )
                     .build()];
 
                 raw_device
@@ -200,24 +324,55 @@ impl Renderer {
                 puffin::profile_scope!("submit main cb");
 
                 // Try to submit the command buffer to the GPU. We might encounter a GPU crash.
-                raw_device
-                    .queue_submit(
-                        self.device.universal_queue.raw,
-                        &submit_info,
-                        main_cb.submit_done_fence,
-                    )
+                self.device
+                    .universal_queue
+                    .submit(raw_device, &submit_info, main_cb.submit_done_fence)
                     .map_err(|err| device.report_error(err.into()))
                     .expect("main queue_submit failed");
+
+                if let Some(async_compute) = async_compute {
+                    puffin::profile_scope!("submit async compute cbs");
+                    Self::submit_async_compute(device, async_compute);
+                }
             };
         }
 
-        // Now that we've done the main submission and the GPU is busy, acquire the presentation image.
+        // Now that we've done the main submission and the GPU is busy, acquire the presentation images.
         // This can block, so we're doing it as late as possible.
-
-        let swapchain_image = swapchain
-            .acquire_next_image()
-            .ok()
-            .expect("swapchain image");
+        //
+        // Out-of-date swapchains can't be drawn to until they're recreated, so their output
+        // goes to a stand-in image which isn't presented. Suboptimal ones are drawn as usual.
+        let swapchain_images: Vec<Option<SwapchainImage>> = swapchains
+            .iter_mut()
+            .map(
+                |swapchain| match swapchain.acquire_next_image(Duration::MAX) {
+                    Ok(image) => Some(image),
+                    Err(SwapchainError::OutOfDate) => {
+                        warn!("The swapchain is out of date; skipping its presentation");
+                        None
+                    }
+                    Err(err) => panic!("Could not acquire swapchain image: {:?}", err),
+                },
+            )
+            .collect();
+
+        let mut stand_in_images: Vec<Arc<Image>> = Vec::new();
+        let output_images: Vec<Arc<Image>> = swapchains
+            .iter()
+            .zip(&swapchain_images)
+            .map(|(swapchain, swapchain_image)| match swapchain_image {
+                Some(swapchain_image) => swapchain_image.image.clone(),
+                None => {
+                    let image = Arc::new(
+                        device
+                            .create_image(swapchain.images[0].desc, vec![])
+                            .expect("stand-in swapchain image"),
+                    );
+                    stand_in_images.push(image.clone());
+                    image
+                }
+            })
+            .collect();
 
         // Execute the rest of the render graph, and submit the presentation command buffer.
         let retired_rg = {
@@ -225,33 +380,36 @@ impl Renderer {
 
             let presentation_cb = &current_frame.presentation_command_buffer;
 
-            // Transition the swapchain to CS write
-            vulkan::barrier::record_image_barrier(
-                device,
-                presentation_cb.raw,
-                vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::Present,
-                    vk_sync::AccessType::ComputeShaderWrite,
-                    vk::ImageAspectFlags::COLOR,
-                )
-                .with_discard(true),
-            );
-
-            let retired_rg =
-                executing_rg.record_presentation_cb(presentation_cb, swapchain_image.image.clone());
-
-            // Transition the swapchain to present
-            vulkan::barrier::record_image_barrier(
-                device,
-                presentation_cb.raw,
-                vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::ComputeShaderWrite,
-                    vk_sync::AccessType::Present,
-                    vk::ImageAspectFlags::COLOR,
-                ),
-            );
+            // Transition the swapchains to CS write
+            for image in &output_images {
+                vulkan::barrier::record_image_barrier(
+                    device,
+                    presentation_cb.raw,
+                    vulkan::barrier::ImageBarrier::new(
+                        image.raw,
+                        vk_sync::AccessType::Present,
+                        vk_sync::AccessType::ComputeShaderWrite,
+                        vk::ImageAspectFlags::COLOR,
+                    )
+                    .with_discard(true),
+                );
+            }
+
+            let retired_rg = executing_rg.record_presentation_cb(presentation_cb, &output_images);
+
+            // Transition the swapchains to present
+            for swapchain_image in swapchain_images.iter().flatten() {
+                vulkan::barrier::record_image_barrier(
+                    device,
+                    presentation_cb.raw,
+                    vulkan::barrier::ImageBarrier::new(
+                        swapchain_image.image.raw,
+                        vk_sync::AccessType::ComputeShaderWrite,
+                        vk_sync::AccessType::Present,
+                        vk::ImageAspectFlags::COLOR,
+                    ),
+                );
+            }
 
             current_frame
                 .profiler_data
@@ -261,12 +419,23 @@ impl Renderer {
             unsafe {
                 raw_device.end_command_buffer(presentation_cb.raw).unwrap();
 
+                let wait_semaphores: Vec<vk::Semaphore> = swapchain_images
+                    .iter()
+                    .flatten()
+                    .map(|image| image.acquire_semaphore)
+                    .collect();
+                let wait_dst_stage_mask =
+                    vec![vk::PipelineStageFlags::COMPUTE_SHADER; wait_semaphores.len()];
+                let signal_semaphores: Vec<vk::Semaphore> = swapchain_images
+                    .iter()
+                    .flatten()
+                    .map(|image| image.rendering_finished_semaphore)
+                    .collect();
+
                 let submit_info = [vk::SubmitInfo::builder()
-                    .wait_semaphores(std::slice::from_ref(&swapchain_image.acquire_semaphore))
-                    .signal_semaphores(std::slice::from_ref(
-                        &swapchain_image.rendering_finished_semaphore,
-                    ))
-                    .wait_dst_stage_mask(&[vk::PipelineStageFlags::COMPUTE_SHADER])
+                    .wait_semaphores(&wait_semaphores)
+                    .signal_semaphores(&signal_semaphores)
+                    .wait_dst_stage_mask(&wait_dst_stage_mask)
                     .command_buffers(std::slice::from_ref(&presentation_cb.raw))
                     .build()];
                 raw_device
@@ -274,40 +443,121 @@ impl Renderer {
                     .expect("reset_fences");
 
                 puffin::profile_scope!("submit presentation cb");
-                raw_device
-                    .queue_submit(
-                        self.device.universal_queue.raw,
-                        &submit_info,
-                        presentation_cb.submit_done_fence,
-                    )
+                self.device
+                    .universal_queue
+                    .submit(raw_device, &submit_info, presentation_cb.submit_done_fence)
                     .map_err(|err| device.report_error(err.into()))
                     .expect("presentation queue_submit failed");
             }
 
-            swapchain.present_image(swapchain_image);
+            for (swapchain, swapchain_image) in swapchains.iter().zip(swapchain_images) {
+                let swapchain_image = match swapchain_image {
+                    Some(swapchain_image) => swapchain_image,
+                    None => continue,
+                };
+                let rendering_finished_semaphore = swapchain_image.rendering_finished_semaphore;
+                match swapchain.present(swapchain_image, rendering_finished_semaphore) {
+                    // Handled in the next frame
+                    Ok(()) | Err(SwapchainError::OutOfDate) | Err(SwapchainError::Suboptimal) => {}
+                    Err(err) => panic!("Could not present image: {:?}", err),
+                }
+            }
 
             retired_rg
         };
 
-        self.temporal_rg_state = match std::mem::take(&mut self.temporal_rg_state) {
+        let temporal_rg_state = match std::mem::take(&mut self.temporal_rg_state) {
             TemporalRg::Inert(_) => {
                 panic!("Trying to retire the render graph, but it's inert. Was prepare_frame not caled?");
             }
-            TemporalRg::Exported(rg) => TemporalRg::Inert(rg.retire_temporal(&retired_rg)),
+            TemporalRg::Exported(rg) => rg,
         };
+        self.temporal_rg_state = TemporalRg::Inert(temporal_rg_state.retire_temporal(&retired_rg));
 
-        retired_rg.release_resources(&mut self.transient_resource_cache);
+        if self.retain_render_graph {
+            self.retained_rg = Some(RetainedRenderGraph {
+                rg: retired_rg.retain(&mut self.transient_resource_cache),
+                temporal_rg_state,
+            });
+        } else {
+            retired_rg.release_resources(&mut self.transient_resource_cache);
+        }
+        self.transient_resource_cache.maintain(&self.device);
+
+        // The retired graph has let go of the stand-ins by now
+        drop(output_images);
+        for image in stand_in_images {
+            let image = Arc::try_unwrap(image)
+                .ok()
+                .expect("stand-in swapchain image still in use");
+            self.device.defer_release_image(image);
+        }
 
         self.dynamic_constants.advance_frame();
         self.device.finish_frame(current_frame);
     }
 
+    /// Submits the command buffers which `ExecutingRenderGraph::record_main_cb` split off
+    /// the main one, in the order described in `AsyncComputeFrame`.
+    unsafe fn submit_async_compute(device: &Device, async_compute: &AsyncComputeFrame) {
+        let raw_device = &device.raw;
+        let async_compute_queue = device.queue(QueueKind::AsyncCompute);
+
+        let submit = |queue: &Queue,
+                      cb: &CommandBuffer,
+                      wait_semaphore: Option<vk::Semaphore>,
+                      signal_semaphore: Option<vk::Semaphore>| {
+            raw_device.end_command_buffer(cb.raw).unwrap();
+
+            let wait_semaphores: Vec<vk::Semaphore> = wait_semaphore.into_iter().collect();
+            let wait_dst_stage_mask =
+                vec![vk::PipelineStageFlags::ALL_COMMANDS; wait_semaphores.len()];
+            let signal_semaphores: Vec<vk::Semaphore> = signal_semaphore.into_iter().collect();
+
+            let submit_info = [vk::SubmitInfo::builder()
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_dst_stage_mask)
+                .signal_semaphores(&signal_semaphores)
+                .command_buffers(std::slice::from_ref(&cb.raw))
+                .build()];
+
+            raw_device
+                .reset_fences(std::slice::from_ref(&cb.submit_done_fence))
+                .expect("reset_fences");
+
+            queue
+                .submit(raw_device, &submit_info, cb.submit_done_fence)
+                .map_err(|err| device.report_error(err.into()))
+                .expect("async compute queue_submit failed");
+        };
+
+        submit(
+            async_compute_queue,
+            &async_compute.command_buffer,
+            Some(async_compute.fork_semaphore),
+            Some(async_compute.join_semaphore),
+        );
+        submit(
+            &device.universal_queue,
+            &async_compute.overlap_command_buffer,
+            None,
+            None,
+        );
+        submit(
+            &device.universal_queue,
+            &async_compute.join_command_buffer,
+            Some(async_compute.join_semaphore),
+            None,
+        );
+    }
+
     // Descriptor set for per-frame data
     fn create_frame_descriptor_set(
         backend: &RenderBackend,
         dynamic_constants: &Buffer,
     ) -> vk::DescriptorSet {
         let device = &backend.device.raw;
+        let stage_flags = backend.device.default_descriptor_set_stage_flags();
 
         let set_binding_flags = [
             vk::DescriptorBindingFlags::PARTIALLY_BOUND,
@@ -329,21 +579,21 @@ impl Renderer {
                             vk::DescriptorSetLayoutBinding::builder()
                                 .descriptor_count(1)
                                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
-                                .stage_flags(vk::ShaderStageFlags::ALL)
+                                .stage_flags(stage_flags)
                                 .binding(0)
                                 .build(),
                             // instance_dynamic_parameters
                             vk::DescriptorSetLayoutBinding::builder()
                                 .descriptor_count(1)
                                 .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
-                                .stage_flags(vk::ShaderStageFlags::ALL)
+                                .stage_flags(stage_flags)
                                 .binding(1)
                                 .build(),
                             // triangle_lights_dyn
                             vk::DescriptorSetLayoutBinding::builder()
                                 .descriptor_count(1)
                                 .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
-                                .stage_flags(vk::ShaderStageFlags::ALL)
+                                .stage_flags(stage_flags)
                                 .binding(2)
                                 .build(),
                         ])
@@ -426,6 +676,44 @@ impl Renderer {
         set
     }
 
+    /// Makes `draw_frame` keep the graph it executed, for `prepare_retained_frame`.
+    pub fn set_retain_render_graph(&mut self, retain: bool) {
+        self.retain_render_graph = retain;
+        if !retain {
+            self.retained_rg = None;
+        }
+    }
+
+    /// Prepares the next frame by re-executing the graph of the last drawn one, instead of
+    /// building a new graph. Returns `false` if there's no retained graph, in which case
+    /// `prepare_frame` must be used. Requires `set_retain_render_graph`.
+    ///
+    /// Only data read at execution time changes between executions, such as the frame constants
+    /// written by `draw_frame`; see `RetiredRenderGraph::retain`.
+    pub fn prepare_retained_frame(&mut self) -> anyhow::Result<bool> {
+        let start = Instant::now();
+
+        let retained = if let Some(retained) = self.retained_rg.take() {
+            retained
+        } else {
+            return Ok(false);
+        };
+
+        // Shaders may still be hot-reloaded
+        if let Err(err) = self.pipeline_cache.prepare_frame(&self.device) {
+            self.retained_rg = Some(retained);
+            return Err(err);
+        }
+
+        self.compiled_rg = Some(retained.rg);
+        self.temporal_rg_state = TemporalRg::Exported(retained.temporal_rg_state);
+
+        self.cpu_stats.prepare_ms = start.elapsed().as_secs_f32() * 1000.0;
+        self.cpu_stats.retained = true;
+
+        Ok(true)
+    }
+
     pub fn prepare_frame<PrepareRenderGraphFn>(
         &mut self,
         prepare_render_graph: PrepareRenderGraphFn,
@@ -433,6 +721,11 @@ impl Renderer {
     where
         PrepareRenderGraphFn: FnOnce(&mut TemporalRenderGraph),
     {
+        let start = Instant::now();
+
+        // Superseded by the graph built now
+        self.retained_rg = None;
+
         let mut rg = TemporalRenderGraph::new(
             match &self.temporal_rg_state {
                 TemporalRg::Inert(state) => state.clone_assuming_inert(),
@@ -455,10 +748,23 @@ impl Renderer {
 
         self.compiled_rg = Some(rg.compile(&mut self.pipeline_cache));
 
+        let build_ms = start.elapsed().as_secs_f32() * 1000.0;
+        self.cpu_stats = RenderGraphCpuStats {
+            build_ms,
+            prepare_ms: build_ms,
+            retained: false,
+        };
+
         match self.pipeline_cache.prepare_frame(&self.device) {
             Ok(()) => {
                 // If the frame preparation succeded, update stored temporal rg state and finish
                 self.temporal_rg_state = TemporalRg::Exported(temporal_rg_state);
+
+                // The state the graph was built from is gone now, so images it dropped can go too
+                if let TemporalRg::Exported(state) = &mut self.temporal_rg_state {
+                    state.0.release_stale_images(&self.device);
+                }
+
                 Ok(())
             }
             Err(err) => {
@@ -499,4 +805,21 @@ impl Renderer {
     pub fn device(&self) -> &Arc<Device> {
         &self.device
     }
+
+    /// Shaders which failed to hot-reload. Their last working pipelines remain in use.
+    pub fn shader_compile_errors(
+        &self,
+    ) -> impl Iterator<Item = &kajiya_backend::shader_compiler::ShaderCompileError> {
+        self.pipeline_cache.compile_errors()
+    }
+
+    /// CPU time spent preparing the last frame's graph.
+    pub fn render_graph_cpu_stats(&self) -> RenderGraphCpuStats {
+        self.cpu_stats
+    }
+
+    /// Transient resource creation and pooling counters for the last drawn frame.
+    pub fn transient_resource_stats(&self) -> TransientResourceCacheStats {
+        self.transient_resource_cache.stats()
+    }
 }
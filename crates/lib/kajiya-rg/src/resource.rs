@@ -74,6 +74,34 @@ impl From<RayTracingAccelerationDesc> for GraphResourceDesc {
     }
 }
 
+/// Extent of an image created by the render graph.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageExtent {
+    Absolute([u32; 3]),
+    /// Fraction of `RenderGraphExecutionParams::output_extent`, e.g. `[0.5, 0.5]` for half-res.
+    /// Resolved when the graph executes, so the image follows the output when it's resized.
+    RelativeToOutput {
+        scale: [f32; 2],
+    },
+}
+
+impl ImageExtent {
+    /// Relative extents are rounded up, and are at least one pixel.
+    pub fn resolve(&self, output_extent: [u32; 2]) -> [u32; 3] {
+        match *self {
+            Self::Absolute(extent) => extent,
+            Self::RelativeToOutput { scale } => {
+                let resolve = |size: u32, scale: f32| ((size as f32 * scale).ceil() as u32).max(1);
+                [
+                    resolve(output_extent[0], scale[0]),
+                    resolve(output_extent[1], scale[1]),
+                    1,
+                ]
+            }
+        }
+    }
+}
+
 pub trait ResourceDesc: Clone + std::fmt::Debug + Into<GraphResourceDesc> {
     type Resource: Resource;
 }
@@ -110,6 +138,8 @@ pub struct Handle<ResType: Resource> {
     pub(crate) raw: GraphRawResourceHandle,
     pub(crate) desc: <ResType as Resource>::Desc,
     pub(crate) marker: PhantomData<ResType>,
+    /// `RenderGraph::graph_id` of the graph the handle was created by
+    pub(crate) graph_id: u32,
 }
 
 #[derive(Debug)]
@@ -145,6 +175,7 @@ impl<ResType: Resource> Handle<ResType> {
             raw: self.raw,
             desc: self.desc.clone(),
             marker: PhantomData,
+            graph_id: self.graph_id,
         }
     }
 }
@@ -202,3 +233,21 @@ impl GpuViewType for GpuUav {
 impl GpuViewType for GpuRt {
     const IS_WRITABLE: bool = true;
 }
+
+#[test]
+fn test_resolve_relative_extent() {
+    let half_res = ImageExtent::RelativeToOutput { scale: [0.5, 0.5] };
+    assert_eq!(half_res.resolve([1920, 1080]), [960, 540, 1]);
+    assert_eq!(half_res.resolve([1281, 721]), [641, 361, 1]);
+    assert_eq!(half_res.resolve([1, 1]), [1, 1, 1]);
+
+    let tiny = ImageExtent::RelativeToOutput {
+        scale: [1.0 / 64.0, 0.0],
+    };
+    assert_eq!(tiny.resolve([1920, 1080]), [30, 1, 1]);
+
+    assert_eq!(
+        ImageExtent::Absolute([4, 4, 1]).resolve([1920, 1080]),
+        [4, 4, 1]
+    );
+}
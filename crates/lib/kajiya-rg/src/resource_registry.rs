@@ -1,4 +1,4 @@
-use crate::{GraphResourceInfo, RenderGraphPipelines};
+use crate::{AccessTypes, GraphResourceInfo, RenderGraphPipelines};
 
 use super::{
     graph::RenderGraphExecutionParams, resource::*, RgComputePipelineHandle,
@@ -7,14 +7,16 @@ use super::{
 use kajiya_backend::{
     ash::vk,
     dynamic_constants::DynamicConstants,
-    vk_sync,
     vulkan::{
         ray_tracing::{RayTracingAcceleration, RayTracingPipeline},
         shader::{ComputePipeline, RasterPipeline},
     },
     BackendError,
 };
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 pub struct PendingRenderResourceInfo {
     pub(crate) resource: GraphResourceInfo,
@@ -55,9 +57,24 @@ pub enum AnyRenderResourceRef<'a> {
     RayTracingAcceleration(&'a RayTracingAcceleration),
 }
 
+impl<'a> AnyRenderResourceRef<'a> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Image(_) => "an image",
+            Self::Buffer(_) => "a buffer",
+            Self::RayTracingAcceleration(_) => "an acceleration structure",
+        }
+    }
+}
+
 pub(crate) struct RegistryResource {
     pub resource: AnyRenderResource,
-    pub access_type: vk_sync::AccessType,
+    pub access_types: AccessTypes,
+    /// Per-mip access types of images whose mips are in different states.
+    /// Empty while the whole image is in `access_types`.
+    pub mip_access_types: Vec<AccessTypes>,
+    /// Set when a pass looks the resource up; used to find unused pass declarations.
+    pub resolved: AtomicBool,
 }
 
 pub struct ResourceRegistry<'exec_params, 'constants> {
@@ -68,6 +85,12 @@ pub struct ResourceRegistry<'exec_params, 'constants> {
 }
 
 impl<'exec_params, 'constants> ResourceRegistry<'exec_params, 'constants> {
+    fn resolve(&self, handle: GraphRawResourceHandle) -> AnyRenderResourceRef {
+        let resource = &self.resources[handle.id as usize];
+        resource.resolved.store(true, Ordering::Relaxed);
+        resource.resource.borrow()
+    }
+
     pub fn image<ViewType: GpuViewType>(&self, resource: Ref<Image, ViewType>) -> &Image {
         self.image_from_raw_handle::<ViewType>(resource.handle)
     }
@@ -76,23 +99,35 @@ impl<'exec_params, 'constants> ResourceRegistry<'exec_params, 'constants> {
         &self,
         handle: GraphRawResourceHandle,
     ) -> &Image {
-        match &self.resources[handle.id as usize].resource.borrow() {
-            AnyRenderResourceRef::Image(img) => *img,
-            _ => panic!(),
+        match self.resolve(handle) {
+            AnyRenderResourceRef::Image(img) => img,
+            res => panic!("Resource #{} is {}, not an image", handle.id, res.kind()),
         }
     }
 
+    /// The desc of the image backing `resource`, including the usage flags the graph gave it.
+    pub fn image_desc<ViewType: GpuViewType>(&self, resource: Ref<Image, ViewType>) -> &ImageDesc {
+        &self.image(resource).desc
+    }
+
     pub fn buffer<ViewType: GpuViewType>(&self, resource: Ref<Buffer, ViewType>) -> &Buffer {
         self.buffer_from_raw_handle::<ViewType>(resource.handle)
     }
 
+    pub fn buffer_desc<ViewType: GpuViewType>(
+        &self,
+        resource: Ref<Buffer, ViewType>,
+    ) -> &BufferDesc {
+        &self.buffer(resource).desc
+    }
+
     pub(crate) fn buffer_from_raw_handle<ViewType: GpuViewType>(
         &self,
         handle: GraphRawResourceHandle,
     ) -> &Buffer {
-        match &self.resources[handle.id as usize].resource.borrow() {
-            AnyRenderResourceRef::Buffer(buffer) => *buffer,
-            _ => panic!(),
+        match self.resolve(handle) {
+            AnyRenderResourceRef::Buffer(buffer) => buffer,
+            res => panic!("Resource #{} is {}, not a buffer", handle.id, res.kind()),
         }
     }
 
@@ -107,9 +142,13 @@ impl<'exec_params, 'constants> ResourceRegistry<'exec_params, 'constants> {
         &self,
         handle: GraphRawResourceHandle,
     ) -> &RayTracingAcceleration {
-        match &self.resources[handle.id as usize].resource.borrow() {
-            AnyRenderResourceRef::RayTracingAcceleration(acc) => *acc,
-            _ => panic!(),
+        match self.resolve(handle) {
+            AnyRenderResourceRef::RayTracingAcceleration(acc) => acc,
+            res => panic!(
+                "Resource #{} is {}, not an acceleration structure",
+                handle.id,
+                res.kind()
+            ),
         }
     }
 
@@ -121,15 +160,17 @@ impl<'exec_params, 'constants> ResourceRegistry<'exec_params, 'constants> {
     where
         's: 'a,
     {
-        let view_desc = view_desc;
-
-        let image = match &self.resources[resource.id as usize].resource.borrow() {
-            AnyRenderResourceRef::Image(img) => *img,
-            _ => panic!(),
-        };
+        let image = self.image_from_raw_handle::<GpuSrv>(resource);
 
         let device = self.execution_params.device;
-        image.view(device, view_desc)
+        image
+            .view(device, view_desc)
+            .map_err(|err| BackendError::ResourceAccess {
+                info: format!(
+                    "Creating view {:?} of image #{} ({:?}) failed: {}",
+                    view_desc, resource.id, image.desc, err
+                ),
+            })
     }
 
     pub fn compute_pipeline(&self, pipeline: RgComputePipelineHandle) -> Arc<ComputePipeline> {
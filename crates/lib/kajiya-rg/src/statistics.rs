@@ -0,0 +1,238 @@
+use crate::{GraphResourceDesc, BARRIERS_SCOPE_NAME, SKIPPED_PASS_SCOPE_NAME};
+use kajiya_backend::gpu_profiler::{self, RenderScopeDesc};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+
+lazy_static::lazy_static! {
+    static ref LATEST_TRANSIENT_MEMORY: Mutex<TransientMemoryStatistics> = Default::default();
+}
+
+/// Memory backing a resource created by the render graph.
+#[derive(Clone, Debug)]
+pub struct TransientResourceMemory {
+    pub name: String,
+    pub desc: GraphResourceDesc,
+    /// Size of the resource's allocation
+    pub size_bytes: u64,
+    /// First and last pass using the resource, indexed after culling.
+    /// `None` if no pass uses it, in which case it's still allocated.
+    pub passes: Option<(usize, usize)>,
+}
+
+/// Memory of the transient resources of the most recently executed render graph.
+#[derive(Clone, Default, Debug)]
+pub struct TransientMemoryStatistics {
+    pub resources: Vec<TransientResourceMemory>,
+    /// Memory of all the transients, as they are allocated now, without aliasing
+    pub total_bytes: u64,
+    /// Largest amount of memory used by transients alive during the same pass;
+    /// what the graph would need if all resources with disjoint lifetimes were aliased
+    pub peak_bytes: u64,
+}
+
+impl TransientMemoryStatistics {
+    pub fn new(resources: Vec<TransientResourceMemory>) -> Self {
+        let total_bytes = resources.iter().map(|res| res.size_bytes).sum();
+
+        let pass_count = resources
+            .iter()
+            .filter_map(|res| res.passes.map(|(_, last)| last + 1))
+            .max()
+            .unwrap_or(0);
+
+        let mut pass_bytes = vec![0u64; pass_count];
+        for res in &resources {
+            if let Some((first, last)) = res.passes {
+                for bytes in &mut pass_bytes[first..=last] {
+                    *bytes += res.size_bytes;
+                }
+            }
+        }
+
+        Self {
+            resources,
+            total_bytes,
+            peak_bytes: pass_bytes.into_iter().max().unwrap_or(0),
+        }
+    }
+
+    pub(crate) fn publish(self) {
+        *LATEST_TRANSIENT_MEMORY.lock() = self;
+    }
+}
+
+/// GPU timings of render graph passes from the most recent frame whose timestamp queries
+/// have been read back. Getting them never waits for the GPU; results lag a frame or two.
+#[derive(Clone, Default)]
+pub struct RenderGraphStatistics {
+    /// Passes in execution order, with their GPU time in milliseconds
+    pub passes: Vec<(RenderScopeDesc, f32)>,
+    /// Time spent in the barriers recorded before passes, in milliseconds
+    pub barriers_ms: f32,
+    /// Passes which `PassBuilder::executes_if` skipped on the CPU. They're still listed in `passes`.
+    /// Passes with GPU predicates can't be told apart, and count as having run.
+    pub skipped_passes: Vec<RenderScopeDesc>,
+    /// Transient memory of the last graph which began executing.
+    /// Unlike the timings, this doesn't lag behind.
+    pub transient_memory: TransientMemoryStatistics,
+}
+
+impl RenderGraphStatistics {
+    pub fn latest() -> Self {
+        let mut res = Self::default();
+        let mut skipped_ids = Vec::new();
+
+        for (scope, ms) in gpu_profiler::get_stats().get_ordered() {
+            if scope.name == BARRIERS_SCOPE_NAME {
+                res.barriers_ms += ms as f32;
+            } else if scope.name == SKIPPED_PASS_SCOPE_NAME {
+                skipped_ids.push(scope.id);
+            } else {
+                res.passes.push((scope, ms as f32));
+            }
+        }
+
+        res.skipped_passes = res
+            .passes
+            .iter()
+            .filter(|(scope, _)| skipped_ids.contains(&scope.id))
+            .map(|(scope, _)| scope.clone())
+            .collect();
+
+        res.transient_memory = LATEST_TRANSIENT_MEMORY.lock().clone();
+
+        res
+    }
+
+    pub fn ran(&self, pass: &RenderScopeDesc) -> bool {
+        !self.skipped_passes.contains(pass)
+    }
+
+    pub fn total_ms(&self) -> f32 {
+        self.passes.iter().map(|(_, ms)| ms).sum::<f32>() + self.barriers_ms
+    }
+}
+
+/// Rolling average of `RenderGraphStatistics` over the last few frames,
+/// for displays which would otherwise flicker.
+pub struct RenderGraphStatisticsHistory {
+    frame_count: usize,
+    passes: HashMap<RenderScopeDesc, VecDeque<f32>>,
+    barriers: VecDeque<f32>,
+    latest: RenderGraphStatistics,
+}
+
+impl RenderGraphStatisticsHistory {
+    pub fn new(frame_count: usize) -> Self {
+        Self {
+            frame_count: frame_count.max(1),
+            passes: Default::default(),
+            barriers: Default::default(),
+            latest: Default::default(),
+        }
+    }
+
+    /// Passes which didn't run in the `stats` frame are dropped from the history.
+    pub fn push(&mut self, stats: RenderGraphStatistics) {
+        let frame_count = self.frame_count;
+        let push = |history: &mut VecDeque<f32>, ms: f32| {
+            if history.len() == frame_count {
+                history.pop_front();
+            }
+            history.push_back(ms);
+        };
+
+        self.passes
+            .retain(|scope, _| stats.passes.iter().any(|(pass, _)| pass == scope));
+
+        for (scope, ms) in &stats.passes {
+            push(self.passes.entry(scope.clone()).or_default(), *ms);
+        }
+        push(&mut self.barriers, stats.barriers_ms);
+
+        self.latest = stats;
+    }
+
+    /// Passes of the latest frame in execution order, with timings averaged over the history.
+    /// Skipped passes are also the ones from the latest frame.
+    pub fn average(&self) -> RenderGraphStatistics {
+        fn average(history: &VecDeque<f32>) -> f32 {
+            history.iter().sum::<f32>() / (history.len() as f32).max(1.0)
+        }
+
+        RenderGraphStatistics {
+            passes: self
+                .latest
+                .passes
+                .iter()
+                .map(|(scope, _)| (scope.clone(), average(&self.passes[scope])))
+                .collect(),
+            barriers_ms: average(&self.barriers),
+            skipped_passes: self.latest.skipped_passes.clone(),
+            transient_memory: self.latest.transient_memory.clone(),
+        }
+    }
+
+    pub fn latest(&self) -> &RenderGraphStatistics {
+        &self.latest
+    }
+}
+
+#[test]
+fn test_statistics_history() {
+    let scope = |name: &str, id: u64| RenderScopeDesc {
+        name: name.to_owned(),
+        id,
+    };
+    let frame = |passes: &[(&str, f32)], barriers_ms: f32| RenderGraphStatistics {
+        passes: passes
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, ms))| (scope(name, idx as u64), *ms))
+            .collect(),
+        barriers_ms,
+        skipped_passes: Vec::new(),
+        transient_memory: Default::default(),
+    };
+
+    let mut history = RenderGraphStatisticsHistory::new(2);
+    history.push(frame(&[("gbuffer", 1.0), ("ssgi", 4.0)], 0.5));
+    history.push(frame(&[("gbuffer", 3.0), ("ssgi", 2.0)], 1.5));
+    history.push(frame(&[("gbuffer", 5.0), ("ssgi", 2.0)], 0.5));
+
+    let average = history.average();
+    assert_eq!(
+        average.passes,
+        vec![(scope("gbuffer", 0), 4.0), (scope("ssgi", 1), 2.0)]
+    );
+    assert_eq!(average.barriers_ms, 1.0);
+    assert_eq!(average.total_ms(), 7.0);
+
+    // A pass which stops running doesn't linger in the averages
+    history.push(frame(&[("gbuffer", 5.0)], 0.5));
+    assert_eq!(history.average().passes, vec![(scope("gbuffer", 0), 5.0)]);
+}
+
+#[test]
+fn test_transient_memory_peak() {
+    use kajiya_backend::{ash::vk, vulkan::image::ImageDesc};
+
+    let resource =
+        |name: &str, size_bytes: u64, passes: Option<(usize, usize)>| TransientResourceMemory {
+            name: name.to_owned(),
+            desc: ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]).into(),
+            size_bytes,
+            passes,
+        };
+
+    let stats = TransientMemoryStatistics::new(vec![
+        resource("gbuffer", 100, Some((0, 2))),
+        resource("ssao", 10, Some((1, 2))),
+        resource("bloom", 40, Some((3, 4))),
+        resource("unused", 5, None),
+    ]);
+
+    assert_eq!(stats.total_bytes, 155);
+    // `gbuffer` and `ssao` are both alive during passes 1 and 2
+    assert_eq!(stats.peak_bytes, 110);
+}
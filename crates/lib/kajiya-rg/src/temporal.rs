@@ -5,11 +5,15 @@ use std::{
 
 use anyhow::Context;
 
-use kajiya_backend::{vk_sync::AccessType, Device, Image, ImageDesc};
+use kajiya_backend::{
+    ash::vk, vk_sync::AccessType, vulkan::barrier::image_aspect_mask_from_format, Device, Image,
+    ImageDesc,
+};
 
 use super::{
-    Buffer, BufferDesc, ExportableGraphResource, ExportedHandle, Handle, RenderGraph, Resource,
-    ResourceDesc, RetiredRenderGraph, TypeEquals,
+    exported_image::ExportedImageState, Buffer, BufferDesc, ExportableGraphResource,
+    ExportedHandle, ExportedImage, Handle, RenderGraph, Resource, ResourceDesc, RetiredRenderGraph,
+    TypeEquals,
 };
 
 pub struct ReadOnlyHandle<ResType: Resource>(Handle<ResType>);
@@ -72,6 +76,11 @@ pub(crate) enum TemporalResourceState {
 #[derive(Default)]
 pub struct TemporalRenderGraphState {
     pub(crate) resources: HashMap<TemporalResourceKey, TemporalResourceState>,
+    /// Which image of each ping-pong pair was written last; see `get_or_create_ping_pong`.
+    pub(crate) ping_pong_parity: HashMap<TemporalResourceKey, bool>,
+    /// Images dropped from `resources`, e.g. after a resize, which the state this one was
+    /// cloned from still refers to. See `release_stale_images`.
+    pub(crate) stale_images: Vec<Arc<Image>>,
 }
 
 impl TemporalRenderGraphState {
@@ -97,6 +106,23 @@ impl TemporalRenderGraphState {
                     }
                 })
                 .collect(),
+            ping_pong_parity: self.ping_pong_parity.clone(),
+            stale_images: Vec::new(),
+        }
+    }
+
+    /// Releases the images dropped from the state, once this is their last owner.
+    /// Must be called after dropping the state this one was cloned from.
+    pub(crate) fn release_stale_images(&mut self, device: &Device) {
+        for image in self.stale_images.drain(..) {
+            match Arc::try_unwrap(image) {
+                // Deferred, as frames in flight may still use it
+                Ok(image) => device.defer_release_image(image),
+                Err(image) => log::warn!(
+                    "Leaking temporal image {:?}, which is still in use",
+                    image.desc
+                ),
+            }
         }
     }
 }
@@ -284,7 +310,113 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
     }
 }
 
+/// A temporal image pair which swaps roles every frame.
+pub struct TemporalPingPong {
+    /// Written in the previous frame
+    pub read: Handle<Image>,
+    /// Written in this frame, and read in the next one
+    pub write: Handle<Image>,
+    /// `read` was just created and cleared, e.g. on the first frame, or after a resize
+    /// changed the desc. Passes should skip blending in history when set.
+    pub was_reset: bool,
+}
+
 impl TemporalRenderGraph {
+    /// Gets the images of the ping-pong pair `key`, creating them if needed.
+    /// Images whose desc doesn't match `desc` anymore are recreated.
+    pub fn get_or_create_ping_pong(
+        &mut self,
+        key: impl Into<TemporalResourceKey>,
+        desc: ImageDesc,
+    ) -> anyhow::Result<TemporalPingPong> {
+        let key = key.into();
+
+        // Reset history gets cleared by a transfer
+        let desc = desc.usage(desc.usage | vk::ImageUsageFlags::TRANSFER_DST);
+
+        let parity = self
+            .temporal_state
+            .ping_pong_parity
+            .entry(key.clone())
+            .or_default();
+        *parity = !*parity;
+
+        let (read_key, write_key) = {
+            let first = TemporalResourceKey(format!("{}:0", key.0));
+            let second = TemporalResourceKey(format!("{}:1", key.0));
+            if *parity {
+                (first, second)
+            } else {
+                (second, first)
+            }
+        };
+
+        if !self.temporal_image_matches(&read_key, &desc)
+            || !self.temporal_image_matches(&write_key, &desc)
+        {
+            self.release_temporal_image(&read_key);
+            self.release_temporal_image(&write_key);
+        }
+
+        let was_reset = !self.temporal_state.resources.contains_key(&read_key);
+
+        let mut read = self.get_or_create_temporal(read_key, desc)?;
+        let write = self.get_or_create_temporal(write_key, desc)?;
+
+        if was_reset {
+            if image_aspect_mask_from_format(desc.format) == vk::ImageAspectFlags::COLOR {
+                crate::imageops::clear_color(&mut self.rg, &mut read, [0.0; 4]);
+            } else {
+                crate::imageops::clear_depth(&mut self.rg, &mut read);
+            }
+        }
+
+        Ok(TemporalPingPong {
+            read,
+            write,
+            was_reset,
+        })
+    }
+
+    /// Missing images count as matching.
+    fn temporal_image_matches(&self, key: &TemporalResourceKey, desc: &ImageDesc) -> bool {
+        match self.temporal_state.resources.get(key) {
+            Some(TemporalResourceState::Inert {
+                resource: TemporalResource::Image(image),
+                ..
+            }) => image.desc == *desc,
+            _ => true,
+        }
+    }
+
+    fn release_temporal_image(&mut self, key: &TemporalResourceKey) {
+        if let Some(TemporalResourceState::Inert {
+            resource: TemporalResource::Image(image),
+            ..
+        }) = self.temporal_state.resources.remove(key)
+        {
+            // Deferred, as frames in flight may still use it
+            match Arc::try_unwrap(image) {
+                Ok(image) => self.device.defer_release_image(image),
+                Err(image) => self.temporal_state.stale_images.push(image),
+            }
+        }
+    }
+
+    /// Keeps the image alive after this frame, in `access_type`. See `ExportedImage`.
+    pub fn export_image(
+        &mut self,
+        handle: Handle<Image>,
+        access_type: AccessType,
+    ) -> ExportedImage {
+        let resource_idx = handle.raw.id;
+        self.rg.export(handle, access_type);
+
+        let state = Arc::new(ExportedImageState::new(self.device.clone()));
+        self.rg.exported_images.push((resource_idx, state.clone()));
+        ExportedImage(state)
+    }
+
     pub fn export_temporal(self) -> (RenderGraph, ExportedTemporalRenderGraphState) {
         let mut rg = self.rg;
         let mut state = self.temporal_state;
@@ -309,6 +441,9 @@ impl TemporalRenderGraph {
                             handle: ExportedResourceHandle::Buffer(handle),
                         }
                     }
+                    ExportableGraphResource::RayTracingAcceleration(_) => {
+                        unreachable!("Temporal resources are images or buffers")
+                    }
                 },
                 TemporalResourceState::Exported { .. } => {
                     unreachable!()
@@ -321,34 +456,47 @@ impl TemporalRenderGraph {
 }
 
 impl ExportedTemporalRenderGraphState {
-    pub fn retire_temporal(self, rg: &RetiredRenderGraph) -> TemporalRenderGraphState {
-        let mut state = self.0;
-
-        for state in state.resources.values_mut() {
-            match state {
-                TemporalResourceState::Inert { .. } => {
-                    // Nothing to do here
-                }
-                TemporalResourceState::Imported { .. } => {
-                    unreachable!()
-                }
-                TemporalResourceState::Exported { resource, handle } => match handle {
-                    ExportedResourceHandle::Image(handle) => {
-                        *state = TemporalResourceState::Inert {
-                            resource: resource.clone(),
-                            access_type: rg.exported_resource(*handle).1,
-                        }
+    /// Takes `&self`, so that the state can be retired again when a retained graph re-executes.
+    pub fn retire_temporal(&self, rg: &RetiredRenderGraph) -> TemporalRenderGraphState {
+        let resources = self
+            .0
+            .resources
+            .iter()
+            .map(|(key, state)| {
+                let state = match state {
+                    TemporalResourceState::Inert {
+                        resource,
+                        access_type,
+                    } => TemporalResourceState::Inert {
+                        resource: resource.clone(),
+                        access_type: *access_type,
+                    },
+                    TemporalResourceState::Imported { .. } => {
+                        unreachable!()
                     }
-                    ExportedResourceHandle::Buffer(handle) => {
-                        *state = TemporalResourceState::Inert {
+                    TemporalResourceState::Exported { resource, handle } => {
+                        TemporalResourceState::Inert {
                             resource: resource.clone(),
-                            access_type: rg.exported_resource(*handle).1,
+                            access_type: match handle {
+                                ExportedResourceHandle::Image(handle) => {
+                                    rg.exported_resource(*handle).1
+                                }
+                                ExportedResourceHandle::Buffer(handle) => {
+                                    rg.exported_resource(*handle).1
+                                }
+                            },
                         }
                     }
-                },
-            }
-        }
+                };
+
+                (key.clone(), state)
+            })
+            .collect();
 
-        state
+        TemporalRenderGraphState {
+            resources,
+            ping_pong_parity: self.0.ping_pong_parity.clone(),
+            stale_images: Vec::new(),
+        }
     }
 }
@@ -0,0 +1,62 @@
+use crate::{Buffer, BufferDesc, Handle, RenderGraph};
+use kajiya_backend::{ash::vk, vk_sync::AccessType};
+
+impl RenderGraph {
+    /// Creates a buffer filled with `data` by an upload pass, recorded right away
+    /// so that it runs before any pass using the buffer.
+    ///
+    /// The bytes are staged through the dynamic constants ring. Uploads which don't fit
+    /// get a dedicated staging buffer, released once the frame retires.
+    pub fn upload_buffer(&mut self, data: &[u8], usage: vk::BufferUsageFlags) -> Handle<Buffer> {
+        assert!(!data.is_empty(), "Can't upload an empty buffer");
+
+        let mut buffer = self.create(BufferDesc::new_gpu_only(
+            data.len(),
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+        ));
+
+        let mut pass = self.add_pass("upload buffer");
+        let buffer_ref = pass.write(&mut buffer, AccessType::TransferWrite);
+        let data = data.to_vec();
+
+        pass.render(move |api| {
+            let staged_offset = api.dynamic_constants().try_push_bytes(&data);
+            let device = api.device();
+
+            let (staging_buffer, staging_offset) = if let Some(offset) = staged_offset {
+                (api.resources.dynamic_constants.buffer.raw, offset as u64)
+            } else {
+                let mut staging_buffer = device.create_buffer(
+                    BufferDesc::new_cpu_to_gpu(data.len(), vk::BufferUsageFlags::TRANSFER_SRC),
+                    "upload staging buffer",
+                    None,
+                )?;
+                staging_buffer.allocation.mapped_slice_mut().unwrap()[..data.len()]
+                    .copy_from_slice(&data);
+
+                let raw = staging_buffer.raw;
+                device.defer_release_buffer(staging_buffer);
+                (raw, 0)
+            };
+
+            let buffer = api.resources.buffer(buffer_ref);
+
+            unsafe {
+                device.raw.cmd_copy_buffer(
+                    api.cb.raw,
+                    staging_buffer,
+                    buffer.raw,
+                    &[vk::BufferCopy {
+                        src_offset: staging_offset,
+                        dst_offset: 0,
+                        size: data.len() as u64,
+                    }],
+                );
+            }
+
+            Ok(())
+        });
+
+        buffer
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{
+    graph::{
+        plan_transitions, GraphResourceCreateInfo, GraphResourceImportInfo, GraphResourceInfo,
+        RecordedPass, ResourceInfo,
+    },
+    AccessTypes, GraphResourceDesc, GraphValidationError, PassResourceAccessSyncType,
+    PassResourceAccessType, RenderGraph,
+};
+use kajiya_backend::{ash::vk, vk_sync, vulkan::barrier::image_aspect_mask_from_format};
+use std::collections::HashSet;
+
+/// What executing a graph would do, as found by `RenderGraph::validate`.
+#[derive(Clone, Debug)]
+pub struct GraphReport {
+    /// Names of the passes which survive culling, in the order they run in
+    pub passes: Vec<String>,
+    /// Indexed by resource id
+    pub resources: Vec<ResourceReport>,
+    /// In the order they're recorded in
+    pub barriers: Vec<PlannedBarrier>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResourceReport {
+    /// As used in logs, e.g. `ssgi.raw#12`
+    pub name: String,
+    /// Indices into `GraphReport::passes` of the first and last pass using the resource,
+    /// or `None` if no pass does. Imported and exported resources live until the ends of the graph.
+    pub lifetime: Option<(usize, usize)>,
+    /// Usage flags the graph gives images, including those of their desc
+    pub image_usage: vk::ImageUsageFlags,
+    /// Usage flags the graph gives buffers, including those of their desc
+    pub buffer_usage: vk::BufferUsageFlags,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PlannedBarrier {
+    /// Index into `GraphReport::passes` of the pass the barrier is recorded for,
+    /// or `None` for the transitions of exported resources to their export access types
+    pub pass: Option<usize>,
+    pub resource: u32,
+    /// `None` for the whole resource
+    pub mip: Option<u32>,
+    pub prev_access: AccessTypes,
+    pub next_access: AccessTypes,
+}
+
+impl RenderGraph {
+    /// Checks the graph without a device, and reports the passes it would run, the lifetimes
+    /// and usage of its resources, and the barriers between passes. No resources are created,
+    /// and no render functions run.
+    ///
+    /// On top of `validation_errors`, this checks the usage flags resources need.
+    /// Barriers are planned as if the graph ran on a single queue.
+    pub fn validate(&self) -> Result<GraphReport, Vec<GraphValidationError>> {
+        let mut errors = self.validation_errors();
+
+        let passes: Vec<&RecordedPass> = self
+            .passes
+            .iter()
+            .zip(self.live_passes())
+            .filter_map(|(pass, live)| live.then(|| pass))
+            .collect();
+
+        let resource_info = self.calculate_resource_info(&passes);
+        errors.extend(self.usage_errors(&resource_info));
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let resources = resource_info
+            .lifetimes
+            .iter()
+            .enumerate()
+            .map(|(id, lifetime)| ResourceReport {
+                name: self.resource_debug_name(id as u32),
+                lifetime: lifetime.first_access.zip(lifetime.last_access),
+                image_usage: resource_info.image_usage_flags[id],
+                buffer_usage: resource_info.buffer_usage_flags[id],
+            })
+            .collect();
+
+        Ok(GraphReport {
+            passes: passes.iter().map(|pass| pass.name.clone()).collect(),
+            resources,
+            barriers: self.plan_barriers(&passes),
+        })
+    }
+
+    fn usage_errors(&self, resource_info: &ResourceInfo) -> Vec<GraphValidationError> {
+        let mut errors = Vec::new();
+
+        for (id, resource) in self.resources.iter().enumerate() {
+            let image_usage = resource_info.image_usage_flags[id];
+            let buffer_usage = resource_info.buffer_usage_flags[id];
+
+            let incompatible = match resource {
+                GraphResourceInfo::Created(GraphResourceCreateInfo {
+                    desc: GraphResourceDesc::Image(desc),
+                    ..
+                }) => {
+                    let aspect_mask = image_aspect_mask_from_format(desc.format);
+                    let mut incompatible = vk::ImageUsageFlags::empty();
+
+                    if !aspect_mask.contains(vk::ImageAspectFlags::COLOR) {
+                        incompatible |= image_usage & vk::ImageUsageFlags::COLOR_ATTACHMENT;
+                    }
+                    if !aspect_mask
+                        .intersects(vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL)
+                    {
+                        incompatible |= image_usage & vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+                    }
+
+                    (!incompatible.is_empty()).then(|| format!("{:?}", incompatible))
+                }
+                GraphResourceInfo::Imported(GraphResourceImportInfo::Image {
+                    resource, ..
+                }) => {
+                    let missing = image_usage & !resource.desc.usage;
+                    (!missing.is_empty()).then(|| format!("{:?}", missing))
+                }
+                GraphResourceInfo::Imported(GraphResourceImportInfo::Buffer {
+                    resource, ..
+                }) => {
+                    let missing = buffer_usage & !resource.desc.usage;
+                    (!missing.is_empty()).then(|| format!("{:?}", missing))
+                }
+                _ => None,
+            };
+
+            if let Some(usage) = incompatible {
+                errors.push(GraphValidationError::IncompatibleUsage {
+                    resource: self.resource_debug_name(id as u32),
+                    usage,
+                });
+            }
+        }
+
+        errors
+    }
+
+    /// Follows the rules the graph records barriers with when it executes.
+    fn plan_barriers(&self, passes: &[&RecordedPass]) -> Vec<PlannedBarrier> {
+        struct TrackedResource {
+            access_types: AccessTypes,
+            mip_access_types: Vec<AccessTypes>,
+            mip_levels: u32,
+        }
+
+        let mut resources: Vec<TrackedResource> = self
+            .resources
+            .iter()
+            .map(|resource| {
+                let (access_type, mip_levels) = match resource {
+                    GraphResourceInfo::Created(GraphResourceCreateInfo {
+                        desc: GraphResourceDesc::Image(desc),
+                        ..
+                    }) => (vk_sync::AccessType::Nothing, desc.mip_levels as u32),
+                    GraphResourceInfo::Created(_) => (vk_sync::AccessType::Nothing, 1),
+                    GraphResourceInfo::Imported(GraphResourceImportInfo::Image {
+                        resource,
+                        access_type,
+                    }) => (*access_type, resource.desc.mip_levels as u32),
+                    GraphResourceInfo::Imported(GraphResourceImportInfo::Buffer {
+                        access_type,
+                        ..
+                    })
+                    | GraphResourceInfo::Imported(
+                        GraphResourceImportInfo::RayTracingAcceleration { access_type, .. },
+                    ) => (*access_type, 1),
+                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage {
+                        ..
+                    }) => (vk_sync::AccessType::ComputeShaderWrite, 1),
+                };
+
+                TrackedResource {
+                    access_types: access_type.into(),
+                    mip_access_types: Vec::new(),
+                    mip_levels,
+                }
+            })
+            .collect();
+
+        let mut barriers = Vec::new();
+        let mut transition = |pass: Option<usize>,
+                              id: u32,
+                              access: PassResourceAccessType,
+                              mip_range: Option<(u32, u32)>| {
+            let resource = &mut resources[id as usize];
+            let transitions = plan_transitions(
+                &mut resource.access_types,
+                &mut resource.mip_access_types,
+                resource.mip_levels,
+                access,
+                mip_range,
+            );
+
+            barriers.extend(
+                transitions
+                    .into_iter()
+                    .map(|(mip, prev_access)| PlannedBarrier {
+                        pass,
+                        resource: id,
+                        mip,
+                        prev_access,
+                        next_access: access.access_types,
+                    }),
+            );
+        };
+
+        for (pass_idx, pass) in passes.iter().enumerate() {
+            // As in execution, whole resource accesses widen per-mip ones in the same pass
+            let whole_resources: HashSet<u32> = pass
+                .read
+                .iter()
+                .chain(pass.write.iter())
+                .filter(|resource_ref| resource_ref.mip_range.is_none())
+                .map(|resource_ref| resource_ref.handle.id)
+                .collect();
+
+            for resource_ref in pass.read.iter().chain(pass.write.iter()) {
+                let mip_range = resource_ref
+                    .mip_range
+                    .filter(|_| !whole_resources.contains(&resource_ref.handle.id));
+
+                transition(
+                    Some(pass_idx),
+                    resource_ref.handle.id,
+                    resource_ref.access,
+                    mip_range,
+                );
+            }
+        }
+
+        for (resource, access_type) in &self.exported_resources {
+            if *access_type != vk_sync::AccessType::Nothing {
+                transition(
+                    None,
+                    resource.raw().id,
+                    PassResourceAccessType::new(
+                        *access_type,
+                        PassResourceAccessSyncType::AlwaysSync,
+                    ),
+                    None,
+                );
+            }
+        }
+
+        barriers
+    }
+}
+
+#[test]
+fn test_validate_report() {
+    use crate::{Handle, Image, ImageDesc};
+    use vk_sync::AccessType;
+
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+    let mut rg = RenderGraph::new();
+    let mut gbuffer = rg.create_named(desc, "gbuffer");
+    let mut dead = rg.create(desc);
+    let mut pyramid = rg.create_named(desc.mip_levels(2), "pyramid");
+
+    let compute_read = AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer;
+    let add_pass = |rg: &mut RenderGraph, name: &str, dst: &mut Handle<Image>| {
+        let mut pass = rg.add_pass(name);
+        pass.write(dst, AccessType::ColorAttachmentWrite);
+    };
+
+    add_pass(&mut rg, "gbuffer", &mut gbuffer);
+    add_pass(&mut rg, "dead", &mut dead);
+    {
+        let mut pass = rg.add_pass("downsample 0");
+        pass.read(&gbuffer, compute_read);
+        pass.write_mips(&mut pyramid, AccessType::ComputeShaderWrite, (0, 1));
+    }
+    {
+        let mut pass = rg.add_pass("downsample 1");
+        pass.read_mips(&pyramid, compute_read, (0, 1));
+        pass.write_mips(&mut pyramid, AccessType::ComputeShaderWrite, (1, 1));
+    }
+    rg.export(
+        pyramid,
+        AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+    );
+
+    let report = rg.validate().unwrap();
+
+    assert_eq!(report.passes, ["gbuffer", "downsample 0", "downsample 1"]);
+
+    assert_eq!(report.resources[0].name, "gbuffer#0");
+    assert_eq!(report.resources[0].lifetime, Some((0, 1)));
+    assert!(report.resources[0]
+        .image_usage
+        .contains(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED));
+    // Only used by the culled pass
+    assert_eq!(report.resources[1].lifetime, None);
+    // Exported, so it lives until the end
+    assert_eq!(report.resources[2].lifetime, Some((1, 2)));
+
+    let barrier =
+        |pass, resource, mip, prev_access: AccessType, next_access: AccessType| PlannedBarrier {
+            pass,
+            resource,
+            mip,
+            prev_access: prev_access.into(),
+            next_access: next_access.into(),
+        };
+
+    assert_eq!(
+        report.barriers,
+        vec![
+            barrier(
+                Some(0),
+                0,
+                None,
+                AccessType::Nothing,
+                AccessType::ColorAttachmentWrite
+            ),
+            barrier(
+                Some(1),
+                0,
+                None,
+                AccessType::ColorAttachmentWrite,
+                compute_read
+            ),
+            barrier(
+                Some(1),
+                2,
+                Some(0),
+                AccessType::Nothing,
+                AccessType::ComputeShaderWrite
+            ),
+            barrier(
+                Some(2),
+                2,
+                Some(0),
+                AccessType::ComputeShaderWrite,
+                compute_read
+            ),
+            barrier(
+                Some(2),
+                2,
+                Some(1),
+                AccessType::Nothing,
+                AccessType::ComputeShaderWrite
+            ),
+            // The export brings all mips to the same state
+            barrier(
+                None,
+                2,
+                Some(0),
+                compute_read,
+                AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer
+            ),
+            barrier(
+                None,
+                2,
+                Some(1),
+                AccessType::ComputeShaderWrite,
+                AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_validate_errors() {
+    use crate::ImageDesc;
+    use vk_sync::AccessType;
+
+    let mut rg = RenderGraph::new();
+    let unwritten = rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]));
+    let mut depth = rg.create_named(ImageDesc::new_2d(vk::Format::D32_SFLOAT, [4, 4]), "depth");
+
+    {
+        let mut pass = rg.add_pass("draw");
+        pass.read(
+            &unwritten,
+            AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut depth, AccessType::ColorAttachmentWrite);
+    }
+
+    assert_eq!(
+        rg.validate().unwrap_err(),
+        vec![
+            GraphValidationError::UnwrittenRead {
+                pass: "draw".to_owned(),
+                resource: "#0".to_owned(),
+            },
+            GraphValidationError::IncompatibleUsage {
+                resource: "depth#1".to_owned(),
+                usage: "COLOR_ATTACHMENT".to_owned(),
+            },
+        ]
+    );
+}
@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+
+use kajiya::{
+    backend::{ash::vk, vk_sync::AccessType, vulkan::RenderBackendConfig, *},
+    frame_desc::WorldFrameDesc,
+    rg,
+    ui_renderer::UiRenderer,
+    world_renderer::WorldRenderer,
+};
+
+use turbosloth::*;
+
+use crate::SimpleMainLoopBuilder;
+
+/// Renders into an offscreen image instead of a window, and reads it back.
+/// Works on machines without a display, and on devices which can't present.
+pub struct HeadlessMainLoop {
+    pub world_renderer: WorldRenderer,
+    ui_renderer: UiRenderer,
+
+    rg_renderer: kajiya::rg::renderer::Renderer,
+    render_extent: [u32; 2],
+    output_image: Arc<Image>,
+
+    // Keeps the device alive; dropped last.
+    _render_backend: RenderBackend,
+}
+
+impl HeadlessMainLoop {
+    pub(crate) fn build(builder: SimpleMainLoopBuilder) -> anyhow::Result<Self> {
+        kajiya::logging::set_up_logging(builder.default_log_level)?;
+        std::env::set_var("SMOL_THREADS", "64"); // HACK; TODO: get a real executor
+
+        let output_extent = builder.resolution;
+        let render_extent = [
+            (output_extent[0] as f32 / builder.temporal_upsampling) as u32,
+            (output_extent[1] as f32 / builder.temporal_upsampling) as u32,
+        ];
+
+        log::info!(
+            "Internal rendering extent: {}x{}, output extent: {}x{}",
+            render_extent[0],
+            render_extent[1],
+            output_extent[0],
+            output_extent[1]
+        );
+
+        let render_backend = RenderBackend::new_headless(RenderBackendConfig {
+            swapchain_extent: output_extent,
+            vsync: false,
+            graphics_debugging: builder.graphics_debugging,
+            device_index: builder.physical_device_index,
+        })?;
+
+        let lazy_cache = LazyCache::create();
+        let world_renderer =
+            WorldRenderer::new(render_extent, output_extent, &render_backend, &lazy_cache)?;
+        let rg_renderer = kajiya::rg::renderer::Renderer::new(&render_backend)?;
+
+        let output_image = Arc::new(
+            render_backend.device.create_image(
+                ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, output_extent)
+                    .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC),
+                vec![],
+            )?,
+        );
+
+        Ok(Self {
+            world_renderer,
+            ui_renderer: UiRenderer::default(),
+            rg_renderer,
+            render_extent,
+            output_image,
+            _render_backend: render_backend,
+        })
+    }
+
+    pub fn render_extent(&self) -> [u32; 2] {
+        self.render_extent
+    }
+
+    /// The desc of images returned by `render_to_image`
+    pub fn output_desc(&self) -> ImageDesc {
+        self.output_image.desc
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.render_extent[0] as f32 / self.render_extent[1] as f32
+    }
+
+    /// Renders `frame_count` frames of `frame_desc`, so that temporal effects can converge,
+    /// and returns the last one as tightly packed sRGB `R8G8B8A8_UNORM` texels.
+    pub fn render_to_image(
+        &mut self,
+        frame_desc: &WorldFrameDesc,
+        frame_count: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(
+            frame_count > 0,
+            "Headless rendering needs at least one frame"
+        );
+
+        let result: Arc<Mutex<Option<Vec<u8>>>> = Default::default();
+
+        for frame_idx in 0..frame_count {
+            let Self {
+                world_renderer,
+                ui_renderer,
+                rg_renderer,
+                output_image,
+                ..
+            } = self;
+
+            let output_extent = [output_image.desc.extent[0], output_image.desc.extent[1]];
+            let is_last_frame = frame_idx + 1 == frame_count;
+
+            rg_renderer.prepare_frame(|rg| {
+                rg.debug_hook = world_renderer.rg_debug_hook.take();
+                rg.graphviz_dump_path = world_renderer.rg_graphviz_dump_path.take();
+                let main_img = world_renderer.prepare_render_graph(rg, frame_desc);
+                let ui_img = ui_renderer.prepare_render_graph(rg);
+
+                let mut output = rg.import(output_image.clone(), AccessType::Nothing);
+                rg::SimpleRenderPass::new_compute(
+                    rg.add_pass("final blit"),
+                    "/shaders/final_blit.hlsl",
+                )
+                .read(&main_img)
+                .read(&ui_img)
+                .write(&mut output)
+                .constants((
+                    main_img.desc().extent_inv_extent_2d(),
+                    [
+                        output_extent[0] as f32,
+                        output_extent[1] as f32,
+                        1.0 / output_extent[0] as f32,
+                        1.0 / output_extent[1] as f32,
+                    ],
+                ))
+                .dispatch([output_extent[0], output_extent[1], 1]);
+
+                if is_last_frame {
+                    let result = result.clone();
+                    rg.readback(&output, move |data| {
+                        *result.lock().unwrap() = Some(data.to_vec());
+                    });
+                }
+            })?;
+
+            rg_renderer.draw_frame_offscreen(
+                |dynamic_constants| {
+                    // Headless frames don't depend on wall-clock time
+                    world_renderer.prepare_frame_constants(
+                        dynamic_constants,
+                        frame_desc,
+                        1.0 / 60.0,
+                    )
+                },
+                output_extent,
+            );
+            world_renderer.retire_frame();
+        }
+
+        self.rg_renderer.flush_readbacks();
+
+        let data = result.lock().unwrap().take();
+        data.ok_or_else(|| anyhow::anyhow!("The output image was not read back"))
+    }
+}
@@ -1,7 +1,9 @@
+mod headless;
 mod input;
 mod main_loop;
 
 pub use glam::*;
+pub use headless::*;
 pub use input::*;
 pub use kajiya::{
     backend::{
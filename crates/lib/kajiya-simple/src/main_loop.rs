@@ -1,7 +1,12 @@
 use std::collections::VecDeque;
 
 use kajiya::{
-    backend::{vulkan::RenderBackendConfig, *},
+    backend::{
+        shader_compiler::ShaderCompileError,
+        transient_resource_cache::TransientResourceCacheStats,
+        vulkan::{swapchain::Swapchain, RenderBackendConfig},
+        *,
+    },
     frame_desc::WorldFrameDesc,
     rg,
     ui_renderer::UiRenderer,
@@ -13,6 +18,8 @@ use kajiya_imgui::ImGuiBackend;
 
 use turbosloth::*;
 
+use crate::HeadlessMainLoop;
+
 use winit::{
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
@@ -26,6 +33,12 @@ pub struct FrameContext<'a> {
     pub events: &'a [Event<'static, ()>],
     pub world_renderer: &'a mut WorldRenderer,
     pub window: &'a winit::window::Window,
+    /// Shaders which failed to hot-reload
+    pub shader_compile_errors: Vec<ShaderCompileError>,
+    /// Render graph transients created, released and pooled in the last frame
+    pub transient_resource_stats: TransientResourceCacheStats,
+    /// CPU time spent building, or re-using, the last frame's render graph
+    pub render_graph_cpu_stats: kajiya::rg::renderer::RenderGraphCpuStats,
 
     #[cfg(feature = "dear-imgui")]
     pub imgui: Option<ImguiContext<'a>>,
@@ -84,14 +97,15 @@ pub enum FullscreenMode {
 }
 
 pub struct SimpleMainLoopBuilder {
-    resolution: [u32; 2],
+    pub(crate) resolution: [u32; 2],
     vsync: bool,
     fullscreen: Option<FullscreenMode>,
-    graphics_debugging: bool,
-    physical_device_index: Option<usize>,
-    default_log_level: log::LevelFilter,
+    secondary_window: Option<WindowBuilder>,
+    pub(crate) graphics_debugging: bool,
+    pub(crate) physical_device_index: Option<usize>,
+    pub(crate) default_log_level: log::LevelFilter,
     window_scale: WindowScale,
-    temporal_upsampling: f32,
+    pub(crate) temporal_upsampling: f32,
 }
 
 impl Default for SimpleMainLoopBuilder {
@@ -106,6 +120,7 @@ impl SimpleMainLoopBuilder {
             resolution: [1280, 720],
             vsync: true,
             fullscreen: None,
+            secondary_window: None,
             graphics_debugging: false,
             physical_device_index: None,
             default_log_level: log::LevelFilter::Warn,
@@ -144,6 +159,14 @@ impl SimpleMainLoopBuilder {
         self
     }
 
+    /// Opens a second window, e.g. a detached debug view, which is rendered and presented
+    /// along with the main one. It currently shows a gradient. Closing it leaves the main
+    /// window running.
+    pub fn secondary_window(mut self, window_builder: Option<WindowBuilder>) -> Self {
+        self.secondary_window = window_builder;
+        self
+    }
+
     // TODO; not hooked up yet
     pub fn window_scale(mut self, window_scale: WindowScale) -> Self {
         self.window_scale = window_scale;
@@ -161,6 +184,18 @@ impl SimpleMainLoopBuilder {
     pub fn build(self, window_builder: WindowBuilder) -> anyhow::Result<SimpleMainLoop> {
         SimpleMainLoop::build(self, window_builder)
     }
+
+    /// Builds a loop without a window, which renders offscreen at `resolution`.
+    /// `vsync`, `fullscreen` and `window_scale` don't apply.
+    pub fn build_headless(self) -> anyhow::Result<HeadlessMainLoop> {
+        HeadlessMainLoop::build(self)
+    }
+}
+
+/// Has its own surface and swapchain on the main window's device.
+struct SecondaryWindow {
+    window: winit::window::Window,
+    swapchain: Swapchain,
 }
 
 pub struct SimpleMainLoop {
@@ -172,6 +207,7 @@ pub struct SimpleMainLoop {
 
     event_loop: EventLoop<()>,
     render_backend: RenderBackend,
+    secondary_window: Option<SecondaryWindow>,
     rg_renderer: kajiya::rg::renderer::Renderer,
     render_extent: [u32; 2],
 }
@@ -250,6 +286,24 @@ impl SimpleMainLoop {
             },
         )?;
 
+        let secondary_window = builder
+            .secondary_window
+            .map(|window_builder| -> anyhow::Result<SecondaryWindow> {
+                let window = window_builder.build(&event_loop)?;
+                let swapchain = render_backend.create_window_swapchain(
+                    &window,
+                    RenderBackendConfig {
+                        swapchain_extent: [window.inner_size().width, window.inner_size().height],
+                        vsync: builder.vsync,
+                        graphics_debugging: builder.graphics_debugging,
+                        device_index: builder.physical_device_index,
+                    },
+                )?;
+
+                Ok(SecondaryWindow { window, swapchain })
+            })
+            .transpose()?;
+
         let lazy_cache = LazyCache::create();
         let world_renderer = WorldRenderer::new(
             render_extent,
@@ -296,6 +350,7 @@ impl SimpleMainLoop {
             optional,
             event_loop,
             render_backend,
+            secondary_window,
             rg_renderer,
             render_extent,
         })
@@ -317,10 +372,15 @@ impl SimpleMainLoop {
             mut optional,
             mut event_loop,
             mut render_backend,
+            mut secondary_window,
             mut rg_renderer,
             render_extent,
         } = self;
 
+        // Closed secondary windows, and the frame index they were closed at. Kept until
+        // the device has destroyed their surfaces, which must not outlive the windows.
+        let mut closed_windows: Vec<(winit::window::Window, u64)> = Vec::new();
+
         let mut events = Vec::new();
 
         let mut last_frame_instant = std::time::Instant::now();
@@ -342,10 +402,24 @@ impl SimpleMainLoop {
             puffin::profile_scope!("main loop");
             puffin::GlobalProfiler::lock().new_frame();
 
+            let mut close_secondary_window = false;
+
             event_loop.run_return(|event, _, control_flow| {
                 puffin::profile_scope!("event handler");
 
                 let _ = &render_backend;
+
+                // The secondary window doesn't take input
+                if let Event::WindowEvent { window_id, event } = &event {
+                    if secondary_window
+                        .as_ref()
+                        .map_or(false, |secondary| secondary.window.id() == *window_id)
+                    {
+                        close_secondary_window |= matches!(event, WindowEvent::CloseRequested);
+                        *control_flow = ControlFlow::Poll;
+                        return;
+                    }
+                }
                 #[cfg(feature = "dear-imgui")]
                 optional
                     .imgui_backend
@@ -386,6 +460,18 @@ impl SimpleMainLoop {
 
             puffin::profile_scope!("MainEventsCleared");
 
+            if close_secondary_window {
+                if let Some(SecondaryWindow { window, swapchain }) = secondary_window.take() {
+                    // Destroyed once the GPU is done with it, along with its surface
+                    drop(swapchain);
+                    window.set_visible(false);
+                    closed_windows.push((window, render_backend.device.frame_index()));
+                }
+            }
+
+            let retired_frame_count = render_backend.device.retired_frame_count();
+            closed_windows.retain(|(_, closed_at)| *closed_at >= retired_frame_count);
+
             // Filter the frame time before passing it to the application and renderer.
             // Fluctuations in frame rendering times cause stutter in animations,
             // and time-dependent effects (such as motion blur).
@@ -423,6 +509,9 @@ impl SimpleMainLoop {
                 events: &events,
                 world_renderer: &mut world_renderer,
                 window: &window,
+                shader_compile_errors: rg_renderer.shader_compile_errors().cloned().collect(),
+                transient_resource_stats: rg_renderer.transient_resource_stats(),
+                render_graph_cpu_stats: rg_renderer.render_graph_cpu_stats(),
 
                 #[cfg(feature = "dear-imgui")]
                 imgui: Some(ImguiContext {
@@ -443,6 +532,7 @@ impl SimpleMainLoop {
                 puffin::profile_scope!("prepare_frame");
                 rg_renderer.prepare_frame(|rg| {
                     rg.debug_hook = world_renderer.rg_debug_hook.take();
+                    rg.graphviz_dump_path = world_renderer.rg_graphviz_dump_path.take();
                     let main_img = world_renderer.prepare_render_graph(rg, &frame_desc);
                     let ui_img = ui_renderer.prepare_render_graph(rg);
 
@@ -464,13 +554,48 @@ impl SimpleMainLoop {
                         ],
                     ))
                     .dispatch([swapchain_extent[0], swapchain_extent[1], 1]);
+
+                    if let Some(secondary_window) = &secondary_window {
+                        let extent = secondary_window.swapchain.extent();
+                        let mut output = rg.get_swap_chain_output(1);
+                        rg::SimpleRenderPass::new_compute(
+                            rg.add_pass("secondary window"),
+                            "/shaders/gradient.hlsl",
+                        )
+                        .write(&mut output)
+                        .constants([
+                            extent[0] as f32,
+                            extent[1] as f32,
+                            1.0 / extent[0] as f32,
+                            1.0 / extent[1] as f32,
+                        ])
+                        .dispatch([extent[0], extent[1], 1]);
+                    }
+
+                    if let Some(dir) = world_renderer.rg_frame_capture_dir.take() {
+                        if let Err(err) = kajiya::frame_capture::capture_frame(rg, dir) {
+                            log::error!("Frame capture failed: {:#}", err);
+                        }
+                    }
                 })
             };
 
             match prepared_frame {
                 Ok(()) => {
                     puffin::profile_scope!("draw_frame");
-                    rg_renderer.draw_frame(
+
+                    // In the order of `get_swap_chain_output`
+                    let mut swapchains = vec![render_backend
+                        .swapchain
+                        .as_mut()
+                        .expect("windowed backends have a swapchain")];
+                    swapchains.extend(
+                        secondary_window
+                            .as_mut()
+                            .map(|secondary| &mut secondary.swapchain),
+                    );
+
+                    rg_renderer.draw_frame_to_swapchains(
                         |dynamic_constants| {
                             world_renderer.prepare_frame_constants(
                                 dynamic_constants,
@@ -478,7 +603,7 @@ impl SimpleMainLoop {
                                 dt_filtered,
                             )
                         },
-                        &mut render_backend.swapchain,
+                        &mut swapchains,
                     );
                     world_renderer.retire_frame();
                     last_error_text = None;
@@ -1,139 +0,0 @@
-use std::collections::HashMap;
-
-use kajiya_backend::{ash::vk, rspirv_reflect, vulkan::device};
-
-lazy_static::lazy_static! {
-    pub static ref BINDLESS_DESCRIPTOR_SET_LAYOUT: HashMap<u32, rspirv_reflect::DescriptorInfo> = [
-        // `meshes`
-        (0, rspirv_reflect::DescriptorInfo {
-            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
-            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
-            name: Default::default(),
-        }),
-        // `vertices`
-        (1, rspirv_reflect::DescriptorInfo {
-            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
-            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
-            name: Default::default(),
-        }),
-        // `bindless_texture_sizes`
-        (2, rspirv_reflect::DescriptorInfo {
-            ty: rspirv_reflect::DescriptorType::STORAGE_BUFFER,
-            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
-            name: Default::default(),
-        }),
-        // `bindless_textures`
-        (BINDLESS_TEXURES_BINDING_INDEX as u32, rspirv_reflect::DescriptorInfo {
-            ty: rspirv_reflect::DescriptorType::SAMPLED_IMAGE,
-            dimensionality: rspirv_reflect::DescriptorDimensionality::RuntimeArray,
-            name: Default::default(),
-        }),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-}
-
-pub const BINDLESS_TEXURES_BINDING_INDEX: usize = 3;
-
-pub fn create_bindless_descriptor_set(device: &device::Device) -> vk::DescriptorSet {
-    let raw_device = &device.raw;
-
-    let set_binding_flags = [
-        vk::DescriptorBindingFlags::PARTIALLY_BOUND,
-        vk::DescriptorBindingFlags::PARTIALLY_BOUND,
-        vk::DescriptorBindingFlags::PARTIALLY_BOUND,
-        vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
-            | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING
-            | vk::DescriptorBindingFlags::PARTIALLY_BOUND
-            | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT,
-    ];
-
-    let mut binding_flags_create_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
-        .binding_flags(&set_binding_flags)
-        .build();
-
-    let descriptor_set_layout = unsafe {
-        raw_device
-            .create_descriptor_set_layout(
-                &vk::DescriptorSetLayoutCreateInfo::builder()
-                    .bindings(&[
-                        // `meshes`
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(0)
-                            .descriptor_count(1)
-                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                            .stage_flags(vk::ShaderStageFlags::ALL)
-                            .build(),
-                        // `vertices`
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(1)
-                            .descriptor_count(1)
-                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                            .stage_flags(vk::ShaderStageFlags::ALL)
-                            .build(),
-                        // `bindless_texture_sizes`
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(2)
-                            .descriptor_count(1)
-                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                            .stage_flags(vk::ShaderStageFlags::ALL)
-                            .build(),
-                        // `bindless_textures`
-                        vk::DescriptorSetLayoutBinding::builder()
-                            .binding(BINDLESS_TEXURES_BINDING_INDEX as _)
-                            .descriptor_count(device.max_bindless_descriptor_count() as _)
-                            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
-                            .stage_flags(vk::ShaderStageFlags::ALL)
-                            .build(),
-                    ])
-                    .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
-                    .push_next(&mut binding_flags_create_info)
-                    .build(),
-                None,
-            )
-            .unwrap()
-    };
-
-    let descriptor_sizes = [
-        vk::DescriptorPoolSize {
-            ty: vk::DescriptorType::STORAGE_BUFFER,
-            descriptor_count: 3,
-        },
-        vk::DescriptorPoolSize {
-            ty: vk::DescriptorType::SAMPLED_IMAGE,
-            descriptor_count: device.max_bindless_descriptor_count() as _,
-        },
-    ];
-
-    let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
-        .pool_sizes(&descriptor_sizes)
-        .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
-        .max_sets(1);
-
-    let descriptor_pool = unsafe {
-        raw_device
-            .create_descriptor_pool(&descriptor_pool_info, None)
-            .unwrap()
-    };
-
-    let variable_descriptor_count = device.max_bindless_descriptor_count() as _;
-    let mut variable_descriptor_count_allocate_info =
-        vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
-            .descriptor_counts(std::slice::from_ref(&variable_descriptor_count))
-            .build();
-
-    let set = unsafe {
-        raw_device
-            .allocate_descriptor_sets(
-                &vk::DescriptorSetAllocateInfo::builder()
-                    .descriptor_pool(descriptor_pool)
-                    .set_layouts(std::slice::from_ref(&descriptor_set_layout))
-                    .push_next(&mut variable_descriptor_count_allocate_info)
-                    .build(),
-            )
-            .unwrap()[0]
-    };
-
-    set
-}
@@ -0,0 +1,281 @@
+//! Writes every image of a frame's render graph to disk, for debugging.
+//!
+//! Float images are written as EXR, and UNORM ones as PNG. An `index.txt` lists
+//! the files along with the descs of their images, and the passes which last wrote them.
+
+use anyhow::Context as _;
+use half::f16;
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{CapturedImageInfo, RenderGraph};
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+/// A directory under `captures/` named after the current time.
+pub fn timestamped_capture_dir() -> PathBuf {
+    PathBuf::from("captures").join(chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string())
+}
+
+/// Reads back every image in `rg` once the frame retires, and writes them into `dir`.
+/// Call once all other passes have been added; see `RenderGraph::capture_images`.
+///
+/// Converting and writing the images happens on a worker thread, so that
+/// large captures don't stall rendering.
+pub fn capture_frame(rg: &mut RenderGraph, dir: impl Into<PathBuf>) -> anyhow::Result<()> {
+    let dir = dir.into();
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Creating frame capture directory {:?}", dir))?;
+
+    let (sender, receiver) = mpsc::channel::<(usize, Vec<u8>)>();
+    let images = rg.capture_images(move |idx, data| {
+        // The worker only stops once all senders are gone
+        let _ = sender.send((idx, data.to_vec()));
+    });
+
+    let file_names: Vec<String> = images
+        .iter()
+        .enumerate()
+        .map(|(idx, image)| capture_file_name(idx, image))
+        .collect();
+
+    let mut index = String::new();
+    for (image, file_name) in images.iter().zip(&file_names) {
+        writeln!(
+            index,
+            "{}\t{}\tlast written by {}\t{:?}",
+            file_name,
+            image.name,
+            image
+                .last_written_by
+                .as_ref()
+                .map_or_else(|| "nothing".to_owned(), |pass| format!("{:?}", pass)),
+            image.desc
+        )
+        .unwrap();
+    }
+
+    std::fs::write(dir.join("index.txt"), index)
+        .with_context(|| format!("Writing frame capture index to {:?}", dir))?;
+
+    std::thread::Builder::new()
+        .name("frame capture".to_owned())
+        .spawn(move || {
+            let mut written = 0;
+
+            for (idx, data) in receiver {
+                let path = dir.join(&file_names[idx]);
+                match write_image(&path, &images[idx].desc, &data) {
+                    Ok(()) => written += 1,
+                    Err(err) => log::error!("Failed to write {:?}: {:#}", path, err),
+                }
+            }
+
+            log::info!(
+                "Frame capture wrote {} of {} images to {:?}",
+                written,
+                images.len(),
+                dir
+            );
+        })
+        .context("Spawning the frame capture thread")?;
+
+    Ok(())
+}
+
+fn capture_file_name(idx: usize, image: &CapturedImageInfo) -> String {
+    let name: String = image
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let extension = if is_png_format(image.desc.format) {
+        "png"
+    } else {
+        "exr"
+    };
+
+    format!("{:04}_{}.{}", idx, name, extension)
+}
+
+fn is_png_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_UNORM
+            | vk::Format::R8G8_UNORM
+            | vk::Format::R8G8B8A8_UNORM
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_UNORM
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A2B10G10R10_UNORM_PACK32
+    )
+}
+
+/// Writes image data as read back by `RenderGraph::readback`, as PNG or EXR depending on the format.
+///
+/// `data` holds the top mip and first layer, with tightly packed rows.
+/// Slices of 3D images are stacked vertically.
+pub fn write_image(path: &Path, desc: &ImageDesc, data: &[u8]) -> anyhow::Result<()> {
+    let width = desc.extent[0];
+    let height = desc.extent[1] * desc.extent[2];
+
+    match desc.format {
+        vk::Format::R8_UNORM => {
+            image::GrayImage::from_raw(width, height, data.to_vec())
+                .context("Invalid image size")?
+                .save(path)?;
+        }
+        vk::Format::R8G8_UNORM => {
+            let rgb = data
+                .chunks_exact(2)
+                .flat_map(|texel| [texel[0], texel[1], 0])
+                .collect();
+
+            image::RgbImage::from_raw(width, height, rgb)
+                .context("Invalid image size")?
+                .save(path)?;
+        }
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => {
+            image::RgbaImage::from_raw(width, height, data.to_vec())
+                .context("Invalid image size")?
+                .save(path)?;
+        }
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+            let rgba = data
+                .chunks_exact(4)
+                .flat_map(|texel| [texel[2], texel[1], texel[0], texel[3]])
+                .collect();
+
+            image::RgbaImage::from_raw(width, height, rgba)
+                .context("Invalid image size")?
+                .save(path)?;
+        }
+        vk::Format::A2B10G10R10_UNORM_PACK32 => {
+            let unorm10 = |bits: u32| ((bits & 0x3ff) * 65535 / 1023) as u16;
+            let rgba = data
+                .chunks_exact(4)
+                .flat_map(|texel| {
+                    let texel = read_u32(texel);
+                    [
+                        unorm10(texel),
+                        unorm10(texel >> 10),
+                        unorm10(texel >> 20),
+                        ((texel >> 30) * 65535 / 3) as u16,
+                    ]
+                })
+                .collect();
+
+            image::ImageBuffer::<image::Rgba<u16>, Vec<u16>>::from_raw(width, height, rgba)
+                .context("Invalid image size")?
+                .save(path)?;
+        }
+        format => {
+            let texels = decode_float_texels(format, data)
+                .with_context(|| format!("Unsupported capture format {:?}", format))?;
+            anyhow::ensure!(
+                texels.len() == width as usize * height as usize,
+                "Invalid image size"
+            );
+
+            exr::prelude::write_rgba_file(path, width as usize, height as usize, |x, y| {
+                let [r, g, b, a] = texels[y * width as usize + x];
+                (r, g, b, a)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Texels of formats written as EXR. Missing channels are zero, with alpha defaulting to one.
+fn decode_float_texels(format: vk::Format, data: &[u8]) -> Option<Vec<[f32; 4]>> {
+    let decode: fn(&[u8]) -> [f32; 4] = match format {
+        vk::Format::R8_SNORM => |t| [(t[0] as i8 as f32 / 127.0).max(-1.0), 0.0, 0.0, 1.0],
+        vk::Format::R8_UINT => |t| [t[0] as f32, 0.0, 0.0, 1.0],
+        vk::Format::R16_UINT => |t| [read_u16(t) as f32, 0.0, 0.0, 1.0],
+        vk::Format::R32_UINT => |t| [read_u32(t) as f32, 0.0, 0.0, 1.0],
+        vk::Format::R16_SFLOAT => |t| [read_f16(t), 0.0, 0.0, 1.0],
+        vk::Format::R16G16_SFLOAT => |t| [read_f16(t), read_f16(&t[2..]), 0.0, 1.0],
+        vk::Format::R16G16B16A16_SFLOAT => |t| {
+            [
+                read_f16(t),
+                read_f16(&t[2..]),
+                read_f16(&t[4..]),
+                read_f16(&t[6..]),
+            ]
+        },
+        vk::Format::R32_SFLOAT | vk::Format::D32_SFLOAT => |t| [read_f32(t), 0.0, 0.0, 1.0],
+        vk::Format::R32G32_SFLOAT => |t| [read_f32(t), read_f32(&t[4..]), 0.0, 1.0],
+        vk::Format::R32G32B32_SFLOAT => {
+            |t| [read_f32(t), read_f32(&t[4..]), read_f32(&t[8..]), 1.0]
+        }
+        vk::Format::R32G32B32A32_SFLOAT => |t| {
+            [
+                read_f32(t),
+                read_f32(&t[4..]),
+                read_f32(&t[8..]),
+                read_f32(&t[12..]),
+            ]
+        },
+        vk::Format::B10G11R11_UFLOAT_PACK32 => |t| {
+            let t = read_u32(t);
+            [
+                unpack_ufloat(t & 0x7ff, 6),
+                unpack_ufloat((t >> 11) & 0x7ff, 6),
+                unpack_ufloat(t >> 22, 5),
+                1.0,
+            ]
+        },
+        vk::Format::E5B9G9R9_UFLOAT_PACK32 => |t| {
+            let t = read_u32(t);
+            let scale = 2f32.powi((t >> 27) as i32 - 15 - 9);
+            [
+                (t & 0x1ff) as f32 * scale,
+                ((t >> 9) & 0x1ff) as f32 * scale,
+                ((t >> 18) & 0x1ff) as f32 * scale,
+                1.0,
+            ]
+        },
+        _ => return None,
+    };
+
+    let texel_size = try_format_texel_size_bytes(format)? as usize;
+    Some(data.chunks_exact(texel_size).map(decode).collect())
+}
+
+/// Unsigned floats with a 5-bit exponent, as in `B10G11R11_UFLOAT_PACK32`.
+fn unpack_ufloat(bits: u32, mantissa_bits: u32) -> f32 {
+    let exponent = bits >> mantissa_bits;
+    let mantissa = (bits & ((1 << mantissa_bits) - 1)) as f32 / (1 << mantissa_bits) as f32;
+
+    match exponent {
+        0 => mantissa * 2f32.powi(-14),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa) * 2f32.powi(exponent as i32 - 15),
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_f16(bytes: &[u8]) -> f32 {
+    f16::from_bits(read_u16(bytes)).to_f32()
+}
+
+fn read_f32(bytes: &[u8]) -> f32 {
+    f32::from_bits(read_u32(bytes))
+}
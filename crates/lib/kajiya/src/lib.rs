@@ -1,5 +1,6 @@
 pub mod camera;
 pub mod default_world_renderer;
+pub mod frame_capture;
 pub mod frame_desc;
 pub mod image_cache;
 pub mod image_lut;
@@ -13,7 +14,6 @@ pub mod world_render_passes;
 pub mod world_renderer;
 pub mod world_renderer_mmap_adapter;
 
-mod bindless_descriptor_set;
 mod buffer_builder;
 
 pub use kajiya_asset as asset;
@@ -105,6 +105,8 @@ impl IrcacheRenderer {
                     RenderPassAttachmentDesc::new(vk::Format::R32G32B32A32_SFLOAT),
                 ],
                 depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                resolve_attachments: &[],
+                subpasses: &[],
             },
         );
 
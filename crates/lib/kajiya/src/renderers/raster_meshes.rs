@@ -145,7 +145,7 @@ pub fn raster_meshes(
             let raw_device = &api.device().raw;
             let cb = api.cb;
 
-            for (draw_idx, instance) in instances.into_iter().enumerate() {
+            for (draw_idx, instance) in instances.iter().enumerate() {
                 let mesh = &meshes[instance.mesh.0];
 
                 raw_device.cmd_bind_index_buffer(
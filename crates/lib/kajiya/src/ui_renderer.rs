@@ -26,7 +26,12 @@ impl UiRenderer {
             let mut pass = rg.add_pass("ui");
 
             pass.raster(&mut ui_tex, AccessType::ColorAttachmentWrite);
-            pass.render(move |api| ui_renderer(api.cb.raw));
+            // The callback can only run once; retained graphs leave the image as is afterwards
+            let mut ui_renderer = Some(ui_renderer);
+            pass.render(move |api| match ui_renderer.take() {
+                Some(ui_renderer) => ui_renderer(api.cb.raw),
+                None => Ok(()),
+            });
 
             ui_tex
         } else {
@@ -1,8 +1,4 @@
 use crate::{
-    bindless_descriptor_set::{
-        create_bindless_descriptor_set, BINDLESS_DESCRIPTOR_SET_LAYOUT,
-        BINDLESS_TEXURES_BINDING_INDEX,
-    },
     buffer_builder::BufferBuilder,
     frame_desc::WorldFrameDesc,
     image_lut::{ComputeImageLut, ImageLut},
@@ -18,7 +14,10 @@ use kajiya_backend::{
     ash::vk::{self, ImageView},
     dynamic_constants::DynamicConstants,
     vk_sync::{self, AccessType},
-    vulkan::{self, device, image::*, ray_tracing::*, shader::*, RenderBackend},
+    vulkan::{
+        self, bindless::BINDLESS_DESCRIPTOR_SET_LAYOUT, device, image::*, ray_tracing::*,
+        shader::*, RenderBackend,
+    },
     BackendError,
 };
 use kajiya_rg::{self as rg};
@@ -32,7 +31,7 @@ use rust_shaders_shared::{
     render_overrides::RenderOverrides,
     view_constants::ViewConstants,
 };
-use std::{collections::HashMap, mem::size_of, sync::Arc};
+use std::{collections::HashMap, mem::size_of, path::PathBuf, sync::Arc};
 use vulkan::buffer::{Buffer, BufferDesc};
 
 const USE_TAA_JITTER: bool = true;
@@ -167,7 +166,6 @@ pub struct WorldRenderer {
     accel_scratch: RayTracingAccelerationScratchBuffer,
 
     bindless_images: Vec<Arc<Image>>,
-    next_bindless_image_id: usize,
     next_instance_handle: usize,
     bindless_texture_sizes: Buffer,
 
@@ -179,6 +177,10 @@ pub struct WorldRenderer {
     supersample_offsets: Vec<Vec2>,
 
     pub rg_debug_hook: Option<rg::GraphDebugHook>,
+    /// Dump the next frame's render graph to this file, in the DOT format
+    pub rg_graphviz_dump_path: Option<PathBuf>,
+    /// Write every image of the next frame's render graph into this directory; see `frame_capture`
+    pub rg_frame_capture_dir: Option<PathBuf>,
     pub render_mode: RenderMode,
     pub reset_reference_accumulation: bool,
 
@@ -358,6 +360,8 @@ impl WorldRenderer {
                     RenderPassAttachmentDesc::new(vk::Format::R16G16B16A16_SFLOAT).garbage_input(),
                 ],
                 depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                resolve_attachments: &[],
+                subpasses: &[],
             },
         );
 
@@ -396,7 +400,7 @@ impl WorldRenderer {
             )
             .unwrap();
 
-        let bindless_descriptor_set = create_bindless_descriptor_set(backend.device.as_ref());
+        let bindless_descriptor_set = backend.device.bindless_descriptor_set().raw;
 
         // `meshes`
         Self::write_descriptor_set_buffer(
@@ -465,11 +469,12 @@ impl WorldRenderer {
             bindless_images: Default::default(),
             image_luts: Default::default(),
 
-            next_bindless_image_id: 0,
             next_instance_handle: 0,
             bindless_texture_sizes,
 
             rg_debug_hook: None,
+            rg_graphviz_dump_path: None,
+            rg_frame_capture_dir: None,
             render_mode: RenderMode::Standard,
             frame_idx: 0u32,
             prev_camera_matrices: None,
@@ -539,29 +544,12 @@ impl WorldRenderer {
     }
 
     fn add_bindless_image_view(&mut self, view: ImageView) -> BindlessImageHandle {
-        let handle = BindlessImageHandle(self.next_bindless_image_id as _);
-        self.next_bindless_image_id += 1;
-
-        let image_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(view)
-            .build();
-
-        let write_descriptor_set = vk::WriteDescriptorSet::builder()
-            .dst_set(self.bindless_descriptor_set)
-            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
-            .dst_binding(BINDLESS_TEXURES_BINDING_INDEX as _)
-            .dst_array_element(handle.0 as _)
-            .image_info(std::slice::from_ref(&image_info))
-            .build();
-
-        unsafe {
-            self.device
-                .raw
-                .update_descriptor_sets(std::slice::from_ref(&write_descriptor_set), &[]);
-        }
+        let handle = self
+            .device
+            .register_bindless_image_view(view)
+            .expect("register_bindless_image_view");
 
-        handle
+        BindlessImageHandle(handle.0)
     }
 
     pub fn add_image_lut(&mut self, computer: impl ComputeImageLut + 'static, id: usize) {
@@ -951,6 +939,10 @@ impl WorldRenderer {
         self.exposure_state[self.render_mode as usize]
     }
 
+    pub fn device(&self) -> &Arc<device::Device> {
+        &self.device
+    }
+
     pub fn prepare_render_graph(
         &mut self,
         rg: &mut rg::TemporalRenderGraph,